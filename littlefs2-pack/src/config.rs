@@ -28,10 +28,20 @@
 
 use globset::{Glob, GlobSet, GlobSetBuilder};
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
-pub const DEFAULT_IMAGE_NAME: &'static str = "filesystem";
+use crate::partition_table::rp2350::XIP_BASE;
+use crate::partition_table::{
+    AnyPartitionTable, PartitionLocation, locate_partition, parse_size_value,
+};
+use crate::presets::find_preset;
+
+mod interpolate;
+mod merge;
+
+pub const DEFAULT_IMAGE_NAME: &str = "filesystem";
 
 /// Errors that can occur when loading or validating a configuration file.
 #[derive(Debug, Error)]
@@ -52,10 +62,36 @@ pub enum ConfigError {
     #[error("specify block_count or image_size, not both")]
     BothSizingMethods,
 
-    /// Neither `block_count` nor `image_size` was specified. One is required.
-    #[error("specify either block_count or image_size")]
+    /// None of `block_count`, `image_size`, or a partition was specified.
+    #[error("specify block_count, image_size, or partition_table/partition_name")]
     NoSizingMethod,
 
+    /// The image is larger than the partition it is meant for.
+    #[error("image size ({image_size}) exceeds partition '{name}' ({size} bytes)")]
+    ImageExceedsPartition {
+        image_size: usize,
+        name: String,
+        size: u32,
+    },
+
+    /// The partition's offset or size is not a multiple of `block_size`.
+    #[error(
+        "partition '{name}' (offset {offset:#x}, size {size:#x}) is not aligned to block_size ({block_size})"
+    )]
+    PartitionAlignment {
+        name: String,
+        offset: u32,
+        size: u32,
+        block_size: usize,
+    },
+
+    /// `block_count * block_size` does not fit in a `usize`.
+    #[error("image size (block_count {block_count} * block_size {block_size}) is too large")]
+    ImageSizeOverflow {
+        block_count: usize,
+        block_size: usize,
+    },
+
     /// `image_size` is not an exact multiple of `block_size`.
     #[error("image_size ({image_size}) must be a multiple of block_size ({block_size})")]
     ImageSizeAlignment {
@@ -67,6 +103,22 @@ pub enum ConfigError {
     #[error("{0} is required when page_size is not set")]
     MissingSize(&'static str),
 
+    /// `block_size` is missing and no `preset` was set to provide it.
+    #[error("block_size is required when preset is not set")]
+    MissingBlockSize,
+
+    /// The image is larger than the flash chip named by `preset`.
+    #[error("image size ({image_size}) exceeds the {flash_size} byte flash of preset \"{preset}\"")]
+    ImageExceedsFlash {
+        image_size: usize,
+        preset: String,
+        flash_size: usize,
+    },
+
+    /// The `preset` name does not match any built-in preset.
+    #[error("unknown preset \"{0}\" (run `littlefs presets` to list them)")]
+    UnknownPreset(String),
+
     /// The configured root directory does not exist on disk.
     #[error("root directory not found at: {0}")]
     RootNotFound(PathBuf),
@@ -112,47 +164,233 @@ pub enum ConfigError {
     },
 
     /// Both `address` and `partition_table`/`partition_name` were specified
-    /// in `[flash.filesystem]`. Only one addressing method is allowed.
+    /// in `[flash.filesystem]` or `[images.flash]`. Only one addressing method is allowed.
     #[error("specify either address or partition_table/partition_name, not both")]
     BothAddressMethods,
 
     /// Neither `address` nor `partition_table`/`partition_name` was specified
-    /// in `[flash.filesystem]`.
-    #[error("specify either address or partition_table/partition_name in [flash.filesystem]")]
+    /// in `[flash.filesystem]` or `[images.flash]`.
+    #[error("specify either address or partition_table/partition_name for the filesystem flash")]
     NoAddressMethod,
 
-    /// Only one of `partition_table` or `partition_name` was specified.
+    /// A `[flash.filesystem]` or `[images.flash]` section has neither a
+    /// `command` nor a built-in `flasher`.
+    #[error("specify a command or a flasher for the filesystem flash")]
+    NoFlashCommand,
+
+    /// Only one of `partition_table` or `partition_name` was specified,
+    /// in either `[image]` or a flash section.
     /// Both are required when using partition-table addressing.
     #[error("both partition_table and partition_name must be specified together")]
     IncompletePartitionConfig,
 
+    /// Neither `[image]`/`[directory]` nor any `[[images]]` entries were given.
+    #[error("config must define [image] and [directory], or at least one [[images]] entry")]
+    NoImages,
+
+    /// A single-image config is missing one of its required sections.
+    #[error("missing [{0}] section")]
+    MissingSection(&'static str),
+
+    /// Top-level `[image]`/`[directory]` were combined with `[[images]]`.
+    #[error("use either [image]/[directory] or [[images]], not both")]
+    MixedImageForms,
+
+    /// `[flash.filesystem]` was used together with `[[images]]`.
+    #[error("[flash.filesystem] cannot be used with [[images]]; set [images.flash] per image")]
+    TopLevelFilesystemFlash,
+
+    /// Two images share the same name, so their outputs would collide.
+    #[error("duplicate image name \"{0}\"")]
+    DuplicateImageName(String),
+
+    /// No image with the requested name exists in the config.
+    #[error("no image named \"{0}\" in config")]
+    ImageNotFound(String),
+
+    /// The config defines several images and none was selected by name.
+    #[error("config defines multiple images ({}), select one by name", .0.join(", "))]
+    AmbiguousImage(Vec<String>),
+
+    /// A config file `extends` itself, directly or through other files.
+    #[error("config inheritance cycle through {0}")]
+    ExtendsCycle(PathBuf),
+
+    /// The `extends` key is not a path string.
+    #[error("`extends` in {0} must be a path string")]
+    InvalidExtends(PathBuf),
+
+    /// A `${VAR}` reference names an unset variable and has no default.
+    #[error("environment variable `{0}` is not set and has no default")]
+    UndefinedVariable(String),
+
+    /// A `${...}` reference is unterminated or has an empty name.
+    #[error("malformed variable reference in \"{0}\"")]
+    InvalidInterpolation(String),
+
+    /// The requested profile is not defined under `[profile]`.
+    #[error("profile \"{0}\" not found")]
+    ProfileNotFound(String),
+
+    /// A `[profile]` entry is not a table of overrides.
+    #[error("profile \"{0}\" must be a table of overrides")]
+    InvalidProfile(String),
+
+    /// More than one profile matched when selecting one automatically.
+    #[error("multiple profiles match ({}), select one explicitly", .0.join(", "))]
+    AmbiguousProfile(Vec<String>),
+
     /// Failed to resolve the flash address from the partition table.
     #[error("failed to read partition table")]
     PartitionTable(#[from] crate::partition_table::PartitionError),
+
+    /// A flash `address` is not a number.
+    #[error("flash address \"{0}\" is not a number")]
+    InvalidAddress(String),
+
+    /// A size such as `flash_size` is not a number.
+    #[error("{key} \"{value}\" is not a size")]
+    InvalidSize { key: &'static str, value: String },
+
+    /// A `[[flash.merge.segments]]` entry has both or neither of
+    /// `address` and `partition`.
+    #[error("merge segment '{0}' needs exactly one of address or partition")]
+    SegmentAddress(String),
+
+    /// `[flash.merge]` sets `fill` without a `flash_size` to fill to.
+    #[error("merge fill needs a flash_size")]
+    FillWithoutFlashSize,
+
+    /// A merge segment names a partition but no partition table is
+    /// configured.
+    #[error("merge segment '{0}' names a partition but no partition table is configured")]
+    NoPartitionTable(String),
 }
 
 /// Raw top-level configuration as deserialized from a TOML file.
 ///
+/// A config either describes a single image with top-level `[image]` and
+/// `[directory]` sections, or several images with an `[[images]]` array.
+///
 /// This is an intermediate representation — use [`Config::from_file`] to
 /// obtain a fully validated and resolved [`Config`].
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct RawConfig {
-    image: RawImageConfig,
-    directory: RawDirectoryConfig,
+    image: Option<RawImageConfig>,
+    directory: Option<RawDirectoryConfig>,
+    #[serde(default)]
+    images: Vec<RawImageTarget>,
     flash: Option<RawFlashConfig>,
 }
 
+impl RawConfig {
+    /// Validate and resolve into a checked [`Config`].
+    ///
+    /// Relative paths are resolved against `base_dir`. The single-image
+    /// form is normalized into a one-element [`Config::images`] list.
+    pub fn resolve(self, base_dir: PathBuf) -> Result<Config, ConfigError> {
+        let (firmware, filesystem, merge, readback, identify_command, identify_options, chip) =
+            match self.flash {
+                Some(flash) => (
+                    Some(flash.firmware),
+                    flash.filesystem,
+                    flash.merge,
+                    flash.readback,
+                    flash.identify_command,
+                    CommandOptions::new(flash.shell, flash.env, flash.cwd, &base_dir),
+                    flash.chip,
+                ),
+                None => (
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    CommandOptions::default(),
+                    None,
+                ),
+            };
+
+        let raw_targets = match (self.image, self.directory, self.images.is_empty()) {
+            // Single image form: [image] + [directory] + [flash.filesystem]
+            (Some(image), Some(directory), true) => vec![RawImageTarget {
+                image,
+                directory,
+                flash: filesystem,
+            }],
+            (Some(_), None, true) => return Err(ConfigError::MissingSection("directory")),
+            (None, Some(_), true) => return Err(ConfigError::MissingSection("image")),
+            (None, None, true) => return Err(ConfigError::NoImages),
+            // Multi image form: [[images]], each with its own flash target
+            (None, None, false) if filesystem.is_some() => {
+                return Err(ConfigError::TopLevelFilesystemFlash);
+            }
+            (None, None, false) => self.images,
+            (_, _, false) => return Err(ConfigError::MixedImageForms),
+        };
+
+        let mut images: Vec<ImageTarget> = Vec::with_capacity(raw_targets.len());
+        for raw in raw_targets {
+            let target = raw.resolve(&base_dir)?;
+            if images.iter().any(|t| t.image.name == target.image.name) {
+                return Err(ConfigError::DuplicateImageName(target.image.name));
+            }
+            images.push(target);
+        }
+
+        // The merged image carries the partition table the images were
+        // sized from unless it names one of its own
+        let image_table = images.iter().find_map(|t| t.partition_table.clone());
+        let merge = merge
+            .map(|m| m.resolve(&base_dir, image_table))
+            .transpose()?;
+
+        Ok(Config {
+            images,
+            firmware: firmware.map(|f| f.resolve(&base_dir)).transpose()?,
+            merge,
+            readback: readback.map(|r| r.resolve(&base_dir)),
+            identify_command,
+            identify_options,
+            chip,
+            profile: None,
+            sources: Vec::new(),
+            env_vars: Vec::new(),
+            base_dir,
+        })
+    }
+}
+
 /// A fully validated and resolved configuration.
 ///
-/// All fields have been checked for consistency and the directory root
-/// has been resolved to an absolute path. Obtain via [`Config::from_file`].
+/// All fields have been checked for consistency and the directory roots
+/// have been resolved to absolute paths. Obtain via [`Config::from_file`].
 #[derive(Debug)]
 pub struct Config {
-    pub image: ImageConfig,
-    pub directory: DirectoryConfig,
-    pub flash: Option<FlashConfig>,
+    /// Every image described by the config, in declaration order. Always
+    /// contains at least one entry.
+    pub images: Vec<ImageTarget>,
+    /// The `[flash.firmware]` section, if present.
+    pub firmware: Option<FirmwareFlash>,
+    /// The `[flash.merge]` section, if present.
+    pub merge: Option<MergeConfig>,
+    /// The `[flash.readback]` section, if present.
+    pub readback: Option<ReadbackFlash>,
+    /// The `[flash] identify_command`, which prints an ID for the connected
+    /// device (such as its MAC address) so the flash cache is kept per
+    /// device.
+    pub identify_command: Option<String>,
+    /// How `identify_command` is run, from the `shell`, `env` and `cwd`
+    /// keys of `[flash]`.
+    pub identify_options: CommandOptions,
+    /// The `[flash] chip`, substituted for `{chip}` in the flash commands.
+    pub chip: Option<String>,
+    /// The `[profile.*]` applied on top of the base config, if any.
+    pub profile: Option<String>,
     base_dir: PathBuf,
+    sources: Vec<PathBuf>,
+    env_vars: Vec<String>,
 }
 
 /// Returns the default block cycle count: -1 (no wear leveling).
@@ -163,45 +401,274 @@ fn default_block_cycles() -> i32 {
 impl Config {
     /// Load, validate, and resolve a configuration from a TOML file.
     ///
-    /// Parses the file into raw config types, then resolves both the
-    /// image and directory sections into their validated forms.
+    /// Parses the file into raw config types, then resolves every image,
+    /// directory, and flash section into its validated form. Any
+    /// `[profile.*]` tables are ignored; see
+    /// [`from_file_with_profile`](Self::from_file_with_profile).
     pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
-        let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
-            path: path.to_owned(),
-            source,
-        })?;
-        let raw: RawConfig = toml::from_str(&contents)?;
+        Self::from_file_with_profile(path, None)
+    }
+
+    /// Load a configuration from a TOML file with a profile applied.
+    ///
+    /// If the file has an `extends = "<path>"` key, the referenced file
+    /// (relative to the one containing the key) is loaded first and this
+    /// file is deep-merged over it; bases may themselves extend further
+    /// files. The `[profile.<name>]` table is then merged over the result:
+    /// nested tables are merged key by key and every other value replaces
+    /// the base one. `[[images]]` entries are overridden by name through
+    /// `[profile.<name>.images.<image>]`.
+    ///
+    /// Finally, `${VAR}` and `${VAR:-default}` references in string values
+    /// are replaced with environment variables. Relative paths in every
+    /// file resolve against the directory of `path`.
+    pub fn from_file_with_profile(path: &Path, profile: Option<&str>) -> Result<Self, ConfigError> {
+        let env = |key: &str| std::env::var(key).ok();
+        let mut env_vars = Vec::new();
+        let mut sources = Vec::new();
+        let table = merge::load_with_extends(path, &env, &mut env_vars, &mut sources)?;
         let base_dir = path.parent().unwrap_or(Path::new(".")).to_owned();
 
-        let image = raw.image.resolve()?;
-        let directory = raw.directory.resolve(&base_dir)?;
-        let flash = raw.flash.map(|f| f.resolve(&base_dir)).transpose()?;
+        let mut config = Self::from_table(table, base_dir, profile, &env, env_vars)?;
+        config.sources = sources;
+        Ok(config)
+    }
 
-        Ok(Config {
-            image,
-            directory,
-            flash,
-            base_dir,
-        })
+    /// List the names of the profiles defined in a TOML file, including
+    /// the ones it inherits through `extends`.
+    pub fn profile_names(path: &Path) -> Result<Vec<String>, ConfigError> {
+        let env = |key: &str| std::env::var(key).ok();
+        let mut table = merge::load_with_extends(path, &env, &mut Vec::new(), &mut Vec::new())?;
+        Ok(merge::take_profiles(&mut table)?.keys().cloned().collect())
+    }
+
+    /// Pick the profile to apply from within a Cargo build script.
+    ///
+    /// In order of precedence: the `LITTLEFS_PROFILE` environment variable
+    /// (which must name a defined profile), the single defined profile
+    /// whose name matches an enabled Cargo feature, or the profile named
+    /// after Cargo's `PROFILE` (`debug`/`release`) if one is defined.
+    pub fn build_profile(path: &Path) -> Result<Option<String>, ConfigError> {
+        let names = Self::profile_names(path)?;
+        select_build_profile(&names, |key| std::env::var(key).ok())
+    }
+
+    /// Apply `profile`, interpolate, and resolve an already merged table.
+    fn from_table(
+        mut table: toml::Table,
+        base_dir: PathBuf,
+        profile: Option<&str>,
+        env: &impl Fn(&str) -> Option<String>,
+        mut env_vars: Vec<String>,
+    ) -> Result<Self, ConfigError> {
+        let profiles = merge::take_profiles(&mut table)?;
+        if let Some(name) = profile {
+            merge::apply_profile(&mut table, &profiles, name)?;
+        }
+        interpolate::interpolate_table(&mut table, env, &mut env_vars)?;
+
+        let raw: RawConfig = toml::Value::Table(table).try_into()?;
+        let mut config = raw.resolve(base_dir)?;
+        config.profile = profile.map(str::to_string);
+        config.env_vars = env_vars;
+        Ok(config)
+    }
+
+    /// Every config file that was read, starting with the one passed to
+    /// [`from_file`](Self::from_file) and followed by its `extends` chain.
+    pub fn source_files(&self) -> &[PathBuf] {
+        &self.sources
+    }
+
+    /// The names of the environment variables referenced by `${VAR}`
+    /// interpolation, whether or not they were set.
+    pub fn env_vars(&self) -> &[String] {
+        &self.env_vars
     }
 
     /// The parent directory of the TOML file, used to resolve relative paths.
     pub fn base_dir(&self) -> &Path {
         &self.base_dir
     }
+
+    /// The first image in [`images`](Self::images), which is the only one
+    /// in a single-image config.
+    pub fn image_config(&self) -> &ImageConfig {
+        &self.images[0].image
+    }
+
+    /// The directory packed into [`image_config`](Self::image_config).
+    pub fn directory(&self) -> &DirectoryConfig {
+        &self.images[0].directory
+    }
+
+    /// The firmware and filesystem flash targets of the first image.
+    /// `None` unless both `[flash.firmware]` and a filesystem flash target
+    /// for the first image are present.
+    pub fn flash(&self) -> Option<FlashConfig> {
+        match (&self.firmware, &self.images[0].flash) {
+            (Some(firmware), Some(filesystem)) => Some(FlashConfig {
+                firmware: firmware.clone(),
+                filesystem: filesystem.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Look up an image by name.
+    ///
+    /// With `None`, the config must describe exactly one image, which is
+    /// returned. Configs with several images require an explicit name.
+    pub fn image(&self, name: Option<&str>) -> Result<&ImageTarget, ConfigError> {
+        match name {
+            Some(name) => self
+                .images
+                .iter()
+                .find(|t| t.image.name == name)
+                .ok_or_else(|| ConfigError::ImageNotFound(name.to_string())),
+            None => match self.images.as_slice() {
+                [only] => Ok(only),
+                _ => Err(ConfigError::AmbiguousImage(
+                    self.images.iter().map(|t| t.image.name.clone()).collect(),
+                )),
+            },
+        }
+    }
+}
+
+/// Select a build profile from `names` using `env` to look up variables.
+fn select_build_profile(
+    names: &[String],
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Option<String>, ConfigError> {
+    if let Some(name) = env("LITTLEFS_PROFILE").filter(|n| !n.is_empty()) {
+        return Ok(Some(name));
+    }
+
+    let by_feature: Vec<&String> = names
+        .iter()
+        .filter(|name| {
+            let feature = name.to_uppercase().replace('-', "_");
+            env(&format!("CARGO_FEATURE_{feature}")).is_some()
+        })
+        .collect();
+    match by_feature.as_slice() {
+        [] => {}
+        [name] => return Ok(Some(name.to_string())),
+        _ => {
+            return Err(ConfigError::AmbiguousProfile(
+                by_feature.into_iter().cloned().collect(),
+            ));
+        }
+    }
+
+    Ok(env("PROFILE").filter(|cargo_profile| names.contains(cargo_profile)))
+}
+
+/// Read a config file to a string.
+fn read_file(path: &Path) -> Result<String, ConfigError> {
+    std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+/// One `[[images]]` entry: an image, the directory packed into it, and
+/// optionally where it is flashed.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawImageTarget {
+    image: RawImageConfig,
+    directory: RawDirectoryConfig,
+    flash: Option<RawFilesystemFlash>,
+}
+
+impl RawImageTarget {
+    fn resolve(mut self, base_dir: &Path) -> Result<ImageTarget, ConfigError> {
+        let partition_table = self
+            .image
+            .partition_table
+            .as_ref()
+            .map(|p| base_dir.join(p));
+
+        // A flash target without an address of its own writes the image
+        // to the partition it was sized from
+        if let Some(flash) = &mut self.flash
+            && flash.address.is_none()
+            && flash.partition_table.is_none()
+            && flash.partition_name.is_none()
+        {
+            flash.partition_table = self.image.partition_table.clone();
+            flash.partition_name = self.image.partition_name.clone();
+        }
+
+        self.image.partition_table.clone_from(&partition_table);
+        let partition = self.image.locate_partition()?;
+
+        // The built-in flasher needs the chip size, which a preset knows
+        let preset_flash_size = self
+            .image
+            .preset
+            .as_deref()
+            .and_then(find_preset)
+            .and_then(|p| u32::try_from(p.flash_size).ok());
+        let mut flash = self.flash.map(|f| f.resolve(base_dir)).transpose()?;
+        if let Some(flash) = &mut flash {
+            flash.flash_size = flash.flash_size.or(preset_flash_size);
+        }
+
+        Ok(ImageTarget {
+            image: self.image.resolve_in(partition.as_ref())?,
+            directory: self.directory.resolve(base_dir)?,
+            flash,
+            partition_table,
+            partition,
+        })
+    }
+}
+
+/// A resolved image together with its source directory and flash target.
+#[derive(Clone, Debug)]
+pub struct ImageTarget {
+    pub image: ImageConfig,
+    pub directory: DirectoryConfig,
+    /// Where to flash this image, from `[flash.filesystem]` or `[images.flash]`.
+    pub flash: Option<FilesystemFlash>,
+    /// The partition table the image size was derived from, if any.
+    pub partition_table: Option<PathBuf>,
+    /// The partition in `partition_table` the image was sized from.
+    pub partition: Option<PartitionLocation>,
+}
+
+impl ImageTarget {
+    /// The flash address this image is written to: the address of its
+    /// flash target if it has one, otherwise the address of the partition
+    /// it was sized from. `None` if neither is configured.
+    pub fn flash_address(&self) -> Result<Option<u32>, ConfigError> {
+        match (&self.flash, &self.partition) {
+            (Some(flash), _) => flash.address_value().map(Some),
+            (None, Some(partition)) => Ok(Some(partition.address)),
+            (None, None) => Ok(None),
+        }
+    }
 }
 
 /// LittleFS image parameters.
 ///
 /// Defines the geometry and sizing of the filesystem image. Supports two
 /// mutually exclusive ways to specify the total size: `block_count` or
-/// `image_size`. The `page_size` field acts as a default for `read_size`
-/// and `write_size` when they are not explicitly set.
+/// `image_size`. With `partition_table` and `partition_name` the size
+/// defaults to the whole partition, and an explicit size is checked to fit
+/// inside it. The `page_size` field acts as a default for `read_size`
+/// and `write_size` when they are not explicitly set, and a `preset` (see
+/// [`crate::presets`]) supplies `block_size`, `read_size` and `write_size`
+/// for anything still unset.
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct RawImageConfig {
     name: Option<String>,
-    block_size: usize,
+    preset: Option<String>,
+    block_size: Option<usize>,
     block_count: Option<usize>,
     image_size: Option<usize>,
     page_size: Option<usize>,
@@ -211,6 +678,12 @@ pub struct RawImageConfig {
     block_cycles: i32,
     cache_size: Option<usize>,
     lookahead_size: Option<usize>,
+    partition_table: Option<PathBuf>,
+    partition_name: Option<String>,
+    /// Block size used when neither `block_size` nor a preset set one.
+    /// Only the builder provides this; config files must be explicit.
+    #[serde(skip)]
+    default_block_size: Option<usize>,
 }
 
 impl RawImageConfig {
@@ -220,45 +693,116 @@ impl RawImageConfig {
     /// and computed fields (e.g. `block_count` from `image_size`) are handled
     /// here. The resulting `ImageConfig` contains only concrete values.
     pub fn resolve(self) -> Result<ImageConfig, ConfigError> {
+        let partition = self.locate_partition()?;
+        self.resolve_in(partition.as_ref())
+    }
+
+    /// Look up the partition named by `partition_table` and
+    /// `partition_name`, if both are set.
+    fn locate_partition(&self) -> Result<Option<PartitionLocation>, ConfigError> {
+        match (&self.partition_table, &self.partition_name) {
+            (Some(table), Some(name)) => Ok(Some(locate_partition(table, name)?)),
+            (None, None) => Ok(None),
+            _ => Err(ConfigError::IncompletePartitionConfig),
+        }
+    }
+
+    /// Resolve against `partition`, already located from this config's
+    /// `partition_table` and `partition_name`.
+    fn resolve_in(self, partition: Option<&PartitionLocation>) -> Result<ImageConfig, ConfigError> {
         let name = self.name.unwrap_or(DEFAULT_IMAGE_NAME.into());
 
+        let preset = match &self.preset {
+            Some(p) => Some(find_preset(p).ok_or_else(|| ConfigError::UnknownPreset(p.clone()))?),
+            None => None,
+        };
+
+        let block_size = self
+            .block_size
+            .or(preset.map(|p| p.block_size))
+            .or(self.default_block_size)
+            .ok_or(ConfigError::MissingBlockSize)?;
+
         let read_size = self
             .read_size
             .or(self.page_size)
+            .or(preset.map(|p| p.read_size))
             .ok_or(ConfigError::MissingSize("read_size"))?;
 
         let write_size = self
             .write_size
             .or(self.page_size)
+            .or(preset.map(|p| p.write_size))
             .ok_or(ConfigError::MissingSize("write_size"))?;
 
-        let block_count = match (self.block_count, self.image_size) {
-            (Some(c), None) => c,
-            (None, Some(s)) if s % self.block_size == 0 => s / self.block_size,
-            (None, Some(s)) => {
+        if let Some(p) = partition
+            && (!(p.offset as usize).is_multiple_of(block_size)
+                || !(p.size as usize).is_multiple_of(block_size))
+        {
+            return Err(ConfigError::PartitionAlignment {
+                name: p.name.clone(),
+                offset: p.offset,
+                size: p.size,
+                block_size,
+            });
+        }
+
+        let block_count = match (self.block_count, self.image_size, partition) {
+            (Some(c), None, _) => c,
+            (None, Some(s), _) if s.is_multiple_of(block_size) => s / block_size,
+            (None, Some(s), _) => {
                 return Err(ConfigError::ImageSizeAlignment {
                     image_size: s,
-                    block_size: self.block_size,
+                    block_size,
                 });
             }
-            (Some(_), Some(_)) => return Err(ConfigError::BothSizingMethods),
-            (None, None) => return Err(ConfigError::NoSizingMethod),
+            (Some(_), Some(_), _) => return Err(ConfigError::BothSizingMethods),
+            (None, None, Some(p)) => p.size as usize / block_size,
+            (None, None, None) => return Err(ConfigError::NoSizingMethod),
         };
 
+        let image_size =
+            block_count
+                .checked_mul(block_size)
+                .ok_or(ConfigError::ImageSizeOverflow {
+                    block_count,
+                    block_size,
+                })?;
+
+        if let Some(p) = preset
+            && image_size > p.flash_size
+        {
+            return Err(ConfigError::ImageExceedsFlash {
+                image_size,
+                preset: p.name.to_string(),
+                flash_size: p.flash_size,
+            });
+        }
+
+        if let Some(p) = partition
+            && image_size > p.size as usize
+        {
+            return Err(ConfigError::ImageExceedsPartition {
+                image_size,
+                name: p.name.clone(),
+                size: p.size,
+            });
+        }
+
         // Cache size: default to max(read_size, write_size), matching the
         // littlefs2 Rust crate's typical usage. Must be a multiple of both
         // read_size and write_size, and must evenly divide block_size.
         let cache_size = self.cache_size.unwrap_or_else(|| read_size.max(write_size));
 
-        if cache_size % read_size != 0
-            || cache_size % write_size != 0
-            || self.block_size % cache_size != 0
+        if !cache_size.is_multiple_of(read_size)
+            || !cache_size.is_multiple_of(write_size)
+            || !block_size.is_multiple_of(cache_size)
         {
             return Err(ConfigError::InvalidCacheSize {
                 cache_size,
                 read_size,
                 write_size,
-                block_size: self.block_size,
+                block_size,
             });
         }
 
@@ -266,17 +810,17 @@ impl RawImageConfig {
         // smallest valid value that covers all blocks (rounded up to 8 bytes).
         // Minimum 8 bytes per LittleFS requirements.
         let lookahead_size = self.lookahead_size.unwrap_or_else(|| {
-            let bytes_needed = (block_count + 7) / 8;
-            let aligned = ((bytes_needed + 7) / 8) * 8;
+            let bytes_needed = block_count.div_ceil(8);
+            let aligned = bytes_needed.div_ceil(8) * 8;
             aligned.max(8)
         });
 
-        if lookahead_size % 8 != 0 || lookahead_size == 0 {
+        if !lookahead_size.is_multiple_of(8) || lookahead_size == 0 {
             return Err(ConfigError::InvalidLookaheadSize(lookahead_size));
         }
 
         Ok(ImageConfig {
-            block_size: self.block_size,
+            block_size,
             block_count,
             read_size,
             write_size,
@@ -295,7 +839,8 @@ impl RawImageConfig {
     pub fn new() -> Self {
         Self {
             name: Some(DEFAULT_IMAGE_NAME.into()),
-            block_size: 16,
+            preset: None,
+            block_size: None,
             block_count: None,
             image_size: None,
             page_size: None,
@@ -304,12 +849,21 @@ impl RawImageConfig {
             block_cycles: -1,
             cache_size: None,
             lookahead_size: None,
+            partition_table: None,
+            partition_name: None,
+            default_block_size: Some(16),
         }
     }
 
+    /// Builder function for setting a geometry preset by name
+    pub fn with_preset(mut self, preset: impl Into<String>) -> Self {
+        self.preset = Some(preset.into());
+        self
+    }
+
     /// Builder function for setting block size
     pub fn with_block_size(mut self, block_size: usize) -> Self {
-        self.block_size = block_size;
+        self.block_size = Some(block_size);
         self
     }
 
@@ -355,6 +909,13 @@ impl RawImageConfig {
         self
     }
 
+    /// Builder function for sizing the image from a partition table entry
+    pub fn with_partition(mut self, table: impl AsRef<Path>, name: impl Into<String>) -> Self {
+        self.partition_table = Some(table.as_ref().to_owned());
+        self.partition_name = Some(name.into());
+        self
+    }
+
     /// Builder function for setting lookahead size (in bytes, must be multiple of 8)
     pub fn with_lookahead_size(mut self, lookahead_size: usize) -> Self {
         self.lookahead_size = Some(lookahead_size);
@@ -362,6 +923,12 @@ impl RawImageConfig {
     }
 }
 
+impl Default for RawImageConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// A consistent and validated image configuration.
 ///
 /// Construct via [`RawImageConfig::resolve`] for guaranteed validity,
@@ -391,24 +958,29 @@ impl ImageConfig {
     }
 }
 
+/// The top-level `[flash]` table.
+///
+/// `filesystem` is only allowed in the single-image form; with `[[images]]`
+/// each image carries its own `[images.flash]` section instead. `shell`,
+/// `env` and `cwd` at this level apply to `identify_command`.
 #[derive(Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 struct RawFlashConfig {
+    identify_command: Option<String>,
+    #[serde(default)]
+    shell: bool,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    cwd: Option<PathBuf>,
+    chip: Option<String>,
     firmware: RawFirmwareFlash,
-    filesystem: RawFilesystemFlash,
-}
-
-impl RawFlashConfig {
-    pub fn resolve(self, base_dir: &Path) -> Result<FlashConfig, ConfigError> {
-        Ok(FlashConfig {
-            firmware: self.firmware.resolve(base_dir)?,
-            filesystem: self.filesystem.resolve(base_dir)?,
-        })
-    }
+    filesystem: Option<RawFilesystemFlash>,
+    merge: Option<RawMergeConfig>,
+    readback: Option<RawReadbackFlash>,
 }
 
 /// Fully resolved flash configuration.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FlashConfig {
     pub firmware: FirmwareFlash,
     pub filesystem: FilesystemFlash,
@@ -422,6 +994,11 @@ pub struct RawFirmwareFlash {
     /// Optional hardcoded path to the firmware binary, used as a fallback
     /// when no path argument is passed in by `cargo run`.
     pub path: Option<String>,
+    #[serde(default)]
+    shell: bool,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    cwd: Option<PathBuf>,
 }
 
 impl RawFirmwareFlash {
@@ -429,24 +1006,98 @@ impl RawFirmwareFlash {
         Ok(FirmwareFlash {
             command: self.command,
             path: self.path.map(|p| base_dir.join(p)),
+            options: CommandOptions::new(self.shell, self.env, self.cwd, base_dir),
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FirmwareFlash {
     pub command: String,
     pub path: Option<PathBuf>,
+    pub options: CommandOptions,
+}
+
+/// How a command template from one of the `[flash.*]` sections is run.
+#[derive(Clone, Debug, Default)]
+pub struct CommandOptions {
+    /// Run the expanded command with the system shell (`sh -c`, or
+    /// `cmd /C` on Windows) so it can use pipes and redirection.
+    /// Otherwise it's split into arguments with shell quoting rules and
+    /// run directly.
+    pub shell: bool,
+    /// Environment variables set for the command.
+    pub env: BTreeMap<String, String>,
+    /// The directory the command runs in, resolved against the config
+    /// file's directory.
+    pub cwd: Option<PathBuf>,
+}
+
+impl CommandOptions {
+    fn new(
+        shell: bool,
+        env: BTreeMap<String, String>,
+        cwd: Option<PathBuf>,
+        base_dir: &Path,
+    ) -> Self {
+        Self {
+            shell,
+            env,
+            cwd: cwd.map(|p| base_dir.join(p)),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
-struct RawFilesystemFlash {
+struct RawReadbackFlash {
     command: String,
+    #[serde(default)]
+    shell: bool,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    cwd: Option<PathBuf>,
+}
+
+impl RawReadbackFlash {
+    fn resolve(self, base_dir: &Path) -> ReadbackFlash {
+        ReadbackFlash {
+            command: self.command,
+            options: CommandOptions::new(self.shell, self.env, self.cwd, base_dir),
+        }
+    }
+}
+
+/// Resolved `[flash.readback]` section, used by `littlefs pull` to read an
+/// image back from the device.
+#[derive(Clone, Debug)]
+pub struct ReadbackFlash {
+    /// The command template. `{path}` is replaced with the file to write
+    /// to, `{address}` and `{length}` with the image's flash address and
+    /// size.
+    pub command: String,
+    pub options: CommandOptions,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct RawFilesystemFlash {
+    command: Option<String>,
+    flasher: Option<Flasher>,
+    baud: Option<u32>,
+    flash_size: Option<String>,
     path: Option<String>,
     address: Option<String>,
-    partition_table: Option<String>,
+    partition_table: Option<PathBuf>,
     partition_name: Option<String>,
+    #[serde(default)]
+    delta: bool,
+    verify_command: Option<String>,
+    #[serde(default)]
+    shell: bool,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    cwd: Option<PathBuf>,
 }
 
 impl RawFilesystemFlash {
@@ -460,6 +1111,8 @@ impl RawFilesystemFlash {
             return Err(ConfigError::IncompletePartitionConfig);
         }
 
+        let partition_table = self.partition_table.as_ref().map(|p| base_dir.join(p));
+
         let address = match (has_address, has_table) {
             // Both addressing methods specified
             (true, true) => return Err(ConfigError::BothAddressMethods),
@@ -469,34 +1122,223 @@ impl RawFilesystemFlash {
             (true, false) => self.address.unwrap(),
             // Resolve from partition table
             (false, true) => {
-                let csv_path = base_dir.join(self.partition_table.as_ref().unwrap());
-                let partition = crate::partition_table::get_partition(
-                    &csv_path,
+                let partition = locate_partition(
+                    partition_table.as_ref().unwrap(),
                     self.partition_name.as_ref().unwrap(),
                 )?;
-                format!("{:#x}", partition.offset)
+                format!("{:#x}", partition.address)
             }
         };
 
+        if self.command.is_none() && self.flasher.is_none() {
+            return Err(ConfigError::NoFlashCommand);
+        }
+
         Ok(FilesystemFlash {
             command: self.command,
+            flasher: self.flasher,
+            baud: self.baud,
+            flash_size: self
+                .flash_size
+                .as_deref()
+                .map(|s| parse_size(s, "flash_size"))
+                .transpose()?,
             path: self.path.map(|p| base_dir.join(p)),
             address,
+            partition_table,
+            partition_name: self.partition_name,
+            delta: self.delta,
+            verify_command: self.verify_command,
+            options: CommandOptions::new(self.shell, self.env, self.cwd, base_dir),
         })
     }
 }
 
 /// Resolved filesystem flash configuration with a concrete address.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FilesystemFlash {
-    /// The command template. `{path}` and `{address}` are replaced at execution time.
-    pub command: String,
+    /// The command template. `{path}`, `{address}`, `{offset}` and
+    /// `{length}` are replaced at execution time, along with the image's
+    /// `{name}`, `{size}`, `{size_hex}`, `{block_size}` and `{partition}`.
+    /// `None` if only a built-in `flasher` is set.
+    pub command: Option<String>,
+    /// A flasher built into `littlefs flash` that writes the image
+    /// instead of `command`.
+    pub flasher: Option<Flasher>,
+    /// The baud rate for the built-in flasher. The bootloader's default
+    /// is kept if unset.
+    pub baud: Option<u32>,
+    /// The size of the flash chip, which the built-in flasher passes to
+    /// the bootloader. Defaults to the flash size of the image's `preset`.
+    pub flash_size: Option<u32>,
     /// Optional path to the filesystem image. If omitted, the image name
     /// and `OUT_DIR` environment variable are used to construct one.
     pub path: Option<PathBuf>,
     /// The resolved flash address (either from `address` directly or
     /// looked up from the partition table).
     pub address: String,
+    /// The partition table the address was resolved from, if any.
+    pub partition_table: Option<PathBuf>,
+    /// The partition in `partition_table` the address was resolved from.
+    pub partition_name: Option<String>,
+    /// Only flash the erase blocks that changed since the last flash,
+    /// running the command once per changed range.
+    pub delta: bool,
+    /// Optional command template that reads the image back from the device
+    /// after flashing. `{readback}` is replaced with the file it should
+    /// write to, alongside `{path}`, `{address}` and `{length}`.
+    pub verify_command: Option<String>,
+    /// How `command` and `verify_command` are run.
+    pub options: CommandOptions,
+}
+
+/// A flasher built into `littlefs flash`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Flasher {
+    /// The serial bootloader in the ROM of ESP32 chips, reached through
+    /// the port given with `--port`.
+    Esp,
+}
+
+impl FilesystemFlash {
+    /// The flash address as a number.
+    pub fn address_value(&self) -> Result<u32, ConfigError> {
+        parse_size_value(&self.address)
+            .map_err(|_| ConfigError::InvalidAddress(self.address.clone()))
+    }
+}
+
+/// Settings for combining the firmware, partition table and filesystem
+/// images into one flashable file, see [`crate::merge`].
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct RawMergeConfig {
+    output: Option<PathBuf>,
+    base_address: Option<String>,
+    flash_size: Option<String>,
+    #[serde(default)]
+    fill: bool,
+    partition_table: Option<PathBuf>,
+    #[serde(default)]
+    segments: Vec<RawMergeSegment>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct RawMergeSegment {
+    name: Option<String>,
+    path: PathBuf,
+    address: Option<String>,
+    partition: Option<String>,
+}
+
+impl RawMergeConfig {
+    /// Resolve paths against `base_dir` and segment addresses against the
+    /// partition table, falling back to `image_table` if the section names
+    /// none.
+    fn resolve(
+        self,
+        base_dir: &Path,
+        image_table: Option<PathBuf>,
+    ) -> Result<MergeConfig, ConfigError> {
+        let partition_table = self
+            .partition_table
+            .map(|p| base_dir.join(p))
+            .or(image_table);
+        let number =
+            |s: &str| parse_size_value(s).map_err(|_| ConfigError::InvalidAddress(s.into()));
+
+        let mut segments = Vec::with_capacity(self.segments.len());
+        for raw in self.segments {
+            let name = raw.name.unwrap_or_else(|| {
+                raw.path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned()
+            });
+            let address = match (&raw.address, &raw.partition, &partition_table) {
+                (Some(address), None, _) => number(address)?,
+                (None, Some(partition), Some(table)) => locate_partition(table, partition)?.address,
+                (None, Some(_), None) => return Err(ConfigError::NoPartitionTable(name)),
+                _ => return Err(ConfigError::SegmentAddress(name)),
+            };
+            segments.push(MergeSegment {
+                name,
+                path: base_dir.join(raw.path),
+                address,
+            });
+        }
+
+        let flash_size = self
+            .flash_size
+            .as_deref()
+            .map(|s| parse_size(s, "flash_size"))
+            .transpose()?;
+        if self.fill && flash_size.is_none() {
+            return Err(ConfigError::FillWithoutFlashSize);
+        }
+
+        Ok(MergeConfig {
+            output: self.output.map(|p| base_dir.join(p)),
+            base_address: match self.base_address.as_deref() {
+                Some(address) => number(address)?,
+                None => default_merge_base(partition_table.as_deref())?,
+            },
+            flash_size,
+            fill: self.fill,
+            partition_table,
+            segments,
+        })
+    }
+}
+
+/// Parse the size `value` of the config field `key`.
+fn parse_size(value: &str, key: &'static str) -> Result<u32, ConfigError> {
+    parse_size_value(value).map_err(|_| ConfigError::InvalidSize {
+        key,
+        value: value.into(),
+    })
+}
+
+/// The base address of a merged image that doesn't set one: the start of
+/// the XIP window for an RP2350 table, whose partition addresses are XIP
+/// addresses, and 0 otherwise.
+pub(crate) fn default_merge_base(partition_table: Option<&Path>) -> Result<u32, ConfigError> {
+    match partition_table
+        .map(AnyPartitionTable::from_file)
+        .transpose()?
+    {
+        Some(AnyPartitionTable::Rp2350(_)) => Ok(XIP_BASE),
+        Some(AnyPartitionTable::Esp(_)) | None => Ok(0),
+    }
+}
+
+/// Resolved `[flash.merge]` section.
+#[derive(Clone, Debug)]
+pub struct MergeConfig {
+    /// Where `littlefs merge` writes the image unless told otherwise.
+    pub output: Option<PathBuf>,
+    /// The address of the first byte of the merged image. Defaults to
+    /// `0x10000000` with an RP2350 partition table and 0 otherwise.
+    pub base_address: u32,
+    /// Flash size the segments are checked against.
+    pub flash_size: Option<u32>,
+    /// Pad the merged image with `0xFF` to `flash_size`.
+    pub fill: bool,
+    /// The partition table written into the merged image, if any.
+    pub partition_table: Option<PathBuf>,
+    /// Extra files, such as the bootloader and app, and their addresses.
+    pub segments: Vec<MergeSegment>,
+}
+
+/// A file placed in the merged image.
+#[derive(Clone, Debug)]
+pub struct MergeSegment {
+    pub name: String,
+    pub path: PathBuf,
+    pub address: u32,
 }
 
 /// Directory traversal settings for collecting files into the image.
@@ -574,7 +1416,7 @@ impl RawDirectoryConfig {
 ///
 /// Construct via [`RawDirectoryConfig::resolve`] for guaranteed validity,
 /// or directly if you are managing correctness yourself.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DirectoryConfig {
     /// The fully resolved root directory path.
     pub resolved_root: PathBuf,
@@ -608,16 +1450,7 @@ mod tests {
     /// Skips file I/O; directory resolves against ".".
     fn parse_and_validate(toml: &str) -> Result<Config, ConfigError> {
         let raw: RawConfig = toml::from_str(toml).map_err(ConfigError::Parse)?;
-        let base_dir = PathBuf::from(".");
-        let image = raw.image.resolve()?;
-        let directory = raw.directory.resolve(&base_dir)?;
-        let flash = raw.flash.map(|f| f.resolve(&base_dir)).transpose()?;
-        Ok(Config {
-            image,
-            directory,
-            flash,
-            base_dir,
-        })
+        raw.resolve(PathBuf::from("."))
     }
 
     fn minimal_image_toml(image_section: &str) -> String {
@@ -646,7 +1479,7 @@ glob_includes = []
     fn default_name() {
         let toml = minimal_image_toml("block_count = 128\npage_size = 256");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.name, DEFAULT_IMAGE_NAME);
+        assert_eq!(config.image_config().name, DEFAULT_IMAGE_NAME);
     }
 
     #[test]
@@ -654,7 +1487,7 @@ glob_includes = []
         let toml =
             minimal_image_toml("block_count = 128\npage_size = 256\nname = \"custom_image\"");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.name, "custom_image");
+        assert_eq!(config.image_config().name, "custom_image");
     }
 
     // -------------------------------------------------------------------------
@@ -665,16 +1498,16 @@ glob_includes = []
     fn block_count_directly() {
         let toml = minimal_image_toml("block_count = 128\npage_size = 256");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.block_count, 128);
-        assert_eq!(config.image.image_size(), 128 * 4096);
+        assert_eq!(config.image_config().block_count, 128);
+        assert_eq!(config.image_config().image_size(), 128 * 4096);
     }
 
     #[test]
     fn image_size_calculates_block_count() {
         let toml = minimal_image_toml("image_size = 524288\npage_size = 256");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.block_count, 128);
-        assert_eq!(config.image.image_size(), 524288);
+        assert_eq!(config.image_config().block_count, 128);
+        assert_eq!(config.image_config().image_size(), 524288);
     }
 
     #[test]
@@ -698,6 +1531,13 @@ glob_includes = []
         assert!(matches!(err, ConfigError::ImageSizeAlignment { .. }));
     }
 
+    #[test]
+    fn overflowing_image_size_rejected() {
+        let toml = minimal_image_toml(&format!("block_count = {}\npage_size = 256", u64::MAX / 2));
+        let err = parse_and_validate(&toml).unwrap_err();
+        assert!(matches!(err, ConfigError::ImageSizeOverflow { .. }));
+    }
+
     // -------------------------------------------------------------------------
     // Image config: page_size / read_size / write_size fallback
     // -------------------------------------------------------------------------
@@ -706,8 +1546,8 @@ glob_includes = []
     fn page_size_sets_both_read_and_write() {
         let toml = minimal_image_toml("block_count = 128\npage_size = 256");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.read_size, 256);
-        assert_eq!(config.image.write_size, 256);
+        assert_eq!(config.image_config().read_size, 256);
+        assert_eq!(config.image_config().write_size, 256);
     }
 
     #[test]
@@ -716,24 +1556,24 @@ glob_includes = []
             "block_count = 128\npage_size = 256\nread_size = 16\nwrite_size = 512",
         );
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.read_size, 16);
-        assert_eq!(config.image.write_size, 512);
+        assert_eq!(config.image_config().read_size, 16);
+        assert_eq!(config.image_config().write_size, 512);
     }
 
     #[test]
     fn partial_override_with_page_size_fallback() {
         let toml = minimal_image_toml("block_count = 128\npage_size = 256\nread_size = 16");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.read_size, 16);
-        assert_eq!(config.image.write_size, 256);
+        assert_eq!(config.image_config().read_size, 16);
+        assert_eq!(config.image_config().write_size, 256);
     }
 
     #[test]
     fn explicit_read_write_without_page_size() {
         let toml = minimal_image_toml("block_count = 128\nread_size = 16\nwrite_size = 512");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.read_size, 16);
-        assert_eq!(config.image.write_size, 512);
+        assert_eq!(config.image_config().read_size, 16);
+        assert_eq!(config.image_config().write_size, 512);
     }
 
     #[test]
@@ -758,14 +1598,14 @@ glob_includes = []
     fn block_cycles_defaults_to_negative_one() {
         let toml = minimal_image_toml("block_count = 128\npage_size = 256");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.block_cycles, -1);
+        assert_eq!(config.image_config().block_cycles, -1);
     }
 
     #[test]
     fn block_cycles_explicit() {
         let toml = minimal_image_toml("block_count = 128\npage_size = 256\nblock_cycles = 500");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.block_cycles, 500);
+        assert_eq!(config.image_config().block_cycles, 500);
     }
 
     // -------------------------------------------------------------------------
@@ -776,7 +1616,7 @@ glob_includes = []
     fn cache_size_defaults_to_max_read_write() {
         let toml = minimal_image_toml("block_count = 64\nread_size = 16\nwrite_size = 256");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.cache_size, 256);
+        assert_eq!(config.image_config().cache_size, 256);
     }
 
     #[test]
@@ -785,7 +1625,7 @@ glob_includes = []
             "block_count = 64\nread_size = 16\nwrite_size = 256\ncache_size = 512",
         );
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.cache_size, 512);
+        assert_eq!(config.image_config().cache_size, 512);
     }
 
     #[test]
@@ -816,7 +1656,7 @@ glob_includes = []
         // 64 blocks → ceil(64/8) = 8, aligned to 8 → 8
         let toml = minimal_image_toml("block_count = 64\npage_size = 256");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.lookahead_size, 8);
+        assert_eq!(config.image_config().lookahead_size, 8);
     }
 
     #[test]
@@ -824,15 +1664,15 @@ glob_includes = []
         // 3096 blocks → ceil(3096/8) = 387, aligned to 8 → 392
         let toml = minimal_image_toml("block_count = 3096\npage_size = 256");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.lookahead_size, 392);
-        assert_eq!(config.image.lookahead_size % 8, 0);
+        assert_eq!(config.image_config().lookahead_size, 392);
+        assert_eq!(config.image_config().lookahead_size % 8, 0);
     }
 
     #[test]
     fn lookahead_size_explicit() {
         let toml = minimal_image_toml("block_count = 64\npage_size = 256\nlookahead_size = 16");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.lookahead_size, 16);
+        assert_eq!(config.image_config().lookahead_size, 16);
     }
 
     #[test]
@@ -857,39 +1697,347 @@ glob_includes = []
     fn block_size_resolved() {
         let toml = minimal_image_toml("block_count = 128\npage_size = 256");
         let config = parse_and_validate(&toml).unwrap();
-        assert_eq!(config.image.block_size, 4096);
+        assert_eq!(config.image_config().block_size, 4096);
     }
 
     // -------------------------------------------------------------------------
-    // Directory config: resolved fields
+    // Image config: presets
     // -------------------------------------------------------------------------
 
+    /// Resolve the body of an `[image]` section on its own.
+    fn resolve_image(image_section: &str) -> Result<ImageConfig, ConfigError> {
+        toml::from_str::<RawImageConfig>(image_section)?.resolve()
+    }
+
     #[test]
-    fn directory_resolved_fields() {
-        let toml = minimal_image_toml("block_count = 128\npage_size = 256");
-        let config = parse_and_validate(&toml).unwrap();
+    fn preset_fills_geometry() {
+        let image = resolve_image("preset = \"esp32-s3-16mb\"\nblock_count = 128").unwrap();
+        assert_eq!(image.block_size, 4096);
+        assert_eq!(image.read_size, 128);
+        assert_eq!(image.write_size, 128);
+    }
 
-        assert!(config.directory.resolved_root.ends_with("."));
-        assert_eq!(config.directory.depth, -1);
-        assert!(config.directory.ignore_hidden);
-        assert!(!config.directory.gitignore);
-        assert!(!config.directory.repo_gitignore);
-        assert!(config.directory.glob_ignores.is_empty());
-        assert!(config.directory.include_set.is_none());
+    #[test]
+    fn builder_keeps_default_block_size_without_preset() {
+        let image = RawImageConfig::new()
+            .with_block_count(128)
+            .with_page_size(16)
+            .resolve()
+            .unwrap();
+        assert_eq!(image.block_size, 16);
+
+        let image = RawImageConfig::new()
+            .with_preset("w25q32")
+            .with_block_count(128)
+            .resolve()
+            .unwrap();
+        assert_eq!(image.block_size, 4096);
     }
 
     #[test]
-    fn directory_with_globs() {
-        let toml = r#"
-[image]
-block_size = 4096
-block_count = 128
-page_size = 256
+    fn image_larger_than_preset_flash_rejected() {
+        let err = resolve_image("preset = \"w25q16\"\nblock_count = 1024").unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::ImageExceedsFlash { preset, flash_size, .. }
+                if preset == "w25q16" && flash_size == 2 * 1024 * 1024
+        ));
+    }
 
-[directory]
-root = "."
-depth = 3
-ignore_hidden = false
+    #[test]
+    fn explicit_fields_override_preset() {
+        let image = resolve_image(
+            "preset = \"rp2350-w25q128\"\nblock_count = 128\nblock_size = 8192\nread_size = 16",
+        )
+        .unwrap();
+        assert_eq!(image.block_size, 8192);
+        assert_eq!(image.read_size, 16);
+        assert_eq!(image.write_size, 256);
+    }
+
+    #[test]
+    fn page_size_overrides_preset() {
+        let image =
+            resolve_image("preset = \"w25q64\"\nblock_count = 128\npage_size = 512").unwrap();
+        assert_eq!(image.read_size, 512);
+        assert_eq!(image.write_size, 512);
+    }
+
+    #[test]
+    fn unknown_preset_rejected() {
+        let err = resolve_image("preset = \"esp8266\"\nblock_count = 128").unwrap_err();
+        assert!(matches!(err, ConfigError::UnknownPreset(name) if name == "esp8266"));
+    }
+
+    #[test]
+    fn missing_block_size_without_preset_rejected() {
+        let err = resolve_image("block_count = 128\npage_size = 256").unwrap_err();
+        assert!(matches!(err, ConfigError::MissingBlockSize));
+    }
+
+    // -------------------------------------------------------------------------
+    // Image config: sizing from a partition table
+    // -------------------------------------------------------------------------
+
+    /// Write a partition table and a config whose `[image]` body is
+    /// `image_section`, then load it.
+    fn load_with_partitions(image_section: &str, extra: &str) -> Result<Config, ConfigError> {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("partitions.csv"),
+            "\
+# Name,   Type, SubType, Offset,   Size
+nvs,      data, nvs,     0x9000,   0x6000
+littlefs, data, fat,     0x200000, 0xE00000
+",
+        )
+        .unwrap();
+        let toml_path = dir.path().join("littlefs.toml");
+        fs::write(
+            &toml_path,
+            format!(
+                r#"
+[image]
+page_size = 256
+{image_section}
+
+[directory]
+root = "."
+depth = -1
+ignore_hidden = true
+gitignore = false
+repo_gitignore = false
+glob_ignores = []
+glob_includes = []
+{extra}
+"#
+            ),
+        )
+        .unwrap();
+        Config::from_file(&toml_path)
+    }
+
+    const PARTITION: &str = "partition_table = \"partitions.csv\"\npartition_name = \"littlefs\"";
+
+    #[test]
+    fn block_count_derived_from_partition() {
+        let config = load_with_partitions(&format!("block_size = 4096\n{PARTITION}"), "").unwrap();
+        assert_eq!(config.image_config().block_count, 0xE00000 / 4096);
+        assert!(
+            config.images[0]
+                .partition_table
+                .as_ref()
+                .unwrap()
+                .ends_with("partitions.csv")
+        );
+    }
+
+    #[test]
+    fn explicit_size_within_partition_accepted() {
+        let config = load_with_partitions(
+            &format!("block_size = 4096\nblock_count = 128\n{PARTITION}"),
+            "",
+        )
+        .unwrap();
+        assert_eq!(config.image_config().block_count, 128);
+    }
+
+    #[test]
+    fn explicit_size_exceeding_partition_rejected() {
+        let err = load_with_partitions(
+            &format!("block_size = 4096\nimage_size = 0x1000000\n{PARTITION}"),
+            "",
+        )
+        .unwrap_err();
+        assert!(matches!(err, ConfigError::ImageExceedsPartition { .. }));
+    }
+
+    #[test]
+    fn partition_not_aligned_to_block_size_rejected() {
+        let err =
+            load_with_partitions(&format!("block_size = 12288\n{PARTITION}"), "").unwrap_err();
+        assert!(matches!(err, ConfigError::PartitionAlignment { .. }));
+    }
+
+    #[test]
+    fn image_partition_without_table_rejected() {
+        let err = load_with_partitions("block_size = 4096\npartition_name = \"littlefs\"", "")
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::IncompletePartitionConfig));
+    }
+
+    #[test]
+    fn flash_address_falls_back_to_image_partition() {
+        let config = load_with_partitions(
+            &format!("block_size = 4096\n{PARTITION}"),
+            "[flash.firmware]\ncommand = \"espflash flash {path}\"\n\n\
+             [flash.filesystem]\ncommand = \"espflash write-bin {address} {path}\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.images[0].flash.as_ref().unwrap().address, "0x200000");
+    }
+
+    #[test]
+    fn rp2350_partition_flashed_at_xip_address() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("partitions.json"),
+            r#"{"partitions": [
+                {"name": "firmware", "size": "2M", "families": ["rp2350-arm-s"]},
+                {"name": "littlefs", "size": "1M", "families": ["data"]}
+            ]}"#,
+        )
+        .unwrap();
+        let toml_path = dir.path().join("littlefs.toml");
+        fs::write(
+            &toml_path,
+            r#"
+[image]
+block_size = 4096
+page_size = 256
+partition_table = "partitions.json"
+partition_name = "littlefs"
+
+[directory]
+root = "."
+depth = -1
+ignore_hidden = true
+gitignore = false
+repo_gitignore = false
+glob_ignores = []
+glob_includes = []
+
+[flash.firmware]
+command = "picotool load -x {path}"
+
+[flash.filesystem]
+command = "picotool load {path} -o {address}"
+"#,
+        )
+        .unwrap();
+        let config = Config::from_file(&toml_path).unwrap();
+
+        assert_eq!(config.image_config().block_count, 256);
+        assert_eq!(
+            config.images[0].flash.as_ref().unwrap().address,
+            "0x10202000"
+        );
+    }
+
+    #[test]
+    fn flash_address_resolved_from_flash_or_partition() {
+        let config = load_with_partitions(&format!("block_size = 4096\n{PARTITION}"), "").unwrap();
+        assert!(config.images[0].flash.is_none());
+        assert_eq!(config.images[0].flash_address().unwrap(), Some(0x200000));
+
+        let config = load_with_partitions(
+            "block_size = 4096\nblock_count = 16",
+            "[flash.firmware]\ncommand = \"espflash flash {path}\"\n\n\
+             [flash.filesystem]\ncommand = \"espflash write-bin {address} {path}\"\n\
+             address = \"0x310000\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.images[0].flash_address().unwrap(), Some(0x310000));
+    }
+
+    // -------------------------------------------------------------------------
+    // Merge config
+    // -------------------------------------------------------------------------
+
+    fn merge_toml(merge_section: &str) -> String {
+        minimal_image_toml("block_count = 128\npage_size = 256")
+            + "\n[flash.firmware]\ncommand = \"true\"\n\n[flash.merge]\n"
+            + merge_section
+    }
+
+    #[test]
+    fn merge_section_resolved() {
+        let config = parse_and_validate(&merge_toml(
+            "flash_size = \"4M\"\nbase_address = \"0x10000000\"\n\n\
+             [[flash.merge.segments]]\npath = \"fw.bin\"\naddress = \"0x10000000\"\n",
+        ))
+        .unwrap();
+        let merge = config.merge.unwrap();
+        assert_eq!(merge.flash_size, Some(4 * 1024 * 1024));
+        assert_eq!(merge.base_address, 0x1000_0000);
+        assert_eq!(merge.segments[0].name, "fw.bin");
+        assert_eq!(merge.segments[0].address, 0x1000_0000);
+    }
+
+    #[test]
+    fn merge_segment_address_errors() {
+        let both = merge_toml(
+            "[[flash.merge.segments]]\npath = \"a.bin\"\naddress = \"0\"\npartition = \"factory\"\n",
+        );
+        assert!(matches!(
+            parse_and_validate(&both),
+            Err(ConfigError::SegmentAddress(_))
+        ));
+
+        let no_table =
+            merge_toml("[[flash.merge.segments]]\npath = \"a.bin\"\npartition = \"factory\"\n");
+        assert!(matches!(
+            parse_and_validate(&no_table),
+            Err(ConfigError::NoPartitionTable(_))
+        ));
+
+        let bad_address =
+            merge_toml("[[flash.merge.segments]]\npath = \"a.bin\"\naddress = \"boot\"\n");
+        assert!(matches!(
+            parse_and_validate(&bad_address),
+            Err(ConfigError::InvalidAddress(_))
+        ));
+    }
+
+    #[test]
+    fn merge_flash_size_errors() {
+        let bad_size = merge_toml("flash_size = \"4Q\"\n");
+        assert!(matches!(
+            parse_and_validate(&bad_size),
+            Err(ConfigError::InvalidSize {
+                key: "flash_size",
+                ..
+            })
+        ));
+
+        let fill_only = merge_toml("fill = true\n");
+        assert!(matches!(
+            parse_and_validate(&fill_only),
+            Err(ConfigError::FillWithoutFlashSize)
+        ));
+        parse_and_validate(&merge_toml("fill = true\nflash_size = \"4M\"\n")).unwrap();
+    }
+
+    // -------------------------------------------------------------------------
+    // Directory config: resolved fields
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn directory_resolved_fields() {
+        let toml = minimal_image_toml("block_count = 128\npage_size = 256");
+        let config = parse_and_validate(&toml).unwrap();
+
+        assert!(config.directory().resolved_root.ends_with("."));
+        assert_eq!(config.directory().depth, -1);
+        assert!(config.directory().ignore_hidden);
+        assert!(!config.directory().gitignore);
+        assert!(!config.directory().repo_gitignore);
+        assert!(config.directory().glob_ignores.is_empty());
+        assert!(config.directory().include_set.is_none());
+    }
+
+    #[test]
+    fn directory_with_globs() {
+        let toml = r#"
+[image]
+block_size = 4096
+block_count = 128
+page_size = 256
+
+[directory]
+root = "."
+depth = 3
+ignore_hidden = false
 gitignore = true
 repo_gitignore = true
 glob_ignores = ["*.bkup", "build"]
@@ -897,12 +2045,12 @@ glob_includes = ["important.txt"]
 "#;
         let config = parse_and_validate(toml).unwrap();
 
-        assert_eq!(config.directory.depth, 3);
-        assert!(!config.directory.ignore_hidden);
-        assert!(config.directory.gitignore);
-        assert!(config.directory.repo_gitignore);
-        assert_eq!(config.directory.glob_ignores, &["*.bkup", "build"]);
-        assert!(config.directory.include_set.is_some());
+        assert_eq!(config.directory().depth, 3);
+        assert!(!config.directory().ignore_hidden);
+        assert!(config.directory().gitignore);
+        assert!(config.directory().repo_gitignore);
+        assert_eq!(config.directory().glob_ignores, &["*.bkup", "build"]);
+        assert!(config.directory().include_set.is_some());
     }
 
     // -------------------------------------------------------------------------
@@ -1084,18 +2232,67 @@ glob_includes = []
         let config = Config::from_file(&toml_path).unwrap();
 
         assert_eq!(config.base_dir(), dir.path());
-        assert_eq!(config.image.block_size, 4096);
-        assert_eq!(config.image.block_count, 64);
-        assert_eq!(config.image.image_size(), 64 * 4096);
-        assert_eq!(config.image.read_size, 16);
-        assert_eq!(config.image.write_size, 512);
-        assert_eq!(config.image.block_cycles, 100);
+        assert_eq!(config.image_config().block_size, 4096);
+        assert_eq!(config.image_config().block_count, 64);
+        assert_eq!(config.image_config().image_size(), 64 * 4096);
+        assert_eq!(config.image_config().read_size, 16);
+        assert_eq!(config.image_config().write_size, 512);
+        assert_eq!(config.image_config().block_cycles, 100);
         // cache_size defaults to max(read_size, write_size) = 512
-        assert_eq!(config.image.cache_size, 512);
+        assert_eq!(config.image_config().cache_size, 512);
         // lookahead_size defaults to ceil8(64/8) = 8
-        assert_eq!(config.image.lookahead_size, 8);
-        assert_eq!(config.directory.resolved_root, dir.path().join("website"));
-        assert!(config.directory.ignore_hidden);
+        assert_eq!(config.image_config().lookahead_size, 8);
+        assert_eq!(config.directory().resolved_root, dir.path().join("website"));
+        assert!(config.directory().ignore_hidden);
+    }
+
+    #[test]
+    fn from_file_with_extends_and_interpolation() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("website")).unwrap();
+        fs::create_dir(dir.path().join("common")).unwrap();
+        fs::write(
+            dir.path().join("common/littlefs.toml"),
+            r#"
+[image]
+block_size = 4096
+block_count = 128
+page_size = 256
+"#,
+        )
+        .unwrap();
+
+        let toml_path = dir.path().join("littlefs.toml");
+        fs::write(
+            &toml_path,
+            r#"
+extends = "common/littlefs.toml"
+
+[image]
+block_count = 64
+
+[directory]
+root = "./${LITTLEFS_TEST_UNSET_ROOT:-website}"
+depth = -1
+ignore_hidden = true
+gitignore = false
+repo_gitignore = false
+glob_ignores = []
+glob_includes = []
+"#,
+        )
+        .unwrap();
+
+        let config = Config::from_file(&toml_path).unwrap();
+
+        assert_eq!(config.image_config().block_size, 4096);
+        assert_eq!(config.image_config().block_count, 64);
+        assert_eq!(
+            config.directory().resolved_root,
+            dir.path().join("./website")
+        );
+        assert_eq!(config.source_files().len(), 2);
+        assert_eq!(config.env_vars(), ["LITTLEFS_TEST_UNSET_ROOT"]);
     }
 
     #[test]
@@ -1205,9 +2402,190 @@ address = "0x08060000"
 "#,
         );
         let config = parse_and_validate(&toml).unwrap();
-        let flash = config.flash.unwrap();
+        let flash = config.flash().unwrap();
         assert_eq!(flash.filesystem.address, "0x08060000");
         assert!(flash.filesystem.path.is_none());
+        assert!(!flash.filesystem.delta);
+    }
+
+    #[test]
+    fn flash_delta_enabled() {
+        let toml = flash_toml(
+            r#"
+[flash.firmware]
+command = "probe-rs run {path}"
+
+[flash.filesystem]
+command = "probe-rs download --base-address {address} {path}"
+address = "0x08060000"
+delta = true
+"#,
+        );
+        let config = parse_and_validate(&toml).unwrap();
+        assert!(config.images[0].flash.as_ref().unwrap().delta);
+    }
+
+    #[test]
+    fn flash_readback_section() {
+        let toml = flash_toml(
+            r#"
+[flash.firmware]
+command = "espflash flash {path}"
+
+[flash.readback]
+command = "espflash read-flash {address} {length} {path}"
+"#,
+        );
+        let config = parse_and_validate(&toml).unwrap();
+        assert_eq!(
+            config.readback.unwrap().command,
+            "espflash read-flash {address} {length} {path}"
+        );
+    }
+
+    #[test]
+    fn flash_command_options() {
+        let toml = flash_toml(
+            r#"
+[flash]
+chip = "esp32s3"
+
+[flash.firmware]
+command = "espflash flash --chip {chip} {path}"
+env = { ESPFLASH_BAUD = "921600" }
+
+[flash.filesystem]
+command = "esptool write-flash {address} {path} | tee flash.log"
+shell = true
+cwd = "tools"
+address = "0x200000"
+"#,
+        );
+        let config = parse_and_validate(&toml).unwrap();
+        assert_eq!(config.chip.as_deref(), Some("esp32s3"));
+
+        let firmware = config.firmware.unwrap().options;
+        assert!(!firmware.shell);
+        assert_eq!(firmware.env["ESPFLASH_BAUD"], "921600");
+        assert!(firmware.cwd.is_none());
+
+        let filesystem = &config.images[0].flash.as_ref().unwrap().options;
+        assert!(filesystem.shell);
+        assert!(filesystem.env.is_empty());
+        assert!(filesystem.cwd.as_ref().unwrap().ends_with("tools"));
+    }
+
+    #[test]
+    fn flash_builtin_flasher() {
+        let toml = flash_toml(
+            r#"
+[flash.firmware]
+command = "espflash flash {path}"
+
+[flash.filesystem]
+flasher = "esp"
+baud = 921600
+flash_size = "4M"
+address = "0x200000"
+"#,
+        );
+        let config = parse_and_validate(&toml).unwrap();
+        let flash = config.images[0].flash.as_ref().unwrap();
+        assert_eq!(flash.flasher, Some(Flasher::Esp));
+        assert_eq!(flash.baud, Some(921600));
+        assert_eq!(flash.flash_size, Some(4 * 1024 * 1024));
+        assert!(flash.command.is_none());
+
+        let err = parse_and_validate(&toml.replace("\"4M\"", "\"4Q\"")).unwrap_err();
+        assert!(
+            matches!(&err, ConfigError::InvalidSize { key: "flash_size", value } if value == "4Q"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn flash_size_defaults_to_preset() {
+        let toml = flash_toml(
+            r#"
+[flash.firmware]
+command = "espflash flash {path}"
+
+[flash.filesystem]
+flasher = "esp"
+address = "0x200000"
+"#,
+        );
+        let config = parse_and_validate(&toml).unwrap();
+        assert_eq!(config.images[0].flash.as_ref().unwrap().flash_size, None);
+
+        let toml = toml.replace("[image]\n", "[image]\npreset = \"esp32-s3-16mb\"\n");
+        let config = parse_and_validate(&toml).unwrap();
+        assert_eq!(
+            config.images[0].flash.as_ref().unwrap().flash_size,
+            Some(16 * 1024 * 1024)
+        );
+    }
+
+    #[test]
+    fn flash_without_command_or_flasher_rejected() {
+        let toml = flash_toml(
+            r#"
+[flash.firmware]
+command = "espflash flash {path}"
+
+[flash.filesystem]
+address = "0x200000"
+"#,
+        );
+        let err = parse_and_validate(&toml).unwrap_err();
+        assert!(matches!(err, ConfigError::NoFlashCommand));
+    }
+
+    #[test]
+    fn flash_verify_command() {
+        let toml = flash_toml(
+            r#"
+[flash.firmware]
+command = "espflash flash {path}"
+
+[flash.filesystem]
+command = "espflash write-bin {address} {path}"
+address = "0x200000"
+verify_command = "espflash read-flash {address} {length} {readback}"
+"#,
+        );
+        let config = parse_and_validate(&toml).unwrap();
+        let flash = config.images[0].flash.as_ref().unwrap();
+        assert_eq!(
+            flash.verify_command.as_deref(),
+            Some("espflash read-flash {address} {length} {readback}")
+        );
+    }
+
+    #[test]
+    fn flash_identify_command() {
+        let toml = flash_toml(
+            r#"
+[flash]
+identify_command = "espflash board-info --port {port} | grep MAC"
+shell = true
+cwd = "tools"
+
+[flash.firmware]
+command = "espflash flash --port {port} {path}"
+"#,
+        );
+        let config = parse_and_validate(&toml).unwrap();
+        assert_eq!(
+            config.identify_command.as_deref(),
+            Some("espflash board-info --port {port} | grep MAC")
+        );
+        assert!(config.identify_options.shell);
+        assert_eq!(
+            config.identify_options.cwd.as_deref(),
+            Some(Path::new("./tools"))
+        );
+        assert!(!config.firmware.unwrap().options.shell);
     }
 
     #[test]
@@ -1225,7 +2603,7 @@ address = "0x200000"
 "#,
         );
         let config = parse_and_validate(&toml).unwrap();
-        let flash = config.flash.unwrap();
+        let flash = config.flash().unwrap();
         assert_eq!(
             flash.firmware.path.as_deref(),
             Some(Path::new("/path/to/binary"))
@@ -1322,6 +2700,255 @@ address = "0x200000"
     fn flash_section_optional() {
         let toml = minimal_image_toml("block_count = 128\npage_size = 256");
         let config = parse_and_validate(&toml).unwrap();
-        assert!(config.flash.is_none());
+        assert!(config.flash().is_none());
+        assert!(config.firmware.is_none());
+    }
+
+    // -------------------------------------------------------------------------
+    // Multiple images
+    // -------------------------------------------------------------------------
+
+    /// Helper: one `[[images]]` entry with the given name and flash address.
+    fn image_entry(name: &str, address: &str) -> String {
+        format!(
+            r#"
+[[images]]
+[images.image]
+name = "{name}"
+block_size = 4096
+block_count = 32
+page_size = 256
+
+[images.directory]
+root = "."
+depth = -1
+ignore_hidden = true
+gitignore = false
+repo_gitignore = false
+glob_ignores = []
+glob_includes = []
+
+[images.flash]
+command = "esptool write-flash {{address}} {{path}}"
+address = "{address}"
+"#
+        )
+    }
+
+    #[test]
+    fn multiple_images_resolved_in_order() {
+        let toml = format!(
+            "{}{}\n[flash.firmware]\ncommand = \"espflash flash {{path}}\"\n",
+            image_entry("assets", "0x200000"),
+            image_entry("userdata", "0x300000"),
+        );
+        let config = parse_and_validate(&toml).unwrap();
+
+        assert_eq!(config.images.len(), 2);
+        assert_eq!(config.image_config().name, "assets");
+        assert_eq!(config.images[1].image.name, "userdata");
+        assert_eq!(config.images[1].flash.as_ref().unwrap().address, "0x300000");
+        assert!(config.firmware.is_some());
+        assert_eq!(config.flash().unwrap().filesystem.address, "0x200000");
+    }
+
+    #[test]
+    fn image_lookup_by_name() {
+        let toml = format!(
+            "{}{}",
+            image_entry("assets", "0x200000"),
+            image_entry("userdata", "0x300000"),
+        );
+        let config = parse_and_validate(&toml).unwrap();
+
+        let target = config.image(Some("userdata")).unwrap();
+        assert_eq!(target.image.name, "userdata");
+        assert!(matches!(
+            config.image(Some("recovery")),
+            Err(ConfigError::ImageNotFound(_))
+        ));
+        assert!(matches!(
+            config.image(None),
+            Err(ConfigError::AmbiguousImage(_))
+        ));
+    }
+
+    #[test]
+    fn single_image_lookup_without_name() {
+        let toml = minimal_image_toml("block_count = 128\npage_size = 256");
+        let config = parse_and_validate(&toml).unwrap();
+        assert_eq!(config.image(None).unwrap().image.name, DEFAULT_IMAGE_NAME);
+    }
+
+    #[test]
+    fn duplicate_image_names_rejected() {
+        let toml = format!(
+            "{}{}",
+            image_entry("assets", "0x200000"),
+            image_entry("assets", "0x300000"),
+        );
+        let err = parse_and_validate(&toml).unwrap_err();
+        assert!(matches!(err, ConfigError::DuplicateImageName(name) if name == "assets"));
+    }
+
+    #[test]
+    fn mixed_image_forms_rejected() {
+        let toml = format!(
+            "{}{}",
+            minimal_image_toml("block_count = 128\npage_size = 256"),
+            image_entry("assets", "0x200000"),
+        );
+        let err = parse_and_validate(&toml).unwrap_err();
+        assert!(matches!(err, ConfigError::MixedImageForms));
+    }
+
+    #[test]
+    fn top_level_filesystem_flash_with_images_rejected() {
+        let toml = format!(
+            r#"{}
+[flash.firmware]
+command = "espflash flash {{path}}"
+
+[flash.filesystem]
+command = "esptool write-flash {{address}} {{path}}"
+address = "0x200000"
+"#,
+            image_entry("assets", "0x200000"),
+        );
+        let err = parse_and_validate(&toml).unwrap_err();
+        assert!(matches!(err, ConfigError::TopLevelFilesystemFlash));
+    }
+
+    #[test]
+    fn no_images_rejected() {
+        let err = parse_and_validate("").unwrap_err();
+        assert!(matches!(err, ConfigError::NoImages));
+    }
+
+    #[test]
+    fn image_without_directory_rejected() {
+        let toml = "[image]\nblock_size = 4096\nblock_count = 128\npage_size = 256\n";
+        let err = parse_and_validate(toml).unwrap_err();
+        assert!(matches!(err, ConfigError::MissingSection("directory")));
+    }
+
+    // -------------------------------------------------------------------------
+    // Profiles
+    // -------------------------------------------------------------------------
+
+    /// Parse a TOML string with `profile` applied, without environment variables.
+    fn parse_with_profile(toml: &str, profile: Option<&str>) -> Result<Config, ConfigError> {
+        let table = toml::from_str(toml)?;
+        Config::from_table(table, PathBuf::from("."), profile, &|_| None, Vec::new())
+    }
+
+    fn profile_toml() -> String {
+        format!(
+            r#"{}
+[profile.debug.directory]
+glob_ignores = []
+glob_includes = ["fixtures/**"]
+
+[profile.feathers3.image]
+block_count = 512
+"#,
+            minimal_image_toml("block_count = 128\npage_size = 256")
+        )
+    }
+
+    #[test]
+    fn profiles_ignored_without_selection() {
+        let config = parse_with_profile(&profile_toml(), None).unwrap();
+        assert!(config.profile.is_none());
+        assert_eq!(config.image_config().block_count, 128);
+        assert!(config.directory().include_set.is_none());
+    }
+
+    #[test]
+    fn profile_overrides_directory_fields() {
+        let config = parse_with_profile(&profile_toml(), Some("debug")).unwrap();
+        assert_eq!(config.profile.as_deref(), Some("debug"));
+        assert_eq!(config.directory().glob_includes, &["fixtures/**"]);
+        // Fields the profile doesn't mention are kept
+        assert!(config.directory().ignore_hidden);
+        assert_eq!(config.image_config().block_count, 128);
+    }
+
+    #[test]
+    fn profile_overrides_image_fields() {
+        let config = parse_with_profile(&profile_toml(), Some("feathers3")).unwrap();
+        assert_eq!(config.image_config().block_count, 512);
+        assert_eq!(config.image_config().read_size, 256);
+    }
+
+    #[test]
+    fn unknown_profile_rejected() {
+        let err = parse_with_profile(&profile_toml(), Some("release")).unwrap_err();
+        assert!(matches!(err, ConfigError::ProfileNotFound(name) if name == "release"));
+    }
+
+    #[test]
+    fn profile_names_listed_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = dir.path().join("littlefs.toml");
+        fs::write(&toml_path, profile_toml()).unwrap();
+
+        let mut names = Config::profile_names(&toml_path).unwrap();
+        names.sort();
+        assert_eq!(names, ["debug", "feathers3"]);
+    }
+
+    #[test]
+    fn build_profile_precedence() {
+        let names = vec!["debug".to_string(), "feathers-s3".to_string()];
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |key: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+
+        // Nothing set
+        assert_eq!(select_build_profile(&names, env(&[])).unwrap(), None);
+        // Cargo PROFILE only applies when a matching profile exists
+        assert_eq!(
+            select_build_profile(&names, env(&[("PROFILE", "debug")])).unwrap(),
+            Some("debug".into())
+        );
+        assert_eq!(
+            select_build_profile(&names, env(&[("PROFILE", "release")])).unwrap(),
+            None
+        );
+        // A feature match beats PROFILE
+        assert_eq!(
+            select_build_profile(
+                &names,
+                env(&[("PROFILE", "debug"), ("CARGO_FEATURE_FEATHERS_S3", "1")])
+            )
+            .unwrap(),
+            Some("feathers-s3".into())
+        );
+        // LITTLEFS_PROFILE beats everything
+        assert_eq!(
+            select_build_profile(
+                &names,
+                env(&[
+                    ("LITTLEFS_PROFILE", "debug"),
+                    ("CARGO_FEATURE_FEATHERS_S3", "1")
+                ])
+            )
+            .unwrap(),
+            Some("debug".into())
+        );
+    }
+
+    #[test]
+    fn build_profile_multiple_feature_matches_rejected() {
+        let names = vec!["a".to_string(), "b".to_string()];
+        let env =
+            |key: &str| matches!(key, "CARGO_FEATURE_A" | "CARGO_FEATURE_B").then(String::new);
+        let err = select_build_profile(&names, env).unwrap_err();
+        assert!(matches!(err, ConfigError::AmbiguousProfile(_)));
     }
 }
@@ -0,0 +1,200 @@
+//! Environment variable interpolation in config string values.
+//!
+//! `${VAR}` is replaced with the value of `VAR`, and `${VAR:-default}`
+//! falls back to `default` when `VAR` is unset or empty. `$$` produces a
+//! literal `$`, so `$${VAR}` is left as `${VAR}`. A `$` followed by
+//! anything else is kept as is, which leaves shell-style `$VAR` in flash
+//! commands untouched.
+//!
+//! Command strings (`command` and any `*_command` key) in a section that
+//! sets `shell = true` are handed to a shell, so there `$$` is kept as `$$`
+//! and a `${VAR}` that is unset and has no default is left for the shell
+//! to expand instead of failing. Without `shell` the command is run
+//! directly and nothing would expand them, so they are treated like any
+//! other string.
+
+use toml::{Table, Value};
+
+use super::ConfigError;
+
+/// Interpolate every string value in `table`, recursing into nested
+/// tables and arrays. The names of all variables looked up are appended
+/// to `used`.
+pub(super) fn interpolate_table(
+    table: &mut Table,
+    env: &impl Fn(&str) -> Option<String>,
+    used: &mut Vec<String>,
+) -> Result<(), ConfigError> {
+    let shell = matches!(table.get("shell"), Some(Value::Boolean(true)));
+    for (key, value) in table.iter_mut() {
+        let shell_command = shell && (key == "command" || key.ends_with("_command"));
+        interpolate_value(value, shell_command, env, used)?;
+    }
+    Ok(())
+}
+
+fn interpolate_value(
+    value: &mut Value,
+    shell_command: bool,
+    env: &impl Fn(&str) -> Option<String>,
+    used: &mut Vec<String>,
+) -> Result<(), ConfigError> {
+    match value {
+        Value::String(s) => *s = interpolate_str(s, shell_command, env, used)?,
+        Value::Array(items) => {
+            for item in items {
+                interpolate_value(item, shell_command, env, used)?;
+            }
+        }
+        Value::Table(table) => interpolate_table(table, env, used)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Expand the `${...}` references in a single string. With
+/// `shell_command` set, `$$` and unset `${VAR}` references are passed
+/// through verbatim for the shell.
+pub(super) fn interpolate_str(
+    s: &str,
+    shell_command: bool,
+    env: &impl Fn(&str) -> Option<String>,
+    used: &mut Vec<String>,
+) -> Result<String, ConfigError> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+
+        if let Some(after_escape) = after.strip_prefix('$') {
+            out.push_str(if shell_command { "$$" } else { "$" });
+            rest = after_escape;
+        } else if let Some(body) = after.strip_prefix('{') {
+            let end = body
+                .find('}')
+                .ok_or_else(|| ConfigError::InvalidInterpolation(s.to_string()))?;
+            let (name, default) = match body[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&body[..end], None),
+            };
+            if name.is_empty() {
+                return Err(ConfigError::InvalidInterpolation(s.to_string()));
+            }
+
+            if !used.iter().any(|u| u == name) {
+                used.push(name.to_string());
+            }
+            match (env(name).filter(|v| !v.is_empty()), default) {
+                (Some(value), _) => out.push_str(&value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) if shell_command => out.push_str(&rest[pos..pos + end + 3]),
+                (None, None) => return Err(ConfigError::UndefinedVariable(name.to_string())),
+            }
+            rest = &body[end + 1..];
+        } else {
+            out.push('$');
+            rest = after;
+        }
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(key: &str) -> Option<String> {
+        match key {
+            "BOARD" => Some("feathers3".into()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    fn expand(s: &str) -> Result<String, ConfigError> {
+        interpolate_str(s, false, &env, &mut Vec::new())
+    }
+
+    fn expand_shell_command(s: &str) -> Result<String, ConfigError> {
+        interpolate_str(s, true, &env, &mut Vec::new())
+    }
+
+    #[test]
+    fn expands_set_variables() {
+        assert_eq!(
+            expand("./boards/${BOARD}/www").unwrap(),
+            "./boards/feathers3/www"
+        );
+    }
+
+    #[test]
+    fn default_used_when_unset_or_empty() {
+        assert_eq!(expand("${PORT:-/dev/ttyACM0}").unwrap(), "/dev/ttyACM0");
+        assert_eq!(expand("${EMPTY:-fallback}").unwrap(), "fallback");
+        assert_eq!(expand("${BOARD:-fallback}").unwrap(), "feathers3");
+        assert_eq!(expand("${PORT:-}").unwrap(), "");
+    }
+
+    #[test]
+    fn undefined_variable_rejected() {
+        let err = expand("${PORT}").unwrap_err();
+        assert!(matches!(err, ConfigError::UndefinedVariable(name) if name == "PORT"));
+    }
+
+    #[test]
+    fn escapes_and_bare_dollars_kept() {
+        assert_eq!(expand("$${BOARD}").unwrap(), "${BOARD}");
+        assert_eq!(expand("cost: $5 $HOME").unwrap(), "cost: $5 $HOME");
+    }
+
+    #[test]
+    fn shell_syntax_kept_in_shell_commands() {
+        assert_eq!(
+            expand_shell_command("sh -c 'echo $$ ${HOME}' ${BOARD} ${PORT:-/dev/ttyACM0}").unwrap(),
+            "sh -c 'echo $$ ${HOME}' feathers3 /dev/ttyACM0"
+        );
+    }
+
+    #[test]
+    fn malformed_references_rejected() {
+        assert!(matches!(
+            expand("${BOARD"),
+            Err(ConfigError::InvalidInterpolation(_))
+        ));
+        assert!(matches!(
+            expand("${}"),
+            Err(ConfigError::InvalidInterpolation(_))
+        ));
+    }
+
+    #[test]
+    fn interpolates_nested_values_and_records_names() {
+        let mut table: Table = toml::from_str(
+            "[d]\nroot = \"${BOARD}\"\nglobs = [\"${MISSING:-x}\"]\nn = 1\ncommand = \"${HOME} $$\"\nshell = true\n",
+        )
+        .unwrap();
+        let mut used = Vec::new();
+        interpolate_table(&mut table, &env, &mut used).unwrap();
+
+        assert_eq!(table["d"]["root"].as_str(), Some("feathers3"));
+        assert_eq!(table["d"]["globs"][0].as_str(), Some("x"));
+        assert_eq!(table["d"]["command"].as_str(), Some("${HOME} $$"));
+        used.sort();
+        assert_eq!(used, ["BOARD", "HOME", "MISSING"]);
+    }
+
+    #[test]
+    fn commands_without_shell_are_interpolated_strictly() {
+        let mut table: Table = toml::from_str("[d]\ncommand = \"flash ${HOME}\"\n").unwrap();
+        let err = interpolate_table(&mut table, &env, &mut Vec::new()).unwrap_err();
+        assert!(matches!(err, ConfigError::UndefinedVariable(name) if name == "HOME"));
+
+        let mut table: Table = toml::from_str("[d]\ncommand = \"echo $$${BOARD}\"\n").unwrap();
+        interpolate_table(&mut table, &env, &mut Vec::new()).unwrap();
+        assert_eq!(table["d"]["command"].as_str(), Some("echo $feathers3"));
+    }
+}
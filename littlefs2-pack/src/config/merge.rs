@@ -0,0 +1,216 @@
+//! Deep merging of TOML tables, used to layer `extends` base files and
+//! `[profile.*]` overrides on top of the configuration before it is
+//! deserialized.
+//!
+//! Tables are merged key by key; any other value in the overlay (scalars
+//! and arrays alike) replaces the base value. Since `[[images]]` entries
+//! can't be addressed by index, a profile overrides them by name through
+//! an `images.<name>` table instead.
+
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+use super::interpolate::interpolate_str;
+use super::{ConfigError, DEFAULT_IMAGE_NAME, read_file};
+
+/// Read the TOML file at `path` and merge it over the chain of files it
+/// `extends`, innermost base first.
+///
+/// The `extends` path is relative to the file containing it and may use
+/// `${VAR}` interpolation. Every file read is appended to `sources`, which
+/// is also used to detect inheritance cycles.
+pub(super) fn load_with_extends(
+    path: &Path,
+    env: &impl Fn(&str) -> Option<String>,
+    used_vars: &mut Vec<String>,
+    sources: &mut Vec<PathBuf>,
+) -> Result<Table, ConfigError> {
+    let canonical = path.canonicalize().map_err(|source| ConfigError::Io {
+        path: path.to_owned(),
+        source,
+    })?;
+    if sources.contains(&canonical) {
+        return Err(ConfigError::ExtendsCycle(path.to_owned()));
+    }
+    sources.push(canonical);
+
+    let mut table: Table = toml::from_str(&read_file(path)?)?;
+
+    let base_path = match table.remove("extends") {
+        None => return Ok(table),
+        Some(Value::String(base)) => interpolate_str(&base, false, env, used_vars)?,
+        Some(_) => return Err(ConfigError::InvalidExtends(path.to_owned())),
+    };
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut base = load_with_extends(&dir.join(base_path), env, used_vars, sources)?;
+
+    merge_tables(&mut base, table);
+    Ok(base)
+}
+
+/// Remove the top-level `profile` table from `table` and return it.
+pub(super) fn take_profiles(table: &mut Table) -> Result<Table, ConfigError> {
+    match table.remove("profile") {
+        None => Ok(Table::new()),
+        Some(Value::Table(profiles)) => Ok(profiles),
+        Some(_) => Err(ConfigError::InvalidProfile("profile".into())),
+    }
+}
+
+/// Apply the overrides of profile `name` from `profiles` onto `base`.
+pub(super) fn apply_profile(
+    base: &mut Table,
+    profiles: &Table,
+    name: &str,
+) -> Result<(), ConfigError> {
+    let mut overlay = match profiles.get(name) {
+        Some(Value::Table(overlay)) => overlay.clone(),
+        Some(_) => return Err(ConfigError::InvalidProfile(name.into())),
+        None => return Err(ConfigError::ProfileNotFound(name.into())),
+    };
+
+    match overlay.remove("images") {
+        Some(Value::Table(by_name)) => merge_images(base, by_name, name)?,
+        Some(images) => {
+            base.insert("images".into(), images);
+        }
+        None => {}
+    }
+
+    merge_tables(base, overlay);
+    Ok(())
+}
+
+/// Recursively merge `overlay` into `base`.
+pub(super) fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match value {
+            Value::Table(inner) if base.get(&key).is_some_and(Value::is_table) => {
+                if let Some(Value::Table(existing)) = base.get_mut(&key) {
+                    merge_tables(existing, inner);
+                }
+            }
+            value => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Merge each `images.<name>` override into the `[[images]]` entry whose
+/// image has that name.
+fn merge_images(base: &mut Table, by_name: Table, profile: &str) -> Result<(), ConfigError> {
+    for (image_name, overlay) in by_name {
+        let Value::Table(overlay) = overlay else {
+            return Err(ConfigError::InvalidProfile(profile.into()));
+        };
+
+        let target = base
+            .get_mut("images")
+            .and_then(Value::as_array_mut)
+            .and_then(|images| {
+                images
+                    .iter_mut()
+                    .filter_map(Value::as_table_mut)
+                    .find(|entry| entry_image_name(entry) == image_name)
+            })
+            .ok_or_else(|| ConfigError::ImageNotFound(image_name.clone()))?;
+
+        merge_tables(target, overlay);
+    }
+    Ok(())
+}
+
+/// The name of an `[[images]]` entry, falling back to the default name.
+fn entry_image_name(entry: &Table) -> &str {
+    entry
+        .get("image")
+        .and_then(|image| image.get("name"))
+        .and_then(Value::as_str)
+        .unwrap_or(DEFAULT_IMAGE_NAME)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(s: &str) -> Table {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn merge_replaces_scalars_and_recurses_into_tables() {
+        let mut base = table("a = 1\n[t]\nx = 1\ny = [1, 2]\n");
+        merge_tables(&mut base, table("[t]\ny = [3]\nz = true\n"));
+
+        assert_eq!(base, table("a = 1\n[t]\nx = 1\ny = [3]\nz = true\n"));
+    }
+
+    #[test]
+    fn profile_overrides_images_by_name() {
+        let mut base = table(
+            "[[images]]\nimage = { name = \"a\", block_count = 1 }\n\
+             [[images]]\nimage = { name = \"b\", block_count = 2 }\n",
+        );
+        let profiles = table("[dev.images.b.image]\nblock_count = 20\n");
+
+        apply_profile(&mut base, &profiles, "dev").unwrap();
+
+        let images = base["images"].as_array().unwrap();
+        assert_eq!(images[0]["image"]["block_count"].as_integer(), Some(1));
+        assert_eq!(images[1]["image"]["block_count"].as_integer(), Some(20));
+    }
+
+    #[test]
+    fn profile_override_of_unknown_image_rejected() {
+        let mut base = table("[[images]]\nimage = { name = \"a\" }\n");
+        let profiles = table("[dev.images.missing.image]\nblock_count = 20\n");
+
+        let err = apply_profile(&mut base, &profiles, "dev").unwrap_err();
+        assert!(matches!(err, ConfigError::ImageNotFound(name) if name == "missing"));
+    }
+
+    #[test]
+    fn extends_merges_base_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("common")).unwrap();
+        std::fs::write(
+            dir.path().join("common/base.toml"),
+            "[image]\nblock_size = 4096\nblock_count = 128\n",
+        )
+        .unwrap();
+        let leaf = dir.path().join("littlefs.toml");
+        std::fs::write(
+            &leaf,
+            "extends = \"./${COMMON:-common}/base.toml\"\n[image]\nblock_count = 64\n",
+        )
+        .unwrap();
+
+        let mut used = Vec::new();
+        let mut sources = Vec::new();
+        let table = load_with_extends(&leaf, &|_| None, &mut used, &mut sources).unwrap();
+
+        assert_eq!(
+            table,
+            toml::from_str("[image]\nblock_size = 4096\nblock_count = 64\n").unwrap()
+        );
+        assert_eq!(sources.len(), 2);
+        assert_eq!(used, ["COMMON"]);
+    }
+
+    #[test]
+    fn extends_cycle_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.toml"), "extends = \"b.toml\"\n").unwrap();
+        std::fs::write(dir.path().join("b.toml"), "extends = \"a.toml\"\n").unwrap();
+
+        let err = load_with_extends(
+            &dir.path().join("a.toml"),
+            &|_| None,
+            &mut Vec::new(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ConfigError::ExtendsCycle(_)));
+    }
+}
@@ -0,0 +1,725 @@
+//! # Image Deltas
+//!
+//! Patches that turn one version of an image into the next, for updating
+//! the LittleFS partition of a device over the air without sending the
+//! whole image.
+//!
+//! - [`BlockPatch`]: the erase blocks that differ between the two raw
+//!   images. The device writes each range to flash as is, so it needs no
+//!   filesystem code to apply it, but the images have to share a layout.
+//!   Pack with a base image (see [`LfsImage::update_from_config`]) to keep
+//!   the patch small.
+//! - [`FilePatch`]: a list of filesystem operations (mkdir, write, remove,
+//!   rename) for a device that applies updates through its own mounted
+//!   filesystem. It only depends on the contents of the two images.
+//!
+//! Either form can be serialized with [`Patch::to_bytes`] and replayed on
+//! an [`LfsImage`] with [`Patch::apply`] to check the result on the host.
+//!
+//! ## Format
+//!
+//! All integers are little endian. A patch starts with the magic `LFSP`, a
+//! version byte (1) and a kind byte (1 for blocks, 2 for files).
+//!
+//! A block patch continues with the `u32` block size, the `u32` image size
+//! and the `u32` number of ranges. Each range is a `u32` offset, a `u32`
+//! length and the data.
+//!
+//! A file patch continues with the `u32` number of operations. Each starts
+//! with an opcode byte and a path (a `u16` length and UTF-8 bytes):
+//! mkdir (1), write (2) followed by a `u32` length and the contents,
+//! remove (3), and rename (4) followed by the new path.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+use thiserror::Error;
+
+use crate::littlefs::{LfsError, LfsImage};
+
+const MAGIC: &[u8; 4] = b"LFSP";
+const VERSION: u8 = 1;
+const KIND_BLOCKS: u8 = 1;
+const KIND_FILES: u8 = 2;
+
+const OP_MKDIR: u8 = 1;
+const OP_WRITE: u8 = 2;
+const OP_REMOVE: u8 = 3;
+const OP_RENAME: u8 = 4;
+
+/// Errors that can occur when building, reading or applying a patch.
+#[derive(Debug, Error)]
+pub enum DeltaError {
+    /// The data doesn't start with the patch magic.
+    #[error("not a LittleFS patch")]
+    BadMagic,
+
+    /// The patch was written by a newer version of the format.
+    #[error("unsupported patch version {0}")]
+    UnsupportedVersion(u8),
+
+    /// The patch ends in the middle of a field.
+    #[error("patch is truncated")]
+    Truncated,
+
+    /// The patch is well formed but makes no sense.
+    #[error("invalid patch: {0}")]
+    Invalid(String),
+
+    /// A size, offset, count or path length doesn't fit in its field of
+    /// the patch format.
+    #[error("{what} ({value}) is too large for the patch format")]
+    TooLarge { what: &'static str, value: usize },
+
+    /// The two images, or the patch and the image it's applied to, don't
+    /// share a geometry.
+    #[error(
+        "geometry mismatch: {expected_size} byte image with {expected_block_size} byte blocks \
+         expected, found {size} bytes with {block_size} byte blocks"
+    )]
+    GeometryMismatch {
+        expected_block_size: usize,
+        expected_size: usize,
+        block_size: usize,
+        size: usize,
+    },
+
+    #[error(transparent)]
+    Lfs(#[from] LfsError),
+}
+
+// ---------------------------------------------------------------------------
+// Block patches
+// ---------------------------------------------------------------------------
+
+/// The byte ranges of `new`, in whole blocks, that differ from `old`.
+///
+/// Adjacent changed blocks are merged into one range. Blocks past the end
+/// of `old` count as changed, since the device may hold anything there.
+pub fn changed_ranges(old: &[u8], new: &[u8], block_size: usize) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (i, block) in new.chunks(block_size).enumerate() {
+        let start = i * block_size;
+        let end = start + block.len();
+        if old.get(start..end) == Some(block) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => ranges.push(start..end),
+        }
+    }
+    ranges
+}
+
+/// A run of changed bytes at an offset into the image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockRange {
+    pub offset: usize,
+    pub data: Vec<u8>,
+}
+
+/// The erase blocks that changed between two images of the same geometry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockPatch {
+    pub block_size: usize,
+    pub image_size: usize,
+    pub ranges: Vec<BlockRange>,
+}
+
+impl BlockPatch {
+    /// The blocks of `new` that differ from `old`.
+    pub fn diff(old: &LfsImage, new: &LfsImage) -> Result<Self, DeltaError> {
+        check_geometry(old, new.config().block_size, new.data().len())?;
+        let block_size = new.config().block_size;
+        let ranges = changed_ranges(old.data(), new.data(), block_size)
+            .into_iter()
+            .map(|range| BlockRange {
+                offset: range.start,
+                data: new.data()[range].to_vec(),
+            })
+            .collect();
+        Ok(Self {
+            block_size,
+            image_size: new.data().len(),
+            ranges,
+        })
+    }
+
+    /// The number of bytes the patch writes.
+    pub fn changed_bytes(&self) -> usize {
+        self.ranges.iter().map(|r| r.data.len()).sum()
+    }
+
+    /// Write the changed ranges into `image`.
+    pub fn apply(&self, image: &mut LfsImage) -> Result<(), DeltaError> {
+        check_geometry(image, self.block_size, self.image_size)?;
+        let data = image.data_mut();
+        for range in &self.ranges {
+            let end = range.offset + range.data.len();
+            data[range.offset..end].copy_from_slice(&range.data);
+        }
+        Ok(())
+    }
+
+    fn write(&self, out: &mut Vec<u8>) -> Result<(), DeltaError> {
+        put_u32(out, "block size", self.block_size)?;
+        put_u32(out, "image size", self.image_size)?;
+        put_u32(out, "range count", self.ranges.len())?;
+        for range in &self.ranges {
+            put_u32(out, "range offset", range.offset)?;
+            put_u32(out, "range length", range.data.len())?;
+            out.extend_from_slice(&range.data);
+        }
+        Ok(())
+    }
+
+    fn read(reader: &mut Reader<'_>) -> Result<Self, DeltaError> {
+        let block_size = reader.u32()?;
+        let image_size = reader.u32()?;
+        let count = reader.u32()?;
+        let mut ranges = Vec::new();
+        for _ in 0..count {
+            let offset = reader.u32()?;
+            let len = reader.u32()?;
+            if offset + len > image_size {
+                return Err(DeltaError::Invalid(format!(
+                    "range {offset:#x}..{:#x} is past the end of the image",
+                    offset + len
+                )));
+            }
+            ranges.push(BlockRange {
+                offset,
+                data: reader.bytes(len)?.to_vec(),
+            });
+        }
+        Ok(Self {
+            block_size,
+            image_size,
+            ranges,
+        })
+    }
+}
+
+/// Check that an image has the expected block size and length.
+fn check_geometry(image: &LfsImage, block_size: usize, size: usize) -> Result<(), DeltaError> {
+    if image.config().block_size != block_size || image.data().len() != size {
+        return Err(DeltaError::GeometryMismatch {
+            expected_block_size: block_size,
+            expected_size: size,
+            block_size: image.config().block_size,
+            size: image.data().len(),
+        });
+    }
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// File patches
+// ---------------------------------------------------------------------------
+
+/// One filesystem operation of a [`FilePatch`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileOp {
+    /// Create a directory.
+    Mkdir { path: String },
+    /// Create or replace a file.
+    Write { path: String, data: Vec<u8> },
+    /// Remove a file or an empty directory.
+    Remove { path: String },
+    /// Move a file whose contents didn't change.
+    Rename { from: String, to: String },
+}
+
+/// The filesystem operations that turn the contents of one image into
+/// those of another.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilePatch {
+    pub ops: Vec<FileOp>,
+}
+
+impl FilePatch {
+    /// The operations that turn the contents of `old` into those of `new`.
+    ///
+    /// A file that disappeared while a file with the same contents appeared
+    /// becomes a rename. The operations are ordered so they can be replayed
+    /// one by one: removals (children first), then new directories (parents
+    /// first), renames and writes.
+    pub fn diff(old: &mut LfsImage, new: &mut LfsImage) -> Result<Self, DeltaError> {
+        let old: BTreeMap<String, Option<Vec<u8>>> = old.contents()?.into_iter().collect();
+        let new: BTreeMap<String, Option<Vec<u8>>> = new.contents()?.into_iter().collect();
+
+        let is_dir = |entries: &BTreeMap<String, Option<Vec<u8>>>, path: &str| {
+            matches!(entries.get(path), Some(None))
+        };
+
+        // Files that are new or have new contents
+        let mut writes: Vec<(&String, &Vec<u8>)> = new
+            .iter()
+            .filter_map(|(path, data)| {
+                let data = data.as_ref()?;
+                let unchanged = old.get(path).and_then(Option::as_ref) == Some(data);
+                (!unchanged).then_some((path, data))
+            })
+            .collect();
+
+        // Files that are gone, or changed between file and directory
+        let gone: Vec<&String> = old
+            .iter()
+            .filter(|(path, data)| match new.get(*path) {
+                Some(new_data) => data.is_none() != new_data.is_none(),
+                None => true,
+            })
+            .map(|(path, _)| path)
+            .collect();
+
+        // A removed file whose contents reappear elsewhere was renamed. Only
+        // files whose directory survives, and whose path doesn't become a
+        // directory, are moved, so the rename can run after the removals
+        // and mkdirs.
+        let mut renames = Vec::new();
+        let mut renamed = BTreeSet::new();
+        for from in &gone {
+            let Some(Some(data)) = old.get(*from) else {
+                continue;
+            };
+            if !is_dir(&new, parent(from)) && parent(from) != "/" {
+                continue;
+            }
+            // A file that becomes a directory is recreated by a mkdir
+            // before the renames run, so it can't be moved away
+            if is_dir(&new, from) {
+                continue;
+            }
+            if let Some(i) = writes
+                .iter()
+                .position(|(to, new_data)| *new_data == data && !old.contains_key(*to))
+            {
+                let (to, _) = writes.remove(i);
+                renames.push(FileOp::Rename {
+                    from: (*from).clone(),
+                    to: to.clone(),
+                });
+                renamed.insert(*from);
+            }
+        }
+
+        let mut ops: Vec<FileOp> = gone
+            .iter()
+            .rev()
+            .filter(|path| !renamed.contains(*path))
+            .map(|path| FileOp::Remove {
+                path: (*path).clone(),
+            })
+            .collect();
+        ops.extend(
+            new.iter()
+                .filter(|(path, data)| data.is_none() && !is_dir(&old, path))
+                .map(|(path, _)| FileOp::Mkdir { path: path.clone() }),
+        );
+        ops.extend(renames);
+        ops.extend(writes.into_iter().map(|(path, data)| FileOp::Write {
+            path: path.clone(),
+            data: data.clone(),
+        }));
+
+        Ok(Self { ops })
+    }
+
+    /// Replay the operations on a mounted `image`.
+    pub fn apply(&self, image: &mut LfsImage) -> Result<(), DeltaError> {
+        image.mount_and_then(|fs| {
+            for op in &self.ops {
+                match op {
+                    FileOp::Mkdir { path } => fs.create_dir(path)?,
+                    FileOp::Write { path, data } => fs.write_file(path, data)?,
+                    FileOp::Remove { path } => fs.remove(path)?,
+                    FileOp::Rename { from, to } => fs.rename(from, to)?,
+                }
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn write(&self, out: &mut Vec<u8>) -> Result<(), DeltaError> {
+        put_u32(out, "operation count", self.ops.len())?;
+        for op in &self.ops {
+            match op {
+                FileOp::Mkdir { path } => {
+                    out.push(OP_MKDIR);
+                    put_path(out, path)?;
+                }
+                FileOp::Write { path, data } => {
+                    out.push(OP_WRITE);
+                    put_path(out, path)?;
+                    put_u32(out, "file size", data.len())?;
+                    out.extend_from_slice(data);
+                }
+                FileOp::Remove { path } => {
+                    out.push(OP_REMOVE);
+                    put_path(out, path)?;
+                }
+                FileOp::Rename { from, to } => {
+                    out.push(OP_RENAME);
+                    put_path(out, from)?;
+                    put_path(out, to)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read(reader: &mut Reader<'_>) -> Result<Self, DeltaError> {
+        let count = reader.u32()?;
+        let mut ops = Vec::new();
+        for _ in 0..count {
+            let op = match reader.u8()? {
+                OP_MKDIR => FileOp::Mkdir {
+                    path: reader.path()?,
+                },
+                OP_WRITE => {
+                    let path = reader.path()?;
+                    let len = reader.u32()?;
+                    FileOp::Write {
+                        path,
+                        data: reader.bytes(len)?.to_vec(),
+                    }
+                }
+                OP_REMOVE => FileOp::Remove {
+                    path: reader.path()?,
+                },
+                OP_RENAME => FileOp::Rename {
+                    from: reader.path()?,
+                    to: reader.path()?,
+                },
+                other => {
+                    return Err(DeltaError::Invalid(format!("unknown operation {other}")));
+                }
+            };
+            ops.push(op);
+        }
+        Ok(Self { ops })
+    }
+}
+
+/// The directory containing an absolute image path.
+fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Patch
+// ---------------------------------------------------------------------------
+
+/// A block-level or file-level patch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Patch {
+    Blocks(BlockPatch),
+    Files(FilePatch),
+}
+
+impl Patch {
+    /// Apply the patch to `image`.
+    pub fn apply(&self, image: &mut LfsImage) -> Result<(), DeltaError> {
+        match self {
+            Patch::Blocks(patch) => patch.apply(image),
+            Patch::Files(patch) => patch.apply(image),
+        }
+    }
+
+    /// Serialize the patch in the format described in the module docs.
+    ///
+    /// Fails if a size, offset or count doesn't fit in 32 bits or a path
+    /// is longer than 65535 bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DeltaError> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        match self {
+            Patch::Blocks(patch) => {
+                out.push(KIND_BLOCKS);
+                patch.write(&mut out)?;
+            }
+            Patch::Files(patch) => {
+                out.push(KIND_FILES);
+                patch.write(&mut out)?;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Parse a patch written by [`to_bytes`](Self::to_bytes).
+    pub fn from_bytes(data: &[u8]) -> Result<Self, DeltaError> {
+        let mut reader = Reader { data };
+        if reader
+            .bytes(MAGIC.len())
+            .map_err(|_| DeltaError::BadMagic)?
+            != MAGIC
+        {
+            return Err(DeltaError::BadMagic);
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(DeltaError::UnsupportedVersion(version));
+        }
+        let patch = match reader.u8()? {
+            KIND_BLOCKS => Patch::Blocks(BlockPatch::read(&mut reader)?),
+            KIND_FILES => Patch::Files(FilePatch::read(&mut reader)?),
+            other => return Err(DeltaError::Invalid(format!("unknown patch kind {other}"))),
+        };
+        if !reader.data.is_empty() {
+            return Err(DeltaError::Invalid(format!(
+                "{} trailing bytes",
+                reader.data.len()
+            )));
+        }
+        Ok(patch)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Encoding helpers
+// ---------------------------------------------------------------------------
+
+fn put_u32(out: &mut Vec<u8>, what: &'static str, value: usize) -> Result<(), DeltaError> {
+    let value = u32::try_from(value).map_err(|_| DeltaError::TooLarge { what, value })?;
+    out.extend_from_slice(&value.to_le_bytes());
+    Ok(())
+}
+
+fn put_path(out: &mut Vec<u8>, path: &str) -> Result<(), DeltaError> {
+    let len = u16::try_from(path.len()).map_err(|_| DeltaError::TooLarge {
+        what: "path length",
+        value: path.len(),
+    })?;
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(path.as_bytes());
+    Ok(())
+}
+
+/// Reads fields from the front of a patch.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DeltaError> {
+        if self.data.len() < len {
+            return Err(DeltaError::Truncated);
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, DeltaError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, DeltaError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    fn path(&mut self) -> Result<String, DeltaError> {
+        let len = u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()) as usize;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|_| DeltaError::Invalid("path is not UTF-8".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ImageConfig;
+    use crate::littlefs::ContentEntry;
+    use crate::littlefs::tests::test_config;
+
+    fn image_with(files: &[(&str, &[u8])]) -> LfsImage {
+        let mut image = LfsImage::new(test_config()).unwrap();
+        image.format().unwrap();
+        image
+            .mount_and_then(|fs| {
+                for (path, data) in files {
+                    let dir = parent(path);
+                    if dir != "/" {
+                        fs.create_dir_all(dir)?;
+                    }
+                    fs.write_file(path, data)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        image
+    }
+
+    fn sorted_contents(image: &mut LfsImage) -> Vec<ContentEntry> {
+        let mut contents = image.contents().unwrap();
+        contents.sort();
+        contents
+    }
+
+    #[test]
+    fn changed_ranges_merge_adjacent_blocks() {
+        let old = vec![0u8; 8 * 16];
+        let mut new = old.clone();
+        new[16] = 1; // block 1
+        new[2 * 16 + 5] = 1; // block 2
+        new[6 * 16] = 1; // block 6
+        assert_eq!(changed_ranges(&old, &new, 16), [16..48, 96..112]);
+        assert!(changed_ranges(&old, &old, 16).is_empty());
+
+        // Blocks the old image didn't cover are always flashed
+        assert_eq!(changed_ranges(&old[..32], &old[..64], 16), vec![32..64]);
+    }
+
+    #[test]
+    fn block_patch_round_trip() {
+        let old = image_with(&[("/a.txt", b"one")]);
+        let mut new = LfsImage::from_data(test_config(), old.data().to_vec()).unwrap();
+        new.mount_and_then(|fs| fs.write_file("/b.bin", &[7; 5000]))
+            .unwrap();
+
+        let patch = Patch::Blocks(BlockPatch::diff(&old, &new).unwrap());
+        let parsed = Patch::from_bytes(&patch.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed, patch);
+
+        let mut patched = LfsImage::from_data(test_config(), old.data().to_vec()).unwrap();
+        parsed.apply(&mut patched).unwrap();
+        assert_eq!(patched.data(), new.data());
+    }
+
+    #[test]
+    fn block_patch_rejects_other_geometry() {
+        let old = image_with(&[]);
+        let new = LfsImage::new(ImageConfig {
+            block_count: 32,
+            ..test_config()
+        })
+        .unwrap();
+        assert!(matches!(
+            BlockPatch::diff(&old, &new),
+            Err(DeltaError::GeometryMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn file_patch_ops() {
+        let mut old = image_with(&[
+            ("/index.html", b"v1"),
+            ("/logo.png", b"png"),
+            ("/old/stale.txt", b"stale"),
+            ("/same.txt", b"same"),
+        ]);
+        let mut new = image_with(&[
+            ("/index.html", b"v2"),
+            ("/img/logo.png", b"png"),
+            ("/same.txt", b"same"),
+        ]);
+
+        let patch = FilePatch::diff(&mut old, &mut new).unwrap();
+        assert_eq!(
+            patch.ops,
+            [
+                FileOp::Remove {
+                    path: "/old/stale.txt".into()
+                },
+                FileOp::Remove {
+                    path: "/old".into()
+                },
+                FileOp::Mkdir {
+                    path: "/img".into()
+                },
+                FileOp::Rename {
+                    from: "/logo.png".into(),
+                    to: "/img/logo.png".into()
+                },
+                FileOp::Write {
+                    path: "/index.html".into(),
+                    data: b"v2".to_vec()
+                },
+            ]
+        );
+
+        let patch = Patch::Files(patch);
+        let parsed = Patch::from_bytes(&patch.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed, patch);
+        parsed.apply(&mut old).unwrap();
+        assert_eq!(sorted_contents(&mut old), sorted_contents(&mut new));
+    }
+
+    #[test]
+    fn file_patch_handles_type_changes() {
+        let mut old = image_with(&[("/config", b"file"), ("/data/x.txt", b"x")]);
+        let mut new = image_with(&[("/config/a.txt", b"a"), ("/data", b"now a file")]);
+
+        let patch = FilePatch::diff(&mut old, &mut new).unwrap();
+        patch.apply(&mut old).unwrap();
+        assert_eq!(sorted_contents(&mut old), sorted_contents(&mut new));
+
+        // A file that becomes a directory while its contents move elsewhere
+        let mut old = image_with(&[("/config", b"same")]);
+        let mut new = image_with(&[("/config/a.txt", b"a"), ("/moved", b"same")]);
+
+        let patch = FilePatch::diff(&mut old, &mut new).unwrap();
+        patch.apply(&mut old).unwrap();
+        assert_eq!(sorted_contents(&mut old), sorted_contents(&mut new));
+    }
+
+    #[test]
+    fn malformed_patches_rejected() {
+        assert!(matches!(
+            Patch::from_bytes(b"nope"),
+            Err(DeltaError::BadMagic)
+        ));
+        assert!(matches!(
+            Patch::from_bytes(b"LFSP\x02\x01"),
+            Err(DeltaError::UnsupportedVersion(2))
+        ));
+
+        let bytes = Patch::Files(FilePatch {
+            ops: vec![FileOp::Write {
+                path: "/a".into(),
+                data: vec![1; 10],
+            }],
+        })
+        .to_bytes()
+        .unwrap();
+        assert!(matches!(
+            Patch::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(DeltaError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn oversized_fields_rejected_when_writing() {
+        let long_path = Patch::Files(FilePatch {
+            ops: vec![FileOp::Mkdir {
+                path: format!("/{}", "a".repeat(u16::MAX as usize)),
+            }],
+        });
+        assert!(matches!(
+            long_path.to_bytes(),
+            Err(DeltaError::TooLarge {
+                what: "path length",
+                ..
+            })
+        ));
+
+        let huge_image = Patch::Blocks(BlockPatch {
+            block_size: 4096,
+            image_size: u32::MAX as usize + 1,
+            ranges: Vec::new(),
+        });
+        assert!(matches!(
+            huge_image.to_bytes(),
+            Err(DeltaError::TooLarge {
+                what: "image size",
+                ..
+            })
+        ));
+    }
+}
@@ -48,33 +48,56 @@
 
 use std::path::Path;
 
-use crate::{config::Config, littlefs::LfsImage, partition_table::get_partition};
+use crate::{
+    config::Config,
+    littlefs::LfsImage,
+    partition_table::{PartitionTable, get_partition},
+    walk::walk_directory,
+};
 
 pub mod config;
+pub mod delta;
 pub mod littlefs;
+pub mod merge;
+pub mod output;
 pub mod partition_table;
+pub mod presets;
 pub mod walk;
 
 /// Generate a LittleFS image and Rust configuration module from a
 /// `littlefs.toml` file.
 ///
 /// Reads the TOML configuration at `littlefs_config`, packs the
-/// directory tree it references into a LittleFS binary image, and
-/// writes two files into `$OUT_DIR`:
+/// directory tree of each image it defines into a LittleFS binary
+/// image, and writes two files per image into `$OUT_DIR`, named after
+/// the image (`filesystem` unless `name` is set):
 ///
-/// - **`filesystem.bin`** — the raw LittleFS image ready to be
+/// - **`<name>.bin`** — the raw LittleFS image ready to be
 ///   flashed to the device.
-/// - **`littlefs_config.rs`** — Rust constants for the image geometry
+/// - **`<name>.rs`** — Rust constants for the image geometry
 ///   (`BLOCK_SIZE`, `BLOCK_COUNT`, `TOTAL_SIZE`, etc.), typenum
 ///   aliases, an `IMAGE` static that embeds the binary via
 ///   `include_bytes!`, and an optional `paths` module mirroring the
 ///   packed directory layout.
 ///
-/// Also copies the built filesystem image up to the target/<profile> directory.
+/// Also copies each built filesystem image up to the target/<profile> directory.
 /// The `$OUT_DIR` is difficult to access during flash or runtime since it's
 /// a hash encoded build directory. This step makes the image much easier to
 /// find at flash time.
 ///
+/// Prints `cargo:rerun-if-changed` directives for the TOML file and any
+/// files it `extends`, the partition tables referenced by the images
+/// and their flash sections, each image's root directory, and every file
+/// and ignore file the walk of the image directories reads, plus
+/// `cargo:rerun-if-env-changed` for every variable the config interpolates.
+/// The images are therefore rebuilt whenever one of their inputs changes.
+///
+/// If the config defines `[profile.*]` tables, one is applied on top of the
+/// base config: the profile named by the `LITTLEFS_PROFILE` environment
+/// variable, else the profile matching an enabled Cargo feature, else the
+/// profile named after Cargo's `PROFILE` (`debug` or `release`). See
+/// [`Config::build_profile`].
+///
 /// # Usage in `build.rs`
 ///
 /// ```rust,no_run
@@ -99,10 +122,11 @@ pub mod walk;
 /// panic behavior is because a build should not proceed if this step
 /// doesn't succeed.
 pub fn pack_and_generate_config(littlefs_config: &Path) {
-    // Load the config from the file
-    let config = Config::from_file(littlefs_config).unwrap();
+    // Load the config from the file, with the build's profile applied
+    println!("cargo:rerun-if-env-changed=LITTLEFS_PROFILE");
+    let profile = Config::build_profile(littlefs_config).unwrap();
+    let config = Config::from_file_with_profile(littlefs_config, profile.as_deref()).unwrap();
 
-    let image_name = config.image.name.clone();
     let out_dir = std::env::var("OUT_DIR").unwrap();
 
     // OUT_DIR is like target/<triple>/<profile>/build/<crate>-<hash>/out
@@ -114,27 +138,51 @@ pub fn pack_and_generate_config(littlefs_config: &Path) {
         .ancestors()
         .nth(3) // out/ -> <crate>-<hash>/ -> build/ -> <profile>/
         .unwrap();
-    let img_file_path = format!("{}/{}.bin", out_dir, image_name);
-    let rust_file_path = format!("{}/{}.rs", out_dir, image_name);
-
-    // Create, format, and pack the image
-    let mut image = LfsImage::new(config.image).unwrap();
-    image.format().unwrap();
-    image.pack_from_config(config.directory).unwrap();
-
-    // Generate the Rust config module
-    std::fs::write(&rust_file_path, &image.emit_rust().unwrap()).unwrap();
-
-    // Generate the image binary
-    let binary = image.into_data();
-    std::fs::write(&img_file_path, &binary).unwrap();
-
-    // Copy the binary up to the profile directory
-    std::fs::copy(
-        &img_file_path,
-        profile_dir.join(format!("{}.bin", image_name)),
-    )
-    .unwrap();
+
+    for source in config.source_files() {
+        emit_rerun_if_changed(source);
+    }
+    for var in config.env_vars() {
+        println!("cargo:rerun-if-env-changed={var}");
+    }
+
+    for target in config.images {
+        let image_name = target.image.name.clone();
+        let img_file_path = format!("{}/{}.bin", out_dir, image_name);
+        let rust_file_path = format!("{}/{}.rs", out_dir, image_name);
+
+        // Walk the directory up front so its inputs can be reported to Cargo
+        let to_pack = walk_directory(&target.directory).unwrap();
+        let flash_csv = target
+            .flash
+            .as_ref()
+            .and_then(|f| f.partition_table.as_ref());
+        for csv in target.partition_table.iter().chain(flash_csv) {
+            emit_rerun_if_changed(csv);
+        }
+        for path in to_pack.rerun_paths(&target.directory) {
+            emit_rerun_if_changed(&path);
+        }
+
+        // Create, format, and pack the image
+        let mut image = LfsImage::new(target.image).unwrap();
+        image.format().unwrap();
+        image.pack_path_set(to_pack).unwrap();
+
+        // Generate the Rust config module
+        std::fs::write(&rust_file_path, image.emit_rust().unwrap()).unwrap();
+
+        // Generate the image binary
+        let binary = image.into_data();
+        std::fs::write(&img_file_path, &binary).unwrap();
+
+        // Copy the binary up to the profile directory
+        std::fs::copy(
+            &img_file_path,
+            profile_dir.join(format!("{}.bin", image_name)),
+        )
+        .unwrap();
+    }
 }
 
 /// Generate a Rust module with partition offset and size constants
@@ -168,7 +216,63 @@ pub fn pack_and_generate_config(littlefs_config: &Path) {
 /// ```
 pub fn generate_esp_partitions_config(partition_csv: &Path, partition_name: &str) {
     let out_dir = std::env::var("OUT_DIR").unwrap();
+    emit_rerun_if_changed(partition_csv);
 
     let partition = get_partition(partition_csv, partition_name).unwrap();
     partition.emit_rust(Path::new(&out_dir)).unwrap();
 }
+
+/// Generate a Rust module with constants for every partition of an
+/// ESP-IDF partition table CSV.
+///
+/// Reads and validates the CSV at `partition_csv` and writes a
+/// `partition_table.rs` file into `$OUT_DIR` containing a module per
+/// partition, a `PARTITIONS` table, and `PartitionType` /
+/// `PartitionSubtype` enums:
+///
+/// ```text
+/// pub const PARTITIONS: &[Partition] = &[nvs::PARTITION, otadata::PARTITION, ...];
+///
+/// pub mod ota_0 {
+///     pub const NAME: &str = "ota_0";
+///     pub const TYPE: PartitionType = PartitionType::App;
+///     pub const SUBTYPE: PartitionSubtype = PartitionSubtype::Ota(0);
+///     pub const OFFSET: u32 = 0x110000;
+///     pub const SIZE: u32 = 0x100000;
+///     // ...
+/// }
+/// ```
+///
+/// Unlike [`generate_esp_partitions_config`], which covers a single
+/// partition, this gives firmware every offset from one CSV.
+///
+/// # Usage in `build.rs`
+///
+/// ```rust,no_run
+/// littlefs2_pack::generate_esp_partition_table_config(
+///     std::path::Path::new("partitions.csv"),
+/// );
+/// ```
+///
+/// Then in your firmware crate:
+///
+/// ```rust,ignore
+/// mod partitions {
+///     include!(concat!(env!("OUT_DIR"), "/partition_table.rs"));
+/// }
+///
+/// let otadata = partitions::otadata::OFFSET;
+/// ```
+pub fn generate_esp_partition_table_config(partition_csv: &Path) {
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    emit_rerun_if_changed(partition_csv);
+
+    let table = PartitionTable::from_csv(partition_csv).unwrap();
+    table.validate(None).unwrap();
+    table.emit_rust(Path::new(&out_dir)).unwrap();
+}
+
+/// Tell Cargo to rerun the build script when `path` changes.
+fn emit_rerun_if_changed(path: &Path) {
+    println!("cargo:rerun-if-changed={}", path.display());
+}
@@ -88,6 +88,29 @@ pub struct ManifestEntry {
     pub size: usize,
 }
 
+/// A path in an image with its contents, `None` for a directory.
+pub type ContentEntry = (String, Option<Vec<u8>>);
+
+/// What [`LfsImage::update_from_config`] changed in an image.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UpdateSummary {
+    /// Files that weren't in the image before.
+    pub added: Vec<String>,
+    /// Files whose contents changed.
+    pub modified: Vec<String>,
+    /// Files and directories that are no longer in the source directory.
+    pub removed: Vec<String>,
+    /// The number of files left as they were, on the same blocks.
+    pub unchanged: usize,
+}
+
+impl UpdateSummary {
+    /// Whether the update changed anything.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.removed.is_empty()
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Configuration
 // ---------------------------------------------------------------------------
@@ -104,12 +127,12 @@ fn validate_for_lfs(config: &ImageConfig) -> Result<(), LfsError> {
             "read_size and write_size must be > 0".into(),
         ));
     }
-    if config.block_size % config.read_size != 0 {
+    if !config.block_size.is_multiple_of(config.read_size) {
         return Err(LfsError::InvalidConfig(
             "block_size must be a multiple of read_size".into(),
         ));
     }
-    if config.block_size % config.write_size != 0 {
+    if !config.block_size.is_multiple_of(config.write_size) {
         return Err(LfsError::InvalidConfig(
             "block_size must be a multiple of write_size".into(),
         ));
@@ -176,6 +199,42 @@ impl LfsImage {
         })
     }
 
+    /// Create an image from data that may have had its trailing erased
+    /// blocks trimmed off (see [`trimmed_data`](Self::trimmed_data)).
+    ///
+    /// `config.block_count` must match the length of `data`. If the
+    /// superblock records a larger filesystem, the data is padded back out
+    /// with `0xFF` and the block count raised to match.
+    pub fn from_trimmed_data(config: ImageConfig, data: Vec<u8>) -> Result<Self, LfsError> {
+        let mut image = Self::from_data(config, data)?;
+        if let Some(block_count) = image.disk_block_count()
+            && block_count > image.config.block_count
+        {
+            image
+                .data
+                .resize(block_count * image.config.block_size, 0xFF);
+            image.config.block_count = block_count;
+        }
+        Ok(image)
+    }
+
+    /// The block count recorded in the superblock, or `None` if the image
+    /// isn't mountable.
+    fn disk_block_count(&mut self) -> Option<usize> {
+        unsafe {
+            let mut cfg = self.build_lfs_config();
+            // A zero block count makes littlefs take it from the superblock
+            cfg.block_count = 0;
+            let mut state: lfs::lfs_t = std::mem::zeroed();
+            check(lfs::lfs_mount(&mut state, &cfg)).ok()?;
+            let mut info: lfs::lfs_fsinfo = std::mem::zeroed();
+            let stat = check(lfs::lfs_fs_stat(&mut state, &mut info));
+            check(lfs::lfs_unmount(&mut state)).ok()?;
+            stat.ok()?;
+            Some(info.block_count as usize)
+        }
+    }
+
     /// Consume the image and return the raw data buffer.
     pub fn into_data(self) -> Vec<u8> {
         self.data
@@ -186,11 +245,116 @@ impl LfsImage {
         &self.data
     }
 
+    /// Get a mutable reference to the raw image data, e.g. to patch blocks
+    /// in place.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
     /// Get the configuration.
     pub fn config(&self) -> &ImageConfig {
         &self.config
     }
 
+    /// The number of bytes that must be flashed for the device to see this
+    /// image: everything up to the end of the last block that isn't fully
+    /// erased (`0xFF`).
+    ///
+    /// LittleFS erases a block before programming it, so whatever is left in
+    /// the trailing blocks on the device is never read. Call
+    /// [`compact`](Self::compact) first to move the used blocks to the start
+    /// of the image.
+    pub fn flashable_len(&self) -> usize {
+        let block_size = self.config.block_size;
+        let used = self
+            .data
+            .chunks(block_size)
+            .rposition(|block| block.iter().any(|&b| b != 0xFF))
+            .map_or(0, |last| last + 1);
+        used * block_size
+    }
+
+    /// The image data without its trailing erased blocks.
+    pub fn trimmed_data(&self) -> &[u8] {
+        &self.data[..self.flashable_len()]
+    }
+
+    /// Grow the filesystem to `block_count` blocks, updating the block count
+    /// stored in the superblock. The new blocks are erased.
+    pub fn grow(&mut self, block_count: usize) -> Result<(), LfsError> {
+        if block_count < self.config.block_count {
+            return Err(LfsError::InvalidConfig(format!(
+                "cannot shrink the image from {} to {block_count} blocks",
+                self.config.block_count
+            )));
+        }
+
+        let old_len = self.data.len();
+        self.data.resize(block_count * self.config.block_size, 0xFF);
+        let result = self.mount_and_then(|fs| unsafe {
+            check(lfs::lfs_fs_grow(
+                fs.state as *const _ as *mut _,
+                block_count as u32,
+            ))
+        });
+        match result {
+            Ok(()) => {
+                self.config.block_count = block_count;
+                Ok(())
+            }
+            Err(e) => {
+                self.data.truncate(old_len);
+                Err(e)
+            }
+        }
+    }
+
+    /// Rewrite the image so that every block in use sits at the start.
+    ///
+    /// LittleFS starts allocating from a pseudo-random block on each mount,
+    /// so a packed image usually has its data scattered across the whole
+    /// image. This repacks the contents into the smallest filesystem that
+    /// holds them and grows it back to the full block count, leaving the
+    /// rest of the image erased so it can be left out when flashing (see
+    /// [`flashable_len`](Self::flashable_len)).
+    pub fn compact(&mut self) -> Result<(), LfsError> {
+        let entries = self.contents()?;
+        let used = self.mount_and_then(|fs| fs.used_blocks())?;
+
+        let full = self.config.block_count;
+        // Leave some slack for metadata compaction while writing
+        let mut block_count = (used + used / 8 + 2).min(full);
+        loop {
+            let mut compact = LfsImage::new(ImageConfig {
+                block_count,
+                ..self.config.clone()
+            })?;
+            compact.format()?;
+            let written = compact.mount_and_then(|fs| {
+                for (path, data) in &entries {
+                    match data {
+                        Some(data) => fs.write_file(path, data)?,
+                        None => fs.create_dir(path)?,
+                    }
+                }
+                Ok(())
+            });
+            match written {
+                Ok(()) => {
+                    compact.grow(full)?;
+                    *self = compact;
+                    return Ok(());
+                }
+                Err(LfsError::Lfs(_, code))
+                    if code == lfs::lfs_error_LFS_ERR_NOSPC && block_count < full =>
+                {
+                    block_count = (block_count * 2).min(full);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     pub fn manifest(&mut self) -> Result<Vec<ManifestEntry>, LfsError> {
         self.mount_and_then(|fs| {
             let mut entries = Vec::new();
@@ -199,6 +363,24 @@ impl LfsImage {
         })
     }
 
+    /// Every entry in the image with its contents, `None` for directories,
+    /// in manifest order (parents before their children).
+    pub fn contents(&mut self) -> Result<Vec<ContentEntry>, LfsError> {
+        self.mount_and_then(|fs| {
+            let mut manifest = Vec::new();
+            fs.walk_recursive("/", &mut manifest)?;
+            let mut entries = Vec::with_capacity(manifest.len());
+            for entry in manifest {
+                let data = match entry.is_dir {
+                    true => None,
+                    false => Some(fs.read_file(&entry.path)?),
+                };
+                entries.push((entry.path, data));
+            }
+            Ok(entries)
+        })
+    }
+
     pub fn pack_from_config(&mut self, dir_config: DirectoryConfig) -> Result<(), LfsError> {
         let to_pack = walk_directory(&dir_config)?;
 
@@ -215,8 +397,79 @@ impl LfsImage {
         Ok(())
     }
 
+    /// Bring an existing image in line with a directory, changing as little
+    /// as possible.
+    ///
+    /// Packing from scratch places every file wherever the allocator
+    /// happens to be, so adding one file can move the blocks of all the
+    /// others. Updating a previous image instead leaves unchanged files on
+    /// their blocks: entries that are gone are removed first, then only new
+    /// and modified files are written. The allocator is deterministic, so
+    /// the same previous image and directory always give the same result.
+    pub fn update_from_config(
+        &mut self,
+        dir_config: DirectoryConfig,
+    ) -> Result<UpdateSummary, LfsError> {
+        let to_pack = walk_directory(&dir_config)?;
+        self.update_path_set(to_pack)
+    }
+
+    /// Like [`update_from_config`](Self::update_from_config), packing every
+    /// file under `directory`.
+    pub fn update_from_dir(&mut self, directory: &Path) -> Result<UpdateSummary, LfsError> {
+        let to_pack = walk_directory_simple(directory)?;
+        self.update_path_set(to_pack)
+    }
+
+    /// Internal function to update the image to match a PathSet
+    pub(crate) fn update_path_set(&mut self, to_pack: PathSet) -> Result<UpdateSummary, LfsError> {
+        self.mount_and_then(|fs| {
+            let mut summary = UpdateSummary::default();
+            let mut existing = Vec::new();
+            fs.walk_recursive("/", &mut existing)?;
+
+            // Remove stale entries first so their blocks are free again.
+            // The manifest lists parents before children, so walk it
+            // backwards to empty each directory before removing it.
+            for entry in existing.iter().rev() {
+                let keep = match entry.is_dir {
+                    true => to_pack.dirs.contains(&entry.path),
+                    false => to_pack.files.contains(&entry.path),
+                };
+                if !keep {
+                    fs.remove(&entry.path)?;
+                    summary.removed.push(entry.path.clone());
+                }
+            }
+            summary.removed.sort();
+
+            let existing: BTreeMap<&str, bool> = existing
+                .iter()
+                .map(|e| (e.path.as_str(), e.is_dir))
+                .collect();
+
+            for path in &to_pack.dirs {
+                fs.create_dir_all(path)?;
+            }
+            for path in &to_pack.files {
+                let data = std::fs::read(to_pack.host_path(path))?;
+                if existing.get(path.as_str()) == Some(&false) {
+                    if fs.read_file(path)? == data {
+                        summary.unchanged += 1;
+                        continue;
+                    }
+                    summary.modified.push(path.clone());
+                } else {
+                    summary.added.push(path.clone());
+                }
+                fs.write_file(path, &data)?;
+            }
+            Ok(summary)
+        })
+    }
+
     /// Internal function to pack a PathSet
-    fn pack_path_set(&mut self, to_pack: PathSet) -> Result<(), LfsError> {
+    pub(crate) fn pack_path_set(&mut self, to_pack: PathSet) -> Result<(), LfsError> {
         self.mount_and_then(|fs| {
             for path in &to_pack.dirs {
                 fs.create_dir_all(path)?;
@@ -478,7 +731,7 @@ fn to_const_name(name: &str) -> String {
 }
 
 /// Convert a directory name (e.g. `my-config`) to a valid Rust module name (`my_config`).
-fn to_mod_name(name: &str) -> String {
+pub(crate) fn to_mod_name(name: &str) -> String {
     let mut out = String::with_capacity(name.len());
     for ch in name.chars() {
         if ch.is_ascii_alphanumeric() {
@@ -765,7 +1018,7 @@ impl<'a> MountedFs<'a> {
                         .iter()
                         .position(|&c| c == 0)
                         .unwrap_or(name_bytes.len());
-                    let name = std::str::from_utf8(&std::slice::from_raw_parts(
+                    let name = std::str::from_utf8(std::slice::from_raw_parts(
                         name_bytes.as_ptr() as *const u8,
                         name_len,
                     ))
@@ -890,11 +1143,12 @@ impl<'a> MountedFs<'a> {
 // ---------------------------------------------------------------------------
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::config::DEFAULT_IMAGE_NAME;
 
-    fn test_config() -> ImageConfig {
+    /// A small image geometry shared by the tests of this crate.
+    pub(crate) fn test_config() -> ImageConfig {
         ImageConfig {
             block_size: 4096,
             block_count: 16,
@@ -1022,6 +1276,75 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn flashable_len_ends_at_last_used_block() {
+        let mut image = LfsImage::new(test_config()).unwrap();
+        assert_eq!(image.flashable_len(), 0);
+
+        image.format().unwrap();
+        let formatted = image.flashable_len();
+        assert!(formatted >= 2 * 4096);
+        assert_eq!(formatted % 4096, 0);
+        assert_eq!(image.trimmed_data().len(), formatted);
+    }
+
+    #[test]
+    fn compact_moves_data_to_start() {
+        let config = ImageConfig {
+            block_count: 256,
+            ..test_config()
+        };
+        let mut image = LfsImage::new(config.clone()).unwrap();
+        image.format().unwrap();
+        image
+            .mount_and_then(|fs| {
+                fs.create_dir("/sub")?;
+                for i in 0..5 {
+                    fs.write_file(&format!("/sub/{i}.bin"), &[i as u8; 3000])?;
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        image.compact().unwrap();
+        assert_eq!(image.config().block_count, 256);
+        assert_eq!(image.data().len(), 256 * 4096);
+        assert!(image.flashable_len() <= 16 * 4096);
+
+        // The trimmed prefix is padded back out to the same filesystem
+        let trimmed = image.trimmed_data().to_vec();
+        let mut reread = LfsImage::from_trimmed_data(
+            ImageConfig {
+                block_count: trimmed.len() / 4096,
+                ..config
+            },
+            trimmed,
+        )
+        .unwrap();
+        assert_eq!(reread.config().block_count, 256);
+        assert_eq!(reread.data().len(), 256 * 4096);
+        reread
+            .mount_and_then(|fs| {
+                for i in 0..5 {
+                    assert_eq!(fs.read_file(&format!("/sub/{i}.bin"))?, [i as u8; 3000]);
+                }
+                // Growing left the whole image free for new files
+                fs.write_file("/big.bin", &[0; 200 * 4096])?;
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn grow_rejects_shrinking() {
+        let mut image = LfsImage::new(test_config()).unwrap();
+        image.format().unwrap();
+        assert!(matches!(image.grow(8), Err(LfsError::InvalidConfig(_))));
+        image.grow(32).unwrap();
+        assert_eq!(image.data().len(), 32 * 4096);
+        assert!(image.is_mountable());
+    }
+
     #[test]
     fn small_block_size() {
         let config = ImageConfig {
@@ -1239,6 +1562,75 @@ mod tests {
         assert_eq!(pack_once(), pack_once());
     }
 
+    #[test]
+    fn update_from_dir_reports_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        create_test_directory(dir.path());
+        let mut image = LfsImage::new(test_config()).unwrap();
+        image.format().unwrap();
+        image.pack_from_dir(dir.path()).unwrap();
+
+        let summary = image.update_from_dir(dir.path()).unwrap();
+        assert!(summary.is_empty());
+        assert_eq!(summary.unchanged, 3);
+
+        std::fs::write(dir.path().join("index.html"), "<html>bye</html>").unwrap();
+        std::fs::write(dir.path().join("about.html"), "<html>about</html>").unwrap();
+        std::fs::remove_dir_all(dir.path().join("js")).unwrap();
+        let summary = image.update_from_dir(dir.path()).unwrap();
+        assert_eq!(summary.added, ["/about.html"]);
+        assert_eq!(summary.modified, ["/index.html"]);
+        assert_eq!(summary.removed, ["/js", "/js/app.js"]);
+        assert_eq!(summary.unchanged, 1);
+
+        let mut fresh = LfsImage::new(test_config()).unwrap();
+        fresh.format().unwrap();
+        fresh.pack_from_dir(dir.path()).unwrap();
+        let paths = |image: &mut LfsImage| -> Vec<String> {
+            image
+                .manifest()
+                .unwrap()
+                .into_iter()
+                .map(|e| e.path)
+                .collect()
+        };
+        assert_eq!(paths(&mut image), paths(&mut fresh));
+        let index = image
+            .mount_and_then(|fs| fs.read_file("/index.html"))
+            .unwrap();
+        assert_eq!(index, b"<html>bye</html>");
+    }
+
+    #[test]
+    fn update_keeps_unchanged_files_in_place() {
+        let config = ImageConfig {
+            block_count: 128,
+            ..test_config()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..20 {
+            std::fs::write(dir.path().join(format!("f{i:02}.bin")), [i as u8; 3000]).unwrap();
+        }
+        let mut image = LfsImage::new(config.clone()).unwrap();
+        image.format().unwrap();
+        image.pack_from_dir(dir.path()).unwrap();
+        let before = image.data().to_vec();
+
+        // A new file early in the alphabet
+        std::fs::write(dir.path().join("a.bin"), [0xAA; 3000]).unwrap();
+        image.update_from_dir(dir.path()).unwrap();
+
+        let changed = |after: &[u8]| {
+            before
+                .chunks(4096)
+                .zip(after.chunks(4096))
+                .filter(|(a, b)| a != b)
+                .count()
+        };
+        // The new file's block plus the root directory's metadata
+        assert!(changed(image.data()) <= 4, "{}", changed(image.data()));
+    }
+
     #[test]
     fn pack_from_dir_empty_directory() {
         let dir = tempfile::tempdir().unwrap();
@@ -0,0 +1,442 @@
+//! # Merged Flash Images
+//!
+//! Combines the bootloader, partition table, app and LittleFS images into
+//! a single file holding every segment at its flash address, with `0xFF`
+//! in the gaps, for factory programming in one step.
+//!
+//! [`MergedImage::from_config`] builds the layout from `[flash.merge]`:
+//!
+//! ```toml
+//! [flash.merge]
+//! output = "target/factory.bin"
+//! flash_size = "4M"
+//!
+//! [[flash.merge.segments]]
+//! path = "target/bootloader.bin"
+//! address = "0x0"
+//!
+//! [[flash.merge.segments]]
+//! path = "target/app.bin"
+//! partition = "factory"
+//! ```
+//!
+//! On top of the listed segments, an ESP-IDF partition table is written
+//! at its offset in the binary format, and every image with a flash
+//! address (from its flash section or the partition it was sized from)
+//! is packed and placed there. RP2350 partition tables only supply
+//! addresses, as the boot ROM reads the table from a block embedded in
+//! the firmware.
+
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::config::{Config, ConfigError, ImageTarget, MergeConfig, default_merge_base};
+use crate::littlefs::{LfsError, LfsImage};
+use crate::partition_table::{AnyPartitionTable, PartitionError};
+
+/// Errors that can occur when building a merged image.
+#[derive(Debug, Error)]
+pub enum MergeError {
+    /// Two segments occupy some of the same flash.
+    #[error("segments '{first}' and '{second}' overlap")]
+    Overlap { first: String, second: String },
+
+    /// A segment starts before the start of the merged image.
+    #[error("segment '{name}' at {address:#x} is below the base address {base:#x}")]
+    BelowBase {
+        name: String,
+        address: u32,
+        base: u32,
+    },
+
+    /// A segment ends past the end of the flash.
+    #[error("segment '{name}' ends at {end:#x}, past the {flash_size:#x} byte flash")]
+    ExceedsFlash {
+        name: String,
+        end: u64,
+        flash_size: u32,
+    },
+
+    /// A segment file could not be read.
+    #[error("failed to read segment file {path}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error(transparent)]
+    PartitionTable(#[from] PartitionError),
+
+    #[error(transparent)]
+    Lfs(#[from] LfsError),
+}
+
+/// A block of data placed at a flash address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub name: String,
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// The first address past the end of the segment.
+    pub fn end(&self) -> u64 {
+        u64::from(self.address) + self.data.len() as u64
+    }
+}
+
+/// A set of segments to combine into one flashable image.
+#[derive(Clone, Debug, Default)]
+pub struct MergedImage {
+    base_address: u32,
+    flash_size: Option<u32>,
+    fill: bool,
+    segments: Vec<Segment>,
+}
+
+impl MergedImage {
+    /// An empty image whose first byte is flashed to `base_address`.
+    pub fn new(base_address: u32) -> Self {
+        Self {
+            base_address,
+            ..Self::default()
+        }
+    }
+
+    /// Builder function for checking the segments fit in `flash_size`
+    /// bytes from the base address
+    pub fn with_flash_size(mut self, flash_size: u32) -> Self {
+        self.flash_size = Some(flash_size);
+        self
+    }
+
+    /// Builder function for padding the image with `0xFF` to the whole
+    /// flash size
+    pub fn with_fill(mut self, fill: bool) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// Builder function for adding a segment
+    pub fn with_segment(mut self, name: impl Into<String>, address: u32, data: Vec<u8>) -> Self {
+        self.add_segment(name, address, data);
+        self
+    }
+
+    /// Add a segment at `address`.
+    pub fn add_segment(&mut self, name: impl Into<String>, address: u32, data: Vec<u8>) {
+        self.segments.push(Segment {
+            name: name.into(),
+            address,
+            data,
+        });
+    }
+
+    /// The segments, in the order they were added.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Check that every segment lies between the base address and the end
+    /// of the flash and that no two segments overlap.
+    pub fn validate(&self) -> Result<(), MergeError> {
+        for segment in &self.segments {
+            if segment.address < self.base_address {
+                return Err(MergeError::BelowBase {
+                    name: segment.name.clone(),
+                    address: segment.address,
+                    base: self.base_address,
+                });
+            }
+            if let Some(flash_size) = self.flash_size
+                && segment.end() > u64::from(self.base_address) + u64::from(flash_size)
+            {
+                return Err(MergeError::ExceedsFlash {
+                    name: segment.name.clone(),
+                    end: segment.end(),
+                    flash_size,
+                });
+            }
+        }
+
+        let mut by_address: Vec<&Segment> = self.segments.iter().collect();
+        by_address.sort_by_key(|s| s.address);
+        for pair in by_address.windows(2) {
+            if pair[0].end() > u64::from(pair[1].address) {
+                return Err(MergeError::Overlap {
+                    first: pair[0].name.clone(),
+                    second: pair[1].name.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validate the layout and write every segment into one buffer that
+    /// starts at the base address. The buffer ends with the last segment,
+    /// or at the flash size with [`with_fill`](Self::with_fill).
+    pub fn build(&self) -> Result<Vec<u8>, MergeError> {
+        self.validate()?;
+
+        let base = u64::from(self.base_address);
+        let end = self.segments.iter().map(Segment::end).max().unwrap_or(base);
+        let len = match (self.fill, self.flash_size) {
+            (true, Some(flash_size)) => u64::from(flash_size),
+            _ => end - base,
+        };
+
+        let mut out = vec![0xFF; len as usize];
+        for segment in &self.segments {
+            let start = (segment.address - self.base_address) as usize;
+            out[start..start + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        Ok(out)
+    }
+
+    /// Build the layout described by a config: the `[flash.merge]`
+    /// segments, the ESP-IDF partition table, and every image that has a
+    /// flash address.
+    ///
+    /// Images are read from their flash `path` if that file exists, and
+    /// packed from their directory otherwise.
+    pub fn from_config(config: &Config) -> Result<Self, MergeError> {
+        // Without a [flash.merge] section, merge the images and the
+        // partition table they were sized from
+        let merge = match &config.merge {
+            Some(merge) => merge.clone(),
+            None => {
+                let partition_table = config.images.iter().find_map(|t| t.partition_table.clone());
+                MergeConfig {
+                    output: None,
+                    base_address: default_merge_base(partition_table.as_deref())?,
+                    flash_size: None,
+                    fill: false,
+                    partition_table,
+                    segments: Vec::new(),
+                }
+            }
+        };
+
+        let mut image = Self::new(merge.base_address).with_fill(merge.fill);
+        if let Some(flash_size) = merge.flash_size {
+            image = image.with_flash_size(flash_size);
+        }
+
+        if let Some(path) = &merge.partition_table {
+            let table = AnyPartitionTable::from_file(path)?;
+            table.validate(merge.flash_size)?;
+            if let AnyPartitionTable::Esp(table) = table {
+                image.add_segment("partition-table", table.table_offset, table.to_bin()?);
+            }
+        }
+
+        for segment in &merge.segments {
+            image.add_segment(&segment.name, segment.address, read(&segment.path)?);
+        }
+
+        for target in &config.images {
+            if let Some(address) = target.flash_address()? {
+                image.add_segment(&target.image.name, address, image_data(target)?);
+            }
+        }
+
+        Ok(image)
+    }
+}
+
+/// Read a segment file.
+fn read(path: &Path) -> Result<Vec<u8>, MergeError> {
+    std::fs::read(path).map_err(|source| MergeError::Read {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+/// The contents of an image: its flash `path` if that exists, otherwise
+/// freshly packed from its directory.
+fn image_data(target: &ImageTarget) -> Result<Vec<u8>, MergeError> {
+    if let Some(path) = target.flash.as_ref().and_then(|f| f.path.as_ref())
+        && path.exists()
+    {
+        return read(path);
+    }
+    let mut image = LfsImage::new(target.image.clone())?;
+    image.format()?;
+    image.pack_from_config(target.directory.clone())?;
+    Ok(image.into_data())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_placed_with_erased_gaps() {
+        let out = MergedImage::new(0)
+            .with_segment("bootloader", 0x0, vec![1; 4])
+            .with_segment("app", 0x10, vec![2; 4])
+            .build()
+            .unwrap();
+
+        assert_eq!(out.len(), 0x14);
+        assert_eq!(&out[..4], &[1; 4]);
+        assert!(out[4..0x10].iter().all(|&b| b == 0xFF));
+        assert_eq!(&out[0x10..], &[2; 4]);
+    }
+
+    #[test]
+    fn base_address_and_fill() {
+        let out = MergedImage::new(0x1000_0000)
+            .with_flash_size(0x100)
+            .with_fill(true)
+            .with_segment("fs", 0x1000_0080, vec![0; 0x10])
+            .build()
+            .unwrap();
+
+        assert_eq!(out.len(), 0x100);
+        assert_eq!(&out[0x80..0x90], &[0; 0x10]);
+    }
+
+    #[test]
+    fn overlap_and_overflow_rejected() {
+        let overlap = MergedImage::new(0)
+            .with_segment("a", 0x0, vec![0; 0x20])
+            .with_segment("b", 0x10, vec![0; 0x10]);
+        assert!(matches!(
+            overlap.build(),
+            Err(MergeError::Overlap { first, second }) if first == "a" && second == "b"
+        ));
+
+        let overflow =
+            MergedImage::new(0)
+                .with_flash_size(0x10)
+                .with_segment("a", 0x8, vec![0; 0x10]);
+        assert!(matches!(
+            overflow.build(),
+            Err(MergeError::ExceedsFlash { .. })
+        ));
+
+        let below = MergedImage::new(0x100).with_segment("a", 0x0, vec![0]);
+        assert!(matches!(below.build(), Err(MergeError::BelowBase { .. })));
+    }
+
+    #[test]
+    fn built_from_config() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("data")).unwrap();
+        std::fs::write(dir.path().join("data/hello.txt"), "hello").unwrap();
+        std::fs::write(dir.path().join("bootloader.bin"), [0xB0; 16]).unwrap();
+        std::fs::write(dir.path().join("app.bin"), [0xA0; 32]).unwrap();
+        std::fs::write(
+            dir.path().join("partitions.csv"),
+            "nvs,      data, nvs,     0x9000,  0x6000\n\
+             factory,  app,  factory, 0x10000, 0x10000\n\
+             littlefs, data, fat,     0x20000, 0x10000\n",
+        )
+        .unwrap();
+        let toml_path = dir.path().join("littlefs.toml");
+        std::fs::write(
+            &toml_path,
+            r#"
+[image]
+block_size = 4096
+page_size = 256
+partition_table = "partitions.csv"
+partition_name = "littlefs"
+
+[directory]
+root = "data"
+depth = -1
+ignore_hidden = true
+gitignore = false
+repo_gitignore = false
+glob_ignores = []
+glob_includes = []
+
+[flash.firmware]
+command = "true"
+
+[flash.merge]
+flash_size = "4M"
+
+[[flash.merge.segments]]
+path = "bootloader.bin"
+address = "0x1000"
+
+[[flash.merge.segments]]
+name = "app"
+path = "app.bin"
+partition = "factory"
+"#,
+        )
+        .unwrap();
+
+        let config = Config::from_file(&toml_path).unwrap();
+        let merged = MergedImage::from_config(&config).unwrap();
+        let names: Vec<&str> = merged.segments().iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["partition-table", "bootloader.bin", "app", "filesystem"]
+        );
+
+        let out = merged.build().unwrap();
+        assert_eq!(out.len(), 0x30000);
+        assert_eq!(&out[0x1000..0x1010], &[0xB0; 16]);
+        assert_eq!(&out[0x8000..0x8002], &[0xAA, 0x50]);
+        assert_eq!(&out[0x10000..0x10020], &[0xA0; 32]);
+
+        let mut fs =
+            LfsImage::from_data(config.images[0].image.clone(), out[0x20000..].to_vec()).unwrap();
+        let hello = fs.mount_and_then(|fs| fs.read_file("/hello.txt")).unwrap();
+        assert_eq!(hello, b"hello");
+    }
+
+    #[test]
+    fn rp2350_images_start_at_the_xip_base() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("data")).unwrap();
+        std::fs::write(
+            dir.path().join("partitions.json"),
+            r#"{"partitions": [
+                {"name": "firmware", "size": "2M", "families": ["rp2350-arm-s"]},
+                {"name": "littlefs", "size": "64K", "families": ["data"]}
+            ]}"#,
+        )
+        .unwrap();
+        let toml_path = dir.path().join("littlefs.toml");
+        std::fs::write(
+            &toml_path,
+            r#"
+[image]
+block_size = 4096
+page_size = 256
+partition_table = "partitions.json"
+partition_name = "littlefs"
+
+[directory]
+root = "data"
+depth = -1
+ignore_hidden = true
+gitignore = false
+repo_gitignore = false
+glob_ignores = []
+glob_includes = []
+"#,
+        )
+        .unwrap();
+
+        let config = Config::from_file(&toml_path).unwrap();
+        let merged = MergedImage::from_config(&config).unwrap();
+        assert_eq!(merged.segments()[0].address, 0x1020_2000);
+
+        let out = merged.build().unwrap();
+        assert_eq!(out.len(), 0x202000 + 0x10000);
+    }
+}
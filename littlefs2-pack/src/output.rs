@@ -0,0 +1,151 @@
+//! # Output Formats
+//!
+//! Encoders for handing an image to flashing tools that don't take a raw
+//! binary. Each format places the image at an absolute flash address, so
+//! the base address has to be known when the file is written.
+//!
+//! - [`uf2`]: 512 byte UF2 blocks for drag-and-drop bootloaders such as
+//!   the RP2040 and RP2350 boot ROMs.
+//! - [`ihex`]: Intel HEX, as taken by most production programmers.
+//! - [`srec`]: Motorola S-records with 32-bit addresses.
+//!
+//! The text formats can leave out records that are entirely `0xFF`. Flash
+//! reads as `0xFF` once erased, so a programmer that erases the target
+//! range first produces the same contents from a much smaller file.
+
+use thiserror::Error;
+
+pub mod ihex;
+pub mod srec;
+pub mod uf2;
+
+/// Errors that can occur when encoding an image.
+#[derive(Debug, Error)]
+pub enum OutputError {
+    /// The base address is not aligned as the format requires.
+    #[error("base address {address:#x} is not aligned to {alignment} bytes")]
+    Misaligned { address: u32, alignment: u32 },
+
+    /// The image would extend past the end of the 32-bit address space.
+    #[error("image of {len} bytes at {address:#x} runs past the end of the address space")]
+    AddressOverflow { address: u32, len: usize },
+}
+
+/// The file format an image is written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The raw image, as returned by [`crate::littlefs::LfsImage::data`].
+    #[default]
+    Bin,
+    /// UF2 blocks, see [`uf2`].
+    Uf2,
+    /// Intel HEX, see [`ihex`].
+    Hex,
+    /// Motorola S-records, see [`srec`].
+    Srec,
+}
+
+impl OutputFormat {
+    /// Every format, in the order they are listed in help text.
+    pub const ALL: &[OutputFormat] = &[
+        OutputFormat::Bin,
+        OutputFormat::Uf2,
+        OutputFormat::Hex,
+        OutputFormat::Srec,
+    ];
+
+    /// Parse a format name as used by `--format`.
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|f| f.name().eq_ignore_ascii_case(s))
+    }
+
+    /// The format's name, which is also its usual file extension.
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Bin => "bin",
+            OutputFormat::Uf2 => "uf2",
+            OutputFormat::Hex => "hex",
+            OutputFormat::Srec => "srec",
+        }
+    }
+
+    /// Whether the format needs a base address to be written.
+    pub fn needs_address(self) -> bool {
+        self != OutputFormat::Bin
+    }
+}
+
+/// Check that `len` bytes starting at `address` fit in the address space.
+fn check_range(address: u32, len: usize) -> Result<(), OutputError> {
+    if u64::from(address) + len as u64 > 1 << 32 {
+        return Err(OutputError::AddressOverflow { address, len });
+    }
+    Ok(())
+}
+
+/// Split `data` into records of at most `record_len` bytes, each paired
+/// with its address.
+///
+/// Records are aligned to `record_len` in the address space, so the first
+/// one is short if `address` is not aligned and none straddles a
+/// boundary that is a multiple of `record_len`. With `skip_erased`,
+/// records that are entirely `0xFF` are left out.
+fn records(
+    data: &[u8],
+    address: u32,
+    record_len: usize,
+    skip_erased: bool,
+) -> impl Iterator<Item = (u32, &[u8])> {
+    let first = (record_len - address as usize % record_len).min(data.len());
+    let (head, tail) = data.split_at(first);
+    std::iter::once((address, head))
+        .filter(|(_, r)| !r.is_empty())
+        .chain(
+            tail.chunks(record_len)
+                .enumerate()
+                .map(move |(i, r)| (address + (first + i * record_len) as u32, r)),
+        )
+        .filter(move |(_, r)| !(skip_erased && r.iter().all(|&b| b == 0xFF)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_parsed_by_name() {
+        assert_eq!(OutputFormat::parse("uf2"), Some(OutputFormat::Uf2));
+        assert_eq!(OutputFormat::parse("BIN"), Some(OutputFormat::Bin));
+        assert_eq!(OutputFormat::parse("elf"), None);
+    }
+
+    #[test]
+    fn records_aligned_to_record_length() {
+        let data = [0u8; 40];
+        let split: Vec<(u32, usize)> = records(&data, 0x1008, 16, false)
+            .map(|(a, r)| (a, r.len()))
+            .collect();
+        assert_eq!(split, [(0x1008, 8), (0x1010, 16), (0x1020, 16)]);
+    }
+
+    #[test]
+    fn erased_records_skipped() {
+        let mut data = vec![0xFF; 64];
+        data[20] = 0x00;
+        let kept: Vec<u32> = records(&data, 0, 16, true).map(|(a, _)| a).collect();
+        assert_eq!(kept, [16]);
+        assert_eq!(records(&data, 0, 16, false).count(), 4);
+    }
+
+    #[test]
+    fn range_checked_against_address_space() {
+        assert!(check_range(0xFFFF_F000, 0x1000).is_ok());
+        assert!(matches!(
+            check_range(0xFFFF_F000, 0x1001),
+            Err(OutputError::AddressOverflow { .. })
+        ));
+    }
+}
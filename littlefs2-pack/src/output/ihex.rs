@@ -0,0 +1,113 @@
+//! The [Intel HEX](https://en.wikipedia.org/wiki/Intel_HEX) format.
+//!
+//! Each line is a record:
+//!
+//! ```text
+//! :LLAAAATT<data>CC
+//! ```
+//!
+//! with the byte count, the low 16 bits of the address, the record type,
+//! the data, and a two's complement checksum. Extended linear address
+//! records (type `04`) set the upper 16 bits whenever they change, and an
+//! end-of-file record (type `01`) closes the file.
+
+use std::fmt::Write as _;
+
+use super::{OutputError, check_range, records};
+use crate::littlefs::LfsImage;
+
+/// Data bytes per record, the common default of programmers and objcopy.
+pub const RECORD_LEN: usize = 16;
+
+const TYPE_DATA: u8 = 0x00;
+const TYPE_END_OF_FILE: u8 = 0x01;
+const TYPE_EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+
+/// Encode `data` as Intel HEX records starting at `base_address`.
+///
+/// With `skip_erased`, records that are entirely `0xFF` are left out.
+pub fn encode(data: &[u8], base_address: u32, skip_erased: bool) -> Result<String, OutputError> {
+    check_range(base_address, data.len())?;
+
+    let mut out = String::new();
+    let mut upper = None;
+    for (address, record) in records(data, base_address, RECORD_LEN, skip_erased) {
+        let high = (address >> 16) as u16;
+        if upper != Some(high) {
+            write_record(
+                &mut out,
+                0,
+                TYPE_EXTENDED_LINEAR_ADDRESS,
+                &high.to_be_bytes(),
+            );
+            upper = Some(high);
+        }
+        write_record(&mut out, address as u16, TYPE_DATA, record);
+    }
+    write_record(&mut out, 0, TYPE_END_OF_FILE, &[]);
+    Ok(out)
+}
+
+/// Append one record and its line ending to `out`.
+fn write_record(out: &mut String, address: u16, record_type: u8, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+    let checksum = bytes
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg();
+
+    out.push(':');
+    for b in bytes.iter().chain([&checksum]) {
+        write!(out, "{b:02X}").unwrap();
+    }
+    out.push('\n');
+}
+
+impl LfsImage {
+    /// Encode the image as Intel HEX, see [`encode`].
+    pub fn to_ihex(&self, base_address: u32, skip_erased: bool) -> Result<String, OutputError> {
+        encode(self.data(), base_address, skip_erased)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_records_match_objcopy() {
+        let hex = encode(b"Hello, LittleFS!", 0x0800_0000, false).unwrap();
+        assert_eq!(
+            hex,
+            ":020000040800F2\n\
+             :1000000048656C6C6F2C204C6974746C6546532188\n\
+             :00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn extended_address_emitted_at_64k_boundaries() {
+        let hex = encode(&[0; 32], 0x0001_FFF0, false).unwrap();
+        let lines: Vec<&str> = hex.lines().collect();
+        assert_eq!(lines[0], ":020000040001F9");
+        assert!(lines[1].starts_with(":10FFF000"));
+        assert_eq!(lines[2], ":020000040002F8");
+        assert!(lines[3].starts_with(":10000000"));
+    }
+
+    #[test]
+    fn erased_records_skipped() {
+        let mut data = vec![0xFF; 0x20000];
+        data[0x1_0000] = 0x42;
+        let hex = encode(&data, 0, true).unwrap();
+        assert_eq!(
+            hex,
+            ":020000040001F9\n\
+             :1000000042FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFBD\n\
+             :00000001FF\n"
+        );
+    }
+}
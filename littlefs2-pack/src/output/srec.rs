@@ -0,0 +1,94 @@
+//! The [Motorola S-record](https://en.wikipedia.org/wiki/SREC_(file_format))
+//! format, using 32-bit addresses.
+//!
+//! Each line is a record:
+//!
+//! ```text
+//! S<type><count><address><data><checksum>
+//! ```
+//!
+//! The file starts with an `S0` header, carries the image in `S3` data
+//! records, gives the number of data records in an `S5` (or `S6`) count
+//! record, and ends with an `S7` termination record.
+
+use std::fmt::Write as _;
+
+use super::{OutputError, check_range, records};
+use crate::littlefs::LfsImage;
+
+/// Data bytes per record, matching objcopy's default.
+pub const RECORD_LEN: usize = 32;
+
+/// Text carried by the `S0` header record.
+const HEADER: &[u8] = b"littlefs";
+
+/// Encode `data` as S-records starting at `base_address`.
+///
+/// With `skip_erased`, records that are entirely `0xFF` are left out.
+pub fn encode(data: &[u8], base_address: u32, skip_erased: bool) -> Result<String, OutputError> {
+    check_range(base_address, data.len())?;
+
+    let mut out = String::new();
+    write_record(&mut out, 0, &[0, 0], HEADER);
+    let mut count = 0u32;
+    for (address, record) in records(data, base_address, RECORD_LEN, skip_erased) {
+        write_record(&mut out, 3, &address.to_be_bytes(), record);
+        count += 1;
+    }
+    if count <= 0xFFFF {
+        write_record(&mut out, 5, &(count as u16).to_be_bytes(), &[]);
+    } else {
+        write_record(&mut out, 6, &count.to_be_bytes()[1..], &[]);
+    }
+    write_record(&mut out, 7, &[0; 4], &[]);
+    Ok(out)
+}
+
+/// Append one record and its line ending to `out`.
+fn write_record(out: &mut String, record_type: u8, address: &[u8], data: &[u8]) {
+    let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+    bytes.extend_from_slice(address);
+    bytes.extend_from_slice(data);
+    let checksum = !bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+
+    write!(out, "S{record_type}").unwrap();
+    for b in bytes.iter().chain([&checksum]) {
+        write!(out, "{b:02X}").unwrap();
+    }
+    out.push('\n');
+}
+
+impl LfsImage {
+    /// Encode the image as S-records, see [`encode`].
+    pub fn to_srec(&self, base_address: u32, skip_erased: bool) -> Result<String, OutputError> {
+        encode(self.data(), base_address, skip_erased)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_records_match_objcopy() {
+        let srec = encode(b"Hello, LittleFS!", 0x0800_0000, false).unwrap();
+        assert_eq!(
+            srec,
+            "S00B00006C6974746C6566738D\n\
+             S3150800000048656C6C6F2C204C6974746C654653217A\n\
+             S5030001FB\n\
+             S70500000000FA\n"
+        );
+    }
+
+    #[test]
+    fn erased_records_skipped_and_counted() {
+        let mut data = vec![0xFF; 256];
+        data[100] = 0;
+        let srec = encode(&data, 0x1000, true).unwrap();
+        let lines: Vec<&str> = srec.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("S32500001060"));
+        assert_eq!(lines[2], "S5030001FB");
+    }
+}
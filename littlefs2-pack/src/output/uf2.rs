@@ -0,0 +1,176 @@
+//! The [UF2](https://github.com/microsoft/uf2) format used by
+//! drag-and-drop bootloaders.
+//!
+//! A UF2 file is a series of 512 byte blocks, each carrying 256 bytes of
+//! payload and the flash address to write it to:
+//!
+//! ```text
+//! magic0 | magic1 | flags | address | payload size | block no | block count | family ID
+//! payload [u8; 476] | magic end
+//! ```
+//!
+//! The family ID lets a bootloader reject files meant for another chip.
+//! On the RP2350 it also picks the partition a download goes to, so
+//! LittleFS images are normally tagged `data` or `absolute`.
+
+use super::{OutputError, check_range};
+use crate::littlefs::LfsImage;
+use crate::partition_table::parse_size_value;
+use crate::partition_table::rp2350::Family;
+
+/// Size of one UF2 block.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Image bytes carried by each block.
+pub const PAYLOAD_SIZE: usize = 256;
+
+/// Size of the data field the payload sits at the start of.
+const DATA_FIELD_SIZE: usize = 476;
+
+const MAGIC_START0: u32 = 0x0A32_4655;
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+
+/// Set when the family ID field holds a family rather than a file size.
+const FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+
+/// Family IDs of other common UF2 bootloaders, as listed in the UF2
+/// repository's `uf2families.json`.
+const OTHER_FAMILIES: &[(&str, u32)] = &[
+    ("samd21", 0x68ED_2B88),
+    ("samd51", 0x5511_4460),
+    ("nrf52840", 0xADA5_2840),
+    ("stm32f4", 0x5775_5A57),
+    ("esp32s2", 0xBFDD_4EEE),
+    ("esp32s3", 0xC47E_5767),
+    ("esp32c3", 0xD42B_A06C),
+];
+
+/// Look up a family ID by name (`rp2350-arm-s`, `data`, `samd51`, ...) or
+/// parse it as a number.
+pub fn family_id(name: &str) -> Option<u32> {
+    let lower = name.to_ascii_lowercase();
+    OTHER_FAMILIES
+        .iter()
+        .find(|(n, _)| *n == lower)
+        .map(|(_, id)| *id)
+        .or_else(|| Family::parse(&lower).map(Family::id))
+        .or_else(|| parse_size_value(name).ok())
+}
+
+/// Wrap `data` in UF2 blocks that write it to flash starting at
+/// `base_address`.
+///
+/// `base_address` must be aligned to the 256 byte payload, as bootloaders
+/// program whole pages. A short final payload is padded with `0xFF`. If
+/// `family_id` is `None` the blocks carry no family and any bootloader
+/// will accept them.
+pub fn encode(
+    data: &[u8],
+    base_address: u32,
+    family_id: Option<u32>,
+) -> Result<Vec<u8>, OutputError> {
+    if !base_address.is_multiple_of(PAYLOAD_SIZE as u32) {
+        return Err(OutputError::Misaligned {
+            address: base_address,
+            alignment: PAYLOAD_SIZE as u32,
+        });
+    }
+    check_range(base_address, data.len())?;
+
+    let (flags, family) = match family_id {
+        Some(id) => (FLAG_FAMILY_ID_PRESENT, id),
+        None => (0, 0),
+    };
+    let count = data.len().div_ceil(PAYLOAD_SIZE) as u32;
+
+    let mut out = Vec::with_capacity(count as usize * BLOCK_SIZE);
+    for (i, chunk) in data.chunks(PAYLOAD_SIZE).enumerate() {
+        let header = [
+            MAGIC_START0,
+            MAGIC_START1,
+            flags,
+            base_address + (i * PAYLOAD_SIZE) as u32,
+            PAYLOAD_SIZE as u32,
+            i as u32,
+            count,
+            family,
+        ];
+        for word in header {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+        out.extend_from_slice(chunk);
+        out.resize(out.len() + PAYLOAD_SIZE - chunk.len(), 0xFF);
+        // Unused tail of the data field
+        out.resize(out.len() + DATA_FIELD_SIZE - PAYLOAD_SIZE, 0x00);
+        out.extend_from_slice(&MAGIC_END.to_le_bytes());
+    }
+    Ok(out)
+}
+
+impl LfsImage {
+    /// Encode the image as UF2, see [`encode`].
+    pub fn to_uf2(
+        &self,
+        base_address: u32,
+        family_id: Option<u32>,
+    ) -> Result<Vec<u8>, OutputError> {
+        encode(self.data(), base_address, family_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(block: &[u8], index: usize) -> u32 {
+        u32::from_le_bytes(block[index * 4..index * 4 + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn blocks_match_uf2_layout() {
+        let data: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let uf2 = encode(&data, 0x1020_0000, family_id("rp2350-arm-s")).unwrap();
+        assert_eq!(uf2.len(), 3 * BLOCK_SIZE);
+
+        for (i, block) in uf2.chunks(BLOCK_SIZE).enumerate() {
+            assert_eq!(word(block, 0), MAGIC_START0);
+            assert_eq!(word(block, 1), MAGIC_START1);
+            assert_eq!(word(block, 2), FLAG_FAMILY_ID_PRESENT);
+            assert_eq!(word(block, 3), 0x1020_0000 + 256 * i as u32);
+            assert_eq!(word(block, 4), 256);
+            assert_eq!(word(block, 5), i as u32);
+            assert_eq!(word(block, 6), 3);
+            assert_eq!(word(block, 7), 0xE48B_FF59);
+            assert_eq!(word(block, 127), MAGIC_END);
+        }
+
+        let last = &uf2[2 * BLOCK_SIZE..];
+        assert_eq!(&last[32..32 + 88], &data[512..]);
+        assert!(last[32 + 88..32 + 256].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn family_ids_by_name_or_number() {
+        assert_eq!(family_id("data"), Some(0xE48B_FF58));
+        assert_eq!(family_id("RP2040"), Some(0xE48B_FF56));
+        assert_eq!(family_id("samd51"), Some(0x5511_4460));
+        assert_eq!(family_id("0x12345678"), Some(0x1234_5678));
+        assert_eq!(family_id("z80"), None);
+    }
+
+    #[test]
+    fn no_family_leaves_flags_clear() {
+        let uf2 = encode(&[0; 256], 0, None).unwrap();
+        assert_eq!(word(&uf2, 2), 0);
+        assert_eq!(word(&uf2, 7), 0);
+    }
+
+    #[test]
+    fn misaligned_base_rejected() {
+        assert!(matches!(
+            encode(&[0; 256], 0x1000_0080, None),
+            Err(OutputError::Misaligned { .. })
+        ));
+    }
+}
@@ -1,7 +1,11 @@
 //! # Partition Table
 //!
-//! Parses ESP-IDF partition table CSV files and emits Rust constants
-//! for a named partition's offset and size.
+//! Parses ESP-IDF partition table CSV files into a [`PartitionTable`],
+//! validates the layout, converts it to and from the binary format the
+//! bootloader reads, and emits Rust constants for a named partition's
+//! offset and size. RP2350 tables are handled by the [`rp2350`] module,
+//! and [`AnyPartitionTable`] / [`locate_partition`] find a partition in a
+//! table of either kind.
 //!
 //! The CSV format is defined by the
 //! [ESP-IDF documentation](https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-guides/partition-tables.html):
@@ -14,18 +18,47 @@
 //!
 //! - Lines starting with `#` are comments.
 //! - Whitespace around fields is ignored.
+//! - `Type` is `app`, `data`, `bootloader`, `partition_table`, or a
+//!   number for custom types. `SubType` is a name known for the type
+//!   (`factory`, `ota_0`, `nvs`, `fat`, `littlefs`, ...) or a number.
 //! - The `Offset` and `Size` fields accept decimal, hex (`0x…`), or
 //!   suffixed values (`K` = 1024, `M` = 1024 × 1024).
-//! - Either field may be blank or `N/A` when offsets are resolved at
-//!   build time by `gen_esp32part.py`.
-//! - The `Flags` column is optional.
+//! - A blank or `N/A` offset is placed directly after the previous
+//!   partition, aligned to 64 KiB for app partitions and 4 KiB otherwise,
+//!   the same way `gen_esp32part.py` does. The first partition follows the
+//!   table itself, which sits at `0x8000` by default.
+//! - The `Flags` column is optional and holds `encrypted` and/or
+//!   `readonly`, separated by `:`.
 
-use csv::{ReaderBuilder, Trim};
-use std::fmt::Write as _;
+use csv::{ReaderBuilder, StringRecord, Trim};
+use std::fmt::{self, Write as _};
+use std::io::Read;
 use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
+use binary::ENTRY_MAGIC;
+use rp2350::Rp2350PartitionTable;
+
+mod binary;
+mod emit;
+pub mod rp2350;
+
+/// Default flash offset of the partition table itself.
+pub const DEFAULT_TABLE_OFFSET: u32 = 0x8000;
+
+/// Flash space reserved for the partition table.
+pub const TABLE_SIZE: u32 = 0x1000;
+
+/// Offset alignment required for app partitions.
+pub const APP_ALIGNMENT: u32 = 0x10000;
+
+/// Offset alignment required for all other partitions.
+pub const DATA_ALIGNMENT: u32 = 0x1000;
+
+/// Longest partition name the binary table can store.
+pub const MAX_NAME_LEN: usize = 16;
+
 /// Errors that can occur when reading a partition table CSV.
 #[derive(Debug, Error)]
 pub enum PartitionError {
@@ -37,17 +70,116 @@ pub enum PartitionError {
     #[error("failed to parse value: {0}")]
     ParseInt(#[from] ParseIntError),
 
+    /// A size or offset does not fit in 32 bits once its suffix is applied.
+    #[error("value '{0}' is too large")]
+    ValueTooLarge(String),
+
+    /// The partition table itself sits too close to the end of the 32-bit
+    /// address space to be followed by any partition.
+    #[error("partition table offset {0:#x} is out of range")]
+    TableOutOfRange(u32),
+
     /// No partition with the requested name was found.
     #[error("partition '{0}' not found")]
     NotFound(String),
 
-    /// The partition's offset or size is blank / `N/A` and has not been
-    /// resolved by `gen_esp32part.py`.
+    /// A required field of the partition is blank or `N/A`.
+    #[error("partition '{name}' has a blank or N/A {field}")]
+    BlankField { name: String, field: &'static str },
+
+    /// The `Type` field is neither a known name nor a number below 256.
+    #[error("partition '{name}' has unknown type '{part_type}'")]
+    UnknownType { name: String, part_type: String },
+
+    /// The `SubType` field is not known for the partition's type.
+    #[error("partition '{name}' has unknown subtype '{subtype}' for type {part_type}")]
+    UnknownSubtype {
+        name: String,
+        part_type: PartitionType,
+        subtype: String,
+    },
+
+    /// The `Flags` field contains something other than `encrypted` or
+    /// `readonly`.
+    #[error("partition '{name}' has unknown flag '{flag}'")]
+    UnknownFlag { name: String, flag: String },
+
+    /// The partition name does not fit in the binary table.
+    #[error("partition name '{0}' is longer than {MAX_NAME_LEN} characters")]
+    NameTooLong(String),
+
+    /// Two partitions share a name.
+    #[error("partition '{0}' is defined more than once")]
+    DuplicateName(String),
+
+    /// The partition offset is not aligned as its type requires.
+    #[error("partition '{name}' offset {offset:#x} is not aligned to {alignment:#x}")]
+    Misaligned {
+        name: String,
+        offset: u32,
+        alignment: u32,
+    },
+
+    /// The partition starts before the end of the partition table.
     #[error(
-        "partition '{name}' has a blank or N/A {field} \
-         (must be resolved by gen_esp32part.py first)"
+        "partition '{name}' offset {offset:#x} is below the end of the partition table at {min:#x}"
     )]
-    BlankField { name: String, field: &'static str },
+    BelowTable { name: String, offset: u32, min: u32 },
+
+    /// A primary `partition_table` entry is not at the table's offset.
+    #[error(
+        "partition '{name}' offset {offset:#x} is not the partition table offset {table_offset:#x}"
+    )]
+    TableEntryMisplaced {
+        name: String,
+        offset: u32,
+        table_offset: u32,
+    },
+
+    /// Two partitions occupy some of the same flash.
+    #[error("partitions '{first}' and '{second}' overlap")]
+    Overlap { first: String, second: String },
+
+    /// The partition ends past the end of the flash.
+    #[error("partition '{name}' ends at {end:#x}, past the {flash_size:#x} byte flash")]
+    ExceedsFlash {
+        name: String,
+        end: u64,
+        flash_size: u32,
+    },
+
+    /// The table has more partitions than the binary format can hold.
+    #[error("{count} partitions do not fit in a binary partition table (at most {max})")]
+    TooManyPartitions { count: usize, max: usize },
+
+    /// A binary table entry has neither the partition nor the MD5 magic.
+    #[error("invalid binary partition table entry at byte {position:#x}")]
+    InvalidEntry { position: usize },
+
+    /// The MD5 checksum of a binary table does not match its entries.
+    #[error("binary partition table MD5 checksum mismatch")]
+    ChecksumMismatch,
+
+    /// A binary table ended without an all-`0xFF` end marker.
+    #[error("binary partition table has no end marker")]
+    MissingEndMarker,
+
+    /// An RP2350 partition table JSON file could not be parsed.
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// An RP2350 partition table is malformed or can't be encoded as a
+    /// picobin block.
+    #[error("invalid RP2350 partition table: {0}")]
+    InvalidPicobin(String),
+
+    /// A partition table file could not be read.
+    #[error("failed to read partition table {path}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
 
     /// Failed to write generated Rust constants.
     #[error("failed to write generated config to {path}")]
@@ -58,17 +190,204 @@ pub enum PartitionError {
     },
 }
 
+// ---------------------------------------------------------------------------
+// Partition types, subtypes, and flags
+// ---------------------------------------------------------------------------
+
+/// The `Type` column of a partition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionType {
+    App,
+    Data,
+    Bootloader,
+    PartitionTable,
+    /// Any other type, given as a number in the CSV.
+    Custom(u8),
+}
+
+impl PartitionType {
+    /// Parse a type name or number.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "app" => Some(Self::App),
+            "data" => Some(Self::Data),
+            "bootloader" => Some(Self::Bootloader),
+            "partition_table" => Some(Self::PartitionTable),
+            _ => parse_u8(s).map(Self::from_u8),
+        }
+    }
+
+    /// The type from its numeric value in the binary table.
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0x00 => Self::App,
+            0x01 => Self::Data,
+            0x02 => Self::Bootloader,
+            0x03 => Self::PartitionTable,
+            other => Self::Custom(other),
+        }
+    }
+
+    /// The numeric value of the type in the binary table.
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Self::App => 0x00,
+            Self::Data => 0x01,
+            Self::Bootloader => 0x02,
+            Self::PartitionTable => 0x03,
+            Self::Custom(value) => value,
+        }
+    }
+
+    /// The offset alignment partitions of this type require.
+    pub fn alignment(self) -> u32 {
+        match self {
+            Self::App => APP_ALIGNMENT,
+            _ => DATA_ALIGNMENT,
+        }
+    }
+
+    /// The known subtype names for this type and their values.
+    fn subtypes(self) -> &'static [(&'static str, u8)] {
+        match self {
+            Self::App => &[("factory", 0x00), ("test", 0x20)],
+            Self::Data => &[
+                ("ota", 0x00),
+                ("phy", 0x01),
+                ("nvs", 0x02),
+                ("coredump", 0x03),
+                ("nvs_keys", 0x04),
+                ("efuse", 0x05),
+                ("undefined", 0x06),
+                ("esphttpd", 0x80),
+                ("fat", 0x81),
+                ("spiffs", 0x82),
+                ("littlefs", 0x83),
+            ],
+            Self::Bootloader => &[("primary", 0x00), ("ota", 0x01), ("recovery", 0x02)],
+            Self::PartitionTable => &[("primary", 0x00), ("ota", 0x01)],
+            Self::Custom(_) => &[],
+        }
+    }
+
+    /// Parse a subtype name or number for this type. App partitions also
+    /// accept `ota_0` through `ota_15`.
+    pub fn parse_subtype(self, s: &str) -> Option<u8> {
+        if let Some((_, value)) = self.subtypes().iter().find(|(name, _)| *name == s) {
+            return Some(*value);
+        }
+        if self == Self::App
+            && let Some(n) = s.strip_prefix("ota_").and_then(|n| n.parse::<u8>().ok())
+            && n < 16
+        {
+            return Some(0x10 + n);
+        }
+        parse_u8(s)
+    }
+
+    /// The name of `subtype` for this type, if it has one.
+    pub fn subtype_name(self, subtype: u8) -> Option<String> {
+        if self == Self::App && (0x10..0x20).contains(&subtype) {
+            return Some(format!("ota_{}", subtype - 0x10));
+        }
+        self.subtypes()
+            .iter()
+            .find(|(_, value)| *value == subtype)
+            .map(|(name, _)| name.to_string())
+    }
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::App => f.write_str("app"),
+            Self::Data => f.write_str("data"),
+            Self::Bootloader => f.write_str("bootloader"),
+            Self::PartitionTable => f.write_str("partition_table"),
+            Self::Custom(value) => write!(f, "{value:#04x}"),
+        }
+    }
+}
+
+/// The `Flags` column of a partition.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PartitionFlags {
+    /// The partition is encrypted when flash encryption is enabled.
+    pub encrypted: bool,
+    /// The partition is mounted read-only by ESP-IDF.
+    pub readonly: bool,
+}
+
+impl PartitionFlags {
+    /// Parse a `:` separated list of flags.
+    fn parse(s: &str, name: &str) -> Result<Self, PartitionError> {
+        let mut flags = Self::default();
+        for flag in s.split(':').map(str::trim).filter(|f| !f.is_empty()) {
+            match flag {
+                "encrypted" => flags.encrypted = true,
+                "readonly" => flags.readonly = true,
+                _ => {
+                    return Err(PartitionError::UnknownFlag {
+                        name: name.to_string(),
+                        flag: flag.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(flags)
+    }
+}
+
+impl fmt::Display for PartitionFlags {
+    /// Formats the flags as in the CSV `Flags` column, e.g. `encrypted:readonly`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [(self.encrypted, "encrypted"), (self.readonly, "readonly")];
+        let set: Vec<&str> = names
+            .into_iter()
+            .filter_map(|(set, name)| set.then_some(name))
+            .collect();
+        f.write_str(&set.join(":"))
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Partitions
+// ---------------------------------------------------------------------------
+
 /// A single resolved partition entry.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Partition {
     pub name: String,
-    pub part_type: String,
-    pub subtype: String,
+    pub part_type: PartitionType,
+    pub subtype: u8,
     pub offset: u32,
     pub size: u32,
+    pub flags: PartitionFlags,
 }
 
 impl Partition {
+    /// The subtype as written in a CSV: its name if it has one, else hex.
+    pub fn subtype_name(&self) -> String {
+        self.part_type
+            .subtype_name(self.subtype)
+            .unwrap_or_else(|| format!("{:#04x}", self.subtype))
+    }
+
+    /// The first byte past the end of the partition.
+    pub fn end(&self) -> u64 {
+        u64::from(self.offset) + u64::from(self.size)
+    }
+
+    /// Whether this is the entry for the primary bootloader or the
+    /// partition table itself, which sit at fixed offsets below the end of
+    /// the table rather than after it.
+    pub fn is_primary_boot_entry(&self) -> bool {
+        matches!(
+            self.part_type,
+            PartitionType::Bootloader | PartitionType::PartitionTable
+        ) && self.subtype == 0x00
+    }
+
     /// Write Rust constants for this partition to a file in `out_dir`.
     ///
     /// Generates `partition_config.rs` containing:
@@ -107,78 +426,396 @@ impl Partition {
     }
 }
 
+// ---------------------------------------------------------------------------
+// Partition table
+// ---------------------------------------------------------------------------
+
+/// Every partition of an ESP-IDF partition table, with blank offsets
+/// resolved.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionTable {
+    /// Flash offset of the partition table itself.
+    pub table_offset: u32,
+    /// The partitions in the order they were defined.
+    pub partitions: Vec<Partition>,
+}
+
+impl PartitionTable {
+    /// Read a partition table CSV, assuming the table sits at the default
+    /// `0x8000` offset.
+    ///
+    /// The table is not validated; see [`PartitionTable::validate`].
+    pub fn from_csv(csv_path: impl AsRef<Path>) -> Result<Self, PartitionError> {
+        let path = csv_path.as_ref();
+        let file = std::fs::File::open(path).map_err(|source| PartitionError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::from_reader(file, DEFAULT_TABLE_OFFSET)
+    }
+
+    /// Read a partition table CSV for a table placed at `table_offset`.
+    pub fn from_reader(reader: impl Read, table_offset: u32) -> Result<Self, PartitionError> {
+        let mut rdr = ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .has_headers(false)
+            .trim(Trim::All)
+            .flexible(true)
+            .from_reader(reader);
+
+        let mut partitions: Vec<Partition> = Vec::new();
+        let mut next_offset = table_offset.saturating_add(TABLE_SIZE);
+
+        for result in rdr.records() {
+            let record = result?;
+            if record.iter().all(str::is_empty) {
+                continue;
+            }
+
+            let partition = parse_record(&record, next_offset)?;
+            let end = partition.offset.saturating_add(partition.size);
+            // The bootloader ends below the table, and blank offsets still
+            // start after it
+            next_offset = match partition.is_primary_boot_entry() {
+                true => next_offset.max(end),
+                false => end,
+            };
+            partitions.push(partition);
+        }
+
+        Ok(Self {
+            table_offset,
+            partitions,
+        })
+    }
+
+    /// Read a partition table from either a CSV or a binary table.
+    ///
+    /// `data` is treated as binary if it starts with a partition entry, or
+    /// if it is a flash dump with a partition entry at `table_offset`. Any
+    /// other input is parsed as CSV, with blank offsets placed after a
+    /// table at `table_offset`.
+    pub fn from_bytes(data: &[u8], table_offset: u32) -> Result<Self, PartitionError> {
+        if data.starts_with(&ENTRY_MAGIC) {
+            return Self::from_bin(data, table_offset);
+        }
+        let dump_table = data.get(table_offset as usize..).unwrap_or_default();
+        if data.len() > TABLE_SIZE as usize && dump_table.starts_with(&ENTRY_MAGIC) {
+            return Self::from_bin(dump_table, table_offset);
+        }
+        Self::from_reader(data, table_offset)
+    }
+
+    /// Write the table as CSV, in the same form `gen_esp32part.py` uses
+    /// when converting a binary table back.
+    pub fn to_csv(&self) -> String {
+        let mut csv =
+            String::from("# ESP-IDF Partition Table\n# Name, Type, SubType, Offset, Size, Flags\n");
+        for p in &self.partitions {
+            writeln!(
+                csv,
+                "{},{},{},{:#x},{},{}",
+                p.name,
+                p.part_type,
+                p.subtype_name(),
+                p.offset,
+                format_size(p.size),
+                p.flags
+            )
+            .unwrap();
+        }
+        csv
+    }
+
+    /// The partition named `name`, if any.
+    pub fn find(&self, name: &str) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.name == name)
+    }
+
+    /// Check the layout for mistakes the bootloader would otherwise trip
+    /// over at runtime: duplicate or overlong names, misaligned offsets,
+    /// partitions placed over the partition table, overlapping partitions,
+    /// and, if `flash_size` is given, partitions past the end of the flash.
+    ///
+    /// Entries for the primary bootloader and the partition table itself
+    /// may sit below the table, but the table entry must be at
+    /// `table_offset`.
+    pub fn validate(&self, flash_size: Option<u32>) -> Result<(), PartitionError> {
+        let min_offset = self
+            .table_offset
+            .checked_add(TABLE_SIZE)
+            .ok_or(PartitionError::TableOutOfRange(self.table_offset))?;
+
+        for (i, p) in self.partitions.iter().enumerate() {
+            if p.name.len() > MAX_NAME_LEN {
+                return Err(PartitionError::NameTooLong(p.name.clone()));
+            }
+            if self.partitions[..i]
+                .iter()
+                .any(|other| other.name == p.name)
+            {
+                return Err(PartitionError::DuplicateName(p.name.clone()));
+            }
+            let alignment = p.part_type.alignment();
+            if !p.offset.is_multiple_of(alignment) {
+                return Err(PartitionError::Misaligned {
+                    name: p.name.clone(),
+                    offset: p.offset,
+                    alignment,
+                });
+            }
+            if p.part_type == PartitionType::PartitionTable
+                && p.is_primary_boot_entry()
+                && p.offset != self.table_offset
+            {
+                return Err(PartitionError::TableEntryMisplaced {
+                    name: p.name.clone(),
+                    offset: p.offset,
+                    table_offset: self.table_offset,
+                });
+            }
+            if p.offset < min_offset && !p.is_primary_boot_entry() {
+                return Err(PartitionError::BelowTable {
+                    name: p.name.clone(),
+                    offset: p.offset,
+                    min: min_offset,
+                });
+            }
+            if let Some(flash_size) = flash_size
+                && p.end() > u64::from(flash_size)
+            {
+                return Err(PartitionError::ExceedsFlash {
+                    name: p.name.clone(),
+                    end: p.end(),
+                    flash_size,
+                });
+            }
+        }
+
+        let mut by_offset: Vec<&Partition> = self.partitions.iter().collect();
+        by_offset.sort_by_key(|p| p.offset);
+        for pair in by_offset.windows(2) {
+            if pair[0].end() > u64::from(pair[1].offset) {
+                return Err(PartitionError::Overlap {
+                    first: pair[0].name.clone(),
+                    second: pair[1].name.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
 /// Read an ESP-IDF partition table CSV and return the [`Partition`]
 /// whose name matches `target_name`.
 ///
+/// The whole table is parsed and validated, so a partition is only
+/// returned from a table the bootloader would accept.
+///
 /// See the [module-level docs](self) for details on the expected CSV format.
 pub fn get_partition(
     csv_path: impl AsRef<Path>,
     target_name: &str,
 ) -> Result<Partition, PartitionError> {
-    let mut rdr = ReaderBuilder::new()
-        .comment(Some(b'#'))
-        .has_headers(false)
-        .trim(Trim::All)
-        .flexible(true)
-        .from_path(csv_path)?;
-
-    // Fields: Name (0), Type (1), SubType (2), Offset (3), Size (4), Flags (5)
-    for result in rdr.records() {
-        let record = result?;
-
-        let name = match record.get(0) {
-            Some(n) if n == target_name => n,
-            _ => continue,
-        };
+    let table = PartitionTable::from_csv(csv_path)?;
+    table.validate(None)?;
+    table
+        .find(target_name)
+        .cloned()
+        .ok_or_else(|| PartitionError::NotFound(target_name.to_string()))
+}
 
-        let part_type = record.get(1).unwrap_or_default().to_string();
-        let subtype = record.get(2).unwrap_or_default().to_string();
+/// A partition table in any of the supported formats.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AnyPartitionTable {
+    /// An ESP-IDF table, from CSV or the binary format.
+    Esp(PartitionTable),
+    /// An RP2350 table, from picotool JSON or a picobin block.
+    Rp2350(Rp2350PartitionTable),
+}
 
-        let offset_str = record
-            .get(3)
-            .ok_or_else(|| PartitionError::NotFound(target_name.to_string()))?;
-        let size_str = record
-            .get(4)
-            .ok_or_else(|| PartitionError::NotFound(target_name.to_string()))?;
+impl AnyPartitionTable {
+    /// Read a partition table file in any supported format, with ESP
+    /// tables assumed to sit at [`DEFAULT_TABLE_OFFSET`].
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, PartitionError> {
+        let path = path.as_ref();
+        let data = std::fs::read(path).map_err(|source| PartitionError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+        Self::from_bytes(&data, DEFAULT_TABLE_OFFSET)
+    }
 
-        let offset = parse_field(offset_str, target_name, "offset")?;
-        let size = parse_field(size_str, target_name, "size")?;
+    /// Detect the format of `data` and parse it.
+    ///
+    /// JSON input is read as a picotool RP2350 table and a picobin block in
+    /// the first 4 KiB as a binary RP2350 table. Anything else is handed to
+    /// [`PartitionTable::from_bytes`], so ESP binary tables and flash dumps
+    /// are checked for before falling back to CSV.
+    pub fn from_bytes(data: &[u8], table_offset: u32) -> Result<Self, PartitionError> {
+        let text_start = data.iter().find(|b| !b.is_ascii_whitespace());
+        if text_start == Some(&b'{') {
+            let json = String::from_utf8_lossy(data);
+            return Ok(Self::Rp2350(Rp2350PartitionTable::from_json(&json)?));
+        }
+        let esp_binary = data.starts_with(&ENTRY_MAGIC)
+            || data
+                .get(table_offset as usize..)
+                .is_some_and(|d| d.starts_with(&ENTRY_MAGIC));
+        if !esp_binary && Rp2350PartitionTable::is_block(data) {
+            return Ok(Self::Rp2350(Rp2350PartitionTable::from_block(data)?));
+        }
+        Ok(Self::Esp(PartitionTable::from_bytes(data, table_offset)?))
+    }
 
-        return Ok(Partition {
-            name: name.to_string(),
-            part_type,
-            subtype,
-            offset,
-            size,
-        });
+    /// Validate the table with the rules of its format.
+    pub fn validate(&self, flash_size: Option<u32>) -> Result<(), PartitionError> {
+        match self {
+            Self::Esp(table) => table.validate(flash_size),
+            Self::Rp2350(table) => table.validate(flash_size),
+        }
     }
 
-    Err(PartitionError::NotFound(target_name.to_string()))
+    /// The location of the partition named `name`, if any.
+    pub fn locate(&self, name: &str) -> Option<PartitionLocation> {
+        match self {
+            Self::Esp(table) => table.find(name).map(|p| PartitionLocation {
+                name: p.name.clone(),
+                offset: p.offset,
+                size: p.size,
+                address: p.offset,
+            }),
+            Self::Rp2350(table) => table.find(name).map(|p| PartitionLocation {
+                name: name.to_string(),
+                offset: p.start,
+                size: p.size,
+                address: p.xip_address(),
+            }),
+        }
+    }
 }
 
-/// Parse an offset or size field, returning [`PartitionError::BlankField`]
-/// for empty / `N/A` values.
-fn parse_field(value: &str, name: &str, field: &'static str) -> Result<u32, PartitionError> {
-    if value.is_empty() || value.eq_ignore_ascii_case("n/a") {
-        return Err(PartitionError::BlankField {
-            name: name.to_string(),
-            field,
-        });
+/// Where a partition lives, independent of the table format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionLocation {
+    pub name: String,
+    /// Offset of the partition from the start of flash.
+    pub offset: u32,
+    pub size: u32,
+    /// The address flashing tools expect: the flash offset for ESP chips,
+    /// and the XIP address (`0x10000000` + offset) for the RP2350.
+    pub address: u32,
+}
+
+/// Read a partition table in any supported format and return the location
+/// of the partition named `target_name`.
+///
+/// Like [`get_partition`], the whole table is validated first.
+pub fn locate_partition(
+    table_path: impl AsRef<Path>,
+    target_name: &str,
+) -> Result<PartitionLocation, PartitionError> {
+    let table = AnyPartitionTable::from_file(table_path)?;
+    table.validate(None)?;
+    table
+        .locate(target_name)
+        .ok_or_else(|| PartitionError::NotFound(target_name.to_string()))
+}
+
+/// Parse one CSV row, placing a blank offset at `next_offset` aligned
+/// for the partition's type.
+///
+/// Fields: Name (0), Type (1), SubType (2), Offset (3), Size (4), Flags (5)
+fn parse_record(record: &StringRecord, next_offset: u32) -> Result<Partition, PartitionError> {
+    let field = |i| record.get(i).unwrap_or_default();
+    let name = field(0).to_string();
+    let blank = |field| PartitionError::BlankField {
+        name: name.clone(),
+        field,
+    };
+
+    let type_str = field(1);
+    if is_blank(type_str) {
+        return Err(blank("type"));
     }
-    parse_size_value(value).map_err(Into::into)
+    let part_type = PartitionType::parse(type_str).ok_or_else(|| PartitionError::UnknownType {
+        name: name.clone(),
+        part_type: type_str.to_string(),
+    })?;
+
+    // Like gen_esp32part.py, only app partitions need an explicit subtype
+    let subtype_str = field(2);
+    let subtype =
+        match (is_blank(subtype_str), part_type) {
+            (true, PartitionType::App) => return Err(blank("subtype")),
+            (true, _) => 0x06,
+            (false, _) => part_type.parse_subtype(subtype_str).ok_or_else(|| {
+                PartitionError::UnknownSubtype {
+                    name: name.clone(),
+                    part_type,
+                    subtype: subtype_str.to_string(),
+                }
+            })?,
+        };
+
+    let offset = match field(3) {
+        offset if is_blank(offset) => next_offset
+            .checked_next_multiple_of(part_type.alignment())
+            .ok_or_else(|| PartitionError::ValueTooLarge(format!("{next_offset:#x}")))?,
+        offset => parse_size_value(offset)?,
+    };
+    let size = match field(4) {
+        size if is_blank(size) => return Err(blank("size")),
+        size => parse_size_value(size)?,
+    };
+    let flags = PartitionFlags::parse(field(5), &name)?;
+
+    Ok(Partition {
+        name,
+        part_type,
+        subtype,
+        offset,
+        size,
+        flags,
+    })
+}
+
+/// Format a size with an `M` or `K` suffix where it divides evenly.
+fn format_size(size: u32) -> String {
+    match size {
+        0 => "0".to_string(),
+        s if s.is_multiple_of(1024 * 1024) => format!("{}M", s / (1024 * 1024)),
+        s if s.is_multiple_of(1024) => format!("{}K", s / 1024),
+        s => format!("{s:#x}"),
+    }
+}
+
+/// Whether a field is empty or `N/A`.
+fn is_blank(value: &str) -> bool {
+    value.is_empty() || value.eq_ignore_ascii_case("n/a")
+}
+
+/// Parse a type or subtype number.
+fn parse_u8(s: &str) -> Option<u8> {
+    parse_size_value(s).ok()?.try_into().ok()
 }
 
 /// Parse a value that may be hex (`0x…`), decimal, or use `K`/`M` suffixes.
-fn parse_size_value(s: &str) -> Result<u32, ParseIntError> {
-    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
-        u32::from_str_radix(hex, 16)
+pub fn parse_size_value(s: &str) -> Result<u32, PartitionError> {
+    let (num, multiplier) = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))
+    {
+        return Ok(u32::from_str_radix(hex, 16)?);
     } else if let Some(num) = s.strip_suffix('K').or_else(|| s.strip_suffix('k')) {
-        num.parse::<u32>().map(|n| n * 1024)
+        (num, 1024)
     } else if let Some(num) = s.strip_suffix('M').or_else(|| s.strip_suffix('m')) {
-        num.parse::<u32>().map(|n| n * 1024 * 1024)
+        (num, 1024 * 1024)
     } else {
-        s.parse::<u32>()
-    }
+        (s, 1)
+    };
+    num.parse::<u32>()?
+        .checked_mul(multiplier)
+        .ok_or_else(|| PartitionError::ValueTooLarge(s.to_string()))
 }
 
 #[cfg(test)]
@@ -230,8 +867,8 @@ ota_1,    app,  ota_1,    ,         1M
 
         let p = get_partition(&path, "littlefs").unwrap();
         assert_eq!(p.name, "littlefs");
-        assert_eq!(p.part_type, "data");
-        assert_eq!(p.subtype, "fat");
+        assert_eq!(p.part_type, PartitionType::Data);
+        assert_eq!(p.subtype_name(), "fat");
         assert_eq!(p.offset, 0x200000);
         assert_eq!(p.size, 0xE00000);
     }
@@ -256,18 +893,12 @@ ota_1,    app,  ota_1,    ,         1M
     }
 
     #[test]
-    fn blank_offset_returns_error() {
+    fn blank_offset_is_auto_placed() {
         let dir = tempfile::tempdir().unwrap();
         let path = write_ota_csv(&dir);
 
-        let err = get_partition(&path, "ota_0").unwrap_err();
-        assert!(matches!(
-            err,
-            PartitionError::BlankField {
-                field: "offset",
-                ..
-            }
-        ));
+        assert_eq!(get_partition(&path, "ota_0").unwrap().offset, 0x110000);
+        assert_eq!(get_partition(&path, "ota_1").unwrap().offset, 0x210000);
     }
 
     #[test]
@@ -285,10 +916,11 @@ ota_1,    app,  ota_1,    ,         1M
     fn emit_rust_generates_constants() {
         let p = Partition {
             name: "littlefs".to_string(),
-            part_type: "data".to_string(),
-            subtype: "fat".to_string(),
+            part_type: PartitionType::Data,
+            subtype: 0x81,
             offset: 0x200000,
             size: 0xE00000,
+            flags: PartitionFlags::default(),
         };
 
         let dir = tempfile::tempdir().unwrap();
@@ -308,4 +940,340 @@ ota_1,    app,  ota_1,    ,         1M
         assert_eq!(parse_size_value("64K").unwrap(), 64 * 1024);
         assert_eq!(parse_size_value("1M").unwrap(), 1024 * 1024);
     }
+
+    #[test]
+    fn parse_size_value_overflow_rejected() {
+        assert_eq!(parse_size_value("4095M").unwrap(), 4095 * 1024 * 1024);
+        assert!(matches!(
+            parse_size_value("4096M"),
+            Err(PartitionError::ValueTooLarge(v)) if v == "4096M"
+        ));
+        assert!(matches!(
+            parse_size_value("5000000K"),
+            Err(PartitionError::ValueTooLarge(_))
+        ));
+    }
+
+    // -----------------------------------------------------------------------
+    // Partition table parsing
+    // -----------------------------------------------------------------------
+
+    fn parse(csv: &str) -> Result<PartitionTable, PartitionError> {
+        PartitionTable::from_reader(csv.as_bytes(), DEFAULT_TABLE_OFFSET)
+    }
+
+    #[test]
+    fn parses_every_row() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = PartitionTable::from_csv(write_ota_csv(&dir)).unwrap();
+
+        let names: Vec<_> = table.partitions.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["nvs", "otadata", "phy_init", "factory", "ota_0", "ota_1"]
+        );
+
+        let ota_1 = table.find("ota_1").unwrap();
+        assert_eq!(ota_1.part_type, PartitionType::App);
+        assert_eq!(ota_1.subtype, 0x11);
+        assert_eq!(ota_1.subtype_name(), "ota_1");
+        assert_eq!(table.find("otadata").unwrap().subtype, 0x00);
+        table.validate(Some(4 * 1024 * 1024)).unwrap();
+    }
+
+    #[test]
+    fn blank_offsets_follow_the_table_and_align_by_type() {
+        let table = parse(
+            "nvs, data, nvs, , 0x5000\n\
+             factory, app, factory, , 1M\n\
+             storage, data, littlefs, , 0x1800\n\
+             spiffs, data, spiffs, N/A, 64K\n",
+        )
+        .unwrap();
+
+        let offsets: Vec<_> = table.partitions.iter().map(|p| p.offset).collect();
+        assert_eq!(offsets, [0x9000, 0x10000, 0x110000, 0x112000]);
+        table.validate(None).unwrap();
+    }
+
+    #[test]
+    fn custom_table_offset_moves_first_partition() {
+        let table =
+            PartitionTable::from_reader(&b"nvs, data, nvs, , 0x4000\n"[..], 0x10000).unwrap();
+        assert_eq!(table.partitions[0].offset, 0x11000);
+    }
+
+    #[test]
+    fn flags_and_numeric_types_parsed() {
+        let table = parse(
+            "nvs_key, data, nvs_keys, 0x9000, 0x1000, encrypted:readonly\n\
+             custom, 0x40, 0x01, 0xa000, 0x1000\n\
+             blank, data, , 0xb000, 0x1000\n",
+        )
+        .unwrap();
+
+        let flags = table.partitions[0].flags;
+        assert!(flags.encrypted && flags.readonly);
+        assert_eq!(table.partitions[1].part_type, PartitionType::Custom(0x40));
+        assert_eq!(table.partitions[1].subtype_name(), "0x01");
+        assert_eq!(table.partitions[2].subtype_name(), "undefined");
+    }
+
+    #[test]
+    fn unknown_fields_rejected() {
+        assert!(matches!(
+            parse("x, firmware, factory, , 1M\n"),
+            Err(PartitionError::UnknownType { .. })
+        ));
+        assert!(matches!(
+            parse("x, app, nvs, , 1M\n"),
+            Err(PartitionError::UnknownSubtype { .. })
+        ));
+        assert!(matches!(
+            parse("x, app, ota_16, , 1M\n"),
+            Err(PartitionError::UnknownSubtype { .. })
+        ));
+        assert!(matches!(
+            parse("x, data, nvs, , 4K, secret\n"),
+            Err(PartitionError::UnknownFlag { flag, .. }) if flag == "secret"
+        ));
+        assert!(matches!(
+            parse("x, app, , , 1M\n"),
+            Err(PartitionError::BlankField {
+                field: "subtype",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse("x, data, nvs, 0x9000\n"),
+            Err(PartitionError::BlankField { field: "size", .. })
+        ));
+    }
+
+    // -----------------------------------------------------------------------
+    // Partition table validation
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn overlap_rejected() {
+        let table = parse(
+            "nvs, data, nvs, 0x9000, 0x8000\n\
+             phy_init, data, phy, 0xf000, 0x1000\n",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            table.validate(None),
+            Err(PartitionError::Overlap { first, second }) if first == "nvs" && second == "phy_init"
+        ));
+    }
+
+    #[test]
+    fn misaligned_app_rejected() {
+        let table = parse("factory, app, factory, 0x18000, 1M\n").unwrap();
+
+        assert!(matches!(
+            table.validate(None),
+            Err(PartitionError::Misaligned {
+                offset: 0x18000,
+                alignment: 0x10000,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn partition_below_table_rejected() {
+        let table = parse("nvs, data, nvs, 0x8000, 0x6000\n").unwrap();
+
+        assert!(matches!(
+            table.validate(None),
+            Err(PartitionError::BelowTable { min: 0x9000, .. })
+        ));
+    }
+
+    #[test]
+    fn primary_boot_entries_below_table_accepted() {
+        let table = parse(
+            "bootloader, bootloader, primary, 0x1000, 0x7000\n\
+             partition_table, partition_table, primary, 0x8000, 0x1000\n\
+             nvs, data, nvs, , 0x6000\n",
+        )
+        .unwrap();
+
+        table.validate(None).unwrap();
+        assert_eq!(table.find("nvs").unwrap().offset, 0x9000);
+    }
+
+    #[test]
+    fn misplaced_table_entry_rejected() {
+        let table = parse("partition_table, partition_table, primary, 0x9000, 0x1000\n").unwrap();
+
+        assert!(matches!(
+            table.validate(None),
+            Err(PartitionError::TableEntryMisplaced {
+                offset: 0x9000,
+                table_offset: 0x8000,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn blank_offset_after_end_of_address_space_rejected() {
+        let err = parse("a, data, nvs, 0xFFFF0000, 0x10000\nb, data, nvs, , 0x1000\n").unwrap_err();
+
+        assert!(matches!(err, PartitionError::ValueTooLarge(v) if v == "0xffffffff"));
+    }
+
+    #[test]
+    fn table_at_end_of_address_space_rejected() {
+        let table = PartitionTable::from_reader("".as_bytes(), 0xFFFF_F800).unwrap();
+
+        assert!(matches!(
+            table.validate(None),
+            Err(PartitionError::TableOutOfRange(0xFFFF_F800))
+        ));
+    }
+
+    #[test]
+    fn missing_csv_reports_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.csv");
+
+        assert!(matches!(
+            PartitionTable::from_csv(&path),
+            Err(PartitionError::Read { path: p, .. }) if p == path
+        ));
+    }
+
+    #[test]
+    fn table_exceeding_flash_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = PartitionTable::from_csv(write_test_csv(&dir)).unwrap();
+
+        table.validate(Some(16 * 1024 * 1024)).unwrap();
+        assert!(matches!(
+            table.validate(Some(4 * 1024 * 1024)),
+            Err(PartitionError::ExceedsFlash { name, end: 0x1000000, .. }) if name == "littlefs"
+        ));
+    }
+
+    #[test]
+    fn duplicate_and_long_names_rejected() {
+        let dup = parse(
+            "nvs, data, nvs, , 0x1000\n\
+             nvs, data, nvs, , 0x1000\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            dup.validate(None),
+            Err(PartitionError::DuplicateName(_))
+        ));
+
+        let long = parse("a_very_long_partition, data, nvs, , 0x1000\n").unwrap();
+        assert!(matches!(
+            long.validate(None),
+            Err(PartitionError::NameTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn get_partition_validates_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("partitions.csv");
+        std::fs::write(
+            &path,
+            "nvs, data, nvs, 0x9000, 0x8000\nlittlefs, data, littlefs, 0xf000, 0x1000\n",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            get_partition(&path, "littlefs"),
+            Err(PartitionError::Overlap { .. })
+        ));
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut table = PartitionTable::from_csv(write_ota_csv(&dir)).unwrap();
+        table.partitions[0].flags.encrypted = true;
+        table.partitions[0].flags.readonly = true;
+
+        let csv = table.to_csv();
+        assert!(csv.contains("\nnvs,data,nvs,0x9000,16K,encrypted:readonly\n"));
+        assert!(csv.contains("\nota_1,app,ota_1,0x210000,1M,\n"));
+        assert_eq!(
+            PartitionTable::from_reader(csv.as_bytes(), DEFAULT_TABLE_OFFSET).unwrap(),
+            table
+        );
+    }
+
+    #[test]
+    fn from_bytes_detects_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = std::fs::read(write_test_csv(&dir)).unwrap();
+        let table = PartitionTable::from_bytes(&csv, DEFAULT_TABLE_OFFSET).unwrap();
+        let bin = table.to_bin().unwrap();
+
+        assert_eq!(
+            PartitionTable::from_bytes(&bin, DEFAULT_TABLE_OFFSET).unwrap(),
+            table
+        );
+
+        let mut dump = vec![0xFF; DEFAULT_TABLE_OFFSET as usize];
+        dump.extend_from_slice(&bin);
+        dump.resize(0x20000, 0xFF);
+        assert_eq!(
+            PartitionTable::from_bytes(&dump, DEFAULT_TABLE_OFFSET).unwrap(),
+            table
+        );
+    }
+
+    #[test]
+    fn any_table_detects_every_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = std::fs::read(write_test_csv(&dir)).unwrap();
+        let esp = PartitionTable::from_bytes(&csv, DEFAULT_TABLE_OFFSET).unwrap();
+        let json = r#"{"partitions": [{"name": "littlefs", "start": "1M", "size": "1M"}]}"#;
+        let rp = Rp2350PartitionTable::from_json(json).unwrap();
+
+        let detect =
+            |data: &[u8]| AnyPartitionTable::from_bytes(data, DEFAULT_TABLE_OFFSET).unwrap();
+        assert_eq!(detect(&csv), AnyPartitionTable::Esp(esp.clone()));
+        assert_eq!(detect(&esp.to_bin().unwrap()), AnyPartitionTable::Esp(esp));
+        assert_eq!(
+            detect(json.as_bytes()),
+            AnyPartitionTable::Rp2350(rp.clone())
+        );
+        assert_eq!(
+            detect(&rp.to_block().unwrap()),
+            AnyPartitionTable::Rp2350(rp)
+        );
+    }
+
+    #[test]
+    fn locate_partition_uses_chip_addresses() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = write_test_csv(&dir);
+        let esp = locate_partition(&csv, "littlefs").unwrap();
+        assert_eq!(esp.address, esp.offset);
+
+        let json = dir.path().join("partitions.json");
+        std::fs::write(
+            &json,
+            r#"{"partitions": [{"name": "littlefs", "start": "1M", "size": "1M"}]}"#,
+        )
+        .unwrap();
+        let rp = locate_partition(&json, "littlefs").unwrap();
+        assert_eq!(rp.offset, 0x100000);
+        assert_eq!(rp.size, 0x100000);
+        assert_eq!(rp.address, 0x10100000);
+
+        assert!(matches!(
+            locate_partition(&json, "missing"),
+            Err(PartitionError::NotFound(_))
+        ));
+    }
 }
@@ -0,0 +1,241 @@
+//! The binary partition table format read by the ESP-IDF bootloader, as
+//! written by `gen_esp32part.py`.
+//!
+//! Each partition is a 32 byte little-endian entry:
+//!
+//! ```text
+//! magic (0xAA 0x50) | type u8 | subtype u8 | offset u32 | size u32 | name [u8; 16] | flags u32
+//! ```
+//!
+//! The entries are followed by an MD5 row (`0xEB 0xEB`, 14 bytes of `0xFF`,
+//! then the MD5 of every preceding byte), and the table is padded with
+//! `0xFF` to 0xC00 bytes. An all-`0xFF` entry marks the end of the table.
+
+use md5::{Digest, Md5};
+
+use super::{
+    MAX_NAME_LEN, Partition, PartitionError, PartitionFlags, PartitionTable, PartitionType,
+};
+
+/// Size of one table entry.
+const ENTRY_SIZE: usize = 32;
+
+/// Length of the padded binary table.
+const BINARY_TABLE_LEN: usize = 0xC00;
+
+/// Magic bytes that start each partition entry.
+pub(super) const ENTRY_MAGIC: [u8; 2] = [0xAA, 0x50];
+
+/// Magic bytes that start the MD5 checksum row.
+const MD5_MAGIC: [u8; 2] = [0xEB, 0xEB];
+
+const FLAG_ENCRYPTED: u32 = 1 << 0;
+const FLAG_READONLY: u32 = 1 << 1;
+
+impl PartitionTable {
+    /// Encode the table in the binary format, with an MD5 checksum row,
+    /// padded to 0xC00 bytes.
+    ///
+    /// The layout is not validated; see [`PartitionTable::validate`].
+    pub fn to_bin(&self) -> Result<Vec<u8>, PartitionError> {
+        // The MD5 row and at least one end marker have to fit after the entries
+        let max = BINARY_TABLE_LEN / ENTRY_SIZE - 2;
+        if self.partitions.len() > max {
+            return Err(PartitionError::TooManyPartitions {
+                count: self.partitions.len(),
+                max,
+            });
+        }
+
+        let mut out = Vec::with_capacity(BINARY_TABLE_LEN);
+        for p in &self.partitions {
+            out.extend_from_slice(&encode_entry(p)?);
+        }
+
+        let md5 = Md5::digest(&out);
+        out.extend_from_slice(&MD5_MAGIC);
+        out.extend_from_slice(&[0xFF; 14]);
+        out.extend_from_slice(&md5);
+
+        out.resize(BINARY_TABLE_LEN, 0xFF);
+        Ok(out)
+    }
+
+    /// Decode a binary partition table found at flash offset
+    /// `table_offset`.
+    ///
+    /// `data` starts at the first entry and may extend past the end of the
+    /// table. If the table has an MD5 row, the checksum is verified.
+    pub fn from_bin(data: &[u8], table_offset: u32) -> Result<Self, PartitionError> {
+        let mut partitions = Vec::new();
+
+        for (i, entry) in data.chunks(ENTRY_SIZE).enumerate() {
+            let position = i * ENTRY_SIZE;
+            if entry.len() < ENTRY_SIZE {
+                break;
+            }
+            if entry.iter().all(|&b| b == 0xFF) {
+                return Ok(Self {
+                    table_offset,
+                    partitions,
+                });
+            }
+            if entry[..2] == MD5_MAGIC {
+                if Md5::digest(&data[..position])[..] != entry[16..] {
+                    return Err(PartitionError::ChecksumMismatch);
+                }
+                continue;
+            }
+            if entry[..2] != ENTRY_MAGIC {
+                return Err(PartitionError::InvalidEntry { position });
+            }
+            partitions.push(decode_entry(entry));
+        }
+
+        Err(PartitionError::MissingEndMarker)
+    }
+}
+
+/// Encode one partition as a 32 byte entry.
+fn encode_entry(p: &Partition) -> Result<[u8; ENTRY_SIZE], PartitionError> {
+    if p.name.len() > MAX_NAME_LEN {
+        return Err(PartitionError::NameTooLong(p.name.clone()));
+    }
+
+    let mut flags = 0;
+    if p.flags.encrypted {
+        flags |= FLAG_ENCRYPTED;
+    }
+    if p.flags.readonly {
+        flags |= FLAG_READONLY;
+    }
+
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[..2].copy_from_slice(&ENTRY_MAGIC);
+    entry[2] = p.part_type.as_u8();
+    entry[3] = p.subtype;
+    entry[4..8].copy_from_slice(&p.offset.to_le_bytes());
+    entry[8..12].copy_from_slice(&p.size.to_le_bytes());
+    entry[12..12 + p.name.len()].copy_from_slice(p.name.as_bytes());
+    entry[28..32].copy_from_slice(&flags.to_le_bytes());
+    Ok(entry)
+}
+
+/// Decode a 32 byte entry whose magic has already been checked.
+fn decode_entry(entry: &[u8]) -> Partition {
+    let u32_at = |i: usize| u32::from_le_bytes(entry[i..i + 4].try_into().unwrap());
+    let name = &entry[12..28];
+    let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    let flags = u32_at(28);
+
+    Partition {
+        name: String::from_utf8_lossy(&name[..name_len]).into_owned(),
+        part_type: PartitionType::from_u8(entry[2]),
+        subtype: entry[3],
+        offset: u32_at(4),
+        size: u32_at(8),
+        flags: PartitionFlags {
+            encrypted: flags & FLAG_ENCRYPTED != 0,
+            readonly: flags & FLAG_READONLY != 0,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition_table::DEFAULT_TABLE_OFFSET;
+
+    fn table() -> PartitionTable {
+        PartitionTable::from_reader(
+            &b"\
+nvs,      data, nvs,      0x9000,  0x6000
+factory,  app,  factory,  0x10000, 1M
+littlefs, data, littlefs, ,        0x100000, readonly
+"[..],
+            DEFAULT_TABLE_OFFSET,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn entries_match_gen_esp32part_layout() {
+        let bin = table().to_bin().unwrap();
+        assert_eq!(bin.len(), BINARY_TABLE_LEN);
+
+        // nvs, data (1), nvs (2), 0x9000, 0x6000, no flags
+        let mut nvs = vec![0xAA, 0x50, 0x01, 0x02];
+        nvs.extend_from_slice(&0x9000u32.to_le_bytes());
+        nvs.extend_from_slice(&0x6000u32.to_le_bytes());
+        nvs.extend_from_slice(b"nvs\0\0\0\0\0\0\0\0\0\0\0\0\0");
+        nvs.extend_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(&bin[..32], &nvs[..]);
+
+        // littlefs subtype and readonly flag
+        assert_eq!(bin[64 + 3], 0x83);
+        assert_eq!(&bin[64 + 28..96], &[2, 0, 0, 0]);
+
+        // MD5 row, then padding
+        assert_eq!(&bin[96..112], &[&MD5_MAGIC[..], &[0xFF; 14]].concat()[..]);
+        assert_eq!(&bin[112..128], &Md5::digest(&bin[..96])[..]);
+        assert!(bin[128..].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let table = table();
+        let bin = table.to_bin().unwrap();
+        assert_eq!(
+            PartitionTable::from_bin(&bin, DEFAULT_TABLE_OFFSET).unwrap(),
+            table
+        );
+    }
+
+    #[test]
+    fn reads_table_from_longer_buffer() {
+        let mut dump = table().to_bin().unwrap();
+        dump.resize(0x10000, 0x00);
+        assert_eq!(
+            PartitionTable::from_bin(&dump, DEFAULT_TABLE_OFFSET)
+                .unwrap()
+                .partitions
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn corrupted_table_rejected() {
+        let mut bin = table().to_bin().unwrap();
+        bin[4] ^= 0x01;
+        assert!(matches!(
+            PartitionTable::from_bin(&bin, DEFAULT_TABLE_OFFSET),
+            Err(PartitionError::ChecksumMismatch)
+        ));
+
+        let mut bin = table().to_bin().unwrap();
+        bin[32] = 0x00;
+        assert!(matches!(
+            PartitionTable::from_bin(&bin, DEFAULT_TABLE_OFFSET),
+            Err(PartitionError::InvalidEntry { position: 32 })
+        ));
+
+        let bin = table().to_bin().unwrap();
+        assert!(matches!(
+            PartitionTable::from_bin(&bin[..96], DEFAULT_TABLE_OFFSET),
+            Err(PartitionError::MissingEndMarker)
+        ));
+    }
+
+    #[test]
+    fn too_many_partitions_rejected() {
+        let mut table = table();
+        let template = table.partitions[0].clone();
+        table.partitions = vec![template; 95];
+
+        assert!(matches!(
+            table.to_bin(),
+            Err(PartitionError::TooManyPartitions { count: 95, max: 94 })
+        ));
+    }
+}
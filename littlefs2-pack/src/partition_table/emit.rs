@@ -0,0 +1,332 @@
+//! Rust code generation for a whole partition table.
+//!
+//! The generated file defines `PartitionType`, `PartitionSubtype` and
+//! `Partition` types, a module per partition with its constants, and a
+//! `PARTITIONS` table listing every partition in CSV order.
+
+use std::fmt::Write as _;
+use std::path::Path;
+
+use super::{Partition, PartitionError, PartitionTable, PartitionType};
+use crate::littlefs::to_mod_name;
+
+/// Rust keywords, strict and reserved, that a sanitized partition name
+/// can't be used as a module name.
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl",
+    "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "static", "struct", "super", "trait", "true", "try", "type", "typeof",
+    "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+/// Types shared by every generated table.
+const PRELUDE: &str = "\
+/// The type of a partition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionType {
+    App,
+    Data,
+    Bootloader,
+    PartitionTable,
+    Custom(u8),
+}
+
+/// A partition of the table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Partition {
+    pub name: &'static str,
+    pub part_type: PartitionType,
+    pub subtype: PartitionSubtype,
+    pub offset: u32,
+    pub size: u32,
+    pub encrypted: bool,
+    pub readonly: bool,
+}
+
+/// Look up a partition by name.
+pub fn find(name: &str) -> Option<&'static Partition> {
+    PARTITIONS.iter().find(|p| p.name == name)
+}
+";
+
+impl PartitionTable {
+    /// Write Rust constants for every partition to a file in `out_dir`.
+    ///
+    /// Generates `partition_table.rs`, which contains a module per
+    /// partition named after it (sanitized to a valid module name):
+    ///
+    /// ```text
+    /// pub mod ota_0 {
+    ///     pub const NAME: &str = "ota_0";
+    ///     pub const OFFSET: u32 = 0x110000;
+    ///     pub const SIZE: u32 = 0x100000;
+    ///     pub const ENCRYPTED: bool = false;
+    ///     pub const READONLY: bool = false;
+    ///     pub const PARTITION: super::Partition = super::Partition { ... };
+    /// }
+    /// ```
+    ///
+    /// plus a `PARTITIONS: &[Partition]` table and a `find(name)` lookup.
+    pub fn emit_rust(&self, out_dir: &Path) -> Result<(), PartitionError> {
+        let path = out_dir.join("partition_table.rs");
+        std::fs::write(&path, self.rust_source())
+            .map_err(|source| PartitionError::EmitRust { path, source })
+    }
+
+    /// The contents of the generated `partition_table.rs`.
+    fn rust_source(&self) -> String {
+        let mod_names = self.mod_names();
+
+        let mut out = String::new();
+        writeln!(out, "// Auto-generated by littlefs2-pack — do not edit.").unwrap();
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "/// Flash offset of the partition table itself.\n\
+             pub const TABLE_OFFSET: u32 = {:#X};",
+            self.table_offset
+        )
+        .unwrap();
+        writeln!(out).unwrap();
+        out.push_str(PRELUDE);
+        writeln!(out).unwrap();
+        emit_subtype_enum(&mut out);
+
+        writeln!(out).unwrap();
+        writeln!(out, "/// Every partition, in table order.").unwrap();
+        writeln!(out, "pub const PARTITIONS: &[Partition] = &[").unwrap();
+        for mod_name in &mod_names {
+            writeln!(out, "    {mod_name}::PARTITION,").unwrap();
+        }
+        writeln!(out, "];").unwrap();
+
+        for (p, mod_name) in self.partitions.iter().zip(&mod_names) {
+            writeln!(out).unwrap();
+            emit_partition_mod(&mut out, p, mod_name);
+        }
+
+        out
+    }
+
+    /// Module names for the partitions, with a `_` suffix added to names
+    /// that sanitize to a keyword and a numeric suffix added where two
+    /// names sanitize to the same module.
+    fn mod_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::with_capacity(self.partitions.len());
+        for p in &self.partitions {
+            let mut base = to_mod_name(&p.name);
+            if KEYWORDS.contains(&base.as_str()) {
+                base.push('_');
+            }
+            let mut name = base.clone();
+            let mut n = 2;
+            while names.contains(&name) {
+                name = format!("{base}_{n}");
+                n += 1;
+            }
+            names.push(name);
+        }
+        names
+    }
+}
+
+/// Write the module for one partition.
+fn emit_partition_mod(out: &mut String, p: &Partition, mod_name: &str) {
+    let part_type = type_variant(p.part_type);
+    let subtype = subtype_variant(p.part_type, p.subtype);
+
+    writeln!(out, "pub mod {mod_name} {{").unwrap();
+    writeln!(out, "    use super::{{PartitionSubtype, PartitionType}};").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "    pub const NAME: &str = {:?};", p.name).unwrap();
+    writeln!(out, "    pub const TYPE: PartitionType = {part_type};").unwrap();
+    writeln!(
+        out,
+        "    pub const SUBTYPE: PartitionSubtype = PartitionSubtype::{subtype};"
+    )
+    .unwrap();
+    writeln!(out, "    pub const OFFSET: u32 = {:#X};", p.offset).unwrap();
+    writeln!(out, "    pub const SIZE: u32 = {:#X};", p.size).unwrap();
+    writeln!(
+        out,
+        "    pub const ENCRYPTED: bool = {};",
+        p.flags.encrypted
+    )
+    .unwrap();
+    writeln!(out, "    pub const READONLY: bool = {};", p.flags.readonly).unwrap();
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "    pub const PARTITION: super::Partition = super::Partition {{"
+    )
+    .unwrap();
+    writeln!(out, "        name: NAME,").unwrap();
+    writeln!(out, "        part_type: TYPE,").unwrap();
+    writeln!(out, "        subtype: SUBTYPE,").unwrap();
+    writeln!(out, "        offset: OFFSET,").unwrap();
+    writeln!(out, "        size: SIZE,").unwrap();
+    writeln!(out, "        encrypted: ENCRYPTED,").unwrap();
+    writeln!(out, "        readonly: READONLY,").unwrap();
+    writeln!(out, "    }};").unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+/// Write the `PartitionSubtype` enum with a variant for every known
+/// subtype name.
+fn emit_subtype_enum(out: &mut String) {
+    writeln!(out, "/// The subtype of a partition.").unwrap();
+    writeln!(out, "#[derive(Clone, Copy, Debug, PartialEq, Eq)]").unwrap();
+    writeln!(out, "pub enum PartitionSubtype {{").unwrap();
+    writeln!(out, "    Factory,").unwrap();
+    writeln!(out, "    Test,").unwrap();
+    writeln!(out, "    /// App partition `ota_<n>`.").unwrap();
+    writeln!(out, "    Ota(u8),").unwrap();
+    for part_type in [
+        PartitionType::Data,
+        PartitionType::Bootloader,
+        PartitionType::PartitionTable,
+    ] {
+        for (_, value) in part_type.subtypes() {
+            writeln!(out, "    {},", subtype_variant(part_type, *value)).unwrap();
+        }
+    }
+    writeln!(out, "    /// A subtype without a name.").unwrap();
+    writeln!(out, "    Other(u8),").unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+/// The generated `PartitionType` value for a type.
+fn type_variant(part_type: PartitionType) -> String {
+    match part_type {
+        PartitionType::App => "PartitionType::App".into(),
+        PartitionType::Data => "PartitionType::Data".into(),
+        PartitionType::Bootloader => "PartitionType::Bootloader".into(),
+        PartitionType::PartitionTable => "PartitionType::PartitionTable".into(),
+        PartitionType::Custom(value) => format!("PartitionType::Custom({value:#04X})"),
+    }
+}
+
+/// The generated `PartitionSubtype` variant for a subtype. Data subtypes
+/// keep their plain names, while bootloader and partition table subtypes
+/// are prefixed with their type so the names don't collide.
+fn subtype_variant(part_type: PartitionType, subtype: u8) -> String {
+    let Some(name) = part_type.subtype_name(subtype) else {
+        return format!("Other({subtype:#04X})");
+    };
+    match (part_type, name.as_str()) {
+        (PartitionType::App, "factory") => "Factory".into(),
+        (PartitionType::App, "test") => "Test".into(),
+        (PartitionType::App, _) => format!("Ota({})", subtype - 0x10),
+        (PartitionType::Data, "ota") => "OtaData".into(),
+        (PartitionType::Data, name) => to_pascal_case(name),
+        (PartitionType::Bootloader, name) => format!("Bootloader{}", to_pascal_case(name)),
+        (PartitionType::PartitionTable, name) => {
+            format!("PartitionTable{}", to_pascal_case(name))
+        }
+        (PartitionType::Custom(_), _) => format!("Other({subtype:#04X})"),
+    }
+}
+
+/// Convert a `snake_case` name to `PascalCase`.
+fn to_pascal_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition_table::DEFAULT_TABLE_OFFSET;
+
+    fn source(csv: &str) -> String {
+        PartitionTable::from_reader(csv.as_bytes(), DEFAULT_TABLE_OFFSET)
+            .unwrap()
+            .rust_source()
+    }
+
+    #[test]
+    fn emits_module_per_partition() {
+        let out = source(
+            "nvs,     data, nvs,     0x9000,  0x4000\n\
+             otadata, data, ota,     0xd000,  0x2000\n\
+             ota_0,   app,  ota_0,   0x10000, 1M\n\
+             ota_1,   app,  ota_1,   ,        1M, readonly\n",
+        );
+
+        assert!(out.contains("pub mod ota_1 {"));
+        assert!(out.contains("    pub const NAME: &str = \"ota_1\";"));
+        assert!(out.contains("    pub const OFFSET: u32 = 0x110000;"));
+        assert!(out.contains("    pub const SIZE: u32 = 0x100000;"));
+        assert!(out.contains("    pub const READONLY: bool = true;"));
+        assert!(
+            out.contains("    pub const SUBTYPE: PartitionSubtype = PartitionSubtype::Ota(1);")
+        );
+        assert!(
+            out.contains("    pub const SUBTYPE: PartitionSubtype = PartitionSubtype::OtaData;")
+        );
+        assert!(out.contains(
+            "pub const PARTITIONS: &[Partition] = &[\n    nvs::PARTITION,\n    otadata::PARTITION,\n"
+        ));
+    }
+
+    #[test]
+    fn subtype_enum_covers_every_named_subtype() {
+        let out = source("storage, data, littlefs, 0x9000, 0x1000\n");
+
+        for variant in [
+            "Nvs,",
+            "Littlefs,",
+            "NvsKeys,",
+            "BootloaderOta,",
+            "Other(u8),",
+        ] {
+            assert!(out.contains(&format!("    {variant}\n")), "{variant}");
+        }
+        assert!(out.contains("pub const SUBTYPE: PartitionSubtype = PartitionSubtype::Littlefs;"));
+    }
+
+    #[test]
+    fn unnamed_types_and_subtypes_use_numbers() {
+        let out = source("custom, 0x40, 0x05, 0x9000, 0x1000\n");
+
+        assert!(out.contains("pub const TYPE: PartitionType = PartitionType::Custom(0x40);"));
+        assert!(
+            out.contains("pub const SUBTYPE: PartitionSubtype = PartitionSubtype::Other(0x05);")
+        );
+    }
+
+    #[test]
+    fn module_names_sanitized_and_deduplicated() {
+        let out = source(
+            "my-data, data, nvs, 0x9000, 0x1000\n\
+             my_data, data, nvs, 0xa000, 0x1000\n\
+             2nd,     data, nvs, 0xb000, 0x1000\n",
+        );
+
+        assert!(out.contains("pub mod my_data {"));
+        assert!(out.contains("pub mod my_data_2 {"));
+        assert!(out.contains("pub mod _2nd {"));
+        assert!(out.contains("    pub const NAME: &str = \"my-data\";"));
+    }
+
+    #[test]
+    fn keyword_names_suffixed_and_names_escaped() {
+        let out = source(
+            "type,     data, nvs, 0x9000, 0x1000\n\
+             \"a\"\"b\\c\", data, nvs, 0xa000, 0x1000\n",
+        );
+
+        assert!(out.contains("pub mod type_ {"));
+        assert!(out.contains("    pub const NAME: &str = \"type\";"));
+        assert!(out.contains("pub mod a_b_c {"));
+        assert!(out.contains(r#"    pub const NAME: &str = "a\"b\\c";"#));
+    }
+}
@@ -0,0 +1,1073 @@
+//! # RP2350 Partition Tables
+//!
+//! The RP2350 boot ROM reads its partition table from a picobin block in
+//! the first 4 KiB of flash. `picotool partition create` builds that block
+//! from a JSON description:
+//!
+//! ```json
+//! {
+//!   "version": [1, 0],
+//!   "unpartitioned": {
+//!     "families": ["absolute"],
+//!     "permissions": { "secure": "rw", "nonsecure": "rw", "bootloader": "rw" }
+//!   },
+//!   "partitions": [
+//!     {
+//!       "name": "firmware",
+//!       "id": 0,
+//!       "size": "2M",
+//!       "families": ["rp2350-arm-s", "rp2350-riscv"],
+//!       "permissions": { "secure": "rw", "nonsecure": "rw", "bootloader": "rw" }
+//!     },
+//!     {
+//!       "name": "littlefs",
+//!       "start": "2056K",
+//!       "size": "1M",
+//!       "families": ["data"],
+//!       "permissions": { "secure": "rw", "nonsecure": "rw", "bootloader": "r" }
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Both forms are read and written here. `start` and `size` accept the
+//! same values as the ESP-IDF CSV (`0x…`, decimal, `K`/`M` suffixes) and
+//! must be multiples of the 4 KiB sector. A partition without `start` is
+//! placed directly after the previous one, with the first placed after
+//! the two sectors reserved for the partition table. Permissions default
+//! to `rw` for every mode when omitted.
+
+use serde::{Deserialize, Serialize};
+
+use super::{PartitionError, parse_size_value};
+
+/// Flash sector size; partitions start and end on sector boundaries.
+pub const SECTOR_SIZE: u32 = 0x1000;
+
+/// Address at which flash is mapped into the RP2350 address space.
+pub const XIP_BASE: u32 = 0x1000_0000;
+
+/// Most partitions a table can hold.
+pub const MAX_PARTITIONS: usize = 16;
+
+/// Most families beyond the default ones a partition can accept.
+pub const MAX_EXTRA_FAMILIES: usize = 3;
+
+/// Offset of the first partition placed without an explicit `start`.
+const FIRST_PARTITION_START: u32 = 2 * SECTOR_SIZE;
+
+/// Highest sector number a partition location can encode.
+const MAX_SECTOR: u32 = 0x1FFF;
+
+const BLOCK_MARKER_START: u32 = 0xFFFF_DED3;
+const BLOCK_MARKER_END: u32 = 0xAB12_3579;
+
+const ITEM_2BS_SIZE_FLAG: u8 = 0x80;
+const ITEM_PARTITION_TABLE: u8 = 0x0A;
+const ITEM_VERSION: u8 = 0x48;
+const ITEM_LAST: u8 = 0xFF;
+
+const PT_SINGLETON: u32 = 1 << 31;
+const PT_COUNT_LSB: u32 = 24;
+
+const LOCATION_LAST_SECTOR_LSB: u32 = 13;
+const PERMISSIONS_LSB: u32 = 26;
+const PERMISSIONS_BITS: u32 = 0xFC00_0000;
+
+const FLAG_HAS_ID: u32 = 1 << 0;
+const FLAG_LINK_TYPE_LSB: u32 = 1;
+const FLAG_LINK_VALUE_LSB: u32 = 3;
+const FLAG_NUM_EXTRA_FAMILIES_LSB: u32 = 7;
+const FLAG_DEFAULT_FAMILY_LSB: u32 = 9;
+const FLAG_AB_NON_BOOTABLE_OWNER_AFFINITY: u32 = 1 << 15;
+const FLAG_HAS_NAME: u32 = 1 << 16;
+const FLAG_NO_REBOOT_ON_UF2_DOWNLOAD: u32 = 1 << 17;
+const FLAG_IGNORED_DURING_ARM_BOOT: u32 = 1 << 18;
+const FLAG_IGNORED_DURING_RISCV_BOOT: u32 = 1 << 19;
+
+// ---------------------------------------------------------------------------
+// Families, permissions, and links
+// ---------------------------------------------------------------------------
+
+/// A UF2 family a partition accepts downloads for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Family {
+    Absolute,
+    Rp2040,
+    Rp2350ArmS,
+    Rp2350ArmNs,
+    Rp2350Riscv,
+    Data,
+    /// Any other family ID.
+    Other(u32),
+}
+
+impl Family {
+    /// The default families, in the order of their partition flag bits.
+    const DEFAULTS: [Family; 6] = [
+        Family::Absolute,
+        Family::Rp2040,
+        Family::Rp2350ArmS,
+        Family::Rp2350ArmNs,
+        Family::Rp2350Riscv,
+        Family::Data,
+    ];
+
+    /// Parse a family name as used by picotool, or a family ID number.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "absolute" => Some(Self::Absolute),
+            "rp2040" => Some(Self::Rp2040),
+            "rp2350-arm-s" => Some(Self::Rp2350ArmS),
+            "rp2350-arm-ns" => Some(Self::Rp2350ArmNs),
+            "rp2350-riscv" => Some(Self::Rp2350Riscv),
+            "data" => Some(Self::Data),
+            _ => parse_size_value(s).ok().map(Self::from_id),
+        }
+    }
+
+    /// The family for a UF2 family ID.
+    pub fn from_id(id: u32) -> Self {
+        Self::DEFAULTS
+            .into_iter()
+            .find(|f| f.id() == id)
+            .unwrap_or(Self::Other(id))
+    }
+
+    /// The UF2 family ID.
+    pub fn id(self) -> u32 {
+        match self {
+            Self::Rp2040 => 0xE48B_FF56,
+            Self::Absolute => 0xE48B_FF57,
+            Self::Data => 0xE48B_FF58,
+            Self::Rp2350ArmS => 0xE48B_FF59,
+            Self::Rp2350Riscv => 0xE48B_FF5A,
+            Self::Rp2350ArmNs => 0xE48B_FF5B,
+            Self::Other(id) => id,
+        }
+    }
+
+    /// The picotool name, or the ID in hex for other families.
+    pub fn name(self) -> String {
+        match self {
+            Self::Absolute => "absolute".into(),
+            Self::Rp2040 => "rp2040".into(),
+            Self::Rp2350ArmS => "rp2350-arm-s".into(),
+            Self::Rp2350ArmNs => "rp2350-arm-ns".into(),
+            Self::Rp2350Riscv => "rp2350-riscv".into(),
+            Self::Data => "data".into(),
+            Self::Other(id) => format!("{id:#010x}"),
+        }
+    }
+
+    /// The partition flag bit for a default family.
+    fn flag(self) -> Option<u32> {
+        Self::DEFAULTS
+            .iter()
+            .position(|f| *f == self)
+            .map(|i| 1 << (FLAG_DEFAULT_FAMILY_LSB + i as u32))
+    }
+}
+
+/// Read and write access for one mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Access {
+    const RW: Self = Self {
+        read: true,
+        write: true,
+    };
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "rw" | "wr" => Some(Self::RW),
+            "r" => Some(Self {
+                read: true,
+                write: false,
+            }),
+            "w" => Some(Self {
+                read: false,
+                write: true,
+            }),
+            "" => Some(Self {
+                read: false,
+                write: false,
+            }),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match (self.read, self.write) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            (false, true) => "w",
+            (false, false) => "",
+        }
+    }
+}
+
+/// Access permissions for secure code, non-secure code, and the
+/// bootloader (picoboot and UF2 downloads).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Permissions {
+    pub secure: Access,
+    pub nonsecure: Access,
+    pub bootloader: Access,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Self {
+            secure: Access::RW,
+            nonsecure: Access::RW,
+            bootloader: Access::RW,
+        }
+    }
+}
+
+impl Permissions {
+    /// The permission bits shared by both partition words.
+    fn to_bits(self) -> u32 {
+        let bits = [
+            self.secure.read,
+            self.secure.write,
+            self.nonsecure.read,
+            self.nonsecure.write,
+            self.bootloader.read,
+            self.bootloader.write,
+        ];
+        bits.iter()
+            .enumerate()
+            .filter(|(_, set)| **set)
+            .fold(0, |acc, (i, _)| acc | 1 << (PERMISSIONS_LSB + i as u32))
+    }
+
+    fn from_bits(word: u32) -> Self {
+        let bit = |i: u32| word & (1 << (PERMISSIONS_LSB + i)) != 0;
+        Self {
+            secure: Access {
+                read: bit(0),
+                write: bit(1),
+            },
+            nonsecure: Access {
+                read: bit(2),
+                write: bit(3),
+            },
+            bootloader: Access {
+                read: bit(4),
+                write: bit(5),
+            },
+        }
+    }
+}
+
+/// A link from a partition to another one in the same table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Link {
+    #[default]
+    None,
+    /// This partition is the B partition of the given A partition.
+    APartition(u8),
+    /// This partition belongs to the given owner partition.
+    OwnerPartition(u8),
+}
+
+// ---------------------------------------------------------------------------
+// Partition table model
+// ---------------------------------------------------------------------------
+
+/// A single RP2350 partition.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rp2350Partition {
+    pub name: Option<String>,
+    pub id: Option<u64>,
+    /// Offset of the partition from the start of flash.
+    pub start: u32,
+    pub size: u32,
+    pub permissions: Permissions,
+    pub families: Vec<Family>,
+    pub link: Link,
+    pub ignored_during_arm_boot: bool,
+    pub ignored_during_riscv_boot: bool,
+    pub no_reboot_on_uf2_download: bool,
+    pub ab_non_bootable_owner_affinity: bool,
+}
+
+impl Rp2350Partition {
+    /// The first byte past the end of the partition.
+    pub fn end(&self) -> u64 {
+        u64::from(self.start) + u64::from(self.size)
+    }
+
+    /// The address of the partition in the XIP flash window.
+    pub fn xip_address(&self) -> u32 {
+        XIP_BASE + self.start
+    }
+
+    /// The partition's name, or its index when it has none.
+    fn label(&self, index: usize) -> String {
+        self.name.clone().unwrap_or_else(|| format!("#{index}"))
+    }
+}
+
+/// An RP2350 partition table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rp2350PartitionTable {
+    /// Table version as `(major, minor)`, if it has one.
+    pub version: Option<(u16, u16)>,
+    /// Whether the table is marked as the only one (no A/B table copy).
+    pub singleton: bool,
+    /// Permissions for flash not covered by any partition.
+    pub unpartitioned_permissions: Permissions,
+    /// Families accepted by flash not covered by any partition. Only the
+    /// default families can be set here.
+    pub unpartitioned_families: Vec<Family>,
+    pub partitions: Vec<Rp2350Partition>,
+}
+
+impl Rp2350PartitionTable {
+    /// The partition named `name`, if any.
+    pub fn find(&self, name: &str) -> Option<&Rp2350Partition> {
+        self.partitions
+            .iter()
+            .find(|p| p.name.as_deref() == Some(name))
+    }
+
+    /// Check the table fits the picobin format and the partitions are
+    /// sector aligned, in range and don't overlap. If `flash_size` is
+    /// given, partitions must also end within it.
+    pub fn validate(&self, flash_size: Option<u32>) -> Result<(), PartitionError> {
+        if self.partitions.len() > MAX_PARTITIONS {
+            return Err(PartitionError::TooManyPartitions {
+                count: self.partitions.len(),
+                max: MAX_PARTITIONS,
+            });
+        }
+
+        for (i, p) in self.partitions.iter().enumerate() {
+            let name = p.label(i);
+            if p.name.as_ref().is_some_and(|n| n.len() > 0x7F) {
+                return Err(PartitionError::NameTooLong(name));
+            }
+            if !p.start.is_multiple_of(SECTOR_SIZE) || !p.size.is_multiple_of(SECTOR_SIZE) {
+                return Err(PartitionError::Misaligned {
+                    name,
+                    offset: p.start,
+                    alignment: SECTOR_SIZE,
+                });
+            }
+            if p.size == 0 || p.end() > u64::from(MAX_SECTOR + 1) * u64::from(SECTOR_SIZE) {
+                return Err(PartitionError::InvalidPicobin(format!(
+                    "partition '{name}' has an empty or out of range location"
+                )));
+            }
+            if let Some(flash_size) = flash_size
+                && p.end() > u64::from(flash_size)
+            {
+                return Err(PartitionError::ExceedsFlash {
+                    name,
+                    end: p.end(),
+                    flash_size,
+                });
+            }
+            let extra = p.families.iter().filter(|f| f.flag().is_none()).count();
+            if extra > MAX_EXTRA_FAMILIES {
+                return Err(PartitionError::InvalidPicobin(format!(
+                    "partition '{name}' accepts {extra} non-default families (at most {MAX_EXTRA_FAMILIES})"
+                )));
+            }
+            if let Link::APartition(n) | Link::OwnerPartition(n) = p.link
+                && usize::from(n) >= self.partitions.len()
+            {
+                return Err(PartitionError::InvalidPicobin(format!(
+                    "partition '{name}' links to missing partition {n}"
+                )));
+            }
+        }
+
+        let mut by_start: Vec<(usize, &Rp2350Partition)> =
+            self.partitions.iter().enumerate().collect();
+        by_start.sort_by_key(|(_, p)| p.start);
+        for pair in by_start.windows(2) {
+            let ((i, first), (j, second)) = (pair[0], pair[1]);
+            if first.end() > u64::from(second.start) {
+                return Err(PartitionError::Overlap {
+                    first: first.label(i),
+                    second: second.label(j),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// picotool JSON
+// ---------------------------------------------------------------------------
+
+/// A size or offset in the JSON: a number of bytes or a string such as
+/// `"64K"` or `"0x10000"`.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum RawSize {
+    Bytes(u32),
+    Text(String),
+}
+
+impl RawSize {
+    fn resolve(&self) -> Result<u32, PartitionError> {
+        match self {
+            RawSize::Bytes(n) => Ok(*n),
+            RawSize::Text(s) => Ok(parse_size_value(s)?),
+        }
+    }
+
+    fn from_bytes(n: u32) -> Self {
+        if n != 0 && n.is_multiple_of(1024) {
+            RawSize::Text(format!("{}K", n / 1024))
+        } else {
+            RawSize::Bytes(n)
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RawPermissions {
+    secure: String,
+    nonsecure: String,
+    bootloader: String,
+}
+
+impl RawPermissions {
+    fn resolve(&self) -> Result<Permissions, PartitionError> {
+        let access = |s: &str| {
+            Access::parse(s).ok_or_else(|| {
+                PartitionError::InvalidPicobin(format!(
+                    "invalid permission '{s}' (expected \"rw\", \"r\", \"w\" or \"\")"
+                ))
+            })
+        };
+        Ok(Permissions {
+            secure: access(&self.secure)?,
+            nonsecure: access(&self.nonsecure)?,
+            bootloader: access(&self.bootloader)?,
+        })
+    }
+
+    fn from_permissions(p: Permissions) -> Self {
+        Self {
+            secure: p.secure.as_str().into(),
+            nonsecure: p.nonsecure.as_str().into(),
+            bootloader: p.bootloader.as_str().into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RawUnpartitioned {
+    #[serde(default)]
+    families: Vec<String>,
+    permissions: Option<RawPermissions>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RawRp2350Partition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start: Option<RawSize>,
+    size: RawSize,
+    #[serde(default)]
+    families: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    permissions: Option<RawPermissions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<(String, u8)>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    ignored_during_arm_boot: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    ignored_during_riscv_boot: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    no_reboot_on_uf2_download: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    ab_non_bootable_owner_affinity: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+impl RawRp2350Partition {
+    fn resolve(self, next_start: u32) -> Result<Rp2350Partition, PartitionError> {
+        let link = match self.link {
+            None => Link::None,
+            Some((kind, n)) if kind == "a" => Link::APartition(n),
+            Some((kind, n)) if kind == "owner" => Link::OwnerPartition(n),
+            Some((kind, _)) => {
+                return Err(PartitionError::InvalidPicobin(format!(
+                    "invalid link type '{kind}' (expected \"a\" or \"owner\")"
+                )));
+            }
+        };
+
+        Ok(Rp2350Partition {
+            name: self.name,
+            id: self.id,
+            start: match &self.start {
+                Some(start) => start.resolve()?,
+                None => next_start,
+            },
+            size: self.size.resolve()?,
+            permissions: match &self.permissions {
+                Some(p) => p.resolve()?,
+                None => Permissions::default(),
+            },
+            families: resolve_families(&self.families)?,
+            link,
+            ignored_during_arm_boot: self.ignored_during_arm_boot,
+            ignored_during_riscv_boot: self.ignored_during_riscv_boot,
+            no_reboot_on_uf2_download: self.no_reboot_on_uf2_download,
+            ab_non_bootable_owner_affinity: self.ab_non_bootable_owner_affinity,
+        })
+    }
+
+    fn from_partition(p: &Rp2350Partition) -> Self {
+        Self {
+            name: p.name.clone(),
+            id: p.id,
+            start: Some(RawSize::from_bytes(p.start)),
+            size: RawSize::from_bytes(p.size),
+            families: p.families.iter().map(|f| f.name()).collect(),
+            permissions: Some(RawPermissions::from_permissions(p.permissions)),
+            link: match p.link {
+                Link::None => None,
+                Link::APartition(n) => Some(("a".into(), n)),
+                Link::OwnerPartition(n) => Some(("owner".into(), n)),
+            },
+            ignored_during_arm_boot: p.ignored_during_arm_boot,
+            ignored_during_riscv_boot: p.ignored_during_riscv_boot,
+            no_reboot_on_uf2_download: p.no_reboot_on_uf2_download,
+            ab_non_bootable_owner_affinity: p.ab_non_bootable_owner_affinity,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RawRp2350PartitionTable {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<(u16, u16)>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    singleton: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unpartitioned: Option<RawUnpartitioned>,
+    partitions: Vec<RawRp2350Partition>,
+}
+
+impl RawRp2350PartitionTable {
+    fn resolve(self) -> Result<Rp2350PartitionTable, PartitionError> {
+        let mut partitions = Vec::with_capacity(self.partitions.len());
+        let mut next_start = FIRST_PARTITION_START;
+        for raw in self.partitions {
+            let partition = raw.resolve(next_start)?;
+            next_start = partition.start.saturating_add(partition.size);
+            partitions.push(partition);
+        }
+
+        let (unpartitioned_permissions, unpartitioned_families) = match self.unpartitioned {
+            Some(u) => (
+                match &u.permissions {
+                    Some(p) => p.resolve()?,
+                    None => Permissions::default(),
+                },
+                resolve_families(&u.families)?,
+            ),
+            None => (Permissions::default(), Vec::new()),
+        };
+        if let Some(family) = unpartitioned_families.iter().find(|f| f.flag().is_none()) {
+            return Err(PartitionError::InvalidPicobin(format!(
+                "unpartitioned space can only accept default families, not {}",
+                family.name()
+            )));
+        }
+
+        Ok(Rp2350PartitionTable {
+            version: self.version,
+            singleton: self.singleton,
+            unpartitioned_permissions,
+            unpartitioned_families,
+            partitions,
+        })
+    }
+}
+
+fn resolve_families(names: &[String]) -> Result<Vec<Family>, PartitionError> {
+    names
+        .iter()
+        .map(|name| {
+            Family::parse(name)
+                .ok_or_else(|| PartitionError::InvalidPicobin(format!("unknown family '{name}'")))
+        })
+        .collect()
+}
+
+impl Rp2350PartitionTable {
+    /// Parse a picotool partition table JSON file.
+    pub fn from_json(json: &str) -> Result<Self, PartitionError> {
+        let raw: RawRp2350PartitionTable = serde_json::from_str(json)?;
+        raw.resolve()
+    }
+
+    /// Write the table as picotool partition table JSON, with every
+    /// partition's `start` filled in.
+    pub fn to_json(&self) -> String {
+        let raw = RawRp2350PartitionTable {
+            version: self.version,
+            singleton: self.singleton,
+            unpartitioned: Some(RawUnpartitioned {
+                families: self
+                    .unpartitioned_families
+                    .iter()
+                    .map(|f| f.name())
+                    .collect(),
+                permissions: Some(RawPermissions::from_permissions(
+                    self.unpartitioned_permissions,
+                )),
+            }),
+            partitions: self
+                .partitions
+                .iter()
+                .map(RawRp2350Partition::from_partition)
+                .collect(),
+        };
+        serde_json::to_string_pretty(&raw).expect("partition table JSON serialization failed")
+    }
+}
+
+// ---------------------------------------------------------------------------
+// picobin block
+// ---------------------------------------------------------------------------
+
+impl Rp2350PartitionTable {
+    /// Encode the table as a picobin block: the start marker, an optional
+    /// version item, the partition table item, the last item, a link to
+    /// itself, and the end marker.
+    ///
+    /// The table is not validated; see [`Rp2350PartitionTable::validate`].
+    pub fn to_block(&self) -> Result<Vec<u8>, PartitionError> {
+        if self.partitions.len() > MAX_PARTITIONS {
+            return Err(PartitionError::TooManyPartitions {
+                count: self.partitions.len(),
+                max: MAX_PARTITIONS,
+            });
+        }
+
+        let mut items: Vec<u32> = Vec::new();
+        if let Some((major, minor)) = self.version {
+            items.push(item_header(ITEM_VERSION, 2));
+            items.push(u32::from(major) << 16 | u32::from(minor));
+        }
+
+        let mut partition_words = Vec::new();
+        for p in &self.partitions {
+            partition_words.extend(encode_partition(p));
+        }
+        let size = 2 + partition_words.len();
+        if size > 0xFF {
+            return Err(PartitionError::InvalidPicobin(
+                "partition table item is too large".into(),
+            ));
+        }
+        let mut header = item_header(ITEM_PARTITION_TABLE, size as u32)
+            | (self.partitions.len() as u32) << PT_COUNT_LSB;
+        if self.singleton {
+            header |= PT_SINGLETON;
+        }
+        items.push(header);
+        items.push(
+            self.unpartitioned_permissions.to_bits() | family_flags(&self.unpartitioned_families),
+        );
+        items.extend(partition_words);
+
+        let mut words = vec![BLOCK_MARKER_START];
+        words.extend(&items);
+        words.push(u32::from(ITEM_LAST) | (items.len() as u32) << 8);
+        words.push(0); // link to the next block: this block again
+        words.push(BLOCK_MARKER_END);
+
+        Ok(words.iter().flat_map(|w| w.to_le_bytes()).collect())
+    }
+
+    /// Decode the first picobin block with a partition table item found in
+    /// the first 4 KiB of `data`, which may be a whole flash dump.
+    pub fn from_block(data: &[u8]) -> Result<Self, PartitionError> {
+        let words: Vec<u32> = data[..data.len().min(SECTOR_SIZE as usize)]
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect();
+
+        for (i, _) in words
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| **w == BLOCK_MARKER_START)
+        {
+            if let Some(table) = decode_block(&words[i + 1..])? {
+                return Ok(table);
+            }
+        }
+        Err(PartitionError::InvalidPicobin(
+            "no picobin block with a partition table found".into(),
+        ))
+    }
+
+    /// Whether `data` holds a picobin block in its first 4 KiB.
+    pub fn is_block(data: &[u8]) -> bool {
+        data[..data.len().min(SECTOR_SIZE as usize)]
+            .chunks_exact(4)
+            .any(|w| w == BLOCK_MARKER_START.to_le_bytes())
+    }
+}
+
+/// A 1-byte-size item header.
+fn item_header(item_type: u8, size_words: u32) -> u32 {
+    u32::from(item_type) | size_words << 8
+}
+
+/// The flag bits for the default families in `families`.
+fn family_flags(families: &[Family]) -> u32 {
+    families
+        .iter()
+        .filter_map(|f| f.flag())
+        .fold(0, |acc, bit| acc | bit)
+}
+
+/// Encode one partition's words.
+fn encode_partition(p: &Rp2350Partition) -> Vec<u32> {
+    let permissions = p.permissions.to_bits();
+    let first_sector = p.start / SECTOR_SIZE;
+    let last_sector = (p.start + p.size) / SECTOR_SIZE - 1;
+    let location = permissions | first_sector | last_sector << LOCATION_LAST_SECTOR_LSB;
+
+    let extra: Vec<u32> = p
+        .families
+        .iter()
+        .filter(|f| f.flag().is_none())
+        .map(|f| f.id())
+        .collect();
+
+    let (link_type, link_value) = match p.link {
+        Link::None => (0, 0),
+        Link::APartition(n) => (1, n),
+        Link::OwnerPartition(n) => (2, n),
+    };
+    let mut flags = permissions
+        | family_flags(&p.families)
+        | (extra.len() as u32) << FLAG_NUM_EXTRA_FAMILIES_LSB
+        | link_type << FLAG_LINK_TYPE_LSB
+        | u32::from(link_value & 0xF) << FLAG_LINK_VALUE_LSB;
+    for (set, bit) in [
+        (p.id.is_some(), FLAG_HAS_ID),
+        (p.name.is_some(), FLAG_HAS_NAME),
+        (p.ignored_during_arm_boot, FLAG_IGNORED_DURING_ARM_BOOT),
+        (p.ignored_during_riscv_boot, FLAG_IGNORED_DURING_RISCV_BOOT),
+        (p.no_reboot_on_uf2_download, FLAG_NO_REBOOT_ON_UF2_DOWNLOAD),
+        (
+            p.ab_non_bootable_owner_affinity,
+            FLAG_AB_NON_BOOTABLE_OWNER_AFFINITY,
+        ),
+    ] {
+        if set {
+            flags |= bit;
+        }
+    }
+
+    let mut words = vec![location, flags];
+    if let Some(id) = p.id {
+        words.push(id as u32);
+        words.push((id >> 32) as u32);
+    }
+    words.extend(extra);
+    if let Some(name) = &p.name {
+        // A length byte followed by the name, zero padded to a whole word
+        let mut bytes = vec![name.len() as u8];
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.resize(bytes.len().next_multiple_of(4), 0);
+        words.extend(
+            bytes
+                .chunks_exact(4)
+                .map(|w| u32::from_le_bytes(w.try_into().unwrap())),
+        );
+    }
+    words
+}
+
+/// Decode the block whose items start at `words`, returning `None` if it
+/// has no partition table item.
+fn decode_block(words: &[u32]) -> Result<Option<Rp2350PartitionTable>, PartitionError> {
+    let truncated = || PartitionError::InvalidPicobin("truncated picobin block".into());
+    let mut version = None;
+    let mut table = None;
+    let mut pos = 0;
+
+    loop {
+        let header = *words.get(pos).ok_or_else(truncated)?;
+        let item_type = header as u8;
+        if item_type == ITEM_LAST {
+            if words.get(pos + 2) != Some(&BLOCK_MARKER_END) {
+                return Err(PartitionError::InvalidPicobin(
+                    "picobin block has no end marker".into(),
+                ));
+            }
+            break;
+        }
+
+        let size = if item_type & ITEM_2BS_SIZE_FLAG != 0 {
+            (header >> 8) & 0xFFFF
+        } else {
+            (header >> 8) & 0xFF
+        } as usize;
+        let item = words.get(pos..pos + size).ok_or_else(truncated)?;
+        if size == 0 {
+            return Err(truncated());
+        }
+
+        match item_type {
+            ITEM_VERSION if size >= 2 => {
+                version = Some(((item[1] >> 16) as u16, item[1] as u16));
+            }
+            ITEM_PARTITION_TABLE => table = Some(decode_partition_table(item)?),
+            _ => {}
+        }
+        pos += size;
+    }
+
+    Ok(table.map(|mut table: Rp2350PartitionTable| {
+        table.version = version;
+        table
+    }))
+}
+
+/// Decode a partition table item, header included.
+fn decode_partition_table(item: &[u32]) -> Result<Rp2350PartitionTable, PartitionError> {
+    let truncated = || PartitionError::InvalidPicobin("truncated partition table item".into());
+    let count = ((item[0] >> PT_COUNT_LSB) & 0x1F) as usize;
+    let unpartitioned = *item.get(1).ok_or_else(truncated)?;
+
+    let mut pos = 2;
+    let mut partitions = Vec::with_capacity(count);
+    for _ in 0..count {
+        let location = *item.get(pos).ok_or_else(truncated)?;
+        let flags = *item.get(pos + 1).ok_or_else(truncated)?;
+        pos += 2;
+
+        let id = if flags & FLAG_HAS_ID != 0 {
+            let words = item.get(pos..pos + 2).ok_or_else(truncated)?;
+            pos += 2;
+            Some(u64::from(words[0]) | u64::from(words[1]) << 32)
+        } else {
+            None
+        };
+
+        let mut families = decode_family_flags(flags);
+        let num_extra = ((flags >> FLAG_NUM_EXTRA_FAMILIES_LSB) & 0x3) as usize;
+        let extra = item.get(pos..pos + num_extra).ok_or_else(truncated)?;
+        families.extend(extra.iter().map(|id| Family::from_id(*id)));
+        pos += num_extra;
+
+        let name = if flags & FLAG_HAS_NAME != 0 {
+            let first = item.get(pos).ok_or_else(truncated)?;
+            let len = (*first & 0x7F) as usize;
+            let n_words = (len + 1).div_ceil(4);
+            let bytes: Vec<u8> = item
+                .get(pos..pos + n_words)
+                .ok_or_else(truncated)?
+                .iter()
+                .flat_map(|w| w.to_le_bytes())
+                .collect();
+            pos += n_words;
+            Some(String::from_utf8_lossy(&bytes[1..=len]).into_owned())
+        } else {
+            None
+        };
+
+        let first_sector = location & MAX_SECTOR;
+        let last_sector = (location >> LOCATION_LAST_SECTOR_LSB) & MAX_SECTOR;
+        let link_value = ((flags >> FLAG_LINK_VALUE_LSB) & 0xF) as u8;
+
+        partitions.push(Rp2350Partition {
+            name,
+            id,
+            start: first_sector * SECTOR_SIZE,
+            size: (last_sector + 1).saturating_sub(first_sector) * SECTOR_SIZE,
+            permissions: Permissions::from_bits(flags & PERMISSIONS_BITS),
+            families,
+            link: match (flags >> FLAG_LINK_TYPE_LSB) & 0x3 {
+                1 => Link::APartition(link_value),
+                2 => Link::OwnerPartition(link_value),
+                _ => Link::None,
+            },
+            ignored_during_arm_boot: flags & FLAG_IGNORED_DURING_ARM_BOOT != 0,
+            ignored_during_riscv_boot: flags & FLAG_IGNORED_DURING_RISCV_BOOT != 0,
+            no_reboot_on_uf2_download: flags & FLAG_NO_REBOOT_ON_UF2_DOWNLOAD != 0,
+            ab_non_bootable_owner_affinity: flags & FLAG_AB_NON_BOOTABLE_OWNER_AFFINITY != 0,
+        });
+    }
+
+    Ok(Rp2350PartitionTable {
+        version: None,
+        singleton: item[0] & PT_SINGLETON != 0,
+        unpartitioned_permissions: Permissions::from_bits(unpartitioned & PERMISSIONS_BITS),
+        unpartitioned_families: decode_family_flags(unpartitioned),
+        partitions,
+    })
+}
+
+/// The default families whose flag bits are set in `flags`.
+fn decode_family_flags(flags: u32) -> Vec<Family> {
+    Family::DEFAULTS
+        .into_iter()
+        .filter(|f| f.flag().is_some_and(|bit| flags & bit != 0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{
+        "version": [1, 2],
+        "unpartitioned": {
+            "families": ["absolute"],
+            "permissions": { "secure": "rw", "nonsecure": "rw", "bootloader": "rw" }
+        },
+        "partitions": [
+            {
+                "name": "A",
+                "id": 0,
+                "size": "1M",
+                "families": ["rp2350-arm-s", "rp2350-riscv"],
+                "permissions": { "secure": "rw", "nonsecure": "rw", "bootloader": "rw" }
+            },
+            {
+                "name": "B",
+                "id": 1,
+                "size": "1M",
+                "families": ["rp2350-arm-s", "rp2350-riscv"],
+                "link": ["a", 0]
+            },
+            {
+                "name": "littlefs",
+                "start": "0x300000",
+                "size": 1048576,
+                "families": ["data", "0x12345678"],
+                "permissions": { "secure": "rw", "nonsecure": "r", "bootloader": "" }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn parses_picotool_json() {
+        let table = Rp2350PartitionTable::from_json(JSON).unwrap();
+        table.validate(Some(4 * 1024 * 1024)).unwrap();
+
+        assert_eq!(table.version, Some((1, 2)));
+        assert_eq!(table.unpartitioned_families, [Family::Absolute]);
+
+        let starts: Vec<_> = table.partitions.iter().map(|p| p.start).collect();
+        assert_eq!(starts, [0x2000, 0x102000, 0x300000]);
+        assert_eq!(table.partitions[1].link, Link::APartition(0));
+
+        let fs = table.find("littlefs").unwrap();
+        assert_eq!(fs.xip_address(), 0x10300000);
+        assert_eq!(fs.families, [Family::Data, Family::Other(0x12345678)]);
+        assert!(!fs.permissions.nonsecure.write);
+        assert!(!fs.permissions.bootloader.read);
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let table = Rp2350PartitionTable::from_json(JSON).unwrap();
+        let block = table.to_block().unwrap();
+
+        assert_eq!(&block[..4], &BLOCK_MARKER_START.to_le_bytes());
+        assert_eq!(&block[block.len() - 4..], &BLOCK_MARKER_END.to_le_bytes());
+        assert_eq!(Rp2350PartitionTable::from_block(&block).unwrap(), table);
+    }
+
+    #[test]
+    fn partition_words_match_picobin_layout() {
+        let p = Rp2350Partition {
+            start: 0x2000,
+            size: 0x3000,
+            families: vec![Family::Data],
+            name: Some("fs".into()),
+            ..Default::default()
+        };
+        let words = encode_partition(&p);
+
+        // Sectors 2..=4, rw for every mode
+        assert_eq!(words[0], 0xFC00_0000 | 2 | 4 << 13);
+        assert_eq!(words[1], 0xFC00_0000 | 1 << 14 | FLAG_HAS_NAME);
+        assert_eq!(words[2], u32::from_le_bytes([2, b'f', b's', 0]));
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let table = Rp2350PartitionTable::from_json(JSON).unwrap();
+        assert_eq!(
+            Rp2350PartitionTable::from_json(&table.to_json()).unwrap(),
+            table
+        );
+    }
+
+    #[test]
+    fn finds_block_in_flash_dump() {
+        let table = Rp2350PartitionTable::from_json(JSON).unwrap();
+        let mut dump = vec![0xFF; 0x100];
+        dump.extend(table.to_block().unwrap());
+        dump.resize(0x10000, 0xFF);
+
+        assert!(Rp2350PartitionTable::is_block(&dump));
+        assert_eq!(Rp2350PartitionTable::from_block(&dump).unwrap(), table);
+    }
+
+    #[test]
+    fn invalid_tables_rejected() {
+        let overlap = r#"{"partitions": [
+            {"name": "a", "start": "8K", "size": "8K"},
+            {"name": "b", "start": "12K", "size": "8K"}
+        ]}"#;
+        assert!(matches!(
+            Rp2350PartitionTable::from_json(overlap)
+                .unwrap()
+                .validate(None),
+            Err(PartitionError::Overlap { .. })
+        ));
+
+        let misaligned = r#"{"partitions": [{"start": "6K", "size": "8K"}]}"#;
+        assert!(matches!(
+            Rp2350PartitionTable::from_json(misaligned)
+                .unwrap()
+                .validate(None),
+            Err(PartitionError::Misaligned { .. })
+        ));
+
+        let bad_family = r#"{"partitions": [{"size": "8K", "families": ["esp32"]}]}"#;
+        assert!(Rp2350PartitionTable::from_json(bad_family).is_err());
+
+        let bad_permissions = r#"{"partitions": [{"size": "8K",
+            "permissions": {"secure": "x", "nonsecure": "", "bootloader": ""}}]}"#;
+        assert!(Rp2350PartitionTable::from_json(bad_permissions).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! # Presets
+//!
+//! Built-in flash geometry for common NOR flash chips, ESP32 variants and
+//! RP2040/RP2350 boards. Setting `preset = "<name>"` in `[image]` fills in
+//! `block_size`, `read_size` and `write_size` from the table below; any of
+//! those fields (or `page_size`) set explicitly still take precedence.
+//!
+//! Presets describe the flash part, not the filesystem partition, so the
+//! image size (`block_count` or `image_size`) must still be given.
+//!
+//! Each family uses the geometry of the firmware that usually mounts the
+//! filesystem, so an image packed with a preset matches what the device
+//! expects:
+//!
+//! - ESP32 parts follow the `esp_littlefs` component used by ESP-IDF:
+//!   4 KiB blocks with 128 byte reads and writes.
+//! - RP2040 and RP2350 boards follow the Arduino-Pico `LittleFS` library:
+//!   4 KiB blocks with 256 byte reads and writes.
+//! - Bare SPI NOR chips use the datasheet geometry: the 4 KiB erase sector
+//!   as the block, single byte reads and the 256 byte program page for
+//!   writes.
+//!
+//! The `flash_size` of a preset is the size of the whole chip, and a
+//! resolved image must fit inside it.
+
+/// Geometry for a flash chip or board.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlashPreset {
+    /// The name used in `preset = "..."`.
+    pub name: &'static str,
+    /// A short human readable description of the part or board.
+    pub description: &'static str,
+    /// Erase sector size, used as the LittleFS block size.
+    pub block_size: usize,
+    /// Minimum read size.
+    pub read_size: usize,
+    /// Minimum program (write) size.
+    pub write_size: usize,
+    /// Total size of the flash chip in bytes.
+    pub flash_size: usize,
+}
+
+const KIB: usize = 1024;
+const MIB: usize = 1024 * 1024;
+
+/// An ESP32 family part, using the `esp_littlefs` geometry.
+const fn esp32(name: &'static str, description: &'static str, flash_size: usize) -> FlashPreset {
+    FlashPreset {
+        name,
+        description,
+        block_size: 4 * KIB,
+        read_size: 128,
+        write_size: 128,
+        flash_size,
+    }
+}
+
+/// An RP2040 or RP2350 board, using the Arduino-Pico `LittleFS` geometry.
+const fn rp(name: &'static str, description: &'static str, flash_size: usize) -> FlashPreset {
+    FlashPreset {
+        name,
+        description,
+        block_size: 4 * KIB,
+        read_size: 256,
+        write_size: 256,
+        flash_size,
+    }
+}
+
+/// A bare SPI NOR chip, using its datasheet sector and page sizes.
+const fn spi_nor(name: &'static str, description: &'static str, flash_size: usize) -> FlashPreset {
+    FlashPreset {
+        name,
+        description,
+        block_size: 4 * KIB,
+        read_size: 1,
+        write_size: 256,
+        flash_size,
+    }
+}
+
+/// Every built-in preset, grouped by family.
+pub const PRESETS: &[FlashPreset] = &[
+    // ESP32 family
+    esp32("esp32-4mb", "ESP32 with 4 MB flash", 4 * MIB),
+    esp32("esp32-8mb", "ESP32 with 8 MB flash", 8 * MIB),
+    esp32("esp32-16mb", "ESP32 with 16 MB flash", 16 * MIB),
+    esp32("esp32-s2-4mb", "ESP32-S2 with 4 MB flash", 4 * MIB),
+    esp32("esp32-s3-4mb", "ESP32-S3 with 4 MB flash", 4 * MIB),
+    esp32("esp32-s3-8mb", "ESP32-S3 with 8 MB flash", 8 * MIB),
+    esp32("esp32-s3-16mb", "ESP32-S3 with 16 MB flash", 16 * MIB),
+    esp32("esp32-c3-4mb", "ESP32-C3 with 4 MB flash", 4 * MIB),
+    esp32("esp32-c6-4mb", "ESP32-C6 with 4 MB flash", 4 * MIB),
+    esp32("esp32-c6-8mb", "ESP32-C6 with 8 MB flash", 8 * MIB),
+    esp32("esp32-h2-4mb", "ESP32-H2 with 4 MB flash", 4 * MIB),
+    // Raspberry Pi RP2040 / RP2350
+    rp(
+        "rp2040-pico",
+        "Raspberry Pi Pico (RP2040, 2 MB W25Q16)",
+        2 * MIB,
+    ),
+    rp("rp2040-w25q16", "RP2040 with W25Q16 (2 MB)", 2 * MIB),
+    rp("rp2040-w25q128", "RP2040 with W25Q128 (16 MB)", 16 * MIB),
+    rp(
+        "rp2350-pico2",
+        "Raspberry Pi Pico 2 (RP2350, 4 MB W25Q32)",
+        4 * MIB,
+    ),
+    rp("rp2350-w25q32", "RP2350 with W25Q32 (4 MB)", 4 * MIB),
+    rp("rp2350-w25q128", "RP2350 with W25Q128 (16 MB)", 16 * MIB),
+    // Generic SPI NOR flash
+    spi_nor("w25q16", "Winbond W25Q16 (2 MB)", 2 * MIB),
+    spi_nor("w25q32", "Winbond W25Q32 (4 MB)", 4 * MIB),
+    spi_nor("w25q64", "Winbond W25Q64 (8 MB)", 8 * MIB),
+    spi_nor("w25q128", "Winbond W25Q128 (16 MB)", 16 * MIB),
+    spi_nor("w25q256", "Winbond W25Q256 (32 MB)", 32 * MIB),
+    spi_nor("gd25q32", "GigaDevice GD25Q32 (4 MB)", 4 * MIB),
+    spi_nor("gd25q64", "GigaDevice GD25Q64 (8 MB)", 8 * MIB),
+    spi_nor("mx25l6433f", "Macronix MX25L6433F (8 MB)", 8 * MIB),
+    spi_nor("mx25l12833f", "Macronix MX25L12833F (16 MB)", 16 * MIB),
+    spi_nor("is25lp128", "ISSI IS25LP128 (16 MB)", 16 * MIB),
+];
+
+/// Look up a preset by name (case-insensitive).
+pub fn find_preset(name: &str) -> Option<&'static FlashPreset> {
+    PRESETS.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preset_names_are_unique() {
+        for (i, preset) in PRESETS.iter().enumerate() {
+            assert!(
+                PRESETS[i + 1..].iter().all(|p| p.name != preset.name),
+                "duplicate preset {}",
+                preset.name
+            );
+        }
+    }
+
+    #[test]
+    fn preset_geometry_is_consistent() {
+        for p in PRESETS {
+            assert!(p.block_size >= 128, "{}", p.name);
+            assert!(p.block_size.is_multiple_of(p.read_size), "{}", p.name);
+            assert!(p.block_size.is_multiple_of(p.write_size), "{}", p.name);
+            assert!(p.flash_size.is_multiple_of(p.block_size), "{}", p.name);
+        }
+    }
+
+    #[test]
+    fn find_preset_is_case_insensitive() {
+        assert_eq!(find_preset("ESP32-S3-16MB").unwrap().flash_size, 16 * MIB);
+        assert!(find_preset("esp8266").is_none());
+    }
+
+    #[test]
+    fn families_use_their_own_geometry() {
+        let esp = find_preset("esp32-4mb").unwrap();
+        let rp = find_preset("rp2040-pico").unwrap();
+        let nor = find_preset("w25q32").unwrap();
+        assert_eq!((esp.read_size, esp.write_size), (128, 128));
+        assert_eq!((rp.read_size, rp.write_size), (256, 256));
+        assert_eq!((nor.read_size, nor.write_size), (1, 256));
+    }
+}
@@ -53,6 +53,49 @@ impl PathSet {
     pub fn host_path(&self, lfs_path: &str) -> PathBuf {
         self.root.join(lfs_path.trim_start_matches('/'))
     }
+
+    /// Host paths whose modification should trigger a repack.
+    ///
+    /// Includes the root, every walked file, and every ignore file the
+    /// walk reads: those in the root and walked directories, and those in
+    /// the root's ancestors, which the walker checks as well. Cargo rescans
+    /// a directory path in full, so the root alone notices files added or
+    /// removed anywhere under it, at the cost of also repacking when an
+    /// ignored file under the root changes.
+    pub fn rerun_paths(&self, config: &DirectoryConfig) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = vec![self.root.clone()];
+        paths.extend(self.files.iter().map(|f| self.host_path(f)));
+
+        let mut ignore_dirs: Vec<PathBuf> = vec![self.root.clone()];
+        ignore_dirs.extend(self.dirs.iter().map(|d| self.host_path(d)));
+        ignore_dirs.extend(
+            self.root
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| !dir.as_os_str().is_empty())
+                .map(Path::to_path_buf),
+        );
+
+        for dir in ignore_dirs {
+            for name in ignore_file_names(config) {
+                let candidate = dir.join(name);
+                if candidate.is_file() && !paths.contains(&candidate) {
+                    paths.push(candidate);
+                }
+            }
+        }
+
+        paths
+    }
+}
+
+/// The per-directory ignore files the walker honours for `config`.
+fn ignore_file_names(config: &DirectoryConfig) -> &'static [&'static str] {
+    if config.gitignore {
+        &[".gitignore", ".ignore"]
+    } else {
+        &[".ignore"]
+    }
 }
 
 /// Build a `WalkBuilder` from a `DirectoryConfig`.
@@ -205,13 +248,13 @@ pub(crate) fn walk_directory(config: &DirectoryConfig) -> Result<PathSet, WalkEr
                 // Ensure parent directories of rescued files are created.
                 // The parent might have been skipped by the main walk
                 // (e.g. a hidden directory containing a rescued file).
-                if let Some(parent) = entry.path().parent() {
-                    if parent != root.as_path() {
-                        let parent_lfs = to_lfs_path(parent, root)?;
-                        if !seen.contains(&parent_lfs) {
-                            seen.files.push(parent_lfs.clone());
-                            to_pack.dirs.push(parent_lfs);
-                        }
+                if let Some(parent) = entry.path().parent()
+                    && parent != root.as_path()
+                {
+                    let parent_lfs = to_lfs_path(parent, root)?;
+                    if !seen.contains(&parent_lfs) {
+                        seen.files.push(parent_lfs.clone());
+                        to_pack.dirs.push(parent_lfs);
                     }
                 }
                 seen.files.push(lfs_path.clone());
@@ -328,7 +371,7 @@ mod tests {
         walk.build()
             .filter_map(|e| e.ok())
             .filter(|e| e.depth() > 0)
-            .filter(|e| e.file_type().map_or(false, |ft| ft.is_file()))
+            .filter(|e| e.file_type().is_some_and(|ft| ft.is_file()))
             .map(|e| e.path().file_name().unwrap().to_string_lossy().to_string())
             .collect()
     }
@@ -697,6 +740,66 @@ mod tests {
         }
     }
 
+    // -------------------------------------------------------------------------
+    // PathSet::rerun_paths
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn rerun_paths_include_root_and_files() {
+        let dir = tempfile::tempdir().unwrap();
+        create_test_directory(dir.path());
+
+        let config = default_dir_config(dir.path());
+        let paths = walk_directory(&config).unwrap();
+        let rerun = paths.rerun_paths(&config);
+
+        assert!(rerun.contains(&dir.path().to_owned()));
+        assert!(!rerun.contains(&dir.path().join("css")));
+        assert!(rerun.contains(&dir.path().join("index.html")));
+        assert!(rerun.contains(&dir.path().join("css/style.css")));
+        assert!(!rerun.contains(&dir.path().join(".hidden")));
+    }
+
+    #[test]
+    fn rerun_paths_include_gitignore_files_when_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        create_test_directory(dir.path());
+        fs::write(dir.path().join(".gitignore"), "build\n").unwrap();
+        fs::write(dir.path().join("css/.gitignore"), "*.map\n").unwrap();
+
+        let mut config = default_dir_config(dir.path());
+        let paths = walk_directory(&config).unwrap();
+        let rerun = paths.rerun_paths(&config);
+        assert!(!rerun.contains(&dir.path().join(".gitignore")));
+
+        config.gitignore = true;
+        let rerun = paths.rerun_paths(&config);
+        assert!(rerun.contains(&dir.path().join(".gitignore")));
+        assert!(rerun.contains(&dir.path().join("css/.gitignore")));
+    }
+
+    #[test]
+    fn rerun_paths_include_ancestor_ignore_files() {
+        let repo = tempfile::tempdir().unwrap();
+        fs::create_dir(repo.path().join(".git")).unwrap();
+        fs::write(repo.path().join(".gitignore"), "*.map\n").unwrap();
+        fs::write(repo.path().join(".ignore"), "*.bak\n").unwrap();
+        let root = repo.path().join("assets");
+        create_test_directory(&root);
+
+        // The walker always reads ancestor .ignore files, and ancestor
+        // .gitignore files with gitignore on
+        let mut config = default_dir_config(&root);
+        let paths = walk_directory(&config).unwrap();
+        let rerun = paths.rerun_paths(&config);
+        assert!(rerun.contains(&repo.path().join(".ignore")));
+        assert!(!rerun.contains(&repo.path().join(".gitignore")));
+
+        config.gitignore = true;
+        let rerun = paths.rerun_paths(&config);
+        assert!(rerun.contains(&repo.path().join(".gitignore")));
+    }
+
     #[test]
     fn host_path_roundtrips_through_simple_walk() {
         let dir = tempfile::tempdir().unwrap();
@@ -0,0 +1,609 @@
+//! # Built-in ESP Flasher
+//!
+//! Writes images through the serial bootloader in the ROM of ESP32 chips,
+//! so `littlefs flash` doesn't need `espflash` or `esptool` installed.
+//! Enabled with the `esp-flasher` feature and selected with
+//! `flasher = "esp"` in a `[flash.filesystem]` section.
+//!
+//! The bootloader speaks a request/response protocol over SLIP frames.
+//! Each request is a direction byte (`0x00`), a command byte, a `u16` data
+//! length, a `u32` checksum and the data. Each response is a direction
+//! byte (`0x01`), the command it answers, a `u16` length, a `u32` value
+//! and the data, which ends with a status byte and an error code. All
+//! integers are little-endian.
+//!
+//! Before anything is written, the MD5 of each 4 KiB sector on the device
+//! is compared with the image, and only the runs of sectors that differ
+//! are erased and written. Data is sent zlib-compressed, and the MD5 of
+//! the whole region is checked at the end.
+
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use md5::{Digest, Md5};
+use serialport::SerialPort;
+use thiserror::Error;
+
+/// Bootloader commands.
+const SYNC: u8 = 0x08;
+const READ_REG: u8 = 0x0A;
+const SPI_SET_PARAMS: u8 = 0x0B;
+const SPI_ATTACH: u8 = 0x0D;
+const CHANGE_BAUDRATE: u8 = 0x0F;
+const FLASH_DEFL_BEGIN: u8 = 0x10;
+const FLASH_DEFL_DATA: u8 = 0x11;
+const SPI_FLASH_MD5: u8 = 0x13;
+
+/// SLIP framing bytes.
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+/// The baud rate the ROM bootloader starts at.
+const ROM_BAUD: u32 = 115_200;
+/// Size of the compressed blocks the ROM bootloader accepts.
+const WRITE_BLOCK: usize = 0x400;
+/// The flash erase sector size.
+const SECTOR: usize = 0x1000;
+/// Flash size given to the ROM's SPI driver when the config names none.
+/// The ROM only uses it to bounds check.
+pub const DEFAULT_FLASH_SIZE: u32 = 16 * 1024 * 1024;
+
+/// Register holding a chip-specific magic value.
+const CHIP_MAGIC_REG: u32 = 0x4000_1000;
+const ESP8266_MAGIC: u32 = 0xFFF0_C101;
+const ESP32_MAGIC: u32 = 0x00F0_1D83;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+const SYNC_TIMEOUT: Duration = Duration::from_millis(100);
+const SYNC_ATTEMPTS: usize = 10;
+
+/// Errors that can occur while talking to the bootloader.
+#[derive(Debug, Error)]
+pub enum EspError {
+    #[error(transparent)]
+    Serial(#[from] serialport::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// The bootloader didn't answer in time.
+    #[error("no response from the bootloader to command {0:#04x}")]
+    Timeout(u8),
+
+    /// The bootloader answered a command with an error.
+    #[error("command {command:#04x} failed with error {code:#04x}")]
+    Failed { command: u8, code: u8 },
+
+    /// A response didn't follow the protocol.
+    #[error("invalid response from the bootloader: {0}")]
+    Protocol(String),
+
+    /// The chip's ROM bootloader can't write compressed data.
+    #[error("the ESP8266 ROM bootloader isn't supported")]
+    UnsupportedChip,
+
+    /// Writes must start on an erase sector.
+    #[error("address {0:#x} is not aligned to a 4 KiB sector")]
+    Unaligned(u32),
+
+    /// The flash contents didn't match the image after writing.
+    #[error("verification failed: the {len} bytes at {address:#x} don't match the image")]
+    Verify { address: u32, len: usize },
+}
+
+/// What [`EspFlasher::write`] did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteSummary {
+    /// The number of sectors the image covers.
+    pub sectors: usize,
+    /// The number of sectors that differed and were written.
+    pub written: usize,
+}
+
+/// A connection to an ESP32 ROM bootloader.
+pub struct EspFlasher {
+    port: Box<dyn SerialPort>,
+    /// Received bytes not yet consumed by [`read_frame`](Self::read_frame).
+    pending: Vec<u8>,
+    /// Whether flash begin commands take the extra encryption word, which
+    /// every chip after the original ESP32 expects.
+    encrypted_param: bool,
+}
+
+impl EspFlasher {
+    /// Reset the chip on `port` into its bootloader and connect to it,
+    /// switching to `baud` afterwards if given. `flash_size` is passed on
+    /// to [`connect`](Self::connect).
+    pub fn open(port: &str, baud: Option<u32>, flash_size: u32) -> Result<Self, EspError> {
+        let port = serialport::new(port, ROM_BAUD)
+            .timeout(DEFAULT_TIMEOUT)
+            .open()?;
+        let mut flasher = Self::new(port);
+        flasher.reset_into_bootloader()?;
+        flasher.connect(flash_size)?;
+        if let Some(baud) = baud {
+            flasher.change_baud(baud)?;
+        }
+        Ok(flasher)
+    }
+
+    /// Wrap a port to a chip that is already in its bootloader. Call
+    /// [`connect`](Self::connect) before anything else.
+    pub fn new(port: Box<dyn SerialPort>) -> Self {
+        Self {
+            port,
+            pending: Vec::new(),
+            encrypted_param: true,
+        }
+    }
+
+    /// Sync with the bootloader, detect the chip and attach the SPI flash,
+    /// telling the ROM it is `flash_size` bytes.
+    pub fn connect(&mut self, flash_size: u32) -> Result<(), EspError> {
+        let mut sync = vec![0x07, 0x07, 0x12, 0x20];
+        sync.extend([0x55; 32]);
+        let mut synced = false;
+        for _ in 0..SYNC_ATTEMPTS {
+            match self.command(SYNC, &sync, 0, SYNC_TIMEOUT) {
+                Ok(_) => {
+                    synced = true;
+                    break;
+                }
+                Err(EspError::Timeout(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if !synced {
+            return Err(EspError::Timeout(SYNC));
+        }
+
+        match self.read_reg(CHIP_MAGIC_REG)? {
+            ESP8266_MAGIC => return Err(EspError::UnsupportedChip),
+            ESP32_MAGIC => self.encrypted_param = false,
+            _ => self.encrypted_param = true,
+        }
+
+        self.command(SPI_ATTACH, &[0; 8], 0, DEFAULT_TIMEOUT)?;
+        let params = words(&[0, flash_size, 0x10000, SECTOR as u32, 0x100, 0xFFFF]);
+        self.command(SPI_SET_PARAMS, &params, 0, DEFAULT_TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Switch the bootloader and the port to a different baud rate.
+    pub fn change_baud(&mut self, baud: u32) -> Result<(), EspError> {
+        self.command(CHANGE_BAUDRATE, &words(&[baud, 0]), 0, DEFAULT_TIMEOUT)?;
+        self.port.set_baud_rate(baud)?;
+        std::thread::sleep(Duration::from_millis(50));
+        self.port.clear(serialport::ClearBuffer::Input)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Read a 32-bit register.
+    pub fn read_reg(&mut self, address: u32) -> Result<u32, EspError> {
+        let (value, _) = self.command(READ_REG, &words(&[address]), 0, DEFAULT_TIMEOUT)?;
+        Ok(value)
+    }
+
+    /// The MD5 of `len` bytes of flash starting at `address`.
+    pub fn flash_md5(&mut self, address: u32, len: usize) -> Result<[u8; 16], EspError> {
+        let timeout = scaled_timeout(len, Duration::from_secs(8));
+        let (_, data) = self.command(
+            SPI_FLASH_MD5,
+            &words(&[address, len as u32, 0, 0]),
+            0,
+            timeout,
+        )?;
+        // The ROM answers in hex, the flasher stub in raw bytes
+        match data.len() {
+            32.. => {
+                let hex = std::str::from_utf8(&data[..32])
+                    .map_err(|_| EspError::Protocol("MD5 isn't hex".into()))?;
+                let mut md5 = [0; 16];
+                for (i, byte) in md5.iter_mut().enumerate() {
+                    *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                        .map_err(|_| EspError::Protocol("MD5 isn't hex".into()))?;
+                }
+                Ok(md5)
+            }
+            16.. => Ok(data[..16].try_into().unwrap()),
+            _ => Err(EspError::Protocol("MD5 response too short".into())),
+        }
+    }
+
+    /// Write `data` to flash at `address`, erasing and writing only the
+    /// sectors whose contents differ, then check the MD5 of the region.
+    pub fn write(&mut self, address: u32, data: &[u8]) -> Result<WriteSummary, EspError> {
+        if !(address as usize).is_multiple_of(SECTOR) {
+            return Err(EspError::Unaligned(address));
+        }
+        let sectors = data.len().div_ceil(SECTOR);
+        let expected: [u8; 16] = Md5::digest(data).into();
+        if self.flash_md5(address, data.len())? == expected {
+            return Ok(WriteSummary {
+                sectors,
+                written: 0,
+            });
+        }
+
+        let mut changed: Vec<Range<usize>> = Vec::new();
+        for start in (0..data.len()).step_by(SECTOR) {
+            let sector = &data[start..(start + SECTOR).min(data.len())];
+            let on_flash = self.flash_md5(address + start as u32, sector.len())?;
+            if on_flash == <[u8; 16]>::from(Md5::digest(sector)) {
+                continue;
+            }
+            match changed.last_mut() {
+                Some(last) if last.end == start => last.end = start + sector.len(),
+                _ => changed.push(start..start + sector.len()),
+            }
+        }
+
+        for range in &changed {
+            self.write_range(address + range.start as u32, &data[range.clone()])?;
+        }
+
+        if self.flash_md5(address, data.len())? != expected {
+            return Err(EspError::Verify {
+                address,
+                len: data.len(),
+            });
+        }
+        Ok(WriteSummary {
+            sectors,
+            written: changed.iter().map(|r| r.len().div_ceil(SECTOR)).sum(),
+        })
+    }
+
+    /// Erase the sectors under `data` and write it compressed.
+    fn write_range(&mut self, address: u32, data: &[u8]) -> Result<(), EspError> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+
+        let erase_size = data.len().div_ceil(WRITE_BLOCK) * WRITE_BLOCK;
+        let blocks = compressed.len().div_ceil(WRITE_BLOCK);
+        let mut begin = vec![
+            erase_size as u32,
+            blocks as u32,
+            WRITE_BLOCK as u32,
+            address,
+        ];
+        if self.encrypted_param {
+            begin.push(0);
+        }
+        let erase_timeout = scaled_timeout(erase_size, Duration::from_secs(30));
+        self.command(FLASH_DEFL_BEGIN, &words(&begin), 0, erase_timeout)?;
+
+        for (seq, block) in compressed.chunks(WRITE_BLOCK).enumerate() {
+            let mut packet = words(&[block.len() as u32, seq as u32, 0, 0]);
+            packet.extend_from_slice(block);
+            let timeout = scaled_timeout(WRITE_BLOCK * 4, Duration::from_secs(40));
+            self.command(FLASH_DEFL_DATA, &packet, checksum(block), timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Hold the chip in reset with RTS, then release it with IO0 pulled low
+    /// through DTR so it starts in the bootloader.
+    fn reset_into_bootloader(&mut self) -> Result<(), EspError> {
+        self.port.write_data_terminal_ready(false)?;
+        self.port.write_request_to_send(true)?;
+        std::thread::sleep(Duration::from_millis(100));
+        self.port.write_data_terminal_ready(true)?;
+        self.port.write_request_to_send(false)?;
+        std::thread::sleep(Duration::from_millis(50));
+        self.port.write_data_terminal_ready(false)?;
+        Ok(())
+    }
+
+    /// Reset the chip so it boots the flashed application.
+    pub fn reset(&mut self) -> Result<(), EspError> {
+        self.port.write_request_to_send(true)?;
+        std::thread::sleep(Duration::from_millis(100));
+        self.port.write_request_to_send(false)?;
+        Ok(())
+    }
+
+    /// Send a command and wait for its response, returning the response's
+    /// value and its data with the status bytes removed.
+    fn command(
+        &mut self,
+        command: u8,
+        data: &[u8],
+        checksum: u32,
+        timeout: Duration,
+    ) -> Result<(u32, Vec<u8>), EspError> {
+        let mut packet = vec![0x00, command];
+        packet.extend((data.len() as u16).to_le_bytes());
+        packet.extend(checksum.to_le_bytes());
+        packet.extend_from_slice(data);
+        self.port.write_all(&slip_encode(&packet))?;
+        self.port.flush()?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let frame = self.read_frame(command, deadline)?;
+            // Skip anything that isn't the answer, like the extra
+            // responses the ROM sends to a sync
+            if frame.len() < 8 || frame[0] != 0x01 || frame[1] != command {
+                continue;
+            }
+            let value = u32::from_le_bytes(frame[4..8].try_into().unwrap());
+            let body = &frame[8..];
+            // Only MD5 responses carry data: 32 hex digits from the ROM or
+            // 16 bytes from the stub. The 2 or 4 status bytes after it
+            // (depending on the chip) start with the status and the error
+            let payload_len = match command {
+                SPI_FLASH_MD5 if body.len() >= 32 + 2 => 32,
+                SPI_FLASH_MD5 => 16,
+                _ => 0,
+            };
+            if body.len() < payload_len + 2 {
+                return Err(EspError::Protocol(format!(
+                    "response to {command:#04x} is too short"
+                )));
+            }
+            let (payload, status) = body.split_at(payload_len);
+            if status[0] != 0 {
+                return Err(EspError::Failed {
+                    command,
+                    code: status[1],
+                });
+            }
+            return Ok((value, payload.to_vec()));
+        }
+    }
+
+    /// Read the next SLIP frame, skipping any bytes outside a frame such
+    /// as boot messages.
+    fn read_frame(&mut self, command: u8, deadline: Instant) -> Result<Vec<u8>, EspError> {
+        let mut frame = Vec::new();
+        let mut in_frame = false;
+        let mut escaped = false;
+        loop {
+            let byte = self.read_byte(command, deadline)?;
+            if !in_frame {
+                in_frame = byte == END;
+                continue;
+            }
+            match (escaped, byte) {
+                (true, ESC_END) => frame.push(END),
+                (true, ESC_ESC) => frame.push(ESC),
+                (true, _) => return Err(EspError::Protocol("invalid SLIP escape".into())),
+                (false, ESC) => {
+                    escaped = true;
+                    continue;
+                }
+                // Back-to-back delimiters between frames
+                (false, END) if frame.is_empty() => {}
+                (false, END) => return Ok(frame),
+                (false, _) => frame.push(byte),
+            }
+            escaped = false;
+        }
+    }
+
+    fn read_byte(&mut self, command: u8, deadline: Instant) -> Result<u8, EspError> {
+        if self.pending.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(EspError::Timeout(command));
+            }
+            self.port.set_timeout(remaining)?;
+            let mut buf = [0; 256];
+            let read = match self.port.read(&mut buf) {
+                Ok(0) => return Err(EspError::Timeout(command)),
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    return Err(EspError::Timeout(command));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            // Kept reversed so bytes are popped off the end in order
+            self.pending.extend(buf[..read].iter().rev());
+        }
+        Ok(self.pending.pop().unwrap())
+    }
+}
+
+/// Little-endian encode a list of words.
+fn words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+/// The checksum of a data block: its bytes XORed with `0xEF`.
+fn checksum(data: &[u8]) -> u32 {
+    u32::from(data.iter().fold(0xEF, |acc, b| acc ^ b))
+}
+
+/// A timeout of `per_mb` for each megabyte of `len`, but at least the
+/// default timeout.
+fn scaled_timeout(len: usize, per_mb: Duration) -> Duration {
+    DEFAULT_TIMEOUT.max(per_mb.mul_f64(len as f64 / 1e6))
+}
+
+/// Wrap a packet in a SLIP frame.
+fn slip_encode(packet: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(packet.len() + 2);
+    out.push(END);
+    for &byte in packet {
+        match byte {
+            END => out.extend([ESC, ESC_END]),
+            ESC => out.extend([ESC, ESC_ESC]),
+            _ => out.push(byte),
+        }
+    }
+    out.push(END);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Decompress, FlushDecompress};
+    use serialport::TTYPort;
+    use std::collections::BTreeSet;
+    use std::thread::JoinHandle;
+
+    const BASE: usize = 0x200000;
+
+    /// What the fake bootloader did, returned when the port is closed.
+    struct FakeRom {
+        flash: Vec<u8>,
+        erased: BTreeSet<usize>,
+        /// The flash size given with `SPI_SET_PARAMS`.
+        flash_size: usize,
+    }
+
+    /// Run a stand-in for an ESP32-S3 ROM bootloader on one end of a
+    /// pseudo-terminal, with `flash` mapped at `BASE`.
+    fn fake_rom(mut port: TTYPort, flash: Vec<u8>) -> JoinHandle<FakeRom> {
+        std::thread::spawn(move || {
+            let mut rom = FakeRom {
+                flash,
+                erased: BTreeSet::new(),
+                flash_size: 0,
+            };
+            let mut write_at = 0;
+            let mut inflate = Decompress::new(true);
+            let mut frame = Vec::new();
+            let mut escaped = false;
+            let mut buf = [0; 4096];
+            loop {
+                let read = match port.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+                    Err(_) => break,
+                };
+                for &byte in &buf[..read] {
+                    match (escaped, byte) {
+                        (true, ESC_END) => frame.push(END),
+                        (true, _) => frame.push(ESC),
+                        (false, ESC) => {
+                            escaped = true;
+                            continue;
+                        }
+                        (false, END) if frame.is_empty() => {}
+                        (false, END) => {
+                            let packet = std::mem::take(&mut frame);
+                            let word = |i: usize| {
+                                u32::from_le_bytes(
+                                    packet[8 + i * 4..12 + i * 4].try_into().unwrap(),
+                                ) as usize
+                            };
+                            let mut value = 0;
+                            let mut data = Vec::new();
+                            match packet[1] {
+                                READ_REG => value = 9, // ESP32-S3
+                                SPI_SET_PARAMS => rom.flash_size = word(1),
+                                FLASH_DEFL_BEGIN => {
+                                    // The S3 expects the encryption word
+                                    assert_eq!(packet.len(), 8 + 20);
+                                    let (size, offset) = (word(0), word(3) - BASE);
+                                    for sector in offset / SECTOR..(offset + size).div_ceil(SECTOR)
+                                    {
+                                        rom.erased.insert(sector);
+                                        rom.flash[sector * SECTOR..][..SECTOR].fill(0xFF);
+                                    }
+                                    write_at = offset;
+                                    inflate = Decompress::new(true);
+                                }
+                                FLASH_DEFL_DATA => {
+                                    let block = &packet[8 + 16..];
+                                    let sum = u32::from_le_bytes(packet[4..8].try_into().unwrap());
+                                    assert_eq!(sum, checksum(block));
+                                    let mut out = vec![0; 64 * 1024];
+                                    let before = inflate.total_out();
+                                    inflate
+                                        .decompress(block, &mut out, FlushDecompress::None)
+                                        .unwrap();
+                                    let len = (inflate.total_out() - before) as usize;
+                                    rom.flash[write_at..write_at + len]
+                                        .copy_from_slice(&out[..len]);
+                                    write_at += len;
+                                }
+                                SPI_FLASH_MD5 => {
+                                    let (offset, len) = (word(0) - BASE, word(1));
+                                    let md5 = Md5::digest(&rom.flash[offset..offset + len]);
+                                    data.extend(format!("{md5:x}").into_bytes());
+                                }
+                                _ => {}
+                            }
+                            // Four status bytes, as the ESP32 ROMs send
+                            data.extend([0, 0, 0, 0]);
+                            let mut response = vec![0x01, packet[1]];
+                            response.extend((data.len() as u16).to_le_bytes());
+                            response.extend((value as u32).to_le_bytes());
+                            response.extend(data);
+                            port.write_all(&slip_encode(&response)).unwrap();
+                            // The ROM answers a sync several times
+                            if packet[1] == SYNC {
+                                for _ in 0..3 {
+                                    port.write_all(&slip_encode(&response)).unwrap();
+                                }
+                            }
+                        }
+                        (false, _) => frame.push(byte),
+                    }
+                    escaped = false;
+                }
+            }
+            rom
+        })
+    }
+
+    #[test]
+    fn slip_escapes_delimiters() {
+        assert_eq!(
+            slip_encode(&[1, END, 2, ESC]),
+            [END, 1, ESC, ESC_END, 2, ESC, ESC_ESC, END]
+        );
+    }
+
+    #[test]
+    fn writes_only_changed_sectors() {
+        let (host, device) = TTYPort::pair().unwrap();
+
+        let old: Vec<u8> = (0..8 * SECTOR).map(|i| (i / 7) as u8).collect();
+        let mut new = old.clone();
+        new[SECTOR + 5] ^= 0xFF;
+        new[2 * SECTOR] ^= 0xFF;
+        new[6 * SECTOR + 100] = END;
+        new[6 * SECTOR + 101] = ESC;
+        let mut flash = vec![0xFF; 16 * SECTOR];
+        flash[..old.len()].copy_from_slice(&old);
+        let rom = fake_rom(device, flash);
+
+        let mut flasher = EspFlasher::new(Box::new(host));
+        flasher.connect(4 * 1024 * 1024).unwrap();
+        let summary = flasher.write(BASE as u32, &new).unwrap();
+        assert_eq!(
+            summary,
+            WriteSummary {
+                sectors: 8,
+                written: 3
+            }
+        );
+
+        // Unchanged images aren't written at all
+        let again = flasher.write(BASE as u32, &new).unwrap();
+        assert_eq!(again.written, 0);
+        assert!(matches!(
+            flasher.write(BASE as u32 + 1, &new),
+            Err(EspError::Unaligned(_))
+        ));
+
+        drop(flasher);
+        let rom = rom.join().unwrap();
+        assert_eq!(&rom.flash[..new.len()], &new[..]);
+        assert_eq!(rom.erased, BTreeSet::from([1, 2, 6]));
+        assert_eq!(rom.flash_size, 4 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,764 @@
+//! # FUSE Mount
+//!
+//! Exposes an image file as a read-write directory, so ordinary tools
+//! (`grep`, editors, `rsync`) work on it directly. Enabled with the `fuse`
+//! feature. Mounting needs `fusermount3` (or `fusermount`) from the FUSE
+//! userspace package.
+//!
+//! Each operation mounts the image with [`LfsImage::mount_and_then`] and
+//! uses the [`MountedFs`](littlefs2_pack::littlefs::MountedFs) calls, so
+//! files are only ever read and written whole: a write reads the file,
+//! patches the range, and writes it back. That is fine for the small files
+//! a device image holds, but slow for large ones.
+//!
+//! Changes are made to the image in memory and written back to the image
+//! file when a file is closed or synced, after each directory entry is
+//! created, removed or renamed, and when the image is unmounted.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::raw::c_int;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
+};
+use littlefs2_pack::littlefs::{LfsError, LfsImage};
+
+/// How long the kernel may cache attributes and lookups. Nothing else
+/// changes the image while it is mounted.
+const TTL: Duration = Duration::from_secs(1);
+
+/// The root directory's inode, fixed by FUSE.
+const ROOT: u64 = fuser::FUSE_ROOT_ID;
+
+/// The `renameat2` flag that fails instead of replacing the target. It
+/// has this value on Linux and isn't defined by `libc` everywhere else.
+const RENAME_NOREPLACE: u32 = 1;
+
+/// An image served through FUSE.
+pub struct LfsFuse {
+    image: LfsImage,
+    /// The image file, written back on unmount.
+    path: PathBuf,
+    /// Whether the image file was trimmed, so it is written back trimmed.
+    trimmed: bool,
+    /// Whether anything changed since the image was loaded.
+    dirty: bool,
+    /// LittleFS paths by inode number. Index 0 is unused, 1 is the root.
+    paths: Vec<String>,
+    inodes: HashMap<String, u64>,
+    /// The owner and times reported for every entry, from the image file,
+    /// since LittleFS keeps neither.
+    uid: u32,
+    gid: u32,
+    time: SystemTime,
+}
+
+impl LfsFuse {
+    /// Serve `image`, which was read from the file at `path`.
+    pub fn new(image: LfsImage, path: &Path) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let full = image.config().block_size * image.config().block_count;
+        Ok(Self {
+            trimmed: (metadata.len() as usize) < full,
+            image,
+            path: path.to_owned(),
+            dirty: false,
+            paths: vec![String::new(), "/".to_string()],
+            inodes: HashMap::from([("/".to_string(), ROOT)]),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            time: metadata.modified()?,
+        })
+    }
+
+    /// Write the image back to its file, if anything changed.
+    pub fn save(&mut self) -> std::io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let data = match self.trimmed {
+            true => self.image.trimmed_data(),
+            false => self.image.data(),
+        };
+        std::fs::write(&self.path, data)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    // -------------------------------------------------------------------------
+    // Inodes
+    // -------------------------------------------------------------------------
+
+    /// The inode for `path`, allocating one the first time it's seen.
+    fn inode(&mut self, path: &str) -> u64 {
+        if let Some(&ino) = self.inodes.get(path) {
+            return ino;
+        }
+        let ino = self.paths.len() as u64;
+        self.paths.push(path.to_string());
+        self.inodes.insert(path.to_string(), ino);
+        ino
+    }
+
+    /// The LittleFS path of an inode.
+    fn path_of(&self, ino: u64) -> Result<String, c_int> {
+        self.paths
+            .get(ino as usize)
+            .filter(|path| !path.is_empty())
+            .cloned()
+            .ok_or(libc::ENOENT)
+    }
+
+    /// The LittleFS path of `name` inside the directory `parent`.
+    fn child_path(&self, parent: u64, name: &OsStr) -> Result<String, c_int> {
+        let name = name.to_str().ok_or(libc::EINVAL)?;
+        let parent = self.path_of(parent)?;
+        Ok(match parent.as_str() {
+            "/" => format!("/{name}"),
+            _ => format!("{parent}/{name}"),
+        })
+    }
+
+    /// Drop the inodes of `path` and everything under it, so an entry
+    /// created there later gets a fresh one.
+    fn forget_inodes(&mut self, path: &str) {
+        let prefix = format!("{path}/");
+        for ino in 0..self.paths.len() {
+            let known = &self.paths[ino];
+            if known == path || known.starts_with(&prefix) {
+                self.inodes.remove(known);
+                self.paths[ino].clear();
+            }
+        }
+    }
+
+    /// Point the inodes of `from` and everything under it at `to`. An
+    /// entry the rename replaced at `to` loses its inode.
+    fn rename_inodes(&mut self, from: &str, to: &str) {
+        if from == to {
+            return;
+        }
+        self.forget_inodes(to);
+
+        let prefix = format!("{from}/");
+        for ino in 0..self.paths.len() {
+            let path = &self.paths[ino];
+            let renamed = match path.strip_prefix(&prefix) {
+                Some(rest) => format!("{to}/{rest}"),
+                None if path == from => to.to_string(),
+                None => continue,
+            };
+            self.inodes.remove(path);
+            self.inodes.insert(renamed.clone(), ino as u64);
+            self.paths[ino] = renamed;
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // Operations
+    // -------------------------------------------------------------------------
+
+    /// The attributes of the entry at `path`.
+    fn attr(&mut self, path: &str) -> Result<FileAttr, c_int> {
+        let entry = self
+            .image
+            .mount_and_then(|fs| fs.stat(path))
+            .map_err(errno)?;
+        let (kind, perm, nlink) = match entry.is_dir {
+            true => (FileType::Directory, 0o755, 2),
+            false => (FileType::RegularFile, 0o644, 1),
+        };
+        Ok(FileAttr {
+            ino: self.inode(path),
+            size: entry.size as u64,
+            blocks: entry.size.div_ceil(512) as u64,
+            atime: self.time,
+            mtime: self.time,
+            ctime: self.time,
+            crtime: self.time,
+            kind,
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: self.image.config().block_size as u32,
+            flags: 0,
+        })
+    }
+
+    fn lookup_entry(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let path = self.child_path(parent, name)?;
+        self.attr(&path)
+    }
+
+    fn read_range(&mut self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        let path = self.path_of(ino)?;
+        let data = self
+            .image
+            .mount_and_then(|fs| fs.read_file(&path))
+            .map_err(errno)?;
+        let start = (offset.max(0) as usize).min(data.len());
+        let end = (start + size as usize).min(data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    fn write_range(&mut self, ino: u64, offset: i64, bytes: &[u8]) -> Result<u32, c_int> {
+        let path = self.path_of(ino)?;
+        let offset = usize::try_from(offset).map_err(|_| libc::EINVAL)?;
+        self.image
+            .mount_and_then(|fs| {
+                let mut data = fs.read_file(&path)?;
+                if data.len() < offset + bytes.len() {
+                    data.resize(offset + bytes.len(), 0);
+                }
+                data[offset..offset + bytes.len()].copy_from_slice(bytes);
+                fs.write_file(&path, &data)
+            })
+            .map_err(errno)?;
+        self.dirty = true;
+        Ok(bytes.len() as u32)
+    }
+
+    fn set_size(&mut self, ino: u64, size: u64) -> Result<FileAttr, c_int> {
+        let path = self.path_of(ino)?;
+        self.image
+            .mount_and_then(|fs| {
+                let mut data = fs.read_file(&path)?;
+                data.resize(size as usize, 0);
+                fs.write_file(&path, &data)
+            })
+            .map_err(errno)?;
+        self.dirty = true;
+        self.attr(&path)
+    }
+
+    fn create_file(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let path = self.child_path(parent, name)?;
+        self.image
+            .mount_and_then(|fs| fs.write_file(&path, &[]))
+            .map_err(errno)?;
+        self.dirty = true;
+        self.attr(&path)
+    }
+
+    fn make_dir(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let path = self.child_path(parent, name)?;
+        self.image
+            .mount_and_then(|fs| fs.create_dir(&path))
+            .map_err(errno)?;
+        self.dirty = true;
+        self.persist()?;
+        self.attr(&path)
+    }
+
+    /// Remove a file (`dir` false) or an empty directory (`dir` true).
+    /// LittleFS removes either with the same call, so the entry is
+    /// checked first to fail `unlink` on a directory and `rmdir` on a file.
+    fn remove_entry(&mut self, parent: u64, name: &OsStr, dir: bool) -> Result<(), c_int> {
+        let path = self.child_path(parent, name)?;
+        self.image
+            .mount_and_then(|fs| {
+                let entry = fs.stat(&path)?;
+                if entry.is_dir != dir {
+                    return Ok(Err(match entry.is_dir {
+                        true => libc::EISDIR,
+                        false => libc::ENOTDIR,
+                    }));
+                }
+                fs.remove(&path).map(Ok)
+            })
+            .map_err(errno)??;
+        self.dirty = true;
+        self.forget_inodes(&path);
+        self.persist()
+    }
+
+    /// Rename an entry. Of the `renameat2` flags only `RENAME_NOREPLACE`
+    /// is supported; `RENAME_EXCHANGE` and the rest fail with `EINVAL`.
+    fn rename_entry(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        if flags & !RENAME_NOREPLACE != 0 {
+            return Err(libc::EINVAL);
+        }
+        let from = self.child_path(parent, name)?;
+        let to = self.child_path(new_parent, new_name)?;
+        self.image
+            .mount_and_then(|fs| {
+                if flags & RENAME_NOREPLACE != 0 && fs.exists(&to) {
+                    return Ok(Err(libc::EEXIST));
+                }
+                fs.rename(&from, &to).map(Ok)
+            })
+            .map_err(errno)??;
+        self.dirty = true;
+        self.rename_inodes(&from, &to);
+        self.persist()
+    }
+
+    /// [`save`](Self::save) for a FUSE reply.
+    fn persist(&mut self) -> Result<(), c_int> {
+        self.save().map_err(|e| {
+            eprintln!("error: failed to write '{}': {e}", self.path.display());
+            libc::EIO
+        })
+    }
+
+    /// The entries of a directory, with `.` and `..` first.
+    fn dir_entries(&mut self, ino: u64) -> Result<Vec<(u64, FileType, String)>, c_int> {
+        let path = self.path_of(ino)?;
+        let listing = self
+            .image
+            .mount_and_then(|fs| fs.read_dir(&path))
+            .map_err(errno)?;
+        let parent = match path.rsplit_once('/') {
+            Some(("", _)) | None => ROOT,
+            Some((parent, _)) => self.inode(parent),
+        };
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (parent, FileType::Directory, "..".to_string()),
+        ];
+        for entry in listing {
+            let child = match path.as_str() {
+                "/" => format!("/{}", entry.name),
+                _ => format!("{path}/{}", entry.name),
+            };
+            let kind = match entry.is_dir {
+                true => FileType::Directory,
+                false => FileType::RegularFile,
+            };
+            entries.push((self.inode(&child), kind, entry.name));
+        }
+        Ok(entries)
+    }
+}
+
+/// The errno for a failed operation. LittleFS error codes are negated
+/// errno values.
+fn errno(e: LfsError) -> c_int {
+    match e {
+        LfsError::Lfs(_, code) if code < 0 => -code,
+        LfsError::NulPath => libc::EINVAL,
+        _ => libc::EIO,
+    }
+}
+
+// -----------------------------------------------------------------------------
+// FUSE callbacks
+// -----------------------------------------------------------------------------
+
+impl Filesystem for LfsFuse {
+    fn destroy(&mut self) {
+        if let Err(e) = self.save() {
+            eprintln!("error: failed to write '{}': {e}", self.path.display());
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_entry(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.path_of(ino).and_then(|path| self.attr(&path)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        // Only truncation means anything to LittleFS; modes, owners and
+        // times are accepted and ignored so tools like `cp -p` still work
+        let attr = match size {
+            Some(size) => self.set_size(ino, size),
+            None => self.path_of(ino).and_then(|path| self.attr(&path)),
+        };
+        match attr {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.make_dir(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove_entry(parent, name, false) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove_entry(parent, name, true) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.rename_entry(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        match self.persist() {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.persist() {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
+        reply.opened(0, 0);
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.read_range(ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.write_range(ino, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = match self.dir_entries(ino) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(e),
+        };
+        // Each entry's offset is where the next read continues from
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let config = self.image.config();
+        let (blocks, block_size) = (config.block_count as u64, config.block_size as u32);
+        match self.image.mount_and_then(|fs| fs.used_blocks()) {
+            Ok(used) => {
+                let free = blocks.saturating_sub(used as u64);
+                reply.statfs(blocks, free, free, 0, 0, block_size, 255, block_size);
+            }
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.create_file(parent, name) {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use littlefs2_pack::config::RawImageConfig;
+
+    fn mounted(dir: &Path) -> LfsFuse {
+        let config = RawImageConfig::new()
+            .with_block_size(4096)
+            .with_block_count(16)
+            .with_page_size(256)
+            .resolve()
+            .unwrap();
+        let mut image = LfsImage::new(config).unwrap();
+        image.format().unwrap();
+        image
+            .mount_and_then(|fs| {
+                fs.create_dir("/web")?;
+                fs.write_file("/web/index.html", b"<html/>")
+            })
+            .unwrap();
+        let path = dir.join("image.bin");
+        std::fs::write(&path, image.data()).unwrap();
+        LfsFuse::new(image, &path).unwrap()
+    }
+
+    #[test]
+    fn lookup_and_read_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = mounted(dir.path());
+
+        let web = fs.lookup_entry(ROOT, OsStr::new("web")).unwrap();
+        assert_eq!(web.kind, FileType::Directory);
+        let index = fs.lookup_entry(web.ino, OsStr::new("index.html")).unwrap();
+        assert_eq!(index.size, 7);
+        assert_eq!(fs.read_range(index.ino, 1, 4).unwrap(), b"html");
+        assert_eq!(fs.read_range(index.ino, 100, 4).unwrap(), b"");
+        assert_eq!(
+            fs.lookup_entry(ROOT, OsStr::new("missing")).unwrap_err(),
+            libc::ENOENT
+        );
+
+        let names: Vec<String> = fs
+            .dir_entries(web.ino)
+            .unwrap()
+            .into_iter()
+            .map(|(_, _, name)| name)
+            .collect();
+        assert_eq!(names, [".", "..", "index.html"]);
+    }
+
+    #[test]
+    fn changes_are_written_back_on_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = mounted(dir.path());
+        let before = std::fs::read(dir.path().join("image.bin")).unwrap();
+
+        // Nothing changed, so nothing is written
+        fs.save().unwrap();
+        assert_eq!(std::fs::read(dir.path().join("image.bin")).unwrap(), before);
+
+        let web = fs.lookup_entry(ROOT, OsStr::new("web")).unwrap();
+        let file = fs.create_file(web.ino, OsStr::new("app.js")).unwrap();
+        fs.write_range(file.ino, 0, b"run()").unwrap();
+        fs.write_range(file.ino, 3, b"(1)").unwrap();
+        fs.set_size(file.ino, 5).unwrap();
+        fs.make_dir(ROOT, OsStr::new("logs")).unwrap();
+        fs.rename_entry(ROOT, OsStr::new("web"), ROOT, OsStr::new("www"), 0)
+            .unwrap();
+        // The renamed file keeps its inode
+        assert_eq!(fs.path_of(file.ino).unwrap(), "/www/app.js");
+        let index = fs.lookup_entry(web.ino, OsStr::new("index.html")).unwrap();
+        fs.remove_entry(web.ino, OsStr::new("index.html"), false)
+            .unwrap();
+        assert_eq!(fs.attr("/www/index.html").unwrap_err(), libc::ENOENT);
+        assert_eq!(fs.read_range(index.ino, 0, 4).unwrap_err(), libc::ENOENT);
+        fs.save().unwrap();
+
+        let data = std::fs::read(dir.path().join("image.bin")).unwrap();
+        let mut image = LfsImage::from_data(fs.image.config().clone(), data).unwrap();
+        image
+            .mount_and_then(|fs| {
+                assert_eq!(fs.read_file("/www/app.js")?, b"run(1"[..]);
+                assert!(fs.stat("/logs")?.is_dir);
+                assert!(!fs.exists("/web"));
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn rename_over_existing_entry_drops_its_inode() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = mounted(dir.path());
+        let web = fs.lookup_entry(ROOT, OsStr::new("web")).unwrap();
+        let old = fs.create_file(web.ino, OsStr::new("old.html")).unwrap();
+        let index = fs.lookup_entry(web.ino, OsStr::new("index.html")).unwrap();
+
+        fs.rename_entry(
+            web.ino,
+            OsStr::new("index.html"),
+            web.ino,
+            OsStr::new("old.html"),
+            0,
+        )
+        .unwrap();
+        assert_eq!(fs.path_of(index.ino).unwrap(), "/web/old.html");
+        assert_eq!(fs.path_of(old.ino).unwrap_err(), libc::ENOENT);
+        let renamed = fs.lookup_entry(web.ino, OsStr::new("old.html")).unwrap();
+        assert_eq!(renamed.ino, index.ino);
+    }
+
+    #[test]
+    fn unlink_and_rmdir_check_the_entry_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = mounted(dir.path());
+        let web = fs.lookup_entry(ROOT, OsStr::new("web")).unwrap();
+        fs.make_dir(ROOT, OsStr::new("logs")).unwrap();
+
+        assert_eq!(
+            fs.remove_entry(ROOT, OsStr::new("logs"), false)
+                .unwrap_err(),
+            libc::EISDIR
+        );
+        assert_eq!(
+            fs.remove_entry(web.ino, OsStr::new("index.html"), true)
+                .unwrap_err(),
+            libc::ENOTDIR
+        );
+
+        fs.remove_entry(ROOT, OsStr::new("logs"), true).unwrap();
+        fs.remove_entry(web.ino, OsStr::new("index.html"), false)
+            .unwrap();
+        assert_eq!(fs.attr("/logs").unwrap_err(), libc::ENOENT);
+        assert_eq!(fs.attr("/web/index.html").unwrap_err(), libc::ENOENT);
+    }
+
+    #[test]
+    fn rename_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = mounted(dir.path());
+        let web = fs.lookup_entry(ROOT, OsStr::new("web")).unwrap();
+        fs.create_file(web.ino, OsStr::new("old.html")).unwrap();
+        let rename = |fs: &mut LfsFuse, to: &str, flags: u32| {
+            fs.rename_entry(
+                web.ino,
+                OsStr::new("index.html"),
+                web.ino,
+                OsStr::new(to),
+                flags,
+            )
+        };
+
+        assert_eq!(
+            rename(&mut fs, "old.html", RENAME_NOREPLACE).unwrap_err(),
+            libc::EEXIST
+        );
+        // RENAME_EXCHANGE isn't supported
+        assert_eq!(rename(&mut fs, "old.html", 2).unwrap_err(), libc::EINVAL);
+        assert!(fs.attr("/web/index.html").is_ok());
+
+        rename(&mut fs, "new.html", RENAME_NOREPLACE).unwrap();
+        assert_eq!(fs.attr("/web/index.html").unwrap_err(), libc::ENOENT);
+    }
+
+    #[test]
+    fn removed_entries_lose_their_inodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = mounted(dir.path());
+        let web = fs.lookup_entry(ROOT, OsStr::new("web")).unwrap();
+        let index = fs.lookup_entry(web.ino, OsStr::new("index.html")).unwrap();
+
+        fs.remove_entry(web.ino, OsStr::new("index.html"), false)
+            .unwrap();
+        let recreated = fs.create_file(web.ino, OsStr::new("index.html")).unwrap();
+        assert_ne!(recreated.ino, index.ino);
+        assert_eq!(fs.path_of(index.ino).unwrap_err(), libc::ENOENT);
+    }
+
+    #[test]
+    fn entry_changes_are_written_immediately() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = mounted(dir.path());
+
+        fs.make_dir(ROOT, OsStr::new("logs")).unwrap();
+        assert!(!fs.dirty);
+        let data = std::fs::read(dir.path().join("image.bin")).unwrap();
+        let mut image = LfsImage::from_data(fs.image.config().clone(), data).unwrap();
+        assert!(image.mount_and_then(|fs| fs.stat("/logs")).unwrap().is_dir);
+    }
+}
@@ -1,9 +1,34 @@
 use anyhow::{Context, Result, bail};
 use clap::{Args, Parser, Subcommand};
-use littlefs2_pack::config::{Config, ImageConfig, RawImageConfig};
-use littlefs2_pack::littlefs::{LfsError, LfsImage, MountedFs};
+#[cfg(feature = "watch")]
+use littlefs2_pack::config::DirectoryConfig;
+use littlefs2_pack::config::{
+    CommandOptions, Config, FilesystemFlash, Flasher, ImageConfig, ImageTarget, RawImageConfig,
+};
+use littlefs2_pack::delta::{BlockPatch, FileOp, FilePatch, Patch, changed_ranges};
+#[cfg(feature = "watch")]
+use littlefs2_pack::littlefs::UpdateSummary;
+use littlefs2_pack::littlefs::{ContentEntry, LfsError, LfsImage, MountedFs};
+use littlefs2_pack::merge::{MergedImage, Segment};
+use littlefs2_pack::output::{OutputFormat, ihex, srec, uf2};
+use littlefs2_pack::partition_table::rp2350::Rp2350PartitionTable;
+use littlefs2_pack::partition_table::{
+    AnyPartitionTable, DEFAULT_TABLE_OFFSET, PartitionTable, parse_size_value,
+};
+use littlefs2_pack::presets::{PRESETS, find_preset};
+#[cfg(feature = "watch")]
+use notify::{RecursiveMode, Watcher};
 use sha2::{Digest, Sha256};
 use std::path::{Path, PathBuf};
+#[cfg(feature = "watch")]
+use std::sync::mpsc::Receiver;
+#[cfg(feature = "watch")]
+use std::time::Duration;
+
+#[cfg(feature = "esp-flasher")]
+mod esp_flasher;
+#[cfg(feature = "fuse")]
+mod fuse;
 
 #[derive(Parser)]
 #[command(
@@ -12,9 +37,8 @@ use std::path::{Path, PathBuf};
     about = "Create, unpack, and inspect LittleFSv2 filesystem images"
 )]
 pub struct Cli {
-    /// Path to a littlefs.toml configuration file
-    #[arg(long, short = 'c', global = true)]
-    config: Option<PathBuf>,
+    #[command(flatten)]
+    source: ConfigSource,
 
     #[command(subcommand)]
     pub command: Commands,
@@ -32,6 +56,57 @@ pub enum Commands {
     Info(InfoCmd),
     /// Run the flash commands from a TOML config file
     Flash(FlashCmd),
+    /// List the built-in flash geometry presets
+    Presets,
+    /// Convert and inspect ESP-IDF and RP2350 partition tables
+    Partitions(PartitionsCmd),
+    /// Combine the bootloader, partition table, app and filesystem images into one flashable file
+    Merge(MergeCmd),
+    /// Write a patch that turns one image into another, for OTA updates
+    Delta(DeltaCmd),
+    /// Apply a patch written by `delta` to an image
+    Apply(ApplyCmd),
+    /// Read an image back from the device with the `[flash.readback]` command
+    Pull(PullCmd),
+    /// Repack an image whenever its source directory changes
+    Watch(WatchCmd),
+    /// Serve the files in a LittleFS2 image over HTTP
+    Serve(ServeCmd),
+    /// Mount a LittleFS2 image as a directory through FUSE
+    Mount(MountCmd),
+}
+
+// ---------------------------------------------------------------------------
+// Config file selection (global options)
+// ---------------------------------------------------------------------------
+
+/// Which config file to load, and which image and profile within it.
+#[derive(Args, Debug, Clone, Default)]
+pub struct ConfigSource {
+    /// Path to a littlefs.toml configuration file
+    #[arg(long, short = 'c', global = true)]
+    config: Option<PathBuf>,
+
+    /// Select an image by name when the config defines several `[[images]]`
+    #[arg(long, short = 'n', global = true, requires = "config")]
+    name: Option<String>,
+
+    /// Apply a `[profile.<PROFILE>]` from the config on top of the base settings
+    #[arg(long, global = true, requires = "config")]
+    profile: Option<String>,
+}
+
+impl ConfigSource {
+    /// Load the config file, if one was given, with the profile applied.
+    fn load(&self) -> Result<Option<Config>> {
+        self.config
+            .as_deref()
+            .map(|path| {
+                Config::from_file_with_profile(path, self.profile.as_deref())
+                    .with_context(|| format!("failed to load config '{}'", path.display()))
+            })
+            .transpose()
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -44,12 +119,17 @@ pub enum Commands {
 /// Without `--config`, these define the image parameters directly.
 #[derive(Args, Debug, Clone)]
 pub struct ImageConfigParams {
+    /// Flash geometry preset (see `littlefs presets`). Fills in the block,
+    /// read, and write sizes unless they are given explicitly.
+    #[arg(long)]
+    pub preset: Option<String>,
+
     /// Filesystem block (erase unit) size in bytes.
     #[arg(short, long)]
     pub block_size: Option<usize>,
 
     /// Total number of blocks in the filesystem.
-    #[arg(short = 'c', long, conflicts_with = "image_size")]
+    #[arg(long, conflicts_with = "image_size")]
     pub block_count: Option<usize>,
 
     /// Total image size in bytes (alternative to --block-count).
@@ -83,21 +163,46 @@ pub struct ImageConfigParams {
     pub lookahead_size: Option<usize>,
 }
 
+impl ImageConfigParams {
+    /// Fill the block, read, and write sizes from `--preset`, if given.
+    ///
+    /// Explicit flags win over the preset, and `--page-size` keeps the
+    /// preset from setting the read and write sizes.
+    fn with_preset_defaults(&self) -> Result<ImageConfigParams> {
+        let mut cli = self.clone();
+        if let Some(name) = &self.preset {
+            let preset = find_preset(name).with_context(|| {
+                format!("unknown preset '{name}' (run `littlefs presets` to list them)")
+            })?;
+            cli.block_size.get_or_insert(preset.block_size);
+            if cli.page_size.is_none() {
+                cli.read_size.get_or_insert(preset.read_size);
+                cli.write_size.get_or_insert(preset.write_size);
+            }
+        }
+        Ok(cli)
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Config resolution: TOML + CLI overrides
 // ---------------------------------------------------------------------------
 
 /// Build an `ImageConfig` entirely from CLI arguments using the builder pattern.
 fn image_config_from_cli(cli: &ImageConfigParams) -> Result<ImageConfig> {
+    let cli = &cli.with_preset_defaults()?;
     let block_size = match cli.block_size {
         Some(bs) => bs,
-        None => bail!("--block-size is required without --config"),
+        None => bail!("--block-size or --preset is required without --config"),
     };
 
     let mut builder = RawImageConfig::new()
         .with_block_size(block_size)
         .with_block_cycles(cli.block_cycles.unwrap_or(-1));
 
+    if let Some(p) = &cli.preset {
+        builder = builder.with_preset(p);
+    }
     if let Some(c) = cli.block_count {
         builder = builder.with_block_count(c);
     }
@@ -167,28 +272,30 @@ fn apply_cli_overrides(base: &ImageConfig, cli: &ImageConfigParams) -> ImageConf
 /// The block count is derived from the file size, since the file
 /// is the source of truth for how large the image is.
 fn image_config_for_reading(
-    config_path: &Option<PathBuf>,
+    source: &ConfigSource,
     cli: &ImageConfigParams,
     data: &[u8],
 ) -> Result<ImageConfig> {
+    let cli = &cli.with_preset_defaults()?;
+
     // Get block_size and read/write sizes from TOML or CLI
     let (block_size, read_size, write_size, block_cycles, cache_size, lookahead_size) =
-        match config_path {
-            Some(path) => {
-                let config = Config::from_file(path)?;
+        match source.load()? {
+            Some(config) => {
+                let image = &config.image(source.name.as_deref())?.image;
                 (
-                    cli.block_size.unwrap_or(config.image.block_size),
-                    cli.read_size.unwrap_or(config.image.read_size),
-                    cli.write_size.unwrap_or(config.image.write_size),
-                    cli.block_cycles.unwrap_or(config.image.block_cycles),
-                    cli.cache_size.or(Some(config.image.cache_size)),
-                    cli.lookahead_size.or(Some(config.image.lookahead_size)),
+                    cli.block_size.unwrap_or(image.block_size),
+                    cli.read_size.unwrap_or(image.read_size),
+                    cli.write_size.unwrap_or(image.write_size),
+                    cli.block_cycles.unwrap_or(image.block_cycles),
+                    cli.cache_size.or(Some(image.cache_size)),
+                    cli.lookahead_size.or(Some(image.lookahead_size)),
                 )
             }
             None => {
                 let block_size = match cli.block_size {
                     Some(bs) => bs,
-                    None => bail!("--block-size is required without --config"),
+                    None => bail!("--block-size or --preset is required without --config"),
                 };
                 let read_size = match cli.read_size.or(cli.page_size) {
                     Some(rs) => rs,
@@ -209,7 +316,7 @@ fn image_config_for_reading(
             }
         };
 
-    if data.is_empty() || data.len() % block_size != 0 {
+    if data.is_empty() || !data.len().is_multiple_of(block_size) {
         bail!(
             "image file size ({}) is not a multiple of block_size ({block_size})",
             data.len()
@@ -251,10 +358,48 @@ pub struct PackCmd {
     #[arg(short, long)]
     pub output: PathBuf,
 
+    /// Move the used blocks to the start of the image and leave out the
+    /// erased blocks after them, so only the used prefix gets flashed
+    #[arg(long)]
+    pub trim: bool,
+
+    /// Start from a previously packed image and only write what changed,
+    /// so unchanged files keep their blocks and block-level updates stay
+    /// small
+    #[arg(long, conflicts_with = "trim")]
+    pub base_image: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub format: OutputParams,
+
     #[command(flatten)]
     pub fs: ImageConfigParams,
 }
 
+/// Output file format and flash placement for `pack`.
+#[derive(Args, Debug, Clone, Default)]
+pub struct OutputParams {
+    /// Output file format: bin, uf2, hex (Intel HEX) or srec (S-records)
+    #[arg(long, value_parser = parse_output_format, default_value = "bin")]
+    pub format: OutputFormat,
+
+    /// Flash address the image is written to. Needed for every format
+    /// except bin; defaults to the image's flash address or partition from
+    /// the config.
+    #[arg(long, value_parser = parse_u32)]
+    pub base_address: Option<u32>,
+
+    /// Leave out records that are entirely erased (0xFF) from hex and srec
+    /// output
+    #[arg(long)]
+    pub skip_erased: bool,
+
+    /// UF2 family: a name such as rp2350-arm-s, rp2040, data or samd51,
+    /// or a numeric family ID
+    #[arg(long, value_parser = parse_family_id)]
+    pub family_id: Option<u32>,
+}
+
 /// Arguments for the `unpack` subcommand.
 #[derive(Args)]
 pub struct UnpackCmd {
@@ -288,6 +433,11 @@ pub struct InfoCmd {
     #[arg(short, long)]
     pub image: PathBuf,
 
+    /// Only print the number of bytes that need to be flashed, for use in
+    /// scripts
+    #[arg(long)]
+    pub flashable_length: bool,
+
     #[command(flatten)]
     pub fs: ImageConfigParams,
 }
@@ -299,6 +449,241 @@ pub struct FlashCmd {
     /// or falls back to the `path` field in `[flash.firmware]`.
     #[arg(value_name = "BINARY")]
     pub binary_path: Option<PathBuf>,
+
+    /// Serial port of the device. Substituted for `{port}` in the flash
+    /// commands, and keys the flash cache if there's no identify_command
+    #[arg(long)]
+    pub port: Option<String>,
+
+    /// Serial number of the device. Substituted for `{serial}` in the flash
+    /// commands, and keys the flash cache if there's no identify_command
+    #[arg(long)]
+    pub serial: Option<String>,
+
+    /// Flash the filesystem images even if they haven't changed
+    #[arg(long, conflicts_with = "skip_fs")]
+    pub force: bool,
+
+    /// Only flash the firmware, leaving the filesystem images alone
+    #[arg(long)]
+    pub skip_fs: bool,
+
+    /// Print the commands instead of running them
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Arguments for the `merge` subcommand.
+#[derive(Args)]
+pub struct MergeCmd {
+    /// Output file path (defaults to `output` in `[flash.merge]`)
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Flash size to check the segments against (overrides `flash_size`)
+    #[arg(long, value_parser = parse_u32)]
+    pub flash_size: Option<u32>,
+
+    /// Pad the merged image with 0xFF to the whole flash size
+    #[arg(long, requires = "flash_size")]
+    pub fill: bool,
+}
+
+/// Arguments for the `delta` subcommand.
+#[derive(Args)]
+pub struct DeltaCmd {
+    /// The image currently on the device
+    pub old: PathBuf,
+
+    /// The image to update it to
+    pub new: PathBuf,
+
+    /// Patch file to write
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Write a list of file operations instead of the changed blocks
+    #[arg(long)]
+    pub files: bool,
+
+    #[command(flatten)]
+    pub fs: ImageConfigParams,
+}
+
+/// Arguments for the `apply` subcommand.
+#[derive(Args)]
+pub struct ApplyCmd {
+    /// Image to apply the patch to
+    pub image: PathBuf,
+
+    /// Patch file written by `delta`
+    pub patch: PathBuf,
+
+    /// Patched image file to write
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    #[command(flatten)]
+    pub fs: ImageConfigParams,
+}
+
+/// Arguments for the `pull` subcommand.
+#[derive(Args)]
+pub struct PullCmd {
+    /// Image file to write. Defaults to `<name>.bin`
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Also unpack the pulled image into this directory
+    #[arg(long, value_name = "DIR")]
+    pub unpack: Option<PathBuf>,
+
+    /// List the files that differ from the image's source directory
+    #[arg(long)]
+    pub diff: bool,
+
+    /// Serial port of the device, substituted for `{port}`
+    #[arg(long)]
+    pub port: Option<String>,
+
+    /// Serial number of the device, substituted for `{serial}`
+    #[arg(long)]
+    pub serial: Option<String>,
+}
+
+/// Arguments for the `watch` subcommand.
+#[derive(Args)]
+pub struct WatchCmd {
+    /// Image file to keep up to date
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Also flash the image with the filesystem flash command after each change
+    #[arg(long)]
+    pub flash: bool,
+
+    /// Serial port of the device, substituted for `{port}`
+    #[arg(long, requires = "flash")]
+    pub port: Option<String>,
+
+    /// Serial number of the device, substituted for `{serial}`
+    #[arg(long, requires = "flash")]
+    pub serial: Option<String>,
+
+    /// How long the directory must be quiet before repacking, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 300)]
+    pub debounce: u64,
+}
+
+/// Arguments for the `serve` subcommand.
+#[derive(Args)]
+pub struct ServeCmd {
+    /// LittleFS2 image file to serve
+    pub image: PathBuf,
+
+    /// Port to listen on
+    #[arg(long, default_value_t = 8080)]
+    pub port: u16,
+
+    /// Address to listen on (0.0.0.0 to allow other machines)
+    #[arg(long, default_value = "127.0.0.1")]
+    pub bind: String,
+
+    #[command(flatten)]
+    pub fs: ImageConfigParams,
+}
+
+/// Arguments for the `mount` subcommand.
+#[derive(Args)]
+pub struct MountCmd {
+    /// LittleFS2 image file to mount. Changes are written back to it on unmount
+    pub image: PathBuf,
+
+    /// Directory to mount the image on
+    pub mountpoint: PathBuf,
+
+    #[command(flatten)]
+    pub fs: ImageConfigParams,
+}
+
+/// Arguments for the `partitions` subcommand.
+#[derive(Args)]
+pub struct PartitionsCmd {
+    #[command(subcommand)]
+    pub action: PartitionsAction,
+}
+
+/// Actions of the `partitions` subcommand.
+#[derive(Subcommand)]
+pub enum PartitionsAction {
+    /// Convert an ESP-IDF CSV to the binary format, or a picotool JSON
+    /// table to an RP2350 picobin block
+    ToBin {
+        /// ESP-IDF partition table CSV or picotool JSON
+        input: PathBuf,
+
+        /// Output binary file path
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[command(flatten)]
+        table: PartitionTableParams,
+    },
+    /// Convert a binary partition table, or a flash dump containing one, to
+    /// CSV (ESP-IDF) or JSON (RP2350)
+    FromBin {
+        /// Binary partition table or flash dump
+        input: PathBuf,
+
+        /// Output file path (printed to stdout if not given)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        #[command(flatten)]
+        table: PartitionTableParams,
+    },
+    /// Print and validate a partition table from a CSV, JSON, binary table, or flash dump
+    Show {
+        /// Partition table CSV, picotool JSON, binary table, or flash dump
+        input: PathBuf,
+
+        #[command(flatten)]
+        table: PartitionTableParams,
+    },
+}
+
+/// Partition table location and flash size, shared by the `partitions` actions.
+#[derive(Args, Debug, Clone)]
+pub struct PartitionTableParams {
+    /// Flash offset of the partition table. Blank CSV offsets are placed
+    /// after it, and it locates the table within a flash dump.
+    #[arg(long, value_parser = parse_u32, default_value_t = DEFAULT_TABLE_OFFSET)]
+    pub table_offset: u32,
+
+    /// Flash size to check the partitions against (e.g. 4M or 0x400000)
+    #[arg(long, value_parser = parse_u32)]
+    pub flash_size: Option<u32>,
+}
+
+/// Parse an output format name.
+fn parse_output_format(s: &str) -> Result<OutputFormat, String> {
+    OutputFormat::parse(s).ok_or_else(|| {
+        let names: Vec<&str> = OutputFormat::ALL.iter().map(|f| f.name()).collect();
+        format!(
+            "unknown format '{s}' (expected one of: {})",
+            names.join(", ")
+        )
+    })
+}
+
+/// Parse a UF2 family name or ID.
+fn parse_family_id(s: &str) -> Result<u32, String> {
+    uf2::family_id(s).ok_or_else(|| format!("unknown UF2 family '{s}'"))
+}
+
+/// Parse a number given in decimal, hex (`0x…`), or with a `K`/`M` suffix.
+fn parse_u32(s: &str) -> Result<u32, String> {
+    parse_size_value(s).map_err(|e| format!("invalid value '{s}': {e}"))
 }
 
 // ---------------------------------------------------------------------------
@@ -309,11 +694,20 @@ fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Pack(args) => cmd_pack(&cli.config, args)?,
-        Commands::Unpack(args) => cmd_unpack(&cli.config, args)?,
-        Commands::List(args) => cmd_list(&cli.config, args)?,
-        Commands::Info(args) => cmd_info(&cli.config, args)?,
-        Commands::Flash(args) => cmd_flash(&cli.config, args)?,
+        Commands::Pack(args) => cmd_pack(&cli.source, args)?,
+        Commands::Unpack(args) => cmd_unpack(&cli.source, args)?,
+        Commands::List(args) => cmd_list(&cli.source, args)?,
+        Commands::Info(args) => cmd_info(&cli.source, args)?,
+        Commands::Flash(args) => cmd_flash(&cli.source, args)?,
+        Commands::Presets => cmd_presets(),
+        Commands::Partitions(args) => cmd_partitions(args)?,
+        Commands::Merge(args) => cmd_merge(&cli.source, args)?,
+        Commands::Delta(args) => cmd_delta(&cli.source, args)?,
+        Commands::Apply(args) => cmd_apply(&cli.source, args)?,
+        Commands::Pull(args) => cmd_pull(&cli.source, args)?,
+        Commands::Watch(args) => cmd_watch(&cli.source, args)?,
+        Commands::Serve(args) => cmd_serve(&cli.source, args)?,
+        Commands::Mount(args) => cmd_mount(&cli.source, args)?,
     }
 
     Ok(())
@@ -328,13 +722,17 @@ fn main() -> Result<()> {
 /// When a TOML config is provided, image geometry and directory settings
 /// are loaded from it with any CLI flags applied as overrides. Without
 /// a config, all parameters must be supplied on the command line.
-fn cmd_pack(config_path: &Option<PathBuf>, args: PackCmd) -> Result<()> {
+fn cmd_pack(source: &ConfigSource, args: PackCmd) -> Result<()> {
     // Resolve everything from TOML + CLI overrides
-    let (image_config, root, directory_config) = match config_path {
-        Some(path) => {
-            let config = Config::from_file(path)?;
-            let image_config = apply_cli_overrides(&config.image, &args.fs);
-            let mut dir_config = config.directory;
+    let mut output = args.format.clone();
+    let (image_config, root, directory_config) = match source.load()? {
+        Some(config) => {
+            let target = config.image(source.name.as_deref())?;
+            if output.base_address.is_none() && output.format.needs_address() {
+                output.base_address = target.flash_address()?;
+            }
+            let image_config = apply_cli_overrides(&target.image, &args.fs.with_preset_defaults()?);
+            let mut dir_config = target.directory.clone();
             // CLI --pack-directory overrides the TOML root
             if let Some(d) = args.pack_directory {
                 dir_config.resolved_root = d;
@@ -355,30 +753,113 @@ fn cmd_pack(config_path: &Option<PathBuf>, args: PackCmd) -> Result<()> {
     let block_count = image_config.block_count;
     let block_size = image_config.block_size;
 
-    let mut image = LfsImage::new(image_config)?;
-    image.format()?;
-
-    match directory_config {
-        Some(dir_config) => image.pack_from_config(dir_config)?,
-        None => image.pack_from_dir(&root)?,
+    let mut image = match &args.base_image {
+        Some(base) => {
+            let mut image = load_base_image(base, image_config)?;
+            let summary = match directory_config {
+                Some(dir_config) => image.update_from_config(dir_config)?,
+                None => image.update_from_dir(&root)?,
+            };
+            println!(
+                "Updated '{}': {} added, {} modified, {} removed, {} unchanged",
+                base.display(),
+                summary.added.len(),
+                summary.modified.len(),
+                summary.removed.len(),
+                summary.unchanged,
+            );
+            image
+        }
+        None => {
+            let mut image = LfsImage::new(image_config)?;
+            image.format()?;
+            match directory_config {
+                Some(dir_config) => image.pack_from_config(dir_config)?,
+                None => image.pack_from_dir(&root)?,
+            };
+            image
+        }
     };
 
-    let data = image.into_data();
-    std::fs::write(&args.output, &data)
+    if args.trim {
+        image.compact()?;
+    }
+    let flashable = image.flashable_len();
+
+    let data = if args.trim {
+        image.trimmed_data().to_vec()
+    } else {
+        image.into_data()
+    };
+    let out = encode_output(data, &output)?;
+    std::fs::write(&args.output, &out)
         .with_context(|| format!("failed to write image to '{}'", args.output.display()))?;
 
     println!(
         "Packed '{}' -> '{}' ({} bytes, {} blocks x {} bytes)",
         root.display(),
         args.output.display(),
-        data.len(),
+        out.len(),
         block_count,
         block_size,
     );
+    println!(
+        "Flashable length: {flashable} bytes ({flashable:#x}, {} blocks)",
+        flashable / block_size
+    );
 
     Ok(())
 }
 
+/// Load the image that `pack --base-image` starts from. It must have been
+/// packed with the same geometry, and may have been trimmed.
+fn load_base_image(path: &Path, config: ImageConfig) -> Result<LfsImage> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("failed to read base image '{}'", path.display()))?;
+    let expected = config.block_count;
+    if data.is_empty() || !data.len().is_multiple_of(config.block_size) {
+        bail!(
+            "base image size ({}) is not a multiple of block_size ({})",
+            data.len(),
+            config.block_size
+        );
+    }
+    let file_config = ImageConfig {
+        block_count: data.len() / config.block_size,
+        ..config
+    };
+    let mut image = LfsImage::from_trimmed_data(file_config, data)?;
+    if image.config().block_count != expected {
+        bail!(
+            "base image '{}' has {} blocks, but the image being packed has {expected}",
+            path.display(),
+            image.config().block_count
+        );
+    }
+    if !image.is_mountable() {
+        bail!("base image '{}' is not a LittleFS image", path.display());
+    }
+    Ok(image)
+}
+
+/// Encode a raw image in the requested output format.
+fn encode_output(data: Vec<u8>, params: &OutputParams) -> Result<Vec<u8>> {
+    if params.format.needs_address() && params.base_address.is_none() {
+        bail!(
+            "--format {} needs --base-address, or a flash address or partition in the config",
+            params.format.name()
+        );
+    }
+    let base = params.base_address.unwrap_or_default();
+    let out = match params.format {
+        OutputFormat::Bin => data,
+        OutputFormat::Uf2 => uf2::encode(&data, base, params.family_id)?,
+        OutputFormat::Hex => ihex::encode(&data, base, params.skip_erased)?.into_bytes(),
+        OutputFormat::Srec => srec::encode(&data, base, params.skip_erased)?.into_bytes(),
+    };
+    Ok(out)
+}
+
 // ---------------------------------------------------------------------------
 // Shared: load an existing image
 // ---------------------------------------------------------------------------
@@ -386,16 +867,17 @@ fn cmd_pack(config_path: &Option<PathBuf>, args: PackCmd) -> Result<()> {
 /// Read an existing image file from disk and wrap it in an [`LfsImage`].
 ///
 /// Image geometry is resolved from the TOML config and/or CLI flags,
-/// with the block count derived from the actual file size.
+/// with the block count derived from the actual file size. Images packed
+/// with `--trim` are padded back out to the size in their superblock.
 fn load_image(
-    config_path: &Option<PathBuf>,
+    source: &ConfigSource,
     cli: &ImageConfigParams,
     image_path: &Path,
 ) -> Result<LfsImage> {
     let data = std::fs::read(image_path)
         .with_context(|| format!("failed to read image '{}'", image_path.display()))?;
-    let config = image_config_for_reading(config_path, cli, &data)?;
-    Ok(LfsImage::from_data(config, data)?)
+    let config = image_config_for_reading(source, cli, &data)?;
+    Ok(LfsImage::from_trimmed_data(config, data)?)
 }
 
 // ---------------------------------------------------------------------------
@@ -403,8 +885,8 @@ fn load_image(
 // ---------------------------------------------------------------------------
 
 /// Extract all files and directories from a LittleFS2 image to a host directory.
-fn cmd_unpack(config_path: &Option<PathBuf>, args: UnpackCmd) -> Result<()> {
-    let mut image = load_image(config_path, &args.fs, &args.image)?;
+fn cmd_unpack(source: &ConfigSource, args: UnpackCmd) -> Result<()> {
+    let mut image = load_image(source, &args.fs, &args.image)?;
 
     std::fs::create_dir_all(&args.unpack_directory)
         .with_context(|| format!("failed to create '{}'", args.unpack_directory.display()))?;
@@ -450,8 +932,8 @@ fn unpack_directory(fs: &MountedFs<'_>, lfs_dir: &str, host_dir: &Path) -> Resul
 // ---------------------------------------------------------------------------
 
 /// Print a tree-style listing of every file and directory in a LittleFS2 image.
-fn cmd_list(config_path: &Option<PathBuf>, args: ListCmd) -> Result<()> {
-    let mut image = load_image(config_path, &args.fs, &args.image)?;
+fn cmd_list(source: &ConfigSource, args: ListCmd) -> Result<()> {
+    let mut image = load_image(source, &args.fs, &args.image)?;
 
     image.mount_and_then(|fs| {
         println!("/");
@@ -493,11 +975,17 @@ fn list_directory(fs: &MountedFs<'_>, lfs_dir: &str, prefix: &str) -> Result<(),
 // ---------------------------------------------------------------------------
 
 /// Print block usage statistics for a LittleFS2 image.
-fn cmd_info(config_path: &Option<PathBuf>, args: InfoCmd) -> Result<()> {
-    let mut image = load_image(config_path, &args.fs, &args.image)?;
+fn cmd_info(source: &ConfigSource, args: InfoCmd) -> Result<()> {
+    let mut image = load_image(source, &args.fs, &args.image)?;
 
     let bc = image.config().block_count;
     let bs = image.config().block_size;
+    let flashable = image.flashable_len();
+
+    if args.flashable_length {
+        println!("{flashable}");
+        return Ok(());
+    }
 
     image.mount_and_then(|fs| {
         let used = fs.used_blocks()?;
@@ -508,6 +996,11 @@ fn cmd_info(config_path: &Option<PathBuf>, args: InfoCmd) -> Result<()> {
         println!("Block count:  {}", bc);
         println!("Blocks used:  {} ({} bytes)", used, used * bs);
         println!("Blocks free:  {} ({} bytes)", free, free * bs);
+        println!(
+            "Flashable:    {} bytes ({} blocks)",
+            flashable,
+            flashable / bs
+        );
         Ok(())
     })?;
 
@@ -515,200 +1008,1922 @@ fn cmd_info(config_path: &Option<PathBuf>, args: InfoCmd) -> Result<()> {
 }
 
 // ---------------------------------------------------------------------------
-// flash
+// presets
 // ---------------------------------------------------------------------------
 
-/// Return the path where the SHA-256 hash of a flashed file is cached.
-///
-/// Hashes are stored under `target/.flash-cache/` so they are cleaned
-/// by `cargo clean` and already covered by `.gitignore`.
-fn cache_path(file: &Path) -> PathBuf {
-    Path::new("target/.flash-cache").join(format!(
-        "{}.sha256",
-        file.file_name().unwrap().to_string_lossy()
-    ))
-}
-
-/// Returns `Some(hash)` if the file needs flashing, `None` if unchanged.
-fn needs_flash(file: &Path) -> Result<Option<String>> {
-    let bytes =
-        std::fs::read(file).with_context(|| format!("failed to read {}", file.display()))?;
-    let hash = format!("{:x}", Sha256::digest(&bytes));
-
-    let cache = cache_path(file);
-    if cache.exists() {
-        let prev = std::fs::read_to_string(&cache).unwrap_or_default();
-        if prev.trim() == hash {
-            return Ok(None);
-        }
+/// Print the table of built-in flash geometry presets.
+fn cmd_presets() {
+    println!(
+        "{:<16} {:>10} {:>10} {:>10} {:>10}  Description",
+        "Name", "Block", "Read", "Write", "Flash"
+    );
+    for p in PRESETS {
+        println!(
+            "{:<16} {:>10} {:>10} {:>10} {:>10}  {}",
+            p.name,
+            p.block_size,
+            p.read_size,
+            p.write_size,
+            format!("{} KiB", p.flash_size / 1024),
+            p.description
+        );
     }
-    Ok(Some(hash))
 }
 
-/// Record that `file` was successfully flashed by writing its hash to the cache.
-///
-/// Called *after* a successful flash so that an interrupted flash will
-/// be retried on the next run.
-fn mark_flashed(file: &Path, hash: &str) -> Result<()> {
-    let cache = cache_path(file);
-    std::fs::create_dir_all(cache.parent().unwrap())?;
-    std::fs::write(&cache, hash)?;
+// ---------------------------------------------------------------------------
+// partitions
+// ---------------------------------------------------------------------------
+
+/// Convert a partition table between its text and binary forms, or print it.
+fn cmd_partitions(args: PartitionsCmd) -> Result<()> {
+    match args.action {
+        PartitionsAction::ToBin {
+            input,
+            output,
+            table,
+        } => {
+            let partitions = read_partition_table(&input, &table)?;
+            partitions
+                .validate(table.flash_size)
+                .with_context(|| format!("invalid partition table '{}'", input.display()))?;
+            let (count, bin) = match &partitions {
+                AnyPartitionTable::Esp(t) => (t.partitions.len(), t.to_bin()?),
+                AnyPartitionTable::Rp2350(t) => (t.partitions.len(), t.to_block()?),
+            };
+            std::fs::write(&output, &bin)
+                .with_context(|| format!("failed to write '{}'", output.display()))?;
+            println!(
+                "Wrote {} partitions -> '{}' ({} bytes)",
+                count,
+                output.display(),
+                bin.len()
+            );
+        }
+        PartitionsAction::FromBin {
+            input,
+            output,
+            table,
+        } => {
+            let text = match read_partition_table(&input, &table)? {
+                AnyPartitionTable::Esp(t) => t.to_csv(),
+                AnyPartitionTable::Rp2350(t) => t.to_json() + "\n",
+            };
+            match output {
+                Some(output) => std::fs::write(&output, text)
+                    .with_context(|| format!("failed to write '{}'", output.display()))?,
+                None => print!("{text}"),
+            }
+        }
+        PartitionsAction::Show { input, table } => {
+            let partitions = read_partition_table(&input, &table)?;
+            match &partitions {
+                AnyPartitionTable::Esp(t) => print_partition_table(t),
+                AnyPartitionTable::Rp2350(t) => print_rp2350_partition_table(t),
+            }
+            partitions
+                .validate(table.flash_size)
+                .with_context(|| format!("invalid partition table '{}'", input.display()))?;
+        }
+    }
     Ok(())
 }
 
-/// Flash firmware and filesystem image to a device.
-///
-/// Intended to be invoked as a Cargo runner (`runner = "littlefs flash"`
-/// in `.cargo/config.toml`) so that `cargo run` triggers a full
-/// flash cycle. The sequence is:
-///
-/// 1. Flash the LittleFS filesystem image (skipped if unchanged).
-/// 2. Flash the firmware binary (always, to preserve normal `cargo run`
-///    behaviour — the firmware command may also start a monitor session).
-///
-/// The filesystem is flashed first because the firmware flash typically
-/// resets the chip and starts execution; the FS must be in place before
-/// the application boots.
-fn cmd_flash(config_path: &Option<PathBuf>, args: FlashCmd) -> Result<()> {
-    // Load the config
-    let config_path = config_path
-        .as_ref()
-        .context("no project config file path handed in!")?;
+/// Read a partition table CSV, JSON, binary table, or flash dump.
+fn read_partition_table(path: &Path, params: &PartitionTableParams) -> Result<AnyPartitionTable> {
+    let data =
+        std::fs::read(path).with_context(|| format!("failed to read '{}'", path.display()))?;
+    AnyPartitionTable::from_bytes(&data, params.table_offset)
+        .with_context(|| format!("failed to parse partition table '{}'", path.display()))
+}
 
-    let config = Config::from_file(config_path).context("failed to load config file")?;
-    let flash_config = config
-        .flash
-        .context("project config file must have [flash] section defined!")?;
+/// Print one line per partition.
+fn print_partition_table(table: &PartitionTable) {
+    println!(
+        "{:<16} {:<15} {:<10} {:>10} {:>10} {:>10}  Flags",
+        "Name", "Type", "SubType", "Offset", "Size", "End"
+    );
+    for p in &table.partitions {
+        println!(
+            "{:<16} {:<15} {:<10} {:>10} {:>10} {:>10}  {}",
+            p.name,
+            p.part_type.to_string(),
+            p.subtype_name(),
+            format!("{:#x}", p.offset),
+            format!("{:#x}", p.size),
+            format!("{:#x}", p.end()),
+            p.flags
+        );
+    }
+}
 
-    // Resolve the firmware binary path: CLI arg (from `cargo run`) takes
-    // priority, then the hardcoded path in [flash.firmware].
-    let binary_path = args
-        .binary_path
-        .or(flash_config.firmware.path)
-        .context("no firmware path (pass as argument or set path in [flash.firmware])")?;
+/// Print one line per RP2350 partition, with its permissions as
+/// secure/non-secure/bootloader access.
+fn print_rp2350_partition_table(table: &Rp2350PartitionTable) {
+    println!(
+        "{:<16} {:>10} {:>10} {:>10} {:<11}  Families",
+        "Name", "Start", "Size", "End", "Permissions"
+    );
+    for (i, p) in table.partitions.iter().enumerate() {
+        let access = |a: littlefs2_pack::partition_table::rp2350::Access| {
+            format!(
+                "{}{}",
+                if a.read { 'r' } else { '-' },
+                if a.write { 'w' } else { '-' }
+            )
+        };
+        let families: Vec<String> = p.families.iter().map(|f| f.name()).collect();
+        println!(
+            "{:<16} {:>10} {:>10} {:>10} {:<11}  {}",
+            p.name.clone().unwrap_or_else(|| format!("#{i}")),
+            format!("{:#x}", p.start),
+            format!("{:#x}", p.size),
+            format!("{:#x}", p.end()),
+            format!(
+                "{}/{}/{}",
+                access(p.permissions.secure),
+                access(p.permissions.nonsecure),
+                access(p.permissions.bootloader)
+            ),
+            families.join(", ")
+        );
+    }
+}
 
-    // Resolve the filesystem image path: explicit config path takes priority,
-    // otherwise derive it from the binary's parent directory (where build.rs
-    // copies it). Only accept the derived path if the file actually exists.
-    let image_path = flash_config
-        .filesystem
-        .path
-        .or_else(|| {
-            binary_path
-                .parent()
-                .map(|dir| dir.join(format!("{}.bin", &config.image.name)))
-                .filter(|p| p.exists())
-        })
-        .context(
-            "no filesystem image found (set path in [flash.filesystem] \
-         or run via `cargo run` so the image can be located automatically)",
-        )?;
-
-    // Flash the filesystem image, but only if it has changed since the last
-    // flash. This avoids unnecessary wear on flash memory for assets that
-    // are typically more static than the firmware itself.
-    if let Some(hash) = needs_flash(&image_path)? {
-        println!("Flashing filesystem image...");
-        run_command(
-            &flash_config.filesystem.command,
-            &[
-                (
-                    "path",
-                    image_path.to_str().context("invalid image file path")?,
-                ),
-                ("address", flash_config.filesystem.address.as_str()),
-            ],
-        )
-        .context("failed to write filesystem binary")?;
-        mark_flashed(&image_path, &hash)?;
-    } else {
-        println!("Filesystem image unchanged, skipping.");
+// ---------------------------------------------------------------------------
+// merge
+// ---------------------------------------------------------------------------
+
+/// Combine every segment of the config into one flashable image.
+fn cmd_merge(source: &ConfigSource, args: MergeCmd) -> Result<()> {
+    let config = source
+        .load()?
+        .context("merge needs a config file (--config)")?;
+
+    let mut merged = MergedImage::from_config(&config)?;
+    if let Some(flash_size) = args.flash_size {
+        merged = merged.with_flash_size(flash_size);
+    }
+    if args.fill {
+        merged = merged.with_fill(true);
     }
+    let output = args
+        .output
+        .or_else(|| config.merge.as_ref().and_then(|m| m.output.clone()))
+        .context("no output path (pass --output or set output in [flash.merge])")?;
 
-    // Always flash the firmware to match normal `cargo run` behaviour.
-    // This command may also reset the chip and start a monitor session,
-    // so it must be the last step.
-    run_command(
-        &flash_config.firmware.command,
-        &[(
-            "path",
-            binary_path.to_str().context("invalid binary file path")?,
-        )],
-    )
-    .context("failed to flash binary")?;
+    let data = merged.build()?;
+    std::fs::write(&output, &data)
+        .with_context(|| format!("failed to write '{}'", output.display()))?;
 
+    println!(
+        "{:<20} {:>10} {:>10} {:>10}",
+        "Segment", "Address", "Size", "End"
+    );
+    let mut segments: Vec<&Segment> = merged.segments().iter().collect();
+    segments.sort_by_key(|s| s.address);
+    for s in segments {
+        println!(
+            "{:<20} {:>10} {:>10} {:>10}",
+            s.name,
+            format!("{:#x}", s.address),
+            format!("{:#x}", s.data.len()),
+            format!("{:#x}", s.end())
+        );
+    }
+    println!("Wrote '{}' ({} bytes)", output.display(), data.len());
     Ok(())
 }
 
-/// Expand a command template and execute it as a subprocess.
-///
-/// Placeholders like `{path}` and `{address}` in `template` are replaced
-/// with the corresponding values from `vars`. The expanded string is then
-/// split on whitespace and executed. Returns an error if the command is
-/// not found or exits with a non-zero status.
-fn run_command(template: &str, vars: &[(&str, &str)]) -> Result<()> {
-    let mut expanded = template.to_string();
-    for (key, value) in vars {
-        expanded = expanded.replace(&format!("{{{key}}}"), value);
-    }
+// ---------------------------------------------------------------------------
+// delta / apply
+// ---------------------------------------------------------------------------
 
-    let parts: Vec<&str> = expanded.split_whitespace().collect();
-    let (program, args) = parts.split_first().context("empty command")?;
+/// Write a block or file patch from `old` to `new`, checking that it
+/// reproduces `new` before writing it.
+fn cmd_delta(source: &ConfigSource, args: DeltaCmd) -> Result<()> {
+    let mut old = load_image(source, &args.fs, &args.old)?;
+    let mut new = load_image(source, &args.fs, &args.new)?;
 
-    let status = std::process::Command::new(program)
-        .args(args)
-        .status()
-        .with_context(|| format!("failed to run: {program}"))?;
+    let patch = match args.files {
+        true => Patch::Files(FilePatch::diff(&mut old, &mut new)?),
+        false => Patch::Blocks(BlockPatch::diff(&old, &new)?),
+    };
 
-    anyhow::ensure!(status.success(), "{program} exited with {status}");
+    patch.apply(&mut old)?;
+    let reproduced = match &patch {
+        Patch::Blocks(_) => old.data() == new.data(),
+        Patch::Files(_) => sorted_contents(&mut old)? == sorted_contents(&mut new)?,
+    };
+    anyhow::ensure!(reproduced, "the patch does not reproduce the new image");
+
+    match &patch {
+        Patch::Blocks(blocks) => println!(
+            "{} changed range(s), {} of {} bytes",
+            blocks.ranges.len(),
+            blocks.changed_bytes(),
+            blocks.image_size
+        ),
+        Patch::Files(files) => {
+            for op in &files.ops {
+                match op {
+                    FileOp::Mkdir { path } => println!("  mkdir   {path}"),
+                    FileOp::Write { path, data } => {
+                        println!("  write   {path} ({} bytes)", data.len())
+                    }
+                    FileOp::Remove { path } => println!("  remove  {path}"),
+                    FileOp::Rename { from, to } => println!("  rename  {from} -> {to}"),
+                }
+            }
+        }
+    }
+
+    let bytes = patch.to_bytes()?;
+    std::fs::write(&args.output, &bytes)
+        .with_context(|| format!("failed to write '{}'", args.output.display()))?;
+    println!("Wrote '{}' ({} bytes)", args.output.display(), bytes.len());
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use littlefs2_pack::config::{DEFAULT_IMAGE_NAME, ImageConfig};
-    use std::fs;
+/// Apply a patch to an image and write the result.
+fn cmd_apply(source: &ConfigSource, args: ApplyCmd) -> Result<()> {
+    let mut image = load_image(source, &args.fs, &args.image)?;
+    let bytes = std::fs::read(&args.patch)
+        .with_context(|| format!("failed to read patch '{}'", args.patch.display()))?;
+    Patch::from_bytes(&bytes)?.apply(&mut image)?;
+
+    anyhow::ensure!(image.is_mountable(), "the patched image is not mountable");
+    std::fs::write(&args.output, image.data())
+        .with_context(|| format!("failed to write '{}'", args.output.display()))?;
+    println!(
+        "Patched '{}' -> '{}'",
+        args.image.display(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// The entries of an image with their contents, sorted by path.
+fn sorted_contents(image: &mut LfsImage) -> Result<Vec<ContentEntry>> {
+    let mut contents = image.contents()?;
+    contents.sort();
+    Ok(contents)
+}
+
+// ---------------------------------------------------------------------------
+// pull
+// ---------------------------------------------------------------------------
+
+/// Read an image back from the device with the `[flash.readback]` command.
+///
+/// The whole image is read from its flash address, so whatever the
+/// firmware wrote at runtime (logs, settings) comes back with it. The
+/// result can be unpacked, and compared with the image's source directory.
+fn cmd_pull(source: &ConfigSource, args: PullCmd) -> Result<()> {
+    let config = source
+        .load()?
+        .context("pull needs a config file with a [flash.readback] section")?;
+    let readback = config
+        .readback
+        .as_ref()
+        .context("config has no [flash.readback] section")?;
+    let target = config.image(source.name.as_deref())?;
+    let name = &target.image.name;
+    let address = target.flash_address()?.with_context(|| {
+        format!("image '{name}' has no flash address (set one in its flash section or partition)")
+    })?;
+    let length = target.image.block_size * target.image.block_count;
+
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(format!("{name}.bin")));
+    let address_str = format!("{address:#x}");
+    let length_str = format!("{length:#x}");
+    let device = device_vars(&config, args.port.as_deref(), args.serial.as_deref());
+    let image_vars = image_vars(target);
+    let mut vars = vec![
+        ("path", output.to_str().context("invalid output file path")?),
+        ("address", address_str.as_str()),
+        ("length", length_str.as_str()),
+    ];
+    vars.extend(borrow_vars(&device));
+    vars.extend(borrow_vars(&image_vars));
+    run_command(&readback.command, &readback.options, &vars, false)
+        .context("failed to read the image from the device")?;
+
+    let mut data =
+        std::fs::read(&output).with_context(|| format!("failed to read {}", output.display()))?;
+    // Some tools read whole sectors, so drop anything past the image. A
+    // short read is padded as erased flash, which still mounts if only
+    // trailing free blocks were missed.
+    if data.len() < length {
+        eprintln!(
+            "warning: read {} of {length} bytes from the device, padding the rest with 0xFF",
+            data.len()
+        );
+    }
+    data.resize(length, 0xFF);
+    let mut image = LfsImage::from_trimmed_data(target.image.clone(), data)?;
+    if !image.is_mountable() {
+        bail!(
+            "'{}' read from the device isn't a LittleFS image",
+            output.display()
+        );
+    }
+    std::fs::write(&output, image.data())
+        .with_context(|| format!("failed to write {}", output.display()))?;
+    println!(
+        "Pulled '{name}' from {address:#x} ({length} bytes) -> '{}'",
+        output.display()
+    );
+
+    if let Some(dir) = &args.unpack {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create '{}'", dir.display()))?;
+        image.mount_and_then(|fs| unpack_directory(fs, "/", dir))?;
+        println!("Unpacked -> '{}'", dir.display());
+    }
+
+    if args.diff {
+        let root = &target.directory.resolved_root;
+        let mut local = LfsImage::new(target.image.clone())?;
+        local.format()?;
+        local.pack_from_config(target.directory.clone())?;
+        let lines = diff_contents(&sorted_contents(&mut local)?, &sorted_contents(&mut image)?);
+        if lines.is_empty() {
+            println!("Device matches '{}'", root.display());
+        } else {
+            println!("Differences from '{}':", root.display());
+            for line in lines {
+                println!("  {line}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// watch
+// ---------------------------------------------------------------------------
+
+/// Repack an image whenever the files in its `[directory]` root change.
+///
+/// The image is updated in place rather than packed from scratch, so
+/// unchanged files stay on their blocks and a delta flash only writes
+/// what was edited. Files the walker ignores are never packed, so changes
+/// to them don't produce a new image.
+#[cfg(feature = "watch")]
+fn cmd_watch(source: &ConfigSource, args: WatchCmd) -> Result<()> {
+    let config = source
+        .load()?
+        .context("watch needs a config file with a [directory] section")?;
+    let target = config.image(source.name.as_deref())?;
+    let name = &target.image.name;
+    let root = &target.directory.resolved_root;
+
+    // Start from the last image written, so the first pass only reports
+    // what changed while nothing was watching
+    let mut image = load_previous_image(&args.output, &target.image)?;
+
+    let device = device_vars(&config, args.port.as_deref(), args.serial.as_deref());
+    let image_vars = image_vars(target);
+    let mut vars = borrow_vars(&device);
+    vars.extend(borrow_vars(&image_vars));
+    let cache = match (args.flash, &target.flash) {
+        (false, _) => None,
+        (true, None) => bail!("image '{name}' has no flash section"),
+        (true, Some(_)) => Some(FlashCache::for_device(
+            &config,
+            args.port.as_deref(),
+            args.serial.as_deref(),
+            &borrow_vars(&device),
+        )?),
+    };
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher
+        .watch(root, RecursiveMode::Recursive)
+        .with_context(|| format!("failed to watch '{}'", root.display()))?;
+    println!("Watching '{}' (Ctrl-C to stop)", root.display());
+
+    let debounce = Duration::from_millis(args.debounce);
+    let mut first = true;
+    loop {
+        match repack(&mut image, &target.directory, &args.output) {
+            Ok(summary) if summary.is_empty() && !first => {}
+            Ok(summary) => {
+                println!(
+                    "Packed '{name}' -> '{}': {} added, {} modified, {} removed, {} unchanged",
+                    args.output.display(),
+                    summary.added.len(),
+                    summary.modified.len(),
+                    summary.removed.len(),
+                    summary.unchanged,
+                );
+                for line in summary_lines(&summary) {
+                    println!("  {line}");
+                }
+                if let Some(cache) = &cache {
+                    // A failed flash (e.g. the board was unplugged) is
+                    // retried on the next change
+                    if let Err(e) = watch_flash(target, &args.output, cache, &vars) {
+                        eprintln!("error: {e:#}");
+                    }
+                }
+            }
+            Err(e) => eprintln!("error: {e:#}"),
+        }
+        first = false;
+        wait_for_change(&rx, &args.output, debounce)?;
+    }
+}
+
+#[cfg(not(feature = "watch"))]
+fn cmd_watch(_source: &ConfigSource, _args: WatchCmd) -> Result<()> {
+    bail!("watching directories needs littlefs to be built with the `watch` feature")
+}
+
+/// Load the image last written to `output`, or a freshly formatted one if
+/// there isn't one yet.
+#[cfg(feature = "watch")]
+fn load_previous_image(output: &Path, config: &ImageConfig) -> Result<LfsImage> {
+    match load_base_image(output, config.clone()) {
+        Ok(image) => Ok(image),
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) =>
+        {
+            let mut image = LfsImage::new(config.clone())?;
+            image.format()?;
+            Ok(image)
+        }
+        Err(e) => Err(e.context(format!(
+            "failed to load the previous image '{}' (delete it to start over)",
+            output.display()
+        ))),
+    }
+}
+
+/// Update `image` to match the directory and write it to `output`.
+///
+/// The output is only rewritten if something changed, or if it doesn't
+/// exist yet. If the update fails, `image` is left as it was.
+#[cfg(feature = "watch")]
+fn repack(
+    image: &mut LfsImage,
+    dir_config: &DirectoryConfig,
+    output: &Path,
+) -> Result<UpdateSummary> {
+    let previous = image.data().to_vec();
+    let summary = match image.update_from_config(dir_config.clone()) {
+        Ok(summary) => summary,
+        Err(e) => {
+            image.data_mut().copy_from_slice(&previous);
+            return Err(e.into());
+        }
+    };
+    if !summary.is_empty() || !output.exists() {
+        std::fs::write(output, image.data())
+            .with_context(|| format!("failed to write image to '{}'", output.display()))?;
+    }
+    Ok(summary)
+}
+
+/// One line per changed file: `+` added, `~` modified and `-` removed.
+#[cfg(feature = "watch")]
+fn summary_lines(summary: &UpdateSummary) -> Vec<String> {
+    let added = summary.added.iter().map(|p| format!("+ {p}"));
+    let modified = summary.modified.iter().map(|p| format!("~ {p}"));
+    let removed = summary.removed.iter().map(|p| format!("- {p}"));
+    added.chain(modified).chain(removed).collect()
+}
+
+/// Flash the watched image if it differs from what the device last got.
+#[cfg(feature = "watch")]
+fn watch_flash(
+    target: &ImageTarget,
+    image_path: &Path,
+    cache: &FlashCache,
+    vars: &[(&str, &str)],
+) -> Result<()> {
+    let Some(hash) = cache.needs_flash(image_path)? else {
+        return Ok(());
+    };
+    println!("Flashing filesystem image '{}'...", target.image.name);
+    flash_filesystem(target, image_path, &hash, false, cache, vars, false)
+}
+
+/// Block until something under the watched directory changes, then until
+/// no more changes arrive for `debounce`, so a save that touches several
+/// files is packed once.
+#[cfg(feature = "watch")]
+fn wait_for_change(
+    rx: &Receiver<notify::Result<notify::Event>>,
+    output: &Path,
+    debounce: Duration,
+) -> Result<()> {
+    // Packing reads every file, and the output may be inside the
+    // directory, so neither counts as a change
+    let output = output.canonicalize().unwrap_or_else(|_| output.to_owned());
+    let is_change = |event: notify::Result<notify::Event>| match event {
+        Ok(event) => {
+            let outside = |p: &PathBuf| p.canonicalize().unwrap_or_else(|_| p.clone()) != output;
+            !event.kind.is_access() && event.paths.iter().any(outside)
+        }
+        Err(e) => {
+            eprintln!("warning: {e}");
+            false
+        }
+    };
+    loop {
+        let event = rx.recv().context("the directory watcher stopped")?;
+        if is_change(event) {
+            break;
+        }
+    }
+    while rx.recv_timeout(debounce).is_ok() {}
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// serve
+// ---------------------------------------------------------------------------
+
+/// Serve the files in an image over HTTP, as the `esp32-littlefs-server`
+/// example does on the device, to check what it will serve before flashing.
+#[cfg(feature = "serve")]
+fn cmd_serve(source: &ConfigSource, args: ServeCmd) -> Result<()> {
+    let mut image = load_image(source, &args.fs, &args.image)?;
+    let address = format!("{}:{}", args.bind, args.port);
+    let server = tiny_http::Server::http(&address)
+        .map_err(|e| anyhow::anyhow!("failed to listen on {address}: {e}"))?;
+    println!(
+        "Serving '{}' on http://{address}/ (Ctrl-C to stop)",
+        args.image.display()
+    );
+
+    for request in server.incoming_requests() {
+        let response = serve_request(&mut image, request.url());
+        println!(
+            "{} {} -> {}",
+            request.method(),
+            request.url(),
+            response.status
+        );
+        let header = tiny_http::Header::from_bytes("Content-Type", response.content_type)
+            .expect("content types are valid header values");
+        let reply = tiny_http::Response::from_data(response.body)
+            .with_status_code(response.status)
+            .with_header(header);
+        if let Err(e) = request.respond(reply) {
+            eprintln!("warning: failed to send response: {e}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "serve"))]
+fn cmd_serve(_source: &ConfigSource, _args: ServeCmd) -> Result<()> {
+    bail!("serving images needs littlefs to be built with the `serve` feature")
+}
+
+/// The answer to one request, from [`serve_request`].
+#[cfg(feature = "serve")]
+struct FileResponse {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+/// Answer a request for `url` from the image.
+///
+/// As on the device, `/` serves `/index.html`, a path containing `..` is
+/// forbidden, and anything that isn't a file in the image is not found.
+/// The path isn't percent-decoded, so a file name the device can't serve
+/// fails here too.
+#[cfg(feature = "serve")]
+fn serve_request(image: &mut LfsImage, url: &str) -> FileResponse {
+    let text = "text/plain; charset=utf-8";
+    // The query string isn't part of the path
+    let url_path = url.split(['?', '#']).next().unwrap_or_default();
+    let Some(lfs_path) = lfs_path_for_url(url_path) else {
+        return FileResponse {
+            status: 403,
+            content_type: text,
+            body: b"Forbidden".to_vec(),
+        };
+    };
+    match image.mount_and_then(|fs| fs.read_file(&lfs_path)) {
+        Ok(body) => FileResponse {
+            status: 200,
+            content_type: content_type(&lfs_path),
+            body,
+        },
+        Err(_) => FileResponse {
+            status: 404,
+            content_type: text,
+            body: b"Not Found".to_vec(),
+        },
+    }
+}
+
+/// The LittleFS path for a URL path, or `None` if it tries to leave the
+/// root. Only the root itself defaults to `index.html`.
+#[cfg(feature = "serve")]
+fn lfs_path_for_url(url_path: &str) -> Option<String> {
+    let trimmed = url_path.trim_start_matches('/');
+    if trimmed.contains("..") {
+        return None;
+    }
+    let file_path = match trimmed.is_empty() {
+        true => "index.html",
+        false => trimmed,
+    };
+    Some(format!("/{file_path}"))
+}
+
+/// Guess a Content-Type from the file extension, as the example does.
+#[cfg(feature = "serve")]
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit('.').next() {
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "application/javascript; charset=utf-8",
+        Some("json") => "application/json; charset=utf-8",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+// ---------------------------------------------------------------------------
+// mount
+// ---------------------------------------------------------------------------
+
+/// Mount an image as a directory until it is unmounted, then write any
+/// changes back to the image file.
+#[cfg(feature = "fuse")]
+fn cmd_mount(source: &ConfigSource, args: MountCmd) -> Result<()> {
+    let mut image = load_image(source, &args.fs, &args.image)?;
+    if !image.is_mountable() {
+        bail!("'{}' is not a LittleFS image", args.image.display());
+    }
+    let fs = fuse::LfsFuse::new(image, &args.image)
+        .with_context(|| format!("failed to read '{}'", args.image.display()))?;
+    let options = [
+        fuser::MountOption::FSName(args.image.display().to_string()),
+        fuser::MountOption::Subtype("littlefs".to_string()),
+        fuser::MountOption::DefaultPermissions,
+    ];
+    println!(
+        "Mounted '{}' on '{}'. Changes are saved as files are closed; unmount it with `fusermount -u {}`.",
+        args.image.display(),
+        args.mountpoint.display(),
+        args.mountpoint.display(),
+    );
+    fuser::mount2(fs, &args.mountpoint, &options)
+        .with_context(|| format!("failed to mount on '{}'", args.mountpoint.display()))?;
+    println!("Unmounted '{}'", args.mountpoint.display());
+    Ok(())
+}
+
+#[cfg(not(feature = "fuse"))]
+fn cmd_mount(_source: &ConfigSource, _args: MountCmd) -> Result<()> {
+    bail!("mounting images needs littlefs to be built with the `fuse` feature")
+}
+
+// ---------------------------------------------------------------------------
+// flash
+// ---------------------------------------------------------------------------
+
+/// Hashes and copies of the images last flashed to a device.
+///
+/// The cache lives under `target/.flash-cache/` so it is cleaned by
+/// `cargo clean` and already covered by `.gitignore`. Each device that
+/// can be told apart (see [`device_id`]) gets its own subdirectory, so
+/// swapping boards doesn't skip flashing an image the new board lacks.
+struct FlashCache {
+    dir: PathBuf,
+}
+
+/// Where the flash cache lives, relative to the working directory.
+const FLASH_CACHE_DIR: &str = "target/.flash-cache";
+
+impl FlashCache {
+    /// The cache for the connected device, identified with [`device_id`].
+    fn for_device(
+        config: &Config,
+        port: Option<&str>,
+        serial: Option<&str>,
+        vars: &[(&str, &str)],
+    ) -> Result<Self> {
+        let device = device_id(config, port, serial, vars)?;
+        Ok(Self::new(Path::new(FLASH_CACHE_DIR), device.as_deref()))
+    }
+
+    /// The cache for `device`, or the shared cache if it's unknown.
+    fn new(root: &Path, device: Option<&str>) -> Self {
+        let dir = match device {
+            Some(device) => root.join(device_dir_name(device)),
+            None => root.to_owned(),
+        };
+        Self { dir }
+    }
+
+    /// Return the path where the SHA-256 hash of a flashed file is cached.
+    fn hash_path(&self, file: &Path) -> PathBuf {
+        self.dir.join(format!(
+            "{}.sha256",
+            file.file_name().unwrap().to_string_lossy()
+        ))
+    }
+
+    /// Return the path where a copy of the last flashed image is kept, so
+    /// the next flash can be limited to the blocks that changed.
+    fn image_path(&self, file: &Path) -> PathBuf {
+        self.dir.join(file.file_name().unwrap())
+    }
+
+    /// Returns `Some(hash)` if the file needs flashing, `None` if unchanged.
+    fn needs_flash(&self, file: &Path) -> Result<Option<String>> {
+        let bytes =
+            std::fs::read(file).with_context(|| format!("failed to read {}", file.display()))?;
+        let hash = format!("{:x}", Sha256::digest(&bytes));
+
+        let cache = self.hash_path(file);
+        if cache.exists() {
+            let prev = std::fs::read_to_string(&cache).unwrap_or_default();
+            if prev.trim() == hash {
+                return Ok(None);
+            }
+        }
+        Ok(Some(hash))
+    }
+
+    /// Record that `file` was successfully flashed by writing its hash to
+    /// the cache.
+    ///
+    /// Called *after* a successful flash so that an interrupted flash will
+    /// be retried on the next run.
+    fn mark_flashed(&self, file: &Path, hash: &str) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.hash_path(file), hash)?;
+        Ok(())
+    }
+
+    /// Keep a copy of the flashed image for the next delta flash.
+    fn store_image(&self, file: &Path) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::copy(file, self.image_path(file))?;
+        Ok(())
+    }
+}
+
+/// A directory name for a device ID. Short single-line IDs such as a port
+/// or MAC address are kept readable; anything else is hashed.
+fn device_dir_name(device: &str) -> String {
+    if device.len() <= 64 && !device.contains(char::is_whitespace) {
+        device
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect()
+    } else {
+        format!("{:x}", Sha256::digest(device.as_bytes()))[..16].to_string()
+    }
+}
+
+/// Identify the connected device, to key the flash cache.
+///
+/// The output of the `[flash] identify_command` (e.g. the chip's MAC
+/// address) is preferred, then `--serial`, then `--port`. Returns `None`
+/// if none of them is available. The identify command runs with the
+/// `shell`, `env` and `cwd` of `[flash]`. It only reads from the device,
+/// so it runs with `--dry-run` too.
+fn device_id(
+    config: &Config,
+    port: Option<&str>,
+    serial: Option<&str>,
+    vars: &[(&str, &str)],
+) -> Result<Option<String>> {
+    if let Some(command) = &config.identify_command {
+        let output = capture_command(command, &config.identify_options, vars)
+            .context("failed to identify the device")?;
+        let id = output.trim();
+        anyhow::ensure!(!id.is_empty(), "identify_command printed nothing");
+        return Ok(Some(id.to_string()));
+    }
+    Ok(serial.or(port).map(str::to_string))
+}
+
+/// Flash only the blocks of `image_path` that differ from `previous`.
+///
+/// If the command template uses `{offset}` or `{length}`, it runs once per
+/// range with `{path}` and `{address}` pointing at the whole image, for
+/// tools that can write part of a file. Otherwise each range is written
+/// to its own file and `{path}` and `{address}` refer to that range.
+fn flash_changed_ranges(
+    fs_flash: &FilesystemFlash,
+    image_path: &Path,
+    block_size: usize,
+    previous: &[u8],
+    cache: &FlashCache,
+    vars: &[(&str, &str)],
+    dry_run: bool,
+) -> Result<()> {
+    let data = std::fs::read(image_path)
+        .with_context(|| format!("failed to read {}", image_path.display()))?;
+    let ranges = changed_ranges(previous, &data, block_size);
+    let changed: usize = ranges.iter().map(|r| r.len()).sum();
+    println!(
+        "{} changed range(s), {changed} of {} bytes",
+        ranges.len(),
+        data.len()
+    );
+
+    let command = fs_flash
+        .command
+        .as_deref()
+        .context("filesystem flash has no command")?;
+    let base = fs_flash.address_value()?;
+    let whole_image = command.contains("{offset}") || command.contains("{length}");
+    let image_str = image_path.to_str().context("invalid image file path")?;
+    let chunk_path = cache.image_path(image_path).with_extension("part");
+
+    for range in ranges {
+        let offset = format!("{:#x}", range.start);
+        let length = format!("{:#x}", range.len());
+        let (path, address) = if whole_image {
+            (image_str.to_string(), fs_flash.address.clone())
+        } else {
+            // A dry run leaves the cache as it was
+            if !dry_run {
+                std::fs::create_dir_all(chunk_path.parent().unwrap())?;
+                std::fs::write(&chunk_path, &data[range.clone()])?;
+            }
+            let address = u64::from(base) + range.start as u64;
+            (
+                chunk_path
+                    .to_str()
+                    .context("invalid cache file path")?
+                    .to_string(),
+                format!("{address:#x}"),
+            )
+        };
+        let mut range_vars = vec![
+            ("path", path.as_str()),
+            ("address", address.as_str()),
+            ("offset", offset.as_str()),
+            ("length", length.as_str()),
+        ];
+        range_vars.extend_from_slice(vars);
+        if dry_run {
+            println!("[dry run] range {offset}+{length}: {path} at {address}");
+        }
+        run_command(command, &fs_flash.options, &range_vars, dry_run)?;
+    }
+
+    if !dry_run && chunk_path.exists() {
+        std::fs::remove_file(&chunk_path)?;
+    }
+    Ok(())
+}
+
+/// Write a filesystem image to the device and record it in the flash cache.
+///
+/// The image is written with its flash command, or the built-in flasher if
+/// one is set. With delta flashing, only the blocks that changed since the
+/// copy of the last flashed image in `cache` are written, unless `full` is
+/// set. Once the image is written (and read back, with a `verify_command`)
+/// its `hash` is recorded so it isn't flashed again while unchanged.
+fn flash_filesystem(
+    target: &ImageTarget,
+    image_path: &Path,
+    hash: &str,
+    full: bool,
+    cache: &FlashCache,
+    vars: &[(&str, &str)],
+    dry_run: bool,
+) -> Result<()> {
+    let image_name = &target.image.name;
+    let fs_flash = target
+        .flash
+        .as_ref()
+        .with_context(|| format!("image '{image_name}' has no flash section"))?;
+
+    // The built-in flasher compares the sectors on the device itself
+    let previous = match fs_flash.delta && !full && fs_flash.flasher.is_none() {
+        true => std::fs::read(cache.image_path(image_path)).ok(),
+        false => None,
+    };
+    match (fs_flash.flasher, previous) {
+        (Some(Flasher::Esp), _) => {
+            let port = vars.iter().find(|(key, _)| *key == "port").map(|(_, v)| *v);
+            flash_esp(fs_flash, image_path, port, dry_run)
+        }
+        (None, Some(previous)) => flash_changed_ranges(
+            fs_flash,
+            image_path,
+            target.image.block_size,
+            &previous,
+            cache,
+            vars,
+            dry_run,
+        ),
+        (None, None) => {
+            let command = fs_flash
+                .command
+                .as_deref()
+                .context("filesystem flash has no command")?;
+            let length = format!("{:#x}", std::fs::metadata(image_path)?.len());
+            let mut full_vars = vec![
+                (
+                    "path",
+                    image_path.to_str().context("invalid image file path")?,
+                ),
+                ("address", fs_flash.address.as_str()),
+                ("offset", "0x0"),
+                ("length", length.as_str()),
+            ];
+            full_vars.extend_from_slice(vars);
+            run_command(command, &fs_flash.options, &full_vars, dry_run)
+        }
+    }
+    .with_context(|| format!("failed to write filesystem image '{image_name}'"))?;
+
+    if let Some(verify_command) = &fs_flash.verify_command {
+        println!("Verifying filesystem image '{image_name}'...");
+        let verified = verify_flash(target, verify_command, image_path, cache, vars, dry_run);
+        if verified.is_err() {
+            // What's on the device is unknown, so the next delta flash
+            // must write the whole image
+            let _ = std::fs::remove_file(cache.image_path(image_path));
+        }
+        verified?;
+    }
+    if dry_run {
+        return Ok(());
+    }
+    cache.mark_flashed(image_path, hash)?;
+    if fs_flash.delta {
+        cache.store_image(image_path)?;
+    }
+    Ok(())
+}
+
+/// Write an image with the built-in ESP flasher, which only erases and
+/// writes the sectors that differ and checks the MD5 afterwards.
+#[cfg(feature = "esp-flasher")]
+fn flash_esp(
+    fs_flash: &FilesystemFlash,
+    image_path: &Path,
+    port: Option<&str>,
+    dry_run: bool,
+) -> Result<()> {
+    let port = port.context("the built-in ESP flasher needs --port")?;
+    let data = std::fs::read(image_path)
+        .with_context(|| format!("failed to read {}", image_path.display()))?;
+    let address = fs_flash.address_value()?;
+    if dry_run {
+        println!(
+            "[dry run] write {} bytes to {address:#x} through {port} with the built-in ESP flasher",
+            data.len()
+        );
+        return Ok(());
+    }
+
+    let flash_size = fs_flash
+        .flash_size
+        .unwrap_or(esp_flasher::DEFAULT_FLASH_SIZE);
+    let mut flasher = esp_flasher::EspFlasher::open(port, fs_flash.baud, flash_size)
+        .with_context(|| format!("failed to connect to the bootloader on {port}"))?;
+    let summary = flasher.write(address, &data)?;
+    println!(
+        "Wrote {} of {} sectors, MD5 verified",
+        summary.written, summary.sectors
+    );
+    flasher.reset()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "esp-flasher"))]
+fn flash_esp(_: &FilesystemFlash, _: &Path, _: Option<&str>, _: bool) -> Result<()> {
+    bail!("the built-in ESP flasher needs littlefs to be built with the `esp-flasher` feature")
+}
+
+/// Read the image back from the device with the `verify_command` and check
+/// it matches `image_path`.
+///
+/// Mismatches are reported per erase block. If the read-back still mounts,
+/// the files that differ are listed as well, which tells a partial write of
+/// one file apart from a corrupted filesystem.
+fn verify_flash(
+    target: &ImageTarget,
+    verify_command: &str,
+    image_path: &Path,
+    cache: &FlashCache,
+    vars: &[(&str, &str)],
+    dry_run: bool,
+) -> Result<()> {
+    let fs_flash = target
+        .flash
+        .as_ref()
+        .context("image has no flash section")?;
+    let image = std::fs::read(image_path)
+        .with_context(|| format!("failed to read {}", image_path.display()))?;
+
+    let readback_path = cache.image_path(image_path).with_extension("readback");
+    std::fs::create_dir_all(readback_path.parent().unwrap())?;
+    if readback_path.exists() {
+        std::fs::remove_file(&readback_path)?;
+    }
+
+    let length = format!("{:#x}", image.len());
+    let mut verify_vars = vec![
+        (
+            "path",
+            image_path.to_str().context("invalid image file path")?,
+        ),
+        ("address", fs_flash.address.as_str()),
+        ("length", length.as_str()),
+        (
+            "readback",
+            readback_path.to_str().context("invalid cache file path")?,
+        ),
+    ];
+    verify_vars.extend_from_slice(vars);
+    run_command(verify_command, &fs_flash.options, &verify_vars, dry_run)
+        .context("failed to read back the image")?;
+    if dry_run {
+        return Ok(());
+    }
+
+    let mut readback = std::fs::read(&readback_path)
+        .with_context(|| format!("failed to read {}", readback_path.display()))?;
+    std::fs::remove_file(&readback_path)?;
+    // Some tools read whole sectors, so only compare the image's length
+    readback.truncate(image.len());
+
+    let block_size = target.image.block_size;
+    let mismatches = mismatched_blocks(&image, &readback, block_size);
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+
+    let base = u64::from(fs_flash.address_value()?);
+    for &(block, differing) in &mismatches {
+        println!(
+            "  block {block} at {:#x}: {differing} byte(s) differ",
+            base + (block * block_size) as u64
+        );
+    }
+    match image_from_flash(&target.image, readback) {
+        Ok(mut flashed) => {
+            let mut expected = image_from_flash(&target.image, image)?;
+            let expected = sorted_contents(&mut expected)?;
+            match sorted_contents(&mut flashed) {
+                Ok(flashed) => {
+                    for line in diff_contents(&expected, &flashed) {
+                        println!("  {line}");
+                    }
+                }
+                Err(e) => println!("  read-back image doesn't mount: {e}"),
+            }
+        }
+        Err(e) => println!("  read-back image doesn't mount: {e}"),
+    }
+    bail!(
+        "read-back differs from '{}' in {} block(s)",
+        target.image.name,
+        mismatches.len()
+    )
+}
+
+/// Wrap data read from flash, or an image file that may have been trimmed,
+/// in an [`LfsImage`]. The block count is taken from the data rather than
+/// the config, and a partial last block is padded out with `0xFF`.
+fn image_from_flash(config: &ImageConfig, mut data: Vec<u8>) -> Result<LfsImage, LfsError> {
+    data.resize(data.len().next_multiple_of(config.block_size), 0xFF);
+    let config = ImageConfig {
+        block_count: data.len() / config.block_size,
+        ..config.clone()
+    };
+    LfsImage::from_trimmed_data(config, data)
+}
+
+/// The blocks of `actual` that differ from `expected`, with the number of
+/// differing bytes in each. Bytes missing from `actual` count as differing.
+fn mismatched_blocks(expected: &[u8], actual: &[u8], block_size: usize) -> Vec<(usize, usize)> {
+    expected
+        .chunks(block_size)
+        .enumerate()
+        .filter_map(|(block, want)| {
+            let start = block * block_size;
+            let got = actual.get(start..).unwrap_or_default();
+            let differing = want
+                .iter()
+                .enumerate()
+                .filter(|&(i, b)| got.get(i) != Some(b))
+                .count();
+            (differing > 0).then_some((block, differing))
+        })
+        .collect()
+}
+
+/// Describe how the entries in `actual` differ from `expected`, one line
+/// per entry. Both must be sorted by path.
+fn diff_contents(expected: &[ContentEntry], actual: &[ContentEntry]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (path, data) in expected {
+        match actual.iter().find(|(p, _)| p == path) {
+            None => lines.push(format!("missing:    {path}")),
+            Some((_, got)) if got != data => lines.push(format!("differs:    {path}")),
+            Some(_) => {}
+        }
+    }
+    for (path, _) in actual {
+        if !expected.iter().any(|(p, _)| p == path) {
+            lines.push(format!("unexpected: {path}"));
+        }
+    }
+    lines
+}
+
+/// Flash firmware and filesystem images to a device.
+///
+/// Intended to be invoked as a Cargo runner (`runner = "littlefs flash"`
+/// in `.cargo/config.toml`) so that `cargo run` triggers a full
+/// flash cycle. The sequence is:
+///
+/// 1. Flash each LittleFS filesystem image that has a flash target
+///    (skipping any that are unchanged on this device, unless `--force`).
+///    `--name` restricts this to a single image, `--skip-fs` skips the
+///    step entirely. With a `verify_command`, each image is read back and
+///    compared after it's written.
+/// 2. Flash the firmware binary (always, to preserve normal `cargo run`
+///    behaviour — the firmware command may also start a monitor session).
+///
+/// The filesystems are flashed first because the firmware flash typically
+/// resets the chip and starts execution; the FS must be in place before
+/// the application boots.
+///
+/// With `--dry-run` the commands are printed instead of run, and the flash
+/// cache is left as it was. The `identify_command` still runs, so the
+/// dry run checks the same device's cache a real flash would.
+fn cmd_flash(source: &ConfigSource, args: FlashCmd) -> Result<()> {
+    // Load the config
+    let config = source
+        .load()?
+        .context("no project config file path handed in!")?;
+    let name = source.name.as_deref();
+    let firmware = config
+        .firmware
+        .as_ref()
+        .context("project config file must have [flash.firmware] section defined!")?;
+
+    // Resolve the firmware binary path: CLI arg (from `cargo run`) takes
+    // priority, then the hardcoded path in [flash.firmware].
+    let binary_path = args
+        .binary_path
+        .clone()
+        .or_else(|| firmware.path.clone())
+        .context("no firmware path (pass as argument or set path in [flash.firmware])")?;
+
+    // {port}, {serial} and {chip} are available to every command
+    let device = device_vars(&config, args.port.as_deref(), args.serial.as_deref());
+    let device_vars = borrow_vars(&device);
+
+    let targets: Vec<&ImageTarget> = match (name, args.skip_fs) {
+        (_, true) => Vec::new(),
+        (Some(name), false) => vec![config.image(Some(name))?],
+        (None, false) => config.images.iter().collect(),
+    };
+
+    // Only identify the device if there's a filesystem to flash
+    let cache = match targets.iter().any(|t| t.flash.is_some()) {
+        true => FlashCache::for_device(
+            &config,
+            args.port.as_deref(),
+            args.serial.as_deref(),
+            &device_vars,
+        )?,
+        false => FlashCache::new(Path::new(FLASH_CACHE_DIR), None),
+    };
+
+    for target in targets {
+        let image_name = &target.image.name;
+        let Some(fs_flash) = &target.flash else {
+            // Only an explicitly selected image must be flashable
+            if name.is_some() {
+                bail!("image '{image_name}' has no flash section");
+            }
+            continue;
+        };
+        let image = image_vars(target);
+        let mut target_vars = device_vars.clone();
+        target_vars.extend(borrow_vars(&image));
+
+        // Resolve the filesystem image path: explicit config path takes priority,
+        // otherwise derive it from the binary's parent directory (where build.rs
+        // copies it). Only accept the derived path if the file actually exists.
+        let image_path = fs_flash
+            .path
+            .clone()
+            .or_else(|| {
+                binary_path
+                    .parent()
+                    .map(|dir| dir.join(format!("{image_name}.bin")))
+                    .filter(|p| p.exists())
+            })
+            .with_context(|| {
+                format!(
+                    "no filesystem image found for '{image_name}' (set path in its flash \
+                     section or run via `cargo run` so the image can be located automatically)"
+                )
+            })?;
+
+        // Flash the filesystem image, but only if it has changed since the last
+        // flash. This avoids unnecessary wear on flash memory for assets that
+        // are typically more static than the firmware itself.
+        let hash = match args.force {
+            true => {
+                let bytes = std::fs::read(&image_path)
+                    .with_context(|| format!("failed to read {}", image_path.display()))?;
+                Some(format!("{:x}", Sha256::digest(&bytes)))
+            }
+            false => cache.needs_flash(&image_path)?,
+        };
+        if let Some(hash) = hash {
+            println!("Flashing filesystem image '{image_name}'...");
+            flash_filesystem(
+                target,
+                &image_path,
+                &hash,
+                args.force,
+                &cache,
+                &target_vars,
+                args.dry_run,
+            )?;
+        } else {
+            println!("Filesystem image '{image_name}' unchanged, skipping.");
+        }
+    }
+
+    // Always flash the firmware to match normal `cargo run` behaviour.
+    // This command may also reset the chip and start a monitor session,
+    // so it must be the last step.
+    let mut vars = vec![(
+        "path",
+        binary_path.to_str().context("invalid binary file path")?,
+    )];
+    vars.extend_from_slice(&device_vars);
+    run_command(&firmware.command, &firmware.options, &vars, args.dry_run)
+        .context("failed to flash binary")?;
+
+    Ok(())
+}
+
+/// The placeholders that describe the device: `{port}` and `{serial}` from
+/// the command line and `{chip}` from `[flash] chip`, where set.
+fn device_vars(
+    config: &Config,
+    port: Option<&str>,
+    serial: Option<&str>,
+) -> Vec<(&'static str, String)> {
+    [
+        ("port", port),
+        ("serial", serial),
+        ("chip", config.chip.as_deref()),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| value.map(|v| (key, v.to_string())))
+    .collect()
+}
+
+/// The placeholders that describe an image: `{name}`, `{size}` and
+/// `{size_hex}` (the full image size), `{block_size}`, and `{partition}` if
+/// the image is placed by a partition table.
+fn image_vars(target: &ImageTarget) -> Vec<(&'static str, String)> {
+    let size = target.image.image_size();
+    let mut vars = vec![
+        ("name", target.image.name.clone()),
+        ("size", size.to_string()),
+        ("size_hex", format!("{size:#x}")),
+        ("block_size", target.image.block_size.to_string()),
+    ];
+    let partition = target
+        .flash
+        .as_ref()
+        .and_then(|f| f.partition_name.clone())
+        .or_else(|| target.partition.as_ref().map(|p| p.name.clone()));
+    if let Some(partition) = partition {
+        vars.push(("partition", partition));
+    }
+    vars
+}
+
+/// Borrow owned placeholder values in the form the command functions take.
+fn borrow_vars<'a>(vars: &'a [(&'static str, String)]) -> Vec<(&'a str, &'a str)> {
+    vars.iter()
+        .map(|(key, value)| (*key, value.as_str()))
+        .collect()
+}
+
+/// Replace each `{key}` in `template` with its value from `vars`, quoting
+/// the values for the shell if `quote` is set. Unknown placeholders are
+/// left as they are, and substituted values are never expanded again.
+fn expand(template: &str, vars: &[(&str, &str)], quote: bool) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after.find('}').and_then(|end| {
+            vars.iter()
+                .find(|(key, _)| *key == &after[..end])
+                .map(|(_, value)| (*value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                match quote {
+                    true => out.push_str(&shell_quote(value)),
+                    false => out.push_str(value),
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Quote a placeholder value for the system shell: POSIX quoting for
+/// `sh`, and `cmd.exe` quoting on Windows.
+fn shell_quote(value: &str) -> String {
+    match cfg!(windows) {
+        true => cmd_quote(value),
+        false => shell_words::quote(value).into_owned(),
+    }
+}
+
+/// Quote a value for `cmd.exe`, which only understands double quotes.
+/// Values without spaces or special characters are left as they are.
+fn cmd_quote(value: &str) -> String {
+    let special = |c: char| c.is_whitespace() || "\"&|<>^%()!,;=".contains(c);
+    if !value.is_empty() && !value.contains(special) {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+/// Split a command template into arguments. Quotes group words on every
+/// platform, but backslashes are only escapes outside Windows, where they
+/// are path separators.
+fn split_template(template: &str) -> Result<Vec<String>> {
+    match cfg!(windows) {
+        true => split_windows(template),
+        false => {
+            shell_words::split(template).with_context(|| format!("invalid command: {template}"))
+        }
+    }
+}
+
+/// Split `line` on whitespace, keeping double-quoted runs together and
+/// treating backslashes literally.
+fn split_windows(line: &str) -> Result<Vec<String>> {
+    let mut parts = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.get_or_insert_default();
+            }
+            c if c.is_whitespace() && !quoted => parts.extend(current.take()),
+            c => current.get_or_insert_default().push(c),
+        }
+    }
+    anyhow::ensure!(!quoted, "invalid command: {line}: unmatched quote");
+    parts.extend(current);
+    Ok(parts)
+}
+
+/// Expand a command template into a subprocess ready to run, along with
+/// the command line it stands for.
+///
+/// The template is split into arguments with shell quoting rules before
+/// the placeholders are filled in, so a path with spaces stays a single
+/// argument. On Windows only double quotes group, and backslashes are
+/// kept as they are. With `shell = true` the whole line is instead handed
+/// to the system shell, with the values quoted for it.
+fn build_command(
+    template: &str,
+    options: &CommandOptions,
+    vars: &[(&str, &str)],
+) -> Result<(std::process::Command, String)> {
+    let (mut command, line) = if options.shell {
+        let line = expand(template, vars, true);
+        anyhow::ensure!(!line.trim().is_empty(), "empty command");
+        #[cfg(windows)]
+        let command = {
+            use std::os::windows::process::CommandExt;
+            // `arg` would quote the line for a C runtime, which cmd.exe
+            // doesn't follow; /S makes it strip just the outer quotes
+            let mut command = std::process::Command::new("cmd");
+            command.raw_arg(format!("/S /C \"{line}\""));
+            command
+        };
+        #[cfg(not(windows))]
+        let command = {
+            let mut command = std::process::Command::new("sh");
+            command.arg("-c").arg(&line);
+            command
+        };
+        (command, line)
+    } else {
+        let parts: Vec<String> = split_template(template)?
+            .iter()
+            .map(|part| expand(part, vars, false))
+            .collect();
+        let (program, args) = parts.split_first().context("empty command")?;
+        let mut command = std::process::Command::new(program);
+        command.args(args);
+        (command, shell_words::join(&parts))
+    };
+
+    command.envs(&options.env);
+    if let Some(cwd) = &options.cwd {
+        command.current_dir(cwd);
+    }
+    Ok((command, line))
+}
+
+/// Expand a command template and execute it as a subprocess.
+///
+/// See [`build_command`] for the expansion. Returns an error if the
+/// command is not found or exits with a non-zero status. With `dry_run`
+/// the command line is printed instead.
+fn run_command(
+    template: &str,
+    options: &CommandOptions,
+    vars: &[(&str, &str)],
+    dry_run: bool,
+) -> Result<()> {
+    let (mut command, line) = build_command(template, options, vars)?;
+    if dry_run {
+        println!("[dry run] {line}");
+        return Ok(());
+    }
+
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command
+        .status()
+        .with_context(|| format!("failed to run: {program}"))?;
+
+    anyhow::ensure!(status.success(), "{program} exited with {status}");
+    Ok(())
+}
+
+/// Expand a command template, execute it, and return what it printed.
+fn capture_command(
+    template: &str,
+    options: &CommandOptions,
+    vars: &[(&str, &str)],
+) -> Result<String> {
+    let (mut command, _) = build_command(template, options, vars)?;
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("failed to run: {program}"))?;
+
+    anyhow::ensure!(
+        output.status.success(),
+        "{program} exited with {}",
+        output.status
+    );
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use littlefs2_pack::config::{DEFAULT_IMAGE_NAME, ImageConfig};
+    use std::fs;
+
+    // -------------------------------------------------------------------------
+    // Helpers
+    // -------------------------------------------------------------------------
+
+    /// Create an ImageConfigParams with all fields None.
+    fn empty_cli() -> ImageConfigParams {
+        ImageConfigParams {
+            preset: None,
+            block_size: None,
+            block_count: None,
+            image_size: None,
+            page_size: None,
+            read_size: None,
+            write_size: None,
+            block_cycles: None,
+            cache_size: None,
+            lookahead_size: None,
+        }
+    }
+
+    /// A `ConfigSource` pointing at `path` with no image or profile selected.
+    fn config_source(path: &Option<PathBuf>) -> ConfigSource {
+        ConfigSource {
+            config: path.clone(),
+            ..ConfigSource::default()
+        }
+    }
+
+    /// Write a minimal littlefs.toml and create the directory root it references.
+    fn write_test_toml(dir: &Path, image_overrides: &str) -> PathBuf {
+        let site_dir = dir.join("site");
+        fs::create_dir_all(&site_dir).unwrap();
+
+        let toml_path = dir.join("littlefs.toml");
+        fs::write(
+            &toml_path,
+            format!(
+                r#"
+[image]
+block_size = 4096
+block_count = 128
+page_size = 256
+read_size = 16
+write_size = 512
+{image_overrides}
+
+[directory]
+root = "./site"
+depth = -1
+ignore_hidden = true
+gitignore = false
+repo_gitignore = false
+glob_ignores = []
+glob_includes = []
+"#
+            ),
+        )
+        .unwrap();
+
+        toml_path
+    }
+
+    // -------------------------------------------------------------------------
+    // image_config_from_cli: valid constructions
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn cli_with_block_count_and_page_size() {
+        let cli = ImageConfigParams {
+            block_size: Some(4096),
+            block_count: Some(64),
+            page_size: Some(256),
+            ..empty_cli()
+        };
+        let config = image_config_from_cli(&cli).unwrap();
+        assert_eq!(config.block_size, 4096);
+        assert_eq!(config.block_count, 64);
+        assert_eq!(config.read_size, 256);
+        assert_eq!(config.write_size, 256);
+        assert_eq!(config.block_cycles, -1);
+    }
+
+    #[test]
+    fn cli_with_image_size() {
+        let cli = ImageConfigParams {
+            block_size: Some(4096),
+            image_size: Some(4096 * 64),
+            page_size: Some(256),
+            ..empty_cli()
+        };
+        let config = image_config_from_cli(&cli).unwrap();
+        assert_eq!(config.block_count, 64);
+        assert_eq!(config.image_size(), 4096 * 64);
+    }
+
+    #[test]
+    fn cli_with_explicit_read_write_sizes() {
+        let cli = ImageConfigParams {
+            block_size: Some(4096),
+            block_count: Some(64),
+            read_size: Some(16),
+            write_size: Some(512),
+            ..empty_cli()
+        };
+        let config = image_config_from_cli(&cli).unwrap();
+        assert_eq!(config.read_size, 16);
+        assert_eq!(config.write_size, 512);
+    }
+
+    #[test]
+    fn cli_read_write_override_page_size() {
+        let cli = ImageConfigParams {
+            block_size: Some(4096),
+            block_count: Some(64),
+            page_size: Some(256),
+            read_size: Some(16),
+            write_size: Some(512),
+            ..empty_cli()
+        };
+        let config = image_config_from_cli(&cli).unwrap();
+        assert_eq!(config.read_size, 16);
+        assert_eq!(config.write_size, 512);
+    }
+
+    #[test]
+    fn cli_explicit_block_cycles() {
+        let cli = ImageConfigParams {
+            block_size: Some(4096),
+            block_count: Some(64),
+            page_size: Some(256),
+            block_cycles: Some(500),
+            ..empty_cli()
+        };
+        let config = image_config_from_cli(&cli).unwrap();
+        assert_eq!(config.block_cycles, 500);
+    }
+
+    #[test]
+    fn cli_block_cycles_defaults_to_negative_one() {
+        let cli = ImageConfigParams {
+            block_size: Some(4096),
+            block_count: Some(64),
+            page_size: Some(256),
+            ..empty_cli()
+        };
+        let config = image_config_from_cli(&cli).unwrap();
+        assert_eq!(config.block_cycles, -1);
+    }
+
+    // -------------------------------------------------------------------------
+    // image_config_from_cli: error cases
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn cli_with_preset() {
+        let cli = ImageConfigParams {
+            preset: Some("esp32-s3-16mb".into()),
+            block_count: Some(64),
+            read_size: Some(16),
+            ..empty_cli()
+        };
+        let config = image_config_from_cli(&cli).unwrap();
+        assert_eq!(config.block_size, 4096);
+        assert_eq!(config.read_size, 16); // explicit flag wins
+        assert_eq!(config.write_size, 128);
+    }
+
+    #[test]
+    fn cli_preset_checks_flash_size() {
+        let cli = ImageConfigParams {
+            preset: Some("w25q16".into()),
+            block_count: Some(1024),
+            ..empty_cli()
+        };
+        assert!(image_config_from_cli(&cli).is_err());
+    }
+
+    #[test]
+    fn cli_unknown_preset_fails() {
+        let cli = ImageConfigParams {
+            preset: Some("esp8266".into()),
+            block_count: Some(64),
+            ..empty_cli()
+        };
+        assert!(image_config_from_cli(&cli).is_err());
+    }
+
+    #[test]
+    fn cli_missing_block_size_fails() {
+        let cli = ImageConfigParams {
+            block_count: Some(64),
+            page_size: Some(256),
+            ..empty_cli()
+        };
+        assert!(image_config_from_cli(&cli).is_err());
+    }
+
+    #[test]
+    fn cli_missing_page_and_read_write_fails() {
+        let cli = ImageConfigParams {
+            block_size: Some(4096),
+            block_count: Some(64),
+            ..empty_cli()
+        };
+        assert!(image_config_from_cli(&cli).is_err());
+    }
+
+    #[test]
+    fn cli_missing_block_count_and_image_size_fails() {
+        let cli = ImageConfigParams {
+            block_size: Some(4096),
+            page_size: Some(256),
+            ..empty_cli()
+        };
+        assert!(image_config_from_cli(&cli).is_err());
+    }
 
     // -------------------------------------------------------------------------
-    // Helpers
+    // apply_cli_overrides
     // -------------------------------------------------------------------------
 
-    /// Create an ImageConfigParams with all fields None.
-    fn empty_cli() -> ImageConfigParams {
-        ImageConfigParams {
-            block_size: None,
-            block_count: None,
-            image_size: None,
-            page_size: None,
-            read_size: None,
-            write_size: None,
-            block_cycles: None,
-            cache_size: None,
-            lookahead_size: None,
+    fn test_base_config() -> ImageConfig {
+        ImageConfig {
+            name: DEFAULT_IMAGE_NAME.into(),
+            block_size: 4096,
+            block_count: 128,
+            read_size: 256,
+            write_size: 256,
+            block_cycles: -1,
+            cache_size: 256,
+            lookahead_size: 16,
         }
     }
 
-    /// Write a minimal littlefs.toml and create the directory root it references.
-    fn write_test_toml(dir: &Path, image_overrides: &str) -> PathBuf {
-        let site_dir = dir.join("site");
-        fs::create_dir_all(&site_dir).unwrap();
+    #[test]
+    fn overrides_no_cli_args_preserves_toml() {
+        let base = ImageConfig {
+            name: DEFAULT_IMAGE_NAME.into(),
+            block_size: 4096,
+            block_count: 128,
+            read_size: 16,
+            write_size: 512,
+            block_cycles: -1,
+            cache_size: 512,
+            lookahead_size: 16,
+        };
+        let cli = empty_cli();
 
-        let toml_path = dir.join("littlefs.toml");
-        fs::write(
-            &toml_path,
+        let config = apply_cli_overrides(&base, &cli);
+        assert_eq!(config.block_size, 4096);
+        assert_eq!(config.block_count, 128);
+        assert_eq!(config.read_size, 16);
+        assert_eq!(config.write_size, 512);
+        assert_eq!(config.cache_size, 512);
+        assert_eq!(config.lookahead_size, 16);
+    }
+
+    #[test]
+    fn overrides_block_size() {
+        let base = test_base_config();
+        let cli = ImageConfigParams {
+            block_size: Some(512),
+            ..empty_cli()
+        };
+
+        let config = apply_cli_overrides(&base, &cli);
+        assert_eq!(config.block_size, 512);
+        assert_eq!(config.block_count, 128); // preserved from TOML
+    }
+
+    #[test]
+    fn overrides_block_count() {
+        let base = test_base_config();
+        let cli = ImageConfigParams {
+            block_count: Some(64),
+            ..empty_cli()
+        };
+
+        let config = apply_cli_overrides(&base, &cli);
+        assert_eq!(config.block_count, 64);
+    }
+
+    #[test]
+    fn overrides_image_size_replaces_block_count() {
+        let base = test_base_config();
+        let cli = ImageConfigParams {
+            image_size: Some(4096 * 32),
+            ..empty_cli()
+        };
+
+        let config = apply_cli_overrides(&base, &cli);
+        assert_eq!(config.block_count, 32);
+        assert_eq!(config.image_size(), 4096 * 32);
+    }
+
+    #[test]
+    fn overrides_read_write_sizes() {
+        let base = test_base_config();
+        let cli = ImageConfigParams {
+            read_size: Some(16),
+            write_size: Some(512),
+            ..empty_cli()
+        };
+
+        let config = apply_cli_overrides(&base, &cli);
+        assert_eq!(config.read_size, 16);
+        assert_eq!(config.write_size, 512);
+    }
+
+    #[test]
+    fn overrides_block_cycles() {
+        let base = test_base_config();
+        let cli = ImageConfigParams {
+            block_cycles: Some(100),
+            ..empty_cli()
+        };
+
+        let config = apply_cli_overrides(&base, &cli);
+        assert_eq!(config.block_cycles, 100);
+    }
+
+    // -------------------------------------------------------------------------
+    // image_config_for_reading: with TOML
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn reading_config_from_toml_and_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = write_test_toml(dir.path(), "");
+        let config_path = Some(toml_path);
+
+        // Simulate a 64-block image file
+        let data = vec![0xFF; 4096 * 64];
+        let config =
+            image_config_for_reading(&config_source(&config_path), &empty_cli(), &data).unwrap();
+
+        // block_count comes from file size, not TOML
+        assert_eq!(config.block_count, 64);
+        // Other params come from TOML
+        assert_eq!(config.block_size, 4096);
+        assert_eq!(config.read_size, 16);
+        assert_eq!(config.write_size, 512);
+    }
+
+    #[test]
+    fn reading_config_cli_overrides_toml() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = write_test_toml(dir.path(), "");
+        let config_path = Some(toml_path);
+
+        let cli = ImageConfigParams {
+            read_size: Some(32),
+            ..empty_cli()
+        };
+
+        let data = vec![0xFF; 4096 * 64];
+        let config = image_config_for_reading(&config_source(&config_path), &cli, &data).unwrap();
+
+        assert_eq!(config.read_size, 32);
+        assert_eq!(config.write_size, 512); // from TOML
+    }
+
+    #[test]
+    fn reading_config_applies_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = write_test_toml(dir.path(), "");
+        let mut toml = fs::read_to_string(&toml_path).unwrap();
+        toml.push_str("\n[profile.fast.image]\nread_size = 256\n");
+        fs::write(&toml_path, toml).unwrap();
+
+        let source = ConfigSource {
+            profile: Some("fast".into()),
+            ..config_source(&Some(toml_path))
+        };
+        let data = vec![0xFF; 4096 * 64];
+        let config = image_config_for_reading(&source, &empty_cli(), &data).unwrap();
+
+        assert_eq!(config.read_size, 256);
+        assert_eq!(config.write_size, 512); // from base TOML
+    }
+
+    #[test]
+    fn reading_config_selects_named_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = dir.path().join("littlefs.toml");
+        let image = |name: &str, block_size: usize| {
             format!(
                 r#"
-[image]
-block_size = 4096
-block_count = 128
+[[images]]
+[images.image]
+name = "{name}"
+block_size = {block_size}
+block_count = 16
 page_size = 256
-read_size = 16
-write_size = 512
-{image_overrides}
 
-[directory]
-root = "./site"
+[images.directory]
+root = "."
 depth = -1
 ignore_hidden = true
 gitignore = false
@@ -716,153 +2931,157 @@ repo_gitignore = false
 glob_ignores = []
 glob_includes = []
 "#
-            ),
+            )
+        };
+        fs::write(
+            &toml_path,
+            format!("{}{}", image("assets", 4096), image("userdata", 8192)),
         )
         .unwrap();
+        let config_path = Some(toml_path);
 
-        toml_path
+        let data = vec![0xFF; 8192 * 16];
+        let source = ConfigSource {
+            name: Some("userdata".into()),
+            ..config_source(&config_path)
+        };
+        let config = image_config_for_reading(&source, &empty_cli(), &data).unwrap();
+        assert_eq!(config.block_size, 8192);
+
+        // Without a name the selection is ambiguous
+        assert!(
+            image_config_for_reading(&config_source(&config_path), &empty_cli(), &data).is_err()
+        );
     }
 
     // -------------------------------------------------------------------------
-    // image_config_from_cli: valid constructions
+    // image_config_for_reading: CLI only
     // -------------------------------------------------------------------------
 
     #[test]
-    fn cli_with_block_count_and_page_size() {
+    fn reading_config_cli_only() {
         let cli = ImageConfigParams {
             block_size: Some(4096),
-            block_count: Some(64),
             page_size: Some(256),
             ..empty_cli()
         };
-        let config = image_config_from_cli(&cli).unwrap();
+
+        let data = vec![0xFF; 4096 * 32];
+        let config = image_config_for_reading(&ConfigSource::default(), &cli, &data).unwrap();
+
         assert_eq!(config.block_size, 4096);
-        assert_eq!(config.block_count, 64);
+        assert_eq!(config.block_count, 32);
         assert_eq!(config.read_size, 256);
         assert_eq!(config.write_size, 256);
-        assert_eq!(config.block_cycles, -1);
     }
 
     #[test]
-    fn cli_with_image_size() {
+    fn reading_config_misaligned_file_fails() {
         let cli = ImageConfigParams {
             block_size: Some(4096),
-            image_size: Some(4096 * 64),
             page_size: Some(256),
             ..empty_cli()
         };
-        let config = image_config_from_cli(&cli).unwrap();
-        assert_eq!(config.block_count, 64);
-        assert_eq!(config.image_size(), 4096 * 64);
-    }
 
-    #[test]
-    fn cli_with_explicit_read_write_sizes() {
-        let cli = ImageConfigParams {
-            block_size: Some(4096),
-            block_count: Some(64),
-            read_size: Some(16),
-            write_size: Some(512),
-            ..empty_cli()
-        };
-        let config = image_config_from_cli(&cli).unwrap();
-        assert_eq!(config.read_size, 16);
-        assert_eq!(config.write_size, 512);
+        let data = vec![0xFF; 5000]; // not a multiple of 4096
+        assert!(image_config_for_reading(&ConfigSource::default(), &cli, &data).is_err());
     }
 
     #[test]
-    fn cli_read_write_override_page_size() {
+    fn reading_config_empty_file_fails() {
         let cli = ImageConfigParams {
             block_size: Some(4096),
-            block_count: Some(64),
             page_size: Some(256),
-            read_size: Some(16),
-            write_size: Some(512),
             ..empty_cli()
         };
-        let config = image_config_from_cli(&cli).unwrap();
-        assert_eq!(config.read_size, 16);
-        assert_eq!(config.write_size, 512);
+
+        let data = vec![];
+        assert!(image_config_for_reading(&ConfigSource::default(), &cli, &data).is_err());
     }
 
     #[test]
-    fn cli_explicit_block_cycles() {
+    fn reading_config_cli_missing_block_size_fails() {
         let cli = ImageConfigParams {
-            block_size: Some(4096),
-            block_count: Some(64),
             page_size: Some(256),
-            block_cycles: Some(500),
             ..empty_cli()
         };
-        let config = image_config_from_cli(&cli).unwrap();
-        assert_eq!(config.block_cycles, 500);
+
+        let data = vec![0xFF; 4096 * 32];
+        assert!(image_config_for_reading(&ConfigSource::default(), &cli, &data).is_err());
     }
 
     #[test]
-    fn cli_block_cycles_defaults_to_negative_one() {
+    fn reading_config_cli_missing_sizes_fails() {
         let cli = ImageConfigParams {
             block_size: Some(4096),
-            block_count: Some(64),
-            page_size: Some(256),
             ..empty_cli()
         };
-        let config = image_config_from_cli(&cli).unwrap();
-        assert_eq!(config.block_cycles, -1);
+
+        let data = vec![0xFF; 4096 * 32];
+        assert!(image_config_for_reading(&ConfigSource::default(), &cli, &data).is_err());
     }
 
     // -------------------------------------------------------------------------
-    // image_config_from_cli: error cases
+    // apply_cli_overrides: cache_size invalidation
     // -------------------------------------------------------------------------
 
     #[test]
-    fn cli_missing_block_size_fails() {
+    fn overrides_cache_size_recomputed_when_read_size_changes() {
+        // base: read=256, write=256, cache=256
+        let base = test_base_config();
         let cli = ImageConfigParams {
-            block_count: Some(64),
-            page_size: Some(256),
+            read_size: Some(512),
             ..empty_cli()
         };
-        assert!(image_config_from_cli(&cli).is_err());
-    }
 
-    #[test]
-    fn cli_missing_page_and_read_write_fails() {
-        let cli = ImageConfigParams {
-            block_size: Some(4096),
-            block_count: Some(64),
-            ..empty_cli()
-        };
-        assert!(image_config_from_cli(&cli).is_err());
+        let config = apply_cli_overrides(&base, &cli);
+        // cache_size should be recomputed as max(new_read, old_write) = max(512, 256) = 512
+        assert_eq!(config.read_size, 512);
+        assert_eq!(config.cache_size, 512);
     }
 
     #[test]
-    fn cli_missing_block_count_and_image_size_fails() {
+    fn overrides_cache_size_recomputed_when_write_size_changes() {
+        let base = test_base_config();
         let cli = ImageConfigParams {
-            block_size: Some(4096),
-            page_size: Some(256),
+            write_size: Some(512),
             ..empty_cli()
         };
-        assert!(image_config_from_cli(&cli).is_err());
-    }
 
-    // -------------------------------------------------------------------------
-    // apply_cli_overrides
-    // -------------------------------------------------------------------------
+        let config = apply_cli_overrides(&base, &cli);
+        assert_eq!(config.write_size, 512);
+        assert_eq!(config.cache_size, 512);
+    }
 
-    fn test_base_config() -> ImageConfig {
-        ImageConfig {
+    #[test]
+    fn overrides_cache_size_recomputed_when_block_size_changes() {
+        // Use a base where cache_size (4096) differs from the default that
+        // resolve() would pick (max(read=256, write=256) = 256), so we can
+        // tell whether the value was carried forward or recomputed.
+        let base = ImageConfig {
             name: DEFAULT_IMAGE_NAME.into(),
             block_size: 4096,
             block_count: 128,
             read_size: 256,
             write_size: 256,
             block_cycles: -1,
-            cache_size: 256,
+            cache_size: 4096,
             lookahead_size: 16,
-        }
+        };
+        let cli = ImageConfigParams {
+            block_size: Some(512),
+            ..empty_cli()
+        };
+
+        let config = apply_cli_overrides(&base, &cli);
+        // cache_size recomputed as max(256, 256) = 256, NOT carried forward as 4096
+        // (which would be invalid: 512 % 4096 != 0)
+        assert_eq!(config.cache_size, 256);
     }
 
     #[test]
-    fn overrides_no_cli_args_preserves_toml() {
+    fn overrides_cache_size_preserved_when_unrelated_fields_change() {
+        // When only block_count changes, cache_size should carry forward
         let base = ImageConfig {
             name: DEFAULT_IMAGE_NAME.into(),
             block_size: 4096,
@@ -873,33 +3092,36 @@ glob_includes = []
             cache_size: 512,
             lookahead_size: 16,
         };
-        let cli = empty_cli();
+        let cli = ImageConfigParams {
+            block_count: Some(64),
+            ..empty_cli()
+        };
 
         let config = apply_cli_overrides(&base, &cli);
-        assert_eq!(config.block_size, 4096);
-        assert_eq!(config.block_count, 128);
-        assert_eq!(config.read_size, 16);
-        assert_eq!(config.write_size, 512);
-        assert_eq!(config.cache_size, 512);
-        assert_eq!(config.lookahead_size, 16);
+        assert_eq!(config.block_count, 64);
+        assert_eq!(config.cache_size, 512); // preserved
     }
 
     #[test]
-    fn overrides_block_size() {
+    fn overrides_explicit_cache_size_always_wins() {
         let base = test_base_config();
         let cli = ImageConfigParams {
-            block_size: Some(512),
+            read_size: Some(512),   // would trigger recomputation
+            cache_size: Some(1024), // but explicit wins
             ..empty_cli()
         };
 
         let config = apply_cli_overrides(&base, &cli);
-        assert_eq!(config.block_size, 512);
-        assert_eq!(config.block_count, 128); // preserved from TOML
+        assert_eq!(config.cache_size, 1024);
     }
 
+    // -------------------------------------------------------------------------
+    // apply_cli_overrides: lookahead_size invalidation
+    // -------------------------------------------------------------------------
+
     #[test]
-    fn overrides_block_count() {
-        let base = test_base_config();
+    fn overrides_lookahead_recomputed_when_block_count_changes() {
+        let base = test_base_config(); // block_count=128, lookahead=16
         let cli = ImageConfigParams {
             block_count: Some(64),
             ..empty_cli()
@@ -907,352 +3129,937 @@ glob_includes = []
 
         let config = apply_cli_overrides(&base, &cli);
         assert_eq!(config.block_count, 64);
+        // lookahead_size recomputed by resolve(), not carried from base
+        assert_ne!(config.lookahead_size, 0);
     }
 
     #[test]
-    fn overrides_image_size_replaces_block_count() {
+    fn overrides_lookahead_recomputed_when_image_size_changes() {
         let base = test_base_config();
         let cli = ImageConfigParams {
-            image_size: Some(4096 * 32),
+            image_size: Some(4096 * 64),
             ..empty_cli()
         };
 
         let config = apply_cli_overrides(&base, &cli);
-        assert_eq!(config.block_count, 32);
-        assert_eq!(config.image_size(), 4096 * 32);
+        assert_eq!(config.block_count, 64);
+        // lookahead_size recomputed, not carried forward
+        assert_ne!(config.lookahead_size, 0);
     }
 
     #[test]
-    fn overrides_read_write_sizes() {
-        let base = test_base_config();
+    fn overrides_lookahead_preserved_when_block_count_unchanged() {
+        let base = test_base_config(); // lookahead=16
         let cli = ImageConfigParams {
-            read_size: Some(16),
-            write_size: Some(512),
+            block_cycles: Some(100), // unrelated change
             ..empty_cli()
         };
 
         let config = apply_cli_overrides(&base, &cli);
-        assert_eq!(config.read_size, 16);
-        assert_eq!(config.write_size, 512);
+        assert_eq!(config.lookahead_size, 16); // preserved
     }
 
     #[test]
-    fn overrides_block_cycles() {
+    fn overrides_explicit_lookahead_always_wins() {
         let base = test_base_config();
         let cli = ImageConfigParams {
-            block_cycles: Some(100),
+            block_count: Some(64),    // would trigger recomputation
+            lookahead_size: Some(32), // but explicit wins
             ..empty_cli()
         };
 
         let config = apply_cli_overrides(&base, &cli);
-        assert_eq!(config.block_cycles, 100);
+        assert_eq!(config.lookahead_size, 32);
     }
 
     // -------------------------------------------------------------------------
-    // image_config_for_reading: with TOML
+    // pack: output formats
     // -------------------------------------------------------------------------
 
     #[test]
-    fn reading_config_from_toml_and_file() {
+    fn uf2_output_wraps_image() {
+        let params = OutputParams {
+            format: OutputFormat::Uf2,
+            base_address: Some(0x1020_0000),
+            family_id: Some(parse_family_id("rp2350-arm-s").unwrap()),
+            ..OutputParams::default()
+        };
+        let out = encode_output(vec![0xAB; 4096], &params).unwrap();
+        assert_eq!(out.len(), 16 * uf2::BLOCK_SIZE);
+        assert_eq!(&out[12..16], &0x1020_0000u32.to_le_bytes());
+        assert_eq!(&out[28..32], &0xE48B_FF59u32.to_le_bytes());
+    }
+
+    #[test]
+    fn hex_and_srec_output_skip_erased_records() {
+        let mut data = vec![0xFF; 4096];
+        data[0x800] = 0x00;
+        let params = |format| OutputParams {
+            format,
+            base_address: Some(0x0800_0000),
+            skip_erased: true,
+            ..OutputParams::default()
+        };
+
+        let hex =
+            String::from_utf8(encode_output(data.clone(), &params(OutputFormat::Hex)).unwrap())
+                .unwrap();
+        assert_eq!(hex.lines().count(), 3);
+        assert!(hex.contains(":10080000"));
+
+        let srec =
+            String::from_utf8(encode_output(data, &params(OutputFormat::Srec)).unwrap()).unwrap();
+        assert!(srec.contains("S32508000800"));
+    }
+
+    #[test]
+    fn trimmed_pack_reads_back() {
         let dir = tempfile::tempdir().unwrap();
         let toml_path = write_test_toml(dir.path(), "");
-        let config_path = Some(toml_path);
-
-        // Simulate a 64-block image file
-        let data = vec![0xFF; 4096 * 64];
-        let config = image_config_for_reading(&config_path, &empty_cli(), &data).unwrap();
+        fs::write(dir.path().join("site/index.html"), "<h1>hi</h1>").unwrap();
+        fs::write(dir.path().join("site/data.bin"), vec![7u8; 10_000]).unwrap();
+        let source = config_source(&Some(toml_path));
+        let out = dir.path().join("trimmed.bin");
+
+        cmd_pack(
+            &source,
+            PackCmd {
+                pack_directory: None,
+                output: out.clone(),
+                trim: true,
+                base_image: None,
+                format: OutputParams::default(),
+                fs: empty_cli(),
+            },
+        )
+        .unwrap();
 
-        // block_count comes from file size, not TOML
-        assert_eq!(config.block_count, 64);
-        // Other params come from TOML
-        assert_eq!(config.block_size, 4096);
-        assert_eq!(config.read_size, 16);
-        assert_eq!(config.write_size, 512);
+        let len = fs::metadata(&out).unwrap().len() as usize;
+        assert!(len < 16 * 4096);
+        assert_eq!(len % 4096, 0);
+
+        let mut image = load_image(&source, &empty_cli(), &out).unwrap();
+        assert_eq!(image.config().block_count, 128);
+        assert_eq!(image.flashable_len(), len);
+        let data = image
+            .mount_and_then(|fs| fs.read_file("/data.bin"))
+            .unwrap();
+        assert_eq!(data, vec![7u8; 10_000]);
     }
 
     #[test]
-    fn reading_config_cli_overrides_toml() {
+    fn delta_and_apply_reproduce_new_image() {
         let dir = tempfile::tempdir().unwrap();
         let toml_path = write_test_toml(dir.path(), "");
-        let config_path = Some(toml_path);
+        let image_config = Config::from_file(&toml_path).unwrap().images[0]
+            .image
+            .clone();
+        let source = config_source(&Some(toml_path));
+        let pack = |files: &[(&str, &str)], output: &Path| {
+            let mut image = LfsImage::new(image_config.clone()).unwrap();
+            image.format().unwrap();
+            image
+                .mount_and_then(|fs| {
+                    for (path, data) in files {
+                        fs.write_file(path, data.as_bytes())?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+            fs::write(output, image.data()).unwrap();
+        };
+        let v1 = dir.path().join("v1.bin");
+        let v2 = dir.path().join("v2.bin");
+        pack(&[("/a.txt", "a"), ("/b.txt", "b")], &v1);
+        pack(&[("/a.txt", "a2"), ("/c.txt", "c")], &v2);
+
+        for files in [false, true] {
+            let patch = dir.path().join("v2.patch");
+            let patched = dir.path().join("patched.bin");
+            cmd_delta(
+                &source,
+                DeltaCmd {
+                    old: v1.clone(),
+                    new: v2.clone(),
+                    output: patch.clone(),
+                    files,
+                    fs: empty_cli(),
+                },
+            )
+            .unwrap();
+            cmd_apply(
+                &source,
+                ApplyCmd {
+                    image: v1.clone(),
+                    patch,
+                    output: patched.clone(),
+                    fs: empty_cli(),
+                },
+            )
+            .unwrap();
+
+            let mut image = load_image(&source, &empty_cli(), &patched).unwrap();
+            let mut expected = load_image(&source, &empty_cli(), &v2).unwrap();
+            assert_eq!(
+                sorted_contents(&mut image).unwrap(),
+                sorted_contents(&mut expected).unwrap()
+            );
+        }
+    }
 
-        let cli = ImageConfigParams {
-            read_size: Some(32),
-            ..empty_cli()
+    #[test]
+    fn base_image_pack_updates_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = write_test_toml(dir.path(), "");
+        fs::write(dir.path().join("site/index.html"), "<h1>v1</h1>").unwrap();
+        let source = config_source(&Some(toml_path));
+        let pack = |output: &Path, base_image: Option<PathBuf>| {
+            cmd_pack(
+                &source,
+                PackCmd {
+                    pack_directory: None,
+                    output: output.to_owned(),
+                    trim: false,
+                    base_image,
+                    format: OutputParams::default(),
+                    fs: empty_cli(),
+                },
+            )
         };
 
-        let data = vec![0xFF; 4096 * 64];
-        let config = image_config_for_reading(&config_path, &cli, &data).unwrap();
+        let v1 = dir.path().join("v1.bin");
+        let v2 = dir.path().join("v2.bin");
+        pack(&v1, None).unwrap();
+        fs::write(dir.path().join("site/index.html"), "<h1>v2</h1>").unwrap();
+        pack(&v2, Some(v1.clone())).unwrap();
+
+        let mut image = load_image(&source, &empty_cli(), &v2).unwrap();
+        let index = image
+            .mount_and_then(|fs| fs.read_file("/index.html"))
+            .unwrap();
+        assert_eq!(index, b"<h1>v2</h1>");
+
+        // The base image has to match the geometry being packed
+        fs::write(&v1, vec![0xFF; 4096 * 64]).unwrap();
+        let err = pack(&v2, Some(v1)).unwrap_err();
+        assert!(err.to_string().contains("64 blocks"), "{err}");
+    }
 
-        assert_eq!(config.read_size, 32);
-        assert_eq!(config.write_size, 512); // from TOML
+    #[test]
+    fn uf2_output_requires_base_address() {
+        let params = OutputParams {
+            format: OutputFormat::Uf2,
+            ..OutputParams::default()
+        };
+        assert!(encode_output(vec![0xFF; 4096], &params).is_err());
+        assert!(parse_output_format("elf").is_err());
+        assert!(parse_family_id("z80").is_err());
     }
 
     // -------------------------------------------------------------------------
-    // image_config_for_reading: CLI only
+    // merge
     // -------------------------------------------------------------------------
 
     #[test]
-    fn reading_config_cli_only() {
-        let cli = ImageConfigParams {
-            block_size: Some(4096),
-            page_size: Some(256),
-            ..empty_cli()
-        };
+    fn merge_writes_segments_to_output() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("boot.bin"), [0xB0; 16]).unwrap();
+        fs::write(dir.path().join("app.bin"), [0xA0; 16]).unwrap();
+        let toml_path = dir.path().join("littlefs.toml");
+        fs::write(
+            &toml_path,
+            r#"
+[image]
+block_size = 4096
+block_count = 2
+page_size = 256
 
-        let data = vec![0xFF; 4096 * 32];
-        let config = image_config_for_reading(&None, &cli, &data).unwrap();
+[directory]
+root = "."
+depth = -1
+ignore_hidden = true
+gitignore = false
+repo_gitignore = false
+glob_ignores = ["*.bin", "*.toml"]
+glob_includes = []
 
-        assert_eq!(config.block_size, 4096);
-        assert_eq!(config.block_count, 32);
-        assert_eq!(config.read_size, 256);
-        assert_eq!(config.write_size, 256);
+[flash.firmware]
+command = "true"
+
+[flash.filesystem]
+command = "true"
+address = "0x2000"
+
+[flash.merge]
+output = "merged.bin"
+
+[[flash.merge.segments]]
+path = "boot.bin"
+address = "0x0"
+
+[[flash.merge.segments]]
+path = "app.bin"
+address = "0x1000"
+"#,
+        )
+        .unwrap();
+
+        cmd_merge(
+            &config_source(&Some(toml_path)),
+            MergeCmd {
+                output: None,
+                flash_size: Some(0x10000),
+                fill: true,
+            },
+        )
+        .unwrap();
+        let merged = fs::read(dir.path().join("merged.bin")).unwrap();
+        assert_eq!(merged.len(), 0x10000);
+        assert_eq!(&merged[..16], &[0xB0; 16]);
+        assert_eq!(&merged[0x1000..0x1010], &[0xA0; 16]);
+        assert!(merged[0x4000..].iter().all(|&b| b == 0xFF));
     }
 
     #[test]
-    fn reading_config_misaligned_file_fails() {
-        let cli = ImageConfigParams {
-            block_size: Some(4096),
-            page_size: Some(256),
-            ..empty_cli()
-        };
+    fn merge_rejects_overflowing_flash() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("app.bin"), [0xA0; 0x2000]).unwrap();
+        let toml_path = dir.path().join("littlefs.toml");
+        fs::write(
+            &toml_path,
+            r#"
+[image]
+block_size = 4096
+block_count = 2
+page_size = 256
 
-        let data = vec![0xFF; 5000]; // not a multiple of 4096
-        assert!(image_config_for_reading(&None, &cli, &data).is_err());
+[directory]
+root = "."
+depth = -1
+ignore_hidden = true
+gitignore = false
+repo_gitignore = false
+glob_ignores = []
+glob_includes = []
+
+[flash.firmware]
+command = "true"
+
+[flash.merge]
+flash_size = "4K"
+
+[[flash.merge.segments]]
+path = "app.bin"
+address = "0x0"
+"#,
+        )
+        .unwrap();
+
+        let result = cmd_merge(
+            &config_source(&Some(toml_path)),
+            MergeCmd {
+                output: Some(dir.path().join("merged.bin")),
+                flash_size: None,
+                fill: false,
+            },
+        );
+        assert!(result.is_err());
+        assert!(!dir.path().join("merged.bin").exists());
     }
 
-    #[test]
-    fn reading_config_empty_file_fails() {
-        let cli = ImageConfigParams {
-            block_size: Some(4096),
-            page_size: Some(256),
-            ..empty_cli()
-        };
+    // -------------------------------------------------------------------------
+    // partitions
+    // -------------------------------------------------------------------------
 
-        let data = vec![];
-        assert!(image_config_for_reading(&None, &cli, &data).is_err());
+    fn table_params(flash_size: Option<u32>) -> PartitionTableParams {
+        PartitionTableParams {
+            table_offset: DEFAULT_TABLE_OFFSET,
+            flash_size,
+        }
     }
 
     #[test]
-    fn reading_config_cli_missing_block_size_fails() {
-        let cli = ImageConfigParams {
-            page_size: Some(256),
-            ..empty_cli()
-        };
+    fn parse_u32_accepts_hex_and_suffixes() {
+        assert_eq!(parse_u32("0x8000").unwrap(), 0x8000);
+        assert_eq!(parse_u32("4M").unwrap(), 4 * 1024 * 1024);
+        assert!(parse_u32("lots").is_err());
+    }
+
+    #[test]
+    fn partitions_csv_to_bin_and_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("partitions.csv");
+        let bin = dir.path().join("partition-table.bin");
+        let back = dir.path().join("back.csv");
+        fs::write(
+            &csv,
+            "nvs, data, nvs, 0x9000, 0x6000\nfactory, app, factory, , 1M\n",
+        )
+        .unwrap();
+
+        cmd_partitions(PartitionsCmd {
+            action: PartitionsAction::ToBin {
+                input: csv.clone(),
+                output: bin.clone(),
+                table: table_params(Some(0x400000)),
+            },
+        })
+        .unwrap();
+        assert_eq!(fs::read(&bin).unwrap().len(), 0xC00);
+
+        cmd_partitions(PartitionsCmd {
+            action: PartitionsAction::FromBin {
+                input: bin,
+                output: Some(back.clone()),
+                table: table_params(None),
+            },
+        })
+        .unwrap();
+        assert!(
+            fs::read_to_string(&back)
+                .unwrap()
+                .contains("factory,app,factory,0x10000,1M,")
+        );
+    }
 
-        let data = vec![0xFF; 4096 * 32];
-        assert!(image_config_for_reading(&None, &cli, &data).is_err());
+    #[test]
+    fn partitions_to_bin_rejects_table_exceeding_flash() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("partitions.csv");
+        let bin = dir.path().join("partition-table.bin");
+        fs::write(&csv, "factory, app, factory, 0x10000, 4M\n").unwrap();
+
+        let result = cmd_partitions(PartitionsCmd {
+            action: PartitionsAction::ToBin {
+                input: csv,
+                output: bin.clone(),
+                table: table_params(Some(0x400000)),
+            },
+        });
+        assert!(result.is_err());
+        assert!(!bin.exists());
     }
 
     #[test]
-    fn reading_config_cli_missing_sizes_fails() {
-        let cli = ImageConfigParams {
-            block_size: Some(4096),
-            ..empty_cli()
-        };
+    fn partitions_json_to_block_and_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let json = dir.path().join("partitions.json");
+        let bin = dir.path().join("pt.bin");
+        let back = dir.path().join("back.json");
+        fs::write(
+            &json,
+            r#"{"partitions": [{"name": "littlefs", "start": "1M", "size": "1M", "families": ["data"]}]}"#,
+        )
+        .unwrap();
 
-        let data = vec![0xFF; 4096 * 32];
-        assert!(image_config_for_reading(&None, &cli, &data).is_err());
+        cmd_partitions(PartitionsCmd {
+            action: PartitionsAction::ToBin {
+                input: json,
+                output: bin.clone(),
+                table: table_params(Some(0x400000)),
+            },
+        })
+        .unwrap();
+        assert!(Rp2350PartitionTable::is_block(&fs::read(&bin).unwrap()));
+
+        cmd_partitions(PartitionsCmd {
+            action: PartitionsAction::FromBin {
+                input: bin,
+                output: Some(back.clone()),
+                table: table_params(None),
+            },
+        })
+        .unwrap();
+        let back = Rp2350PartitionTable::from_json(&fs::read_to_string(&back).unwrap()).unwrap();
+        assert_eq!(back.find("littlefs").unwrap().start, 0x100000);
     }
 
     // -------------------------------------------------------------------------
-    // apply_cli_overrides: cache_size invalidation
+    // Flash helpers: FlashCache
     // -------------------------------------------------------------------------
 
     #[test]
-    fn overrides_cache_size_recomputed_when_read_size_changes() {
-        // base: read=256, write=256, cache=256
-        let base = test_base_config();
-        let cli = ImageConfigParams {
-            read_size: Some(512),
-            ..empty_cli()
-        };
+    fn needs_flash_returns_hash_for_new_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FlashCache::new(&dir.path().join("cache"), None);
+        let file = dir.path().join("new_file_test.bin");
+        fs::write(&file, b"hello").unwrap();
 
-        let config = apply_cli_overrides(&base, &cli);
-        // cache_size should be recomputed as max(new_read, old_write) = max(512, 256) = 512
-        assert_eq!(config.read_size, 512);
-        assert_eq!(config.cache_size, 512);
+        let result = cache.needs_flash(&file).unwrap();
+        assert!(result.is_some(), "new file should need flashing");
     }
 
     #[test]
-    fn overrides_cache_size_recomputed_when_write_size_changes() {
-        let base = test_base_config();
-        let cli = ImageConfigParams {
-            write_size: Some(512),
-            ..empty_cli()
-        };
+    fn needs_flash_returns_none_after_mark_flashed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FlashCache::new(&dir.path().join("cache"), None);
+        let file = dir.path().join("mark_test.bin");
+        fs::write(&file, b"hello").unwrap();
 
-        let config = apply_cli_overrides(&base, &cli);
-        assert_eq!(config.write_size, 512);
-        assert_eq!(config.cache_size, 512);
+        let hash = cache.needs_flash(&file).unwrap().unwrap();
+        cache.mark_flashed(&file, &hash).unwrap();
+
+        let result = cache.needs_flash(&file).unwrap();
+        assert!(result.is_none(), "unchanged file should not need flashing");
     }
 
     #[test]
-    fn overrides_cache_size_recomputed_when_block_size_changes() {
-        // Use a base where cache_size (4096) differs from the default that
-        // resolve() would pick (max(read=256, write=256) = 256), so we can
-        // tell whether the value was carried forward or recomputed.
-        let base = ImageConfig {
-            name: DEFAULT_IMAGE_NAME.into(),
-            block_size: 4096,
-            block_count: 128,
-            read_size: 256,
-            write_size: 256,
-            block_cycles: -1,
-            cache_size: 4096,
-            lookahead_size: 16,
-        };
-        let cli = ImageConfigParams {
-            block_size: Some(512),
-            ..empty_cli()
-        };
+    fn needs_flash_returns_hash_after_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FlashCache::new(&dir.path().join("cache"), None);
+        let file = dir.path().join("changed_test.bin");
+        fs::write(&file, b"hello").unwrap();
 
-        let config = apply_cli_overrides(&base, &cli);
-        // cache_size recomputed as max(256, 256) = 256, NOT carried forward as 4096
-        // (which would be invalid: 512 % 4096 != 0)
-        assert_eq!(config.cache_size, 256);
+        let hash = cache.needs_flash(&file).unwrap().unwrap();
+        cache.mark_flashed(&file, &hash).unwrap();
+
+        // Modify the file
+        fs::write(&file, b"world").unwrap();
+
+        let result = cache.needs_flash(&file).unwrap();
+        assert!(result.is_some(), "modified file should need flashing");
     }
 
     #[test]
-    fn overrides_cache_size_preserved_when_unrelated_fields_change() {
-        // When only block_count changes, cache_size should carry forward
-        let base = ImageConfig {
-            name: DEFAULT_IMAGE_NAME.into(),
-            block_size: 4096,
-            block_count: 128,
-            read_size: 16,
-            write_size: 512,
-            block_cycles: -1,
-            cache_size: 512,
-            lookahead_size: 16,
-        };
-        let cli = ImageConfigParams {
-            block_count: Some(64),
-            ..empty_cli()
-        };
+    fn needs_flash_errors_on_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FlashCache::new(dir.path(), None);
+        let result = cache.needs_flash(Path::new("/nonexistent/file.bin"));
+        assert!(result.is_err());
+    }
 
-        let config = apply_cli_overrides(&base, &cli);
-        assert_eq!(config.block_count, 64);
-        assert_eq!(config.cache_size, 512); // preserved
+    #[test]
+    fn flash_cache_kept_per_device() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("device_test.bin");
+        fs::write(&file, b"hello").unwrap();
+        let root = dir.path().join("cache");
+
+        let first = FlashCache::new(&root, Some("aa:bb:cc:dd:ee:ff"));
+        let hash = first.needs_flash(&file).unwrap().unwrap();
+        first.mark_flashed(&file, &hash).unwrap();
+        assert!(first.needs_flash(&file).unwrap().is_none());
+
+        // Another board hasn't been flashed with this image yet
+        let second = FlashCache::new(&root, Some("/dev/ttyUSB1"));
+        assert!(second.needs_flash(&file).unwrap().is_some());
+        assert!(root.join("aa_bb_cc_dd_ee_ff").is_dir());
     }
 
     #[test]
-    fn overrides_explicit_cache_size_always_wins() {
-        let base = test_base_config();
-        let cli = ImageConfigParams {
-            read_size: Some(512),   // would trigger recomputation
-            cache_size: Some(1024), // but explicit wins
-            ..empty_cli()
-        };
+    fn device_dir_name_hashes_long_ids() {
+        assert_eq!(device_dir_name("/dev/ttyACM0"), "_dev_ttyACM0");
+        let multi_line = device_dir_name("Chip is ESP32-S3\nMAC: aa:bb:cc:dd:ee:ff\n");
+        assert_eq!(multi_line.len(), 16);
+        assert!(multi_line.chars().all(|c| c.is_ascii_hexdigit()));
+    }
 
-        let config = apply_cli_overrides(&base, &cli);
-        assert_eq!(config.cache_size, 1024);
+    #[test]
+    fn device_id_uses_flash_identify_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = write_test_toml(dir.path(), "");
+        let mut toml = fs::read_to_string(&toml_path).unwrap();
+        toml.push_str(
+            "\n[flash]\nidentify_command = \"echo mac-{port} | tr a-z A-Z\"\nshell = true\n\n\
+             [flash.firmware]\ncommand = \"true\"\n",
+        );
+        fs::write(&toml_path, toml).unwrap();
+        let config = Config::from_file(&toml_path).unwrap();
+
+        let id = device_id(&config, Some("acm0"), Some("1234"), &[("port", "acm0")]).unwrap();
+        assert_eq!(id.as_deref(), Some("MAC-ACM0"));
+
+        let config = Config::from_file(&write_test_toml(dir.path(), "")).unwrap();
+        let id = device_id(&config, Some("acm0"), Some("1234"), &[]).unwrap();
+        assert_eq!(id.as_deref(), Some("1234"));
     }
 
     // -------------------------------------------------------------------------
-    // apply_cli_overrides: lookahead_size invalidation
+    // Delta flashing
     // -------------------------------------------------------------------------
 
     #[test]
-    fn overrides_lookahead_recomputed_when_block_count_changes() {
-        let base = test_base_config(); // block_count=128, lookahead=16
-        let cli = ImageConfigParams {
-            block_count: Some(64),
-            ..empty_cli()
+    fn delta_flash_writes_changed_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let image_path = dir.path().join("delta_flash_test.bin");
+        let old = vec![0xFFu8; 4 * 4096];
+        let mut new = old.clone();
+        new[4096] = 0;
+        new[3 * 4096 + 10] = 0;
+        fs::write(&image_path, &new).unwrap();
+
+        let flash = |command: String| FilesystemFlash {
+            command: Some(command),
+            path: None,
+            address: "0x200000".into(),
+            partition_table: None,
+            partition_name: None,
+            flasher: None,
+            baud: None,
+            flash_size: None,
+            delta: true,
+            verify_command: None,
+            options: CommandOptions::default(),
         };
 
-        let config = apply_cli_overrides(&base, &cli);
-        assert_eq!(config.block_count, 64);
-        // lookahead_size recomputed by resolve(), not carried from base
-        assert_ne!(config.lookahead_size, 0);
+        // Each range written to its own file at its own address
+        let out = dir.path().join("out");
+        fs::create_dir(&out).unwrap();
+        let per_range = flash(format!("cp {{path}} {}/{{address}}.bin", out.display()));
+        let cache = FlashCache::new(&dir.path().join("cache"), None);
+        flash_changed_ranges(&per_range, &image_path, 4096, &old, &cache, &[], false).unwrap();
+        let chunk = fs::read(out.join("0x201000.bin")).unwrap();
+        assert_eq!(chunk, &new[4096..2 * 4096]);
+        assert!(out.join("0x203000.bin").exists());
+        assert!(!out.join("0x200000.bin").exists());
+
+        // With {offset}/{length}, the whole image is passed for each range
+        let sliced = dir.path().join("sliced");
+        fs::create_dir(&sliced).unwrap();
+        let whole = flash(format!(
+            "cp {{path}} {}/{{offset}}-{{length}}.bin",
+            sliced.display()
+        ));
+        flash_changed_ranges(&whole, &image_path, 4096, &old, &cache, &[], false).unwrap();
+        assert_eq!(fs::read(sliced.join("0x1000-0x1000.bin")).unwrap(), new);
+        assert!(sliced.join("0x3000-0x1000.bin").exists());
+
+        // A dry run writes no chunk files into the cache
+        let dry_cache = dir.path().join("dry-cache");
+        let cache = FlashCache::new(&dry_cache, None);
+        flash_changed_ranges(&per_range, &image_path, 4096, &old, &cache, &[], true).unwrap();
+        assert!(!dry_cache.exists());
     }
 
+    // -------------------------------------------------------------------------
+    // pull
+    // -------------------------------------------------------------------------
+
     #[test]
-    fn overrides_lookahead_recomputed_when_image_size_changes() {
-        let base = test_base_config();
-        let cli = ImageConfigParams {
-            image_size: Some(4096 * 64),
-            ..empty_cli()
+    fn pull_reads_and_unpacks_device_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = write_test_toml(dir.path(), "");
+        fs::write(dir.path().join("site/index.html"), "<html/>").unwrap();
+
+        // What's on the "device": the packed site plus a log written at runtime
+        let config = Config::from_file(&toml_path).unwrap();
+        let target = &config.images[0];
+        let mut device = LfsImage::new(target.image.clone()).unwrap();
+        device.format().unwrap();
+        device.pack_from_config(target.directory.clone()).unwrap();
+        device
+            .mount_and_then(|fs| fs.write_file("/log.txt", b"booted"))
+            .unwrap();
+        let dump = dir.path().join("device.bin");
+        fs::write(&dump, device.data()).unwrap();
+
+        let mut toml = fs::read_to_string(&toml_path).unwrap();
+        toml.push_str(&format!(
+            "\n[flash.firmware]\ncommand = \"true\"\n\n\
+             [flash.filesystem]\ncommand = \"true\"\naddress = \"0x200000\"\n\n\
+             [flash.readback]\ncommand = \"cp {} {{path}}\"\n",
+            dump.display()
+        ));
+        fs::write(&toml_path, toml).unwrap();
+
+        let output = dir.path().join("pulled.bin");
+        let unpacked = dir.path().join("pulled");
+        cmd_pull(
+            &config_source(&Some(toml_path.clone())),
+            PullCmd {
+                output: Some(output.clone()),
+                unpack: Some(unpacked.clone()),
+                diff: true,
+                port: None,
+                serial: None,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(fs::read(&output).unwrap(), device.data());
+        assert_eq!(fs::read(unpacked.join("log.txt")).unwrap(), b"booted");
+        assert_eq!(fs::read(unpacked.join("index.html")).unwrap(), b"<html/>");
+
+        // A read that stops short of the image is padded back out
+        fs::write(&dump, device.trimmed_data()).unwrap();
+        let pull = || {
+            cmd_pull(
+                &config_source(&Some(toml_path.clone())),
+                PullCmd {
+                    output: Some(output.clone()),
+                    unpack: None,
+                    diff: false,
+                    port: None,
+                    serial: None,
+                },
+            )
         };
+        pull().unwrap();
+        assert_eq!(fs::read(&output).unwrap(), device.data());
 
-        let config = apply_cli_overrides(&base, &cli);
-        assert_eq!(config.block_count, 64);
-        // lookahead_size recomputed, not carried forward
-        assert_ne!(config.lookahead_size, 0);
+        // A read of something other than a filesystem fails
+        fs::write(&dump, vec![0x5A; device.data().len()]).unwrap();
+        let err = pull().unwrap_err();
+        assert!(err.to_string().contains("isn't a LittleFS image"), "{err}");
     }
 
-    #[test]
-    fn overrides_lookahead_preserved_when_block_count_unchanged() {
-        let base = test_base_config(); // lookahead=16
-        let cli = ImageConfigParams {
-            block_cycles: Some(100), // unrelated change
-            ..empty_cli()
-        };
+    // -------------------------------------------------------------------------
+    // watch
+    // -------------------------------------------------------------------------
 
-        let config = apply_cli_overrides(&base, &cli);
-        assert_eq!(config.lookahead_size, 16); // preserved
+    #[test]
+    #[cfg(feature = "watch")]
+    fn repack_writes_output_only_on_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = write_test_toml(dir.path(), "");
+        fs::write(dir.path().join("site/index.html"), "<html/>").unwrap();
+        let target = Config::from_file(&toml_path).unwrap().images.remove(0);
+        let mut image = LfsImage::new(target.image.clone()).unwrap();
+        image.format().unwrap();
+        let output = dir.path().join("out.bin");
+
+        let summary = repack(&mut image, &target.directory, &output).unwrap();
+        assert_eq!(summary.added, ["/index.html"]);
+        assert_eq!(fs::read(&output).unwrap(), image.data());
+
+        // Nothing changed, so the output is left alone
+        fs::remove_file(&output).unwrap();
+        fs::write(&output, b"stale").unwrap();
+        let summary = repack(&mut image, &target.directory, &output).unwrap();
+        assert!(summary.is_empty());
+        assert_eq!(fs::read(&output).unwrap(), b"stale");
+
+        fs::write(dir.path().join("site/index.html"), "<html>new</html>").unwrap();
+        fs::write(dir.path().join("site/app.js"), "run()").unwrap();
+        let summary = repack(&mut image, &target.directory, &output).unwrap();
+        assert_eq!(summary_lines(&summary), ["+ /app.js", "~ /index.html"]);
+        assert_eq!(fs::read(&output).unwrap(), image.data());
     }
 
     #[test]
-    fn overrides_explicit_lookahead_always_wins() {
-        let base = test_base_config();
-        let cli = ImageConfigParams {
-            block_count: Some(64),    // would trigger recomputation
-            lookahead_size: Some(32), // but explicit wins
-            ..empty_cli()
+    #[cfg(feature = "watch")]
+    fn previous_image_falls_back_only_when_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = write_test_toml(dir.path(), "");
+        let target = Config::from_file(&toml_path).unwrap().images.remove(0);
+        let output = dir.path().join("out.bin");
+
+        let image = load_previous_image(&output, &target.image).unwrap();
+        assert_eq!(image.config().block_count, target.image.block_count);
+
+        fs::write(&output, b"not an image").unwrap();
+        let Err(err) = load_previous_image(&output, &target.image) else {
+            panic!("a corrupt previous image should not be replaced");
         };
+        assert!(
+            err.to_string()
+                .contains("failed to load the previous image")
+        );
+    }
 
-        let config = apply_cli_overrides(&base, &cli);
-        assert_eq!(config.lookahead_size, 32);
+    #[test]
+    #[cfg(feature = "watch")]
+    fn repack_failure_keeps_previous_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = write_test_toml(dir.path(), "");
+        fs::write(dir.path().join("site/index.html"), "<html/>").unwrap();
+        let target = Config::from_file(&toml_path).unwrap().images.remove(0);
+        let mut image = LfsImage::new(target.image.clone()).unwrap();
+        image.format().unwrap();
+        let output = dir.path().join("out.bin");
+        repack(&mut image, &target.directory, &output).unwrap();
+        let before = image.data().to_vec();
+
+        // More data than the image can hold
+        let size = target.image.block_size * target.image.block_count;
+        fs::write(dir.path().join("site/big.bin"), vec![0xAB; size]).unwrap();
+        assert!(repack(&mut image, &target.directory, &output).is_err());
+        assert_eq!(image.data(), before);
+        assert_eq!(fs::read(&output).unwrap(), before);
     }
 
     // -------------------------------------------------------------------------
-    // Flash helpers: needs_flash / mark_flashed
+    // serve
     // -------------------------------------------------------------------------
 
     #[test]
-    fn needs_flash_returns_hash_for_new_file() {
-        let dir = tempfile::tempdir().unwrap();
-        let file = dir.path().join("new_file_test.bin");
-        fs::write(&file, b"hello").unwrap();
-
-        let result = needs_flash(&file).unwrap();
-        assert!(result.is_some(), "new file should need flashing");
+    #[cfg(feature = "serve")]
+    fn lfs_path_for_url_defaults_root_and_rejects_traversal() {
+        assert_eq!(lfs_path_for_url("/").as_deref(), Some("/index.html"));
+        assert_eq!(lfs_path_for_url("").as_deref(), Some("/index.html"));
+        assert_eq!(
+            lfs_path_for_url("/css/a.css").as_deref(),
+            Some("/css/a.css")
+        );
+        // Only the root gets an index
+        assert_eq!(lfs_path_for_url("/docs/").as_deref(), Some("/docs/"));
+        assert_eq!(lfs_path_for_url("/../secret"), None);
     }
 
     #[test]
-    fn needs_flash_returns_none_after_mark_flashed() {
+    #[cfg(feature = "serve")]
+    fn serve_request_reads_files_from_image() {
         let dir = tempfile::tempdir().unwrap();
-        let file = dir.path().join("mark_test.bin");
-        fs::write(&file, b"hello").unwrap();
+        let toml_path = write_test_toml(dir.path(), "");
+        fs::create_dir(dir.path().join("site/css")).unwrap();
+        fs::write(dir.path().join("site/index.html"), "<html/>").unwrap();
+        fs::write(dir.path().join("site/css/style.css"), "body{}").unwrap();
+        let target = Config::from_file(&toml_path).unwrap().images.remove(0);
+        let mut image = LfsImage::new(target.image.clone()).unwrap();
+        image.format().unwrap();
+        image.pack_from_config(target.directory.clone()).unwrap();
+
+        let root = serve_request(&mut image, "/?v=2");
+        assert_eq!(root.status, 200);
+        assert_eq!(root.content_type, "text/html; charset=utf-8");
+        assert_eq!(root.body, b"<html/>");
+
+        let css = serve_request(&mut image, "/css/style.css");
+        assert_eq!(css.status, 200);
+        assert_eq!(css.content_type, "text/css; charset=utf-8");
+
+        assert_eq!(serve_request(&mut image, "/missing.js").status, 404);
+        assert_eq!(serve_request(&mut image, "/css").status, 404);
+        assert_eq!(serve_request(&mut image, "/css/../index.html").status, 403);
+    }
 
-        let hash = needs_flash(&file).unwrap().unwrap();
-        mark_flashed(&file, &hash).unwrap();
+    // -------------------------------------------------------------------------
+    // Read-back verification
+    // -------------------------------------------------------------------------
 
-        let result = needs_flash(&file).unwrap();
-        assert!(result.is_none(), "unchanged file should not need flashing");
+    #[test]
+    fn mismatched_blocks_counts_bytes_per_block() {
+        let expected = vec![0u8; 3 * 16];
+        let mut actual = expected.clone();
+        actual[17] = 1;
+        actual[18] = 1;
+        actual.truncate(40);
+        assert_eq!(mismatched_blocks(&expected, &actual, 16), [(1, 2), (2, 8)]);
+        assert!(mismatched_blocks(&expected, &expected, 16).is_empty());
     }
 
     #[test]
-    fn needs_flash_returns_hash_after_file_changes() {
+    fn verify_flash_detects_bad_readback() {
         let dir = tempfile::tempdir().unwrap();
-        let file = dir.path().join("changed_test.bin");
-        fs::write(&file, b"hello").unwrap();
+        let toml_path = write_test_toml(dir.path(), "");
+        let mut target = Config::from_file(&toml_path).unwrap().images.remove(0);
+        target.flash = Some(FilesystemFlash {
+            command: Some("true".into()),
+            path: None,
+            address: "0x200000".into(),
+            partition_table: None,
+            partition_name: None,
+            flasher: None,
+            baud: None,
+            flash_size: None,
+            delta: false,
+            verify_command: None,
+            options: CommandOptions::default(),
+        });
+        let cache = FlashCache::new(&dir.path().join("cache"), None);
+
+        let mut image = LfsImage::new(target.image.clone()).unwrap();
+        image.format().unwrap();
+        image
+            .mount_and_then(|fs| fs.write_file("/hello.txt", b"hello"))
+            .unwrap();
+        let image_path = dir.path().join("verify_test.bin");
+        fs::write(&image_path, image.data()).unwrap();
+
+        // A faithful read-back passes
+        verify_flash(
+            &target,
+            "cp {path} {readback}",
+            &image_path,
+            &cache,
+            &[],
+            false,
+        )
+        .unwrap();
 
-        let hash = needs_flash(&file).unwrap().unwrap();
-        mark_flashed(&file, &hash).unwrap();
+        // A read-back missing its last blocks fails
+        let partial = dir.path().join("partial.bin");
+        fs::write(&partial, &image.data()[..4096]).unwrap();
+        let command = format!("cp {} {{readback}}", partial.display());
+        let err = verify_flash(&target, &command, &image_path, &cache, &[], false).unwrap_err();
+        assert!(err.to_string().contains("differs"), "{err}");
+    }
 
-        // Modify the file
-        fs::write(&file, b"world").unwrap();
+    #[test]
+    fn verify_flash_accepts_trimmed_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml_path = write_test_toml(dir.path(), "");
+        let mut target = Config::from_file(&toml_path).unwrap().images.remove(0);
+        target.flash = Some(FilesystemFlash {
+            command: Some("true".into()),
+            path: None,
+            address: "0x200000".into(),
+            partition_table: None,
+            partition_name: None,
+            flasher: None,
+            baud: None,
+            flash_size: None,
+            delta: false,
+            verify_command: None,
+            options: CommandOptions::default(),
+        });
+        let cache = FlashCache::new(&dir.path().join("cache"), None);
+
+        let mut image = LfsImage::new(target.image.clone()).unwrap();
+        image.format().unwrap();
+        image
+            .mount_and_then(|fs| fs.write_file("/hello.txt", b"hello"))
+            .unwrap();
+        let trimmed = image.trimmed_data().to_vec();
+        assert!(trimmed.len() < image.data().len());
+        let mut reloaded = image_from_flash(&target.image, trimmed.clone()).unwrap();
+        assert_eq!(reloaded.config().block_count, target.image.block_count);
+        assert_eq!(
+            sorted_contents(&mut reloaded).unwrap(),
+            sorted_contents(&mut image).unwrap()
+        );
+        let image_path = dir.path().join("trimmed.bin");
+        fs::write(&image_path, &trimmed).unwrap();
+
+        verify_flash(
+            &target,
+            "cp {path} {readback}",
+            &image_path,
+            &cache,
+            &[],
+            false,
+        )
+        .unwrap();
 
-        let result = needs_flash(&file).unwrap();
-        assert!(result.is_some(), "modified file should need flashing");
+        // A corrupted read-back is reported as a block mismatch rather
+        // than failing to load the trimmed image
+        let mut corrupted = trimmed.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xFF;
+        let bad = dir.path().join("bad.bin");
+        fs::write(&bad, &corrupted).unwrap();
+        let command = format!("cp {} {{readback}}", bad.display());
+        let err = verify_flash(&target, &command, &image_path, &cache, &[], false).unwrap_err();
+        assert!(err.to_string().contains("differs"), "{err}");
     }
 
     #[test]
-    fn needs_flash_errors_on_missing_file() {
-        let result = needs_flash(Path::new("/nonexistent/file.bin"));
-        assert!(result.is_err());
+    fn diff_contents_lists_changed_entries() {
+        let entry = |path: &str, data: Option<&[u8]>| (path.to_string(), data.map(<[u8]>::to_vec));
+        let expected = [
+            entry("/a", Some(b"a")),
+            entry("/b", Some(b"b")),
+            entry("/d", None),
+        ];
+        let actual = [
+            entry("/a", Some(b"x")),
+            entry("/c", Some(b"c")),
+            entry("/d", None),
+        ];
+        assert_eq!(
+            diff_contents(&expected, &actual),
+            ["differs:    /a", "missing:    /b", "unexpected: /c"]
+        );
     }
 
     // -------------------------------------------------------------------------
@@ -1264,31 +4071,124 @@ glob_includes = []
         // `true` ignores arguments and always succeeds
         run_command(
             "true {path} {address}",
+            &CommandOptions::default(),
             &[("path", "/fw.bin"), ("address", "0x1000")],
+            false,
         )
         .unwrap();
     }
 
     #[test]
     fn run_command_no_placeholders() {
-        run_command("true", &[]).unwrap();
+        run_command("true", &CommandOptions::default(), &[], false).unwrap();
     }
 
     #[test]
     fn run_command_nonzero_exit_is_error() {
-        let result = run_command("false", &[]);
+        let result = run_command("false", &CommandOptions::default(), &[], false);
         assert!(result.is_err());
     }
 
     #[test]
     fn run_command_missing_program_is_error() {
-        let result = run_command("nonexistent_binary_that_should_not_exist_xxyz", &[]);
+        let result = run_command(
+            "nonexistent_binary_that_should_not_exist_xxyz",
+            &CommandOptions::default(),
+            &[],
+            false,
+        );
         assert!(result.is_err());
     }
 
     #[test]
     fn run_command_empty_template_is_error() {
-        let result = run_command("", &[]);
+        let result = run_command("", &CommandOptions::default(), &[], false);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn capture_command_returns_stdout() {
+        let out = capture_command(
+            "echo {port}",
+            &CommandOptions::default(),
+            &[("port", "/dev/ttyUSB0")],
+        )
+        .unwrap();
+        assert_eq!(out.trim(), "/dev/ttyUSB0");
+        assert!(capture_command("false", &CommandOptions::default(), &[]).is_err());
+    }
+
+    #[test]
+    fn expand_quotes_values_and_skips_unknown_placeholders() {
+        let vars = [("path", "my image.bin"), ("address", "{path}")];
+        assert_eq!(
+            expand("w {address} {path} {other}", &vars, false),
+            "w {path} my image.bin {other}"
+        );
+        assert_eq!(expand("w {path}", &vars, true), "w 'my image.bin'");
+    }
+
+    #[test]
+    fn command_arguments_keep_spaces() {
+        let out = capture_command(
+            "printf '%s|' {path} \"two words\"",
+            &CommandOptions::default(),
+            &[("path", "/tmp/my image.bin")],
+        )
+        .unwrap();
+        assert_eq!(out, "/tmp/my image.bin|two words|");
+    }
+
+    #[test]
+    fn shell_command_with_env_and_cwd() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = CommandOptions {
+            shell: true,
+            env: [("GREETING".to_string(), "hello".to_string())].into(),
+            cwd: Some(dir.path().to_owned()),
+        };
+        let out = capture_command(
+            "echo $GREETING {name} | tr a-z A-Z; pwd",
+            &options,
+            &[("name", "it's")],
+        )
+        .unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("HELLO IT'S"));
+        assert_eq!(
+            Path::new(lines.next().unwrap()).canonicalize().unwrap(),
+            dir.path().canonicalize().unwrap()
+        );
+    }
+
+    #[test]
+    fn windows_templates_keep_backslashes() {
+        assert_eq!(
+            split_windows(r#"C:\Espressif\esptool.exe write_flash {address} {path}"#).unwrap(),
+            [
+                r"C:\Espressif\esptool.exe",
+                "write_flash",
+                "{address}",
+                "{path}"
+            ]
+        );
+        assert_eq!(
+            split_windows(r#""C:\Program Files\tool.exe" -o "" x"#).unwrap(),
+            [r"C:\Program Files\tool.exe", "-o", "", "x"]
+        );
+        assert!(split_windows(r#"tool "open"#).is_err());
+    }
+
+    #[test]
+    fn cmd_quote_uses_double_quotes() {
+        assert_eq!(cmd_quote(r"C:\out\fs.bin"), r"C:\out\fs.bin");
+        assert_eq!(cmd_quote("my image.bin"), "\"my image.bin\"");
+        assert_eq!(cmd_quote("say \"hi\"&"), "\"say \"\"hi\"\"&\"");
+        assert_eq!(cmd_quote(""), "\"\"");
+    }
+
+    #[test]
+    fn dry_run_does_not_execute() {
+        run_command("false {path}", &CommandOptions::default(), &[], true).unwrap();
+    }
 }